@@ -0,0 +1,51 @@
+//! 100 writes awaited one after another against the same writes joined with
+//! `join_all`, which submits them to the kernel together
+//!
+//! Run with `cargo bench --bench batch_write`
+
+use agrippa::fs::File;
+use agrippa::runtime::{Priority, Reactor, Result};
+use agrippa::util::join_all;
+use std::time::{Duration, Instant};
+
+const WRITES: usize = 100;
+const WRITE_SIZE: usize = 4096;
+const ROUNDS: u32 = 100;
+
+/// Return the average time of writing `WRITES` chunks to the file at `path`
+fn run(path: std::path::PathBuf, batched: bool) -> Result<Duration> {
+    let reactor = Reactor::new(1024 * 1024)?;
+    let result = std::rc::Rc::new(std::cell::Cell::new(Duration::from_secs(0)));
+    let r = result.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let file = File::create(&path).await?;
+        let chunk = vec![7u8; WRITE_SIZE];
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            if batched {
+                let writes = (0..WRITES).map(|i| file.write_all(&chunk, (i * WRITE_SIZE) as u64));
+                for res in join_all(writes).await {
+                    res?;
+                }
+            } else {
+                for i in 0..WRITES {
+                    file.write_all(&chunk, (i * WRITE_SIZE) as u64).await?;
+                }
+            }
+        }
+        r.set(start.elapsed() / ROUNDS);
+        Ok(())
+    });
+    reactor.run()?;
+    Ok(result.get())
+}
+
+fn main() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("agrippa-bench-{}", std::process::id()));
+    for &(name, batched) in &[("sequential", false), ("batched", true)] {
+        let elapsed = run(path.clone(), batched)?;
+        println!("{:>10}: {} writes in {:?}", name, WRITES, elapsed);
+    }
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
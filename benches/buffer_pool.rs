@@ -0,0 +1,75 @@
+//! Reads into a fresh `Vec` per read against reads into pooled scratch buffers,
+//! counting the allocations made by each
+//!
+//! Run with `cargo bench --bench buffer_pool`
+
+use agrippa::fs::File;
+use agrippa::runtime::{Priority, Reactor, Result};
+use agrippa::util::acquire_buffer;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Allocator counting the allocations made through it
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const READS: usize = 10_000;
+const READ_SIZE: usize = 64 * 1024;
+
+/// Run `READS` reads of the file at `path`, return the time taken and the
+/// number of allocations made
+fn run(path: std::path::PathBuf, pooled: bool) -> Result<(Duration, usize)> {
+    let reactor = Reactor::new(1024 * 1024)?;
+    let result = std::rc::Rc::new(std::cell::Cell::new((Duration::from_secs(0), 0)));
+    let r = result.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let file = File::open(&path).await?;
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..READS {
+            if pooled {
+                let mut buf = acquire_buffer(READ_SIZE).await?;
+                file.read(&mut buf, 0).await?;
+            } else {
+                let mut buf = vec![0; READ_SIZE];
+                file.read(&mut buf, 0).await?;
+            }
+        }
+        r.set((
+            start.elapsed(),
+            ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        ));
+        Ok(())
+    });
+    reactor.run()?;
+    Ok(result.get())
+}
+
+fn main() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("agrippa-bench-{}", std::process::id()));
+    std::fs::write(&path, vec![1u8; READ_SIZE])?;
+    for &(name, pooled) in &[("vec", false), ("pooled", true)] {
+        let (elapsed, allocations) = run(path.clone(), pooled)?;
+        println!(
+            "{:>6}: {} reads in {:?}, {} allocations",
+            name, READS, elapsed, allocations
+        );
+    }
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Iterating a directory of many files with borrowed and with owned entries
+//!
+//! Run with `cargo bench --bench read_dir`
+
+use agrippa::fs::read_dir;
+use agrippa::runtime::{Priority, Reactor, Result};
+use std::time::{Duration, Instant};
+
+const FILES: usize = 20_000;
+
+/// Return the time taken to iterate the directory at `path`, and the number of entries
+fn run(path: std::path::PathBuf, owned: bool) -> Result<(Duration, usize)> {
+    let reactor = Reactor::new(1024 * 1024)?;
+    let result = std::rc::Rc::new(std::cell::Cell::new((Duration::from_secs(0), 0)));
+    let r = result.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let start = Instant::now();
+        let mut dir = read_dir(&path).await?;
+        let mut count = 0;
+        let mut name_bytes = 0;
+        if owned {
+            while let Some(entry) = dir.next_owned().await? {
+                count += 1;
+                name_bytes += entry.name().len();
+            }
+        } else {
+            while let Some(entry) = dir.next_entry().await? {
+                count += 1;
+                name_bytes += entry.name().len();
+            }
+        }
+        assert!(name_bytes >= count);
+        r.set((start.elapsed(), count));
+        Ok(())
+    });
+    reactor.run()?;
+    Ok(result.get())
+}
+
+fn main() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("agrippa-bench-{}", std::process::id()));
+    std::fs::create_dir(&path)?;
+    for i in 0..FILES {
+        std::fs::File::create(path.join(format!("file-{}", i)))?;
+    }
+    for &(name, owned) in &[("borrowed", false), ("owned", true)] {
+        let (elapsed, count) = run(path.clone(), owned)?;
+        assert_eq!(count, FILES);
+        println!("{:>8}: {} entries in {:?}", name, count, elapsed);
+    }
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
+}
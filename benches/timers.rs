@@ -0,0 +1,51 @@
+//! 100k concurrent sleeps with a kernel timeout per sleep, with the timer heap
+//! and with the timing wheel
+//!
+//! Run with `cargo bench --bench timers`
+
+use agrippa::runtime::{Priority, Reactor, ReactorConfig, Result};
+use agrippa::util::{sleep, uring_sleep};
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+const SLEEPS: usize = 100_000;
+
+#[derive(Clone, Copy)]
+enum Timers {
+    /// An IORING_OP_TIMEOUT per sleep
+    PerTimer,
+    Heap,
+    Wheel,
+}
+
+fn run(timers: Timers) -> Result<Duration> {
+    let mut config = ReactorConfig::new();
+    config.timer_wheel(matches!(timers, Timers::Wheel));
+    let reactor = Reactor::with_config(&config)?;
+    let mut rng = rand::thread_rng();
+    let start = Instant::now();
+    for _ in 0..SLEEPS {
+        let duration = Duration::from_micros(rng.gen_range(1_000, 100_000));
+        reactor.spawn(Priority::Normal, async move {
+            match timers {
+                Timers::PerTimer => uring_sleep(duration).await,
+                Timers::Heap | Timers::Wheel => sleep(duration).await,
+            }
+        });
+    }
+    reactor.run()?;
+    // The sleeps themselves take up to 100ms, the rest is timer overhead
+    Ok(start.elapsed())
+}
+
+fn main() -> Result<()> {
+    for &(name, timers) in &[
+        ("uring", Timers::PerTimer),
+        ("heap", Timers::Heap),
+        ("wheel", Timers::Wheel),
+    ] {
+        let elapsed = run(timers)?;
+        println!("{:>5}: {} sleeps in {:?}", name, SLEEPS, elapsed);
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! CPU time used by the reactor while a verbs connection waits for a receive that
+//! never arrives. A reactor that blocks for completions uses close to none, a
+//! reactor that spins on the completion queue uses a full core.
+//!
+//! Run with `cargo bench --features verbs --bench verbs_idle`, on a host with an rdma device
+
+use agrippa::runtime::{Error, Priority, Reactor, ReactorConfig, Result};
+use agrippa::util::timeout;
+use agrippa::verbs;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const IDLE: Duration = Duration::from_secs(2);
+
+/// Return the user and system CPU time used by the process so far
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) }, 0);
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Return the fraction of a core used while `b` waits idle for a receive
+fn run(config: &ReactorConfig) -> Result<f64> {
+    let reactor = Reactor::with_config(config)?;
+    let result = Rc::new(Cell::new(0.0));
+    let r = result.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let a = verbs::connect().await?;
+        let b = verbs::connect().await?;
+        let (a_address, b_address) = (a.local_address(), b.local_address());
+        let a = a.connect(&b_address)?;
+        let b = b.connect(&a_address)?;
+        a.wait_until_rts().await?;
+        b.wait_until_rts().await?;
+
+        let (start, cpu_start) = (Instant::now(), cpu_time());
+        match timeout(IDLE, b.recv()).await {
+            Err(Error::Timeout) => (),
+            Ok(_) => return Err(Error::Internal("unexpected receive")),
+            Err(e) => return Err(e),
+        }
+        r.set((cpu_time() - cpu_start).as_secs_f64() / start.elapsed().as_secs_f64());
+        drop(a);
+        Ok(())
+    });
+    reactor.run()?;
+    Ok(result.get())
+}
+
+fn main() -> Result<()> {
+    let mut with_max_wait = ReactorConfig::new();
+    with_max_wait.max_wait(Duration::from_millis(100));
+    for (name, config) in [
+        ("default", ReactorConfig::new()),
+        ("max_wait", with_max_wait),
+    ] {
+        match run(&config) {
+            Ok(usage) => println!("{:>10}: {:.1}% cpu while idle", name, usage * 100.0),
+            Err(e) => {
+                println!("no rdma device: {}", e);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
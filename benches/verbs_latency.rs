@@ -0,0 +1,57 @@
+//! Round trip latency of small messages over a loopback verbs connection, which
+//! is dominated by how soon the reactor polls a task once its completion arrives
+//!
+//! Run with `cargo bench --features verbs --bench verbs_latency`, on a host with an rdma device
+
+use agrippa::runtime::{Priority, Reactor, ReactorConfig, Result};
+use agrippa::verbs;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const ROUNDS: u32 = 10_000;
+
+/// Return the average time of a send completion, and of a round trip
+fn run() -> Result<(Duration, Duration)> {
+    let mut config = ReactorConfig::new();
+    config.verbs_buffer_size(4096);
+    let reactor = Reactor::with_config(&config)?;
+    let result = Rc::new(Cell::new((Duration::ZERO, Duration::ZERO)));
+    let r = result.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let a = verbs::connect().await?;
+        let b = verbs::connect().await?;
+        let (a_address, b_address) = (a.local_address(), b.local_address());
+        let a = a.connect(&b_address)?;
+        let b = b.connect(&a_address)?;
+        a.wait_until_rts().await?;
+        b.wait_until_rts().await?;
+
+        let mut send = Duration::ZERO;
+        let start = Instant::now();
+        for imm in 0..ROUNDS {
+            let send_start = Instant::now();
+            a.send_with_imm(verbs::get_buffer().await?, imm).await?;
+            send += send_start.elapsed();
+            // Bounce the message back
+            let buffer = b.recv().await?;
+            b.send(buffer).await?;
+            verbs::put_buffer(a.recv().await?).await?;
+        }
+        r.set((send / ROUNDS, start.elapsed() / ROUNDS));
+        Ok(())
+    });
+    reactor.run()?;
+    Ok(result.get())
+}
+
+fn main() -> Result<()> {
+    match run() {
+        Ok((send, round_trip)) => {
+            println!("      send: {:?}", send);
+            println!("round trip: {:?}", round_trip);
+        }
+        Err(e) => println!("no rdma device: {}", e),
+    }
+    Ok(())
+}
@@ -20,8 +20,8 @@ fn main() -> Result<()> {
         let connection_builder = verbs::connect().await?;
         let addr = connection_builder.local_address();
         info!("Send addr {:?}", addr);
-        socket.write_item(&addr).await;
-        let remote_addr = unsafe { socket.read_item::<verbs::VerbsAddr>().await? };
+        socket.write_struct(&addr).await?;
+        let remote_addr = socket.read_struct::<verbs::VerbsAddr>().await?;
         socket.close().await?;
         info!("Got remote addr {:?}", remote_addr);
         let conn = connection_builder.connect(&remote_addr)?;
@@ -19,7 +19,7 @@ fn main() -> Result<()> {
         file.close().await?;
 
         let file = File::create("out").await?;
-        file.write(&data, 0).await?;
+        file.write_all(&data, 0).await?;
         file.close().await?;
 
         Ok(())
@@ -0,0 +1,42 @@
+use agrippa::compat::Compat;
+use agrippa::{
+    runtime::{Priority, Reactor, Result},
+    tcp::listen,
+    util::spawn_task,
+};
+use futures::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use log::info;
+
+extern crate simple_logger;
+
+/// Answer each line sent by the client with the line reversed,
+/// using the line codec of the futures crate
+async fn handle_client(socket: Compat<agrippa::tcp::Socket>) -> Result<()> {
+    let mut socket = BufReader::new(socket);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if socket.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let reversed: String = line.trim_end().chars().rev().collect();
+        info!("Got line {:?}", line.trim_end());
+        socket.write_all(reversed.as_bytes()).await?;
+        socket.write_all(b"\n").await?;
+        socket.flush().await?;
+    }
+}
+
+fn main() -> Result<()> {
+    simple_logger::init().unwrap();
+
+    let reactor = Reactor::new(1024 * 1024)?;
+    reactor.spawn(Priority::Normal, async move {
+        let listener = listen("127.0.0.1:1234").await?;
+        loop {
+            let socket = Compat::new(listener.accept().await?);
+            spawn_task(Priority::Normal, handle_client(socket)).await?;
+        }
+    });
+    reactor.run()
+}
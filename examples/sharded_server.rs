@@ -0,0 +1,42 @@
+use agrippa::runtime::{run_sharded, Priority, ReactorConfig, Result};
+use agrippa::tcp::{ListenOptions, Socket};
+use agrippa::util::spawn_task;
+use log::info;
+
+extern crate simple_logger;
+
+async fn handle_client(socket: &Socket) -> Result<()> {
+    let mut data = [0; 4096];
+    loop {
+        let len = socket.read(&mut data).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        socket.write_all(&data[..len]).await?;
+    }
+}
+
+fn main() -> Result<()> {
+    simple_logger::init().unwrap();
+
+    // Each shard accepts its share of the connections on its own listener
+    run_sharded(4, &ReactorConfig::new(), |shard, reactor| {
+        reactor.spawn(Priority::Normal, async move {
+            let listener = ListenOptions::new()
+                .reuse_port(true)
+                .listen("127.0.0.1:1234")
+                .await?;
+            info!("Shard {} listening", shard);
+            loop {
+                let socket = listener.accept().await?;
+                spawn_task(Priority::Normal, async move {
+                    let res = handle_client(&socket).await;
+                    socket.close().await?;
+                    res
+                })
+                .await?;
+            }
+        });
+        Ok(())
+    })
+}
@@ -26,7 +26,7 @@ fn main() -> Result<()> {
 
                 //let mut buffer = [0 as u8; 10
 
-                let remote_addr = unsafe { socket.read_item::<verbs_util::VerbsAddr>().await? };
+                let remote_addr = socket.read_struct::<verbs_util::VerbsAddr>().await?;
 
                 let connection_builder = verbs::connect().await?;
                 let local_addr = connection_builder.local_address();
@@ -34,7 +34,7 @@ fn main() -> Result<()> {
                 info!("Got addr {:?}", remote_addr);
                 //let read = socket.read(&mut buffer).await?;
 
-                socket.write_item(&local_addr).await?;
+                socket.write_struct(&local_addr).await?;
                 info!("Send addr {:?}", local_addr);
 
                 let conn = connection_builder.connect(&remote_addr)?;
@@ -0,0 +1,208 @@
+use crate::runtime::{current_task, detach_in_flight, Error, Result, TaskRef};
+use crate::util::{AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+type Pending<T> = Pin<Box<dyn Future<Output = T>>>;
+
+fn to_io_error(error: Error) -> std::io::Error {
+    use std::io::ErrorKind;
+    match error {
+        Error::Io(e) => e,
+        Error::Cancel => std::io::Error::new(ErrorKind::Interrupted, "Cancel"),
+        Error::Timeout => std::io::Error::new(ErrorKind::TimedOut, "Timeout"),
+        Error::Eof => std::io::Error::new(ErrorKind::UnexpectedEof, "Eof"),
+        e => std::io::Error::new(ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Adapter implementing `futures::io::AsyncRead` and `futures::io::AsyncWrite`
+/// for an agrippa stream such as [`tcp::Socket`], so that it can be used with
+/// protocol crates built on the futures io traits.
+///
+/// The adapter can only be polled by a task running on an agrippa reactor,
+/// possibly through futures combinators. The io is performed by the reactor
+/// and wakes the agrippa task, not the waker of the context passed to the
+/// poll functions. When polled by any other executor the poll functions fail
+/// with an error instead.
+///
+/// A read or write started by a poll that returned pending stays in flight
+/// until it is polled again. If the adapter is dropped meanwhile, the
+/// operation is canceled, and its buffer is freed once it has completed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::compat::Compat;
+/// use agrippa::tcp::connect;
+/// use futures::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+/// # async fn example() -> agrippa::runtime::Result<()> {
+/// let mut socket = BufReader::new(Compat::new(connect("127.0.0.1:1234").await?));
+/// socket.write_all(b"hello\n").await?;
+/// let mut line = String::new();
+/// socket.read_line(&mut line).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`tcp::Socket`]: ../tcp/struct.Socket.html
+pub struct Compat<S: 'static> {
+    inner: Rc<S>,
+    /// The read in flight, if any
+    read: Option<Pending<(Vec<u8>, Result<usize>)>>,
+    /// The write in flight, if any
+    write: Option<Pending<Result<usize>>>,
+    /// Bytes read but not yet returned, and the position of the first of them
+    buffer: Vec<u8>,
+    position: usize,
+    /// io_uring operations started by the read and write in flight, with the
+    /// tasks that started them
+    ops: Vec<(TaskRef, u64)>,
+}
+
+impl<S: 'static> Compat<S> {
+    pub fn new(inner: S) -> Self {
+        Compat {
+            inner: Rc::new(inner),
+            read: None,
+            write: None,
+            buffer: Vec::new(),
+            position: 0,
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return the wrapped stream.
+    ///
+    /// Fails if a read or write is in flight. Bytes read but not yet returned
+    /// by `poll_read` are lost.
+    pub fn into_inner(self) -> Result<S> {
+        if self.read.is_some() || self.write.is_some() {
+            return Err(Error::Internal("Compat has an operation in flight"));
+        }
+        let inner = self.inner.clone();
+        drop(self);
+        Rc::try_unwrap(inner).map_err(|_| Error::Internal("Compat stream is shared"))
+    }
+
+    /// Poll `pending`, recording the io_uring operations it starts
+    fn poll_tracked<T>(
+        ops: &mut Vec<(TaskRef, u64)>,
+        task: &TaskRef,
+        pending: &mut Pending<T>,
+        context: &mut Context,
+    ) -> Poll<T> {
+        let before = task.in_flight.borrow().len();
+        let res = pending.as_mut().poll(context);
+        ops.extend(
+            task.in_flight.borrow()[before..]
+                .iter()
+                .map(|op| (task.clone(), *op)),
+        );
+        ops.retain(|(task, op)| task.in_flight.borrow().contains(op));
+        res
+    }
+}
+
+impl<S: 'static> Drop for Compat<S> {
+    fn drop(&mut self) {
+        let read = self.read.take();
+        let write = self.write.take();
+        if read.is_none() && write.is_none() {
+            return;
+        }
+        // The operations must complete before their buffers are freed
+        detach_in_flight(&self.ops, Box::new((read, write)));
+    }
+}
+
+impl<S: AsyncRead + 'static> futures::io::AsyncRead for Compat<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let task = current_task().map_err(to_io_error)?;
+        if this.position == this.buffer.len() {
+            if this.read.is_none() {
+                let inner = this.inner.clone();
+                let mut data = std::mem::replace(&mut this.buffer, Vec::new());
+                data.resize(buf.len(), 0);
+                this.read = Some(Box::pin(async move {
+                    let res = inner.read(&mut data).await;
+                    (data, res)
+                }));
+            }
+            let read = this.read.as_mut().unwrap();
+            let (mut data, res) = match Self::poll_tracked(&mut this.ops, &task, read, context) {
+                Poll::Ready(v) => v,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.read = None;
+            let len = res.map_err(to_io_error)?;
+            data.truncate(len);
+            this.buffer = data;
+            this.position = 0;
+        }
+        let len = std::cmp::min(buf.len(), this.buffer.len() - this.position);
+        buf[..len].copy_from_slice(&this.buffer[this.position..this.position + len]);
+        this.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<S: AsyncWrite + 'static> Compat<S> {
+    fn poll_pending_write(
+        &mut self,
+        task: &TaskRef,
+        context: &mut Context,
+    ) -> Poll<std::io::Result<usize>> {
+        let res = match self.write.as_mut() {
+            Some(write) => match Self::poll_tracked(&mut self.ops, task, write, context) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            },
+            None => return Poll::Ready(Ok(0)),
+        };
+        self.write = None;
+        Poll::Ready(res.map_err(to_io_error))
+    }
+}
+
+impl<S: AsyncWrite + 'static> futures::io::AsyncWrite for Compat<S> {
+    /// Write bytes from `buf`. If a previous call returned pending, the
+    /// write it started is completed instead, and its result is returned.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let task = current_task().map_err(to_io_error)?;
+        if this.write.is_none() {
+            let inner = this.inner.clone();
+            let data = buf.to_vec();
+            this.write = Some(Box::pin(async move { inner.write(&data).await }));
+        }
+        this.poll_pending_write(&task, context)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let task = current_task().map_err(to_io_error)?;
+        this.poll_pending_write(&task, context).map_ok(|_| ())
+    }
+
+    /// Complete the write in flight. The stream itself is closed when it is
+    /// dropped, or by closing the stream returned by `into_inner`
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<()>> {
+        self.poll_flush(context)
+    }
+}
@@ -1,7 +1,15 @@
-use crate::io_uring_util::{Close, Fd, OpenAt, Read, Write};
-use crate::runtime::{Error, Result};
+use crate::io_uring_util::{
+    Close, FGetXattr, FSetXattr, Fallocate, Fd, Fsync, OpenAt, OwnedRead, OwnedWrite, Read,
+    SyncFileRange, Write, IORING_OP_FGETXATTR, IORING_OP_FSETXATTR,
+};
+use crate::runtime::{current_task, Error, Priority, Result, Task};
+use crate::sys::{IORING_OP_CLOSE, IORING_OP_OPENAT};
+use crate::util::{acquire_buffer, current_reactor, run_on_thread, try_join_all};
 use libc;
+use log::info;
+use std::cell::{Cell, RefCell};
 use std::path::Path;
+use std::rc::Rc;
 
 /// Options and flags which can be used to configure how a file is opened.
 ///
@@ -59,6 +67,9 @@ pub struct OpenOptions {
     no_atime: bool,
     no_follow: bool,
     temp_file: bool,
+    path: bool,
+    directory: bool,
+    nonblock: bool,
     user_read: bool,
     user_write: bool,
     user_execute: bool,
@@ -70,6 +81,10 @@ pub struct OpenOptions {
     other_execute: bool,
     set_user_id: bool,
     set_group_id: bool,
+    /// Mode set with `mode_exact`, overriding the mode flags
+    mode_exact: Option<u32>,
+    /// Set the mode with fchmod after opening, bypassing the umask
+    chmod: bool,
 }
 
 impl OpenOptions {
@@ -98,6 +113,9 @@ impl OpenOptions {
             no_atime: false,
             no_follow: false,
             temp_file: false,
+            path: false,
+            directory: false,
+            nonblock: false,
             user_read: true,
             user_write: true,
             user_execute: false,
@@ -109,6 +127,8 @@ impl OpenOptions {
             other_execute: false,
             set_user_id: false,
             set_group_id: false,
+            mode_exact: None,
+            chmod: false,
         }
     }
 
@@ -335,6 +355,58 @@ impl OpenOptions {
         self
     }
 
+    /// Obtain a file descriptor that only references a location in the filesystem (O_PATH).
+    ///
+    /// The resulting `File` can not be read from or written to. It can be used as
+    /// the directory relative opens are resolved against, and to stat the path
+    /// without following a final symbolic link when combined with `no_follow(true)`.
+    /// All flags other than `close_on_exec`, `directory` and `no_follow` are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use agrippa::fs::OpenOptions;
+    ///
+    /// let dir = OpenOptions::new().path(true).directory(true).open("/srv/data").await?;
+    /// ```
+    pub fn path(&mut self, path: bool) -> &mut Self {
+        self.path = path;
+        self
+    }
+
+    /// If pathname is not a directory, cause the open to fail with the error ENOTDIR (O_DIRECTORY).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use agrippa::fs::OpenOptions;
+    ///
+    /// let dir = OpenOptions::new().read(true).directory(true).open("/tmp").await?;
+    /// ```
+    pub fn directory(&mut self, directory: bool) -> &mut Self {
+        self.directory = directory;
+        self
+    }
+
+    /// Open the file in non-blocking mode (O_NONBLOCK).
+    ///
+    /// Opening the read end of a FIFO without a writer then returns right away
+    /// instead of waiting for a writer, and the same for device files that would
+    /// otherwise block in open. Reads and writes through io_uring still wait for
+    /// data, as io_uring polls non-blocking files internally.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use agrippa::fs::OpenOptions;
+    ///
+    /// let fifo = OpenOptions::new().read(true).nonblock(true).open("/run/app.fifo").await?;
+    /// ```
+    pub fn nonblock(&mut self, nonblock: bool) -> &mut Self {
+        self.nonblock = nonblock;
+        self
+    }
+
     /// Set user has read permission mode flag (0o400)
     pub fn user_read(&mut self, user_read: bool) -> &mut Self {
         self.user_read = user_read;
@@ -405,6 +477,50 @@ impl OpenOptions {
         self
     }
 
+    /// Set the full mode a created file gets, such as `0o600`, instead of
+    /// building it from the individual mode flags, which are then ignored.
+    ///
+    /// Like the mode flags, the mode is subject to the umask of the process
+    /// when the file is created, so bits set in the umask are cleared. Use
+    /// [`create_mode`] to get exactly the given permissions.
+    ///
+    /// [`create_mode`]: #method.create_mode
+    pub fn mode_exact(&mut self, mode: u32) -> &mut Self {
+        self.mode_exact = Some(mode & 0o7777);
+        self.chmod = false;
+        self
+    }
+
+    /// Like [`mode_exact`], but also set the mode of the file with fchmod after
+    /// opening it, so that it gets exactly the permissions given regardless of
+    /// the umask.
+    ///
+    /// The mode is also set if the file already existed, combine with
+    /// `create(true)` and `exclusive(true)` to only ever apply it to a new file. Until the fchmod
+    /// the file has the mode with the umask applied, which is never more
+    /// permissive than the requested one.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::OpenOptions;
+    ///
+    /// let file = OpenOptions::new()
+    ///     .write(true)
+    ///     .create(true)
+    ///     .exclusive(true)
+    ///     .create_mode(0o600)
+    ///     .open("secret.key")
+    ///     .await?;
+    /// ```
+    ///
+    /// [`mode_exact`]: #method.mode_exact
+    pub fn create_mode(&mut self, mode: u32) -> &mut Self {
+        self.mode_exact(mode);
+        self.chmod = true;
+        self
+    }
+
     fn flags(&self) -> u32 {
         let mut flags = 0;
         if self.read && self.write {
@@ -441,10 +557,22 @@ impl OpenOptions {
         if self.temp_file {
             flags |= libc::O_TMPFILE;
         }
+        if self.path {
+            flags |= libc::O_PATH;
+        }
+        if self.directory {
+            flags |= libc::O_DIRECTORY;
+        }
+        if self.nonblock {
+            flags |= libc::O_NONBLOCK;
+        }
         flags as u32
     }
 
     fn mode(&self) -> u32 {
+        if let Some(mode) = self.mode_exact {
+            return mode;
+        }
         let mut mode = 0;
         if self.user_read {
             mode |= libc::S_IRUSR;
@@ -495,16 +623,167 @@ impl OpenOptions {
         use std::os::unix::ffi::OsStrExt;
 
         let path = std::ffi::CString::new(path.as_ref().as_os_str().as_bytes())?;
+        if !current_reactor().await?.supports(IORING_OP_OPENAT as u8) {
+            // Fall back to a blocking open on kernels without IORING_OP_OPENAT
+            let fd = unsafe { libc::open(path.as_ptr(), self.flags() as i32, self.mode()) };
+            if fd < 0 {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            return self.opened(Fd { fd });
+        }
         let fd = OpenAt::new(path.as_ref(), None, self.flags(), self.mode()).await?;
-        return Ok(File { fd });
+        self.opened(fd)
+    }
+
+    /// Apply the options that take effect after opening
+    fn opened(&self, fd: Fd) -> Result<File> {
+        if self.chmod {
+            if unsafe { libc::fchmod(fd.fd, self.mode()) } < 0 {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(File::new(fd))
+    }
+}
+
+/// Per write flags, see [`File::write_with_flags`]
+///
+/// Flags are combined with `|`.
+///
+/// [`File::write_with_flags`]: struct.File.html#method.write_with_flags
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RwFlags(i32);
+
+impl RwFlags {
+    /// High priority write, polled for completion by the kernel
+    pub const HIPRI: RwFlags = RwFlags(0x1);
+    /// Make the written data durable before the write completes, like `O_DSYNC`
+    pub const DSYNC: RwFlags = RwFlags(0x2);
+    /// Make the written data and metadata durable before the write completes, like `O_SYNC`
+    pub const SYNC: RwFlags = RwFlags(0x4);
+    /// Write at the end of the file ignoring the offset, like `O_APPEND`
+    pub const APPEND: RwFlags = RwFlags(0x10);
+
+    /// No flags
+    pub fn empty() -> Self {
+        RwFlags(0)
+    }
+
+    /// The raw RWF_* value of the flags
+    pub fn bits(self) -> i32 {
+        self.0
+    }
+
+    /// Return true if all flags in `other` are set
+    pub fn contains(self, other: RwFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for RwFlags {
+    type Output = RwFlags;
+    fn bitor(self, other: RwFlags) -> RwFlags {
+        RwFlags(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for RwFlags {
+    fn bitor_assign(&mut self, other: RwFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// Flags of [`File::sync_range`], see sync_file_range(2)
+///
+/// Flags are combined with `|`.
+///
+/// [`File::sync_range`]: struct.File.html#method.sync_range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncRangeFlags(u32);
+
+impl SyncRangeFlags {
+    /// Wait for writeback already in progress on the range to complete
+    pub const WAIT_BEFORE: SyncRangeFlags = SyncRangeFlags(libc::SYNC_FILE_RANGE_WAIT_BEFORE);
+    /// Start writeback of the dirty pages in the range
+    pub const WRITE: SyncRangeFlags = SyncRangeFlags(libc::SYNC_FILE_RANGE_WRITE);
+    /// Wait for writeback of the range to complete
+    pub const WAIT_AFTER: SyncRangeFlags = SyncRangeFlags(libc::SYNC_FILE_RANGE_WAIT_AFTER);
+
+    /// No flags, the operation does nothing
+    pub fn empty() -> Self {
+        SyncRangeFlags(0)
+    }
+
+    /// Start writeback of the range and wait for it to complete
+    pub fn all() -> Self {
+        Self::WAIT_BEFORE | Self::WRITE | Self::WAIT_AFTER
+    }
+
+    /// The raw SYNC_FILE_RANGE_* value of the flags
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Return true if all flags in `other` are set
+    pub fn contains(self, other: SyncRangeFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for SyncRangeFlags {
+    type Output = SyncRangeFlags;
+    fn bitor(self, other: SyncRangeFlags) -> SyncRangeFlags {
+        SyncRangeFlags(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for SyncRangeFlags {
+    fn bitor_assign(&mut self, other: SyncRangeFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// Block layer io priority of an operation, see ioprio_set(2)
+///
+/// Levels range from 0, the highest priority, to 7, larger levels are clamped to 7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Only served when no other io is pending
+    Idle,
+    /// The default class, with the given level
+    BestEffort(u8),
+    /// Served before any other io, with the given level. Requires CAP_SYS_ADMIN
+    RealTime(u8),
+}
+
+impl IoPriority {
+    /// The priority in the encoding of the `ioprio` field of io_uring sqes
+    fn bits(self) -> u16 {
+        const IOPRIO_CLASS_SHIFT: u16 = 13;
+        let (class, level) = match self {
+            IoPriority::RealTime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        class << IOPRIO_CLASS_SHIFT | std::cmp::min(level, 7) as u16
     }
 }
 
 pub struct File {
-    fd: Fd,
+    /// Shared with the tasks of detached writes in flight
+    fd: Rc<Fd>,
+    /// Tasks of detached writes that may not have finished
+    detached: RefCell<Vec<Task>>,
 }
 
 impl File {
+    fn new(fd: Fd) -> File {
+        File {
+            fd: Rc::new(fd),
+            detached: RefCell::new(Vec::new()),
+        }
+    }
+
     /// Attempts to open a file in read-only mode.
     ///
     /// See the [`OpenOptions::open`] method for more details.
@@ -553,18 +832,205 @@ impl File {
 
     //TODO openat openat2 statx fadvice madvice
 
+    /// Take ownership of a std file, so that it can be used with the reactor.
+    /// The file is closed when the returned `File` is closed or dropped.
+    pub fn from_std(file: std::fs::File) -> File {
+        File::new(Fd {
+            fd: std::os::unix::io::IntoRawFd::into_raw_fd(file),
+        })
+    }
+
     /// Close the file
     ///
     /// # Note
     ///
-    /// The file is closed synchronsly if it is dropped without calling this method
+    /// The file is closed synchronsly if it is dropped without calling this method,
+    /// in which case any error from the close, like a deferred write error, is lost.
+    /// Use [`sync_and_close`] to make sure written data has reached the disk.
+    ///
+    /// If detached writes are still in flight, the file is instead closed
+    /// synchronously when the last of them completes, use [`flush_and_close`]
+    /// to wait for them.
+    ///
+    /// [`sync_and_close`]: #method.sync_and_close
+    /// [`flush_and_close`]: #method.flush_and_close
     pub async fn close(self) -> Result<()> {
-        let Self { fd } = self;
+        let fd = match Rc::try_unwrap(self.fd) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(()),
+        };
+        if !current_reactor().await?.supports(IORING_OP_CLOSE as u8) {
+            // Fall back to a blocking close on kernels without IORING_OP_CLOSE
+            if unsafe { libc::close(fd.into_raw()) } < 0 {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            return Ok(());
+        }
         Close::new(fd).await?;
         Ok(())
     }
 
-    pub async fn write(&self, data: &[u8], offset: u64) -> Result<()> {
+    /// Wait for all detached writes of the file to complete, then close it
+    ///
+    /// This makes sure the file is not closed while writes started by
+    /// [`write_detached`] are in flight, so that the close can report errors.
+    /// Errors of the detached writes themselves are still only reported by the
+    /// reactor. The data is not flushed to the disk, see [`sync_and_close`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// log.write_detached(b"shutting down\n".to_vec(), offset)?;
+    /// log.flush_and_close().await?;
+    /// ```
+    ///
+    /// [`write_detached`]: #method.write_detached
+    /// [`sync_and_close`]: #method.sync_and_close
+    pub async fn flush_and_close(self) -> Result<()> {
+        let detached = self.detached.replace(Vec::new());
+        for task in detached {
+            task.wait().await?;
+        }
+        self.close().await
+    }
+
+    /// Flush the data and metadata of the file to the disk (fsync)
+    pub async fn sync_all(&self) -> Result<()> {
+        Fsync::new(&self.fd, false).await
+    }
+
+    /// Flush the data of the file to the disk, and only the metadata
+    /// needed to read it back (fdatasync)
+    pub async fn sync_data(&self) -> Result<()> {
+        Fsync::new(&self.fd, true).await
+    }
+
+    /// Write back the data of `len` bytes of the file starting at `offset`
+    /// (sync_file_range)
+    ///
+    /// A `len` of 0 means up to the end of the file. This is much cheaper than
+    /// [`sync_data`] when only a small part of a large file is dirty, like the
+    /// tail of a write ahead log, but it is not a durability guarantee: no
+    /// metadata is flushed, not even the file size or the allocation of the
+    /// blocks written, and the disk write cache is not flushed. It is only
+    /// safe on its own for overwriting preallocated blocks on a disk without a
+    /// volatile cache, otherwise follow it with [`sync_data`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::{File, SyncRangeFlags};
+    ///
+    /// file.write_all(record, offset).await?;
+    /// file.sync_range(offset, record.len() as u64, SyncRangeFlags::all()).await?;
+    /// ```
+    ///
+    /// [`sync_data`]: #method.sync_data
+    pub async fn sync_range(&self, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<()> {
+        // The length of the io_uring operation is 32 bit, sync larger ranges in chunks
+        const MAX_CHUNK: u64 = 1 << 31;
+        if len == 0 {
+            return SyncFileRange::new(&self.fd, offset, 0, flags.bits()).await;
+        }
+        let end = offset.checked_add(len).ok_or_else(|| {
+            Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "range past the end of the file",
+            ))
+        })?;
+        let mut offset = offset;
+        while offset < end {
+            let chunk = std::cmp::min(end - offset, MAX_CHUNK);
+            SyncFileRange::new(&self.fd, offset, chunk as u32, flags.bits()).await?;
+            offset += chunk;
+        }
+        Ok(())
+    }
+
+    /// Flush the file to the disk and close it, returning the first error of the two steps.
+    ///
+    /// The file is closed even if the flush fails. Unlike dropping the file, this
+    /// can not silently lose a write error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::File;
+    ///
+    /// let file = File::create("foo.txt").await?;
+    /// file.write_all(b"Hello", 0).await?;
+    /// file.sync_and_close().await?;
+    /// ```
+    pub async fn sync_and_close(self) -> Result<()> {
+        let sync = self.sync_all().await;
+        let close = self.close().await;
+        sync.and(close)
+    }
+
+    /// Write bytes to the file at the given offset, return the number of bytes written
+    ///
+    /// This may write fewer bytes than in `data`, use [`write_all`]
+    /// to write all of it.
+    ///
+    /// [`write_all`]: #method.write_all
+    pub async fn write(&self, data: &[u8], offset: u64) -> Result<usize> {
+        Write::new(&self.fd, data, offset).await
+    }
+
+    /// Write bytes to the file at the given offset, taking ownership of `data`,
+    /// and return the number of bytes written together with `data` for reuse
+    ///
+    /// As the future owns the buffer, no borrow has to outlive the write, so
+    /// the write can be moved into a spawned task. Dropping the future while the
+    /// write is in flight cancels the write, and the buffer is freed once the
+    /// kernel is done with it. Like [`write`], this may write fewer bytes than
+    /// in `data`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::runtime::Priority;
+    /// use agrippa::util::spawn_task;
+    ///
+    /// let data = vec![0u8; 4096];
+    /// spawn_task(Priority::Normal, async move {
+    ///     let (written, data) = file.write_owned(data, 0).await?;
+    ///     Ok(())
+    /// })
+    /// .await?;
+    /// ```
+    ///
+    /// [`write`]: #method.write
+    pub async fn write_owned(&self, data: Vec<u8>, offset: u64) -> Result<(usize, Vec<u8>)> {
+        OwnedWrite::new(&self.fd, data, offset).await
+    }
+
+    /// Write bytes to the file at the given offset with the given flags,
+    /// return the number of bytes written
+    ///
+    /// With `RwFlags::DSYNC` the data is durable once the write completes,
+    /// which saves a separate [`sync_data`] for example for log writes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::RwFlags;
+    ///
+    /// let written = file.write_with_flags(b"record\n", offset, RwFlags::DSYNC).await?;
+    /// ```
+    ///
+    /// [`sync_data`]: #method.sync_data
+    pub async fn write_with_flags(
+        &self,
+        data: &[u8],
+        offset: u64,
+        flags: RwFlags,
+    ) -> Result<usize> {
+        Write::with_flags(&self.fd, data, offset, flags.bits()).await
+    }
+
+    /// Write all bytes of data to the file at the given offset
+    pub async fn write_all(&self, data: &[u8], offset: u64) -> Result<()> {
         let mut start = 0;
 
         while start != data.len() {
@@ -578,24 +1044,960 @@ impl File {
         Ok(())
     }
 
+    /// Write bytes to the file at the given offset with the given io priority,
+    /// return the number of bytes written
+    ///
+    /// See [`read_with_priority`].
+    ///
+    /// [`read_with_priority`]: #method.read_with_priority
+    pub async fn write_with_priority(
+        &self,
+        data: &[u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<usize> {
+        Write::new(&self.fd, data, offset)
+            .with_ioprio(priority.bits())
+            .await
+    }
+
+    /// Write all of `data` to the file at the given offset in the background,
+    /// returning without waiting for the write to complete.
+    ///
+    /// The write runs in a task spawned on the current reactor, which owns `data`
+    /// until the write completes. Any error is only reported by the reactor like
+    /// for any failed task, so this is meant for writes like telemetry where the
+    /// caller does not need to know when or whether the data landed. Detached writes
+    /// are not ordered with respect to each other or to other writes of the file.
+    /// Use [`flush_and_close`] to wait for them before closing the file.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// log.write_detached(format!("{} requests\n", count).into_bytes(), offset)?;
+    /// ```
+    ///
+    /// [`flush_and_close`]: #method.flush_and_close
+    pub fn write_detached(&self, data: Vec<u8>, offset: u64) -> Result<()> {
+        let reactor = current_task()?.reactor.clone();
+        let fd = self.fd.clone();
+        let task = reactor.spawn_named("write_detached", Priority::Normal, async move {
+            let mut start = 0;
+            while start != data.len() {
+                let written = Write::new(&fd, &data[start..], offset + start as u64).await?;
+                if written == 0 {
+                    return Err(Error::Eof);
+                }
+                start += written;
+            }
+            Ok(())
+        });
+        let mut detached = self.detached.borrow_mut();
+        detached.retain(|task| !task.is_finished());
+        detached.push(task);
+        Ok(())
+    }
+
+    /// Zero `len` bytes of the file starting at `offset`, without writing the zeros.
+    ///
+    /// The filesystem may allocate blocks for the range, and extends the file
+    /// if the range goes past the end of it (FALLOC_FL_ZERO_RANGE).
+    pub async fn zero_range(&self, offset: u64, len: u64) -> Result<()> {
+        Fallocate::new(&self.fd, libc::FALLOC_FL_ZERO_RANGE, offset, len).await
+    }
+
+    /// Deallocate `len` bytes of the file starting at `offset`, the range reads back as zeros.
+    ///
+    /// The size of the file is not changed, even if the range goes past the end of it
+    /// (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).
+    pub async fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+        Fallocate::new(
+            &self.fd,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+        .await
+    }
+
+    /// Return the value of the extended attribute `name` of the file, such as
+    /// `user.checksum`
+    ///
+    /// The size of the value is queried first, then the value is read, which is
+    /// retried if the value grew in between. Uses IORING_OP_FGETXATTR where
+    /// supported (Linux 5.19), elsewhere fgetxattr(2) on a separate thread.
+    /// Fails with `ENODATA` if the file has no such attribute.
+    pub async fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let name = std::ffi::CString::new(name)?;
+        let uring = current_reactor().await?.supports(IORING_OP_FGETXATTR as u8);
+        loop {
+            let (_, size) = self.fgetxattr(uring, &name, Vec::new()).await?;
+            match self.fgetxattr(uring, &name, vec![0; size]).await {
+                Ok((mut value, len)) => {
+                    value.truncate(len);
+                    return Ok(value);
+                }
+                Err(Error::Io(e)) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read the value of an extended attribute into `value`, return it with
+    /// the length of the value
+    async fn fgetxattr(
+        &self,
+        uring: bool,
+        name: &std::ffi::CStr,
+        mut value: Vec<u8>,
+    ) -> Result<(Vec<u8>, usize)> {
+        if uring {
+            return FGetXattr::new(&self.fd, name.to_owned(), value).await;
+        }
+        let fd = self.fd.fd;
+        let name = name.to_owned();
+        run_on_thread(move || {
+            let ret = unsafe {
+                libc::fgetxattr(
+                    fd,
+                    name.as_ptr(),
+                    value.as_mut_ptr() as *mut libc::c_void,
+                    value.len(),
+                )
+            };
+            if ret < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok((value, ret as usize))
+            }
+        })
+        .await?
+        .map_err(Error::from)
+    }
+
+    /// Set the value of the extended attribute `name` of the file, creating
+    /// the attribute if it does not exist
+    ///
+    /// Uses IORING_OP_FSETXATTR where supported (Linux 5.19), elsewhere
+    /// fsetxattr(2) on a separate thread.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// file.set_xattr("user.origin", b"https://example.com").await?;
+    /// assert_eq!(file.get_xattr("user.origin").await?, b"https://example.com");
+    /// ```
+    pub async fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        let name = std::ffi::CString::new(name)?;
+        if current_reactor().await?.supports(IORING_OP_FSETXATTR as u8) {
+            return FSetXattr::new(&self.fd, name, value.to_vec(), 0).await;
+        }
+        let fd = self.fd.fd;
+        let value = value.to_vec();
+        run_on_thread(move || {
+            let ret = unsafe {
+                libc::fsetxattr(
+                    fd,
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await?
+        .map_err(Error::from)
+    }
+
     pub async fn read(&self, data: &mut [u8], offset: u64) -> Result<usize> {
         Read::new(&self.fd, data, offset).await
     }
 
+    /// Read into each buffer from its offset in the file, with all the reads in
+    /// flight at the same time, and return the number of bytes read into each.
+    ///
+    /// The reads are submitted to the kernel together in one system call, which
+    /// suits reading many pages or columns spread over a file. Like `read`, a read
+    /// may return fewer bytes than its buffer holds, for instance at the end of the
+    /// file. If a read fails, the others are canceled and the error is returned
+    /// once the kernel is done with all the buffers.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let mut header = [0u8; 64];
+    /// let mut footer = [0u8; 64];
+    /// let counts = file
+    ///     .read_scatter(&mut [(0, &mut header[..]), (size - 64, &mut footer[..])])
+    ///     .await?;
+    /// ```
+    pub async fn read_scatter(&self, requests: &mut [(u64, &mut [u8])]) -> Result<Vec<usize>> {
+        try_join_all(
+            requests
+                .iter_mut()
+                .map(|(offset, data)| Read::new(&self.fd, data, *offset)),
+        )
+        .await
+    }
+
+    /// Read bytes from the file at the given offset into the capacity of `data`,
+    /// taking ownership of it, and return the number of bytes read together with
+    /// `data`, whose length is set to that number
+    ///
+    /// Any previous content of `data` is discarded, so reserve the capacity to
+    /// read with `Vec::with_capacity`. As the future owns the buffer, dropping it
+    /// while the read is in flight is safe, the read is canceled and the buffer
+    /// is freed once the kernel is done with it, and no borrow has to outlive the
+    /// read, which makes this the read to use in spawned tasks.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let (n, page) = file.read_owned(Vec::with_capacity(4096), offset).await?;
+    /// assert_eq!(page.len(), n);
+    /// ```
+    pub async fn read_owned(&self, data: Vec<u8>, offset: u64) -> Result<(usize, Vec<u8>)> {
+        OwnedRead::new(&self.fd, data, offset).await
+    }
+
+    /// Read bytes from the file at the given offset with the given io priority,
+    /// return the number of bytes read
+    ///
+    /// This lets background work like scrubbing or compaction yield to latency
+    /// sensitive io of the same disk. The priority is only honored by io schedulers
+    /// that support priorities, like bfq, and not for buffered io served from the
+    /// page cache, elsewhere it has no effect.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::IoPriority;
+    ///
+    /// let n = file.read_with_priority(&mut buf, offset, IoPriority::Idle).await?;
+    /// ```
+    pub async fn read_with_priority(
+        &self,
+        data: &mut [u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<usize> {
+        Read::new(&self.fd, data, offset)
+            .with_ioprio(priority.bits())
+            .await
+    }
+
+    /// Read bytes from the current file position, and advance it by the number of
+    /// bytes read, like read(2). Return the number of bytes read, 0 at the end of the file.
+    ///
+    /// Unlike [`read`], which reads at an explicit offset and leaves the file
+    /// position alone, successive calls continue where the previous one stopped.
+    /// This is also how pipes and other files without offsets are read.
+    ///
+    /// Concurrent streaming reads of the same file may complete in any order,
+    /// so they should be awaited one at a time.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let mut buf = [0u8; 4096];
+    /// loop {
+    ///     let n = file.read_streaming(&mut buf).await?;
+    ///     if n == 0 {
+    ///         break;
+    ///     }
+    ///     handle(&buf[..n]);
+    /// }
+    /// ```
+    ///
+    /// [`read`]: #method.read
+    pub async fn read_streaming(&self, data: &mut [u8]) -> Result<usize> {
+        // An offset of -1 makes io_uring use and advance the file position
+        Read::new(&self.fd, data, u64::max_value()).await
+    }
+
+    /// Fill `data` from the file at the given offset, fail with `Error::Eof`
+    /// if the file ends before
+    async fn read_exact_at(&self, data: &mut [u8], offset: u64) -> Result<()> {
+        let mut start = 0;
+        while start != data.len() {
+            let read = Read::new(&self.fd, &mut data[start..], offset + start as u64).await?;
+            if read == 0 {
+                return Err(Error::Eof);
+            }
+            start += read;
+        }
+        Ok(())
+    }
+
+    /// Read the whole file into `buf`, return the number of bytes read
+    ///
+    /// The existing contents of `buf` are cleared, but its capacity is reused,
+    /// and it is only grown if the file does not fit. This avoids an allocation
+    /// per read when the same buffer is used to read files repeatedly.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::File;
+    ///
+    /// let mut buf = Vec::with_capacity(64 * 1024);
+    /// for path in &paths {
+    ///     let file = File::open(path).await?;
+    ///     let len = file.read_into(&mut buf).await?;
+    ///     handle(&buf[..len]);
+    /// }
+    /// ```
+    pub async fn read_into(&self, buf: &mut Vec<u8>) -> Result<usize> {
+        buf.clear();
+        // Read into the whole capacity, zeroed to not expose uninitialized memory
+        let capacity = std::cmp::max(buf.capacity(), 4096);
+        buf.resize(capacity, 0);
+        let mut start = 0;
+        loop {
+            if start == buf.len() {
+                let len = buf.len();
+                buf.resize(len * 2, 0);
+            }
+            match Read::new(&self.fd, &mut buf[start..], start as u64).await {
+                Ok(0) => {
+                    buf.truncate(start);
+                    return Ok(start);
+                }
+                Ok(read) => start += read,
+                Err(e) => {
+                    buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
     pub async fn read_all(&self) -> Result<Vec<u8>> {
-        let mut data: Vec<u8> = Vec::new();
-        data.resize(128 * 1024, 0);
+        // Read into a pooled scratch buffer, so that only the result is allocated
+        let mut data = acquire_buffer(128 * 1024).await?;
         let mut start = 0;
         loop {
             let read = Read::new(&self.fd, &mut data[start..], start as u64).await?;
             start += read;
             if start != data.len() {
-                data.resize(start, 0);
-                return Ok(data);
+                return Ok(data[..start].to_vec());
+            }
+            let len = data.len();
+            data.resize(len * 2);
+        }
+    }
+}
+
+impl std::os::unix::io::AsRawFd for File {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd.fd
+    }
+}
+
+impl std::os::unix::io::FromRawFd for File {
+    /// Take ownership of a raw file descriptor, it is closed when the file is dropped
+    unsafe fn from_raw_fd(fd: std::os::unix::io::RawFd) -> File {
+        File::new(Fd { fd })
+    }
+}
+
+impl std::os::unix::io::IntoRawFd for File {
+    /// Consume the file without closing it, returning the raw file descriptor
+    fn into_raw_fd(self) -> std::os::unix::io::RawFd {
+        match Rc::try_unwrap(self.fd) {
+            Ok(fd) => fd.into_raw(),
+            Err(fd) => {
+                // Detached writes are still in flight, leak the reference so
+                // that the descriptor is not closed when they complete
+                let raw = fd.fd;
+                std::mem::forget(fd);
+                raw
+            }
+        }
+    }
+}
+
+/// Size of the buffer `ReadDir` reads directory entries into
+const READ_DIR_BUFFER: usize = 64 * 1024;
+
+/// Entry of a directory returned by [`ReadDir::next_entry`], borrowing
+/// the name from the buffer of the `ReadDir`
+///
+/// [`ReadDir::next_entry`]: struct.ReadDir.html#method.next_entry
+pub struct DirEntry<'a> {
+    ino: u64,
+    kind: u8,
+    name: &'a [u8],
+}
+
+impl<'a> DirEntry<'a> {
+    /// The inode number of the entry
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// The type of the entry, one of the `libc::DT_*` values. Some filesystems
+    /// always report `DT_UNKNOWN`, in which case the type must be found with stat
+    pub fn file_type(&self) -> u8 {
+        self.kind
+    }
+
+    /// The name of the entry within the directory
+    pub fn name(&self) -> &'a std::ffi::OsStr {
+        std::os::unix::ffi::OsStrExt::from_bytes(self.name)
+    }
+
+    /// Copy the entry, so that it no longer borrows from the `ReadDir`
+    pub fn to_owned(&self) -> OwnedDirEntry {
+        OwnedDirEntry {
+            ino: self.ino,
+            kind: self.kind,
+            name: self.name().to_os_string(),
+        }
+    }
+}
+
+/// Entry of a directory returned by [`ReadDir::next_owned`]
+///
+/// [`ReadDir::next_owned`]: struct.ReadDir.html#method.next_owned
+#[derive(Clone, Debug)]
+pub struct OwnedDirEntry {
+    ino: u64,
+    kind: u8,
+    name: std::ffi::OsString,
+}
+
+impl OwnedDirEntry {
+    /// The inode number of the entry
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// The type of the entry, see [`DirEntry::file_type`]
+    ///
+    /// [`DirEntry::file_type`]: struct.DirEntry.html#method.file_type
+    pub fn file_type(&self) -> u8 {
+        self.kind
+    }
+
+    /// The name of the entry within the directory
+    pub fn name(&self) -> &std::ffi::OsStr {
+        &self.name
+    }
+}
+
+/// Parse the linux_dirent64 record at `start` of `buf`, returning the entry
+/// and the length of the record
+fn parse_dirent(buf: &[u8], start: usize) -> (DirEntry<'_>, usize) {
+    // struct linux_dirent64 { u64 d_ino; s64 d_off; u16 d_reclen; u8 d_type; char d_name[]; }
+    const NAME_OFFSET: usize = 19;
+    let reclen = u16::from_ne_bytes([buf[start + 16], buf[start + 17]]) as usize;
+    let mut ino = [0u8; 8];
+    ino.copy_from_slice(&buf[start..start + 8]);
+    let name = &buf[start + NAME_OFFSET..start + reclen];
+    let entry = DirEntry {
+        ino: u64::from_ne_bytes(ino),
+        kind: buf[start + 18],
+        name: &name[..name.iter().position(|c| *c == 0).unwrap_or(name.len())],
+    };
+    (entry, reclen)
+}
+
+/// Result of a getdents64 call of the thread of a `DirReader`, with the buffer
+type DirBatch = (Vec<u8>, std::io::Result<usize>);
+
+/// Thread making the getdents64 calls of a `ReadDir`, so that a thread is not
+/// spawned for every batch.
+///
+/// The buffer of each batch is handed to the thread through `requests`. The
+/// thread stores the result and writes a byte to a pipe, whose read end the
+/// reactor reads with io_uring. The thread exits once the `DirReader` is dropped.
+struct DirReader {
+    requests: std::sync::mpsc::Sender<Vec<u8>>,
+    result: std::sync::Arc<std::sync::Mutex<Option<DirBatch>>>,
+    /// Read end of the pipe, shared with the thread so that it stays open
+    /// while the thread may write to the pipe
+    signal: std::sync::Arc<Fd>,
+    /// Set while a batch has been requested and its result not taken yet
+    pending: bool,
+}
+
+impl DirReader {
+    fn new(dir: Fd) -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        let signal = std::sync::Arc::new(Fd { fd: fds[0] });
+        let write_end = Fd { fd: fds[1] };
+        let (requests, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+        let result = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let (thread_result, thread_signal) = (result.clone(), signal.clone());
+        std::thread::Builder::new()
+            .name("agrippa-read-dir".to_string())
+            .spawn(move || {
+                let _signal = thread_signal;
+                for mut buf in receiver {
+                    let ret = unsafe {
+                        libc::syscall(
+                            libc::SYS_getdents64,
+                            dir.fd,
+                            buf.as_mut_ptr() as *mut libc::c_void,
+                            buf.len(),
+                        )
+                    };
+                    let ret = if ret < 0 {
+                        Err(std::io::Error::last_os_error())
+                    } else {
+                        Ok(ret as usize)
+                    };
+                    *thread_result.lock().unwrap() = Some((buf, ret));
+                    let byte = 1u8;
+                    if unsafe { libc::write(write_end.fd, &byte as *const u8 as *const _, 1) } != 1
+                    {
+                        break;
+                    }
+                }
+            })?;
+        Ok(DirReader {
+            requests,
+            result,
+            signal,
+            pending: false,
+        })
+    }
+
+    /// Read the next batch of entries into `buf`, returning the buffer with
+    /// the number of bytes read.
+    ///
+    /// If an earlier call was abandoned, for instance as its task was canceled,
+    /// the batch it requested is returned instead, and `buf` is dropped.
+    async fn read(&mut self, buf: Vec<u8>) -> Result<DirBatch> {
+        if !self.pending {
+            self.requests
+                .send(buf)
+                .map_err(|_| Error::Internal("Directory reader thread exited"))?;
+            self.pending = true;
+        }
+        let mut byte = [0u8; 1];
+        Read::new(&self.signal, &mut byte, 0).await?;
+        self.pending = false;
+        let batch = self.result.lock().unwrap().take();
+        batch.ok_or(Error::Internal(
+            "Directory reader thread did not produce a result",
+        ))
+    }
+}
+
+/// Iterator over the entries of a directory, see [`read_dir`]
+///
+/// Entries are read with getdents64 in large batches into a buffer. As io_uring
+/// has no operation for this, the system call is made on a thread of the
+/// `ReadDir`, so that the reactor is not blocked by a slow directory. The `.`
+/// and `..` entries are skipped.
+///
+/// [`read_dir`]: fn.read_dir.html
+pub struct ReadDir {
+    reader: DirReader,
+    buf: Vec<u8>,
+    /// Position of the next entry in `buf`
+    pos: usize,
+    /// Number of valid bytes in `buf`
+    len: usize,
+    eof: bool,
+}
+
+impl ReadDir {
+    /// Read the next batch of entries into the buffer
+    async fn fill(&mut self) -> Result<()> {
+        let mut buf = std::mem::replace(&mut self.buf, Vec::new());
+        if buf.is_empty() {
+            // A previous fill was abandoned while the buffer was on the thread
+            buf = vec![0; READ_DIR_BUFFER];
+        }
+        let (buf, ret) = self.reader.read(buf).await?;
+        self.buf = buf;
+        self.pos = 0;
+        self.len = ret?;
+        self.eof = self.len == 0;
+        Ok(())
+    }
+
+    /// Return the next entry of the directory, borrowing its name from the
+    /// buffer of the `ReadDir`, so that no allocation is made per entry.
+    /// Return `None` once all entries have been returned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::fs::read_dir;
+    ///
+    /// let mut dir = read_dir("/var/spool").await?;
+    /// while let Some(entry) = dir.next_entry().await? {
+    ///     count += entry.name().len();
+    /// }
+    /// ```
+    pub async fn next_entry(&mut self) -> Result<Option<DirEntry<'_>>> {
+        let start = loop {
+            if self.pos >= self.len {
+                if self.eof {
+                    return Ok(None);
+                }
+                self.fill().await?;
+                continue;
+            }
+            let start = self.pos;
+            let (entry, reclen) = parse_dirent(&self.buf, start);
+            let dots = entry.name == b"." || entry.name == b"..";
+            self.pos += reclen;
+            if !dots {
+                break start;
             }
-            data.resize(data.len() * 2, 0);
+        };
+        Ok(Some(parse_dirent(&self.buf, start).0))
+    }
+
+    /// Return the next entry of the directory as an owned value,
+    /// `None` once all entries have been returned.
+    pub async fn next_owned(&mut self) -> Result<Option<OwnedDirEntry>> {
+        Ok(self.next_entry().await?.map(|e| e.to_owned()))
+    }
+}
+
+/// Open the directory at `path` to iterate over its entries
+pub async fn read_dir<P: AsRef<Path>>(path: P) -> Result<ReadDir> {
+    let file = OpenOptions::new()
+        .read(true)
+        .directory(true)
+        .open(path)
+        .await?;
+    let fd = Fd {
+        fd: std::os::unix::io::IntoRawFd::into_raw_fd(file),
+    };
+    Ok(ReadDir {
+        reader: DirReader::new(fd)?,
+        buf: vec![0; READ_DIR_BUFFER],
+        pos: 0,
+        len: 0,
+        eof: false,
+    })
+}
+
+/// Size of the length and checksum prefix of each record in an `AppendLog`
+const RECORD_HEADER: u64 = 8;
+
+/// CRC-32 (IEEE) of `data`, continuing from the checksum `crc` of earlier data
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Header of a record in an `AppendLog`, the checksum covers the length as
+/// well, so that a zeroed header is not a valid empty record
+fn record_header(record: &[u8]) -> [u8; RECORD_HEADER as usize] {
+    let len = (record.len() as u32).to_le_bytes();
+    let crc = crc32(crc32(0, &len), record);
+    let mut header = [0u8; RECORD_HEADER as usize];
+    header[..4].copy_from_slice(&len);
+    header[4..].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// Append only log of records stored in a file
+///
+/// Each record is stored with a 4 byte little endian length prefix and a 4 byte
+/// CRC-32 of the length and the record, and is identified by the offset it was
+/// appended at.
+///
+/// # Durability
+///
+/// [`append`] returns once the record has been written to the file, at which
+/// point it can be read back with [`read_at`], but it may not have reached the disk.
+/// [`commit`] waits for the appends started before it was called to be written,
+/// and flushes them to the disk, so that several appends can share a single flush.
+/// Records that were not committed may be lost on a crash. When the log is opened,
+/// the records are scanned, and the log is truncated at the first record that is
+/// torn or whose checksum does not match, such as the zeroes of a preallocated
+/// range that was never written.
+///
+/// If an append fails, or is dropped before it has been written, the log is
+/// left with a gap, and is poisoned: all later appends and commits fail, and
+/// the log must be opened again to recover the records before the gap.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::fs::AppendLog;
+///
+/// let log = AppendLog::open("queue.log").await?;
+/// let a = log.append(b"first").await?;
+/// let b = log.append(b"second").await?;
+/// log.commit().await?;
+/// assert_eq!(log.read_at(b).await?, b"second");
+/// ```
+///
+/// [`append`]: #method.append
+/// [`read_at`]: #method.read_at
+/// [`commit`]: #method.commit
+pub struct AppendLog {
+    file: File,
+    /// Offset the next record is appended at
+    end: Cell<u64>,
+    /// End of the space preallocated for the file
+    allocated: Cell<u64>,
+    /// Number of bytes to preallocate at a time, 0 to not preallocate
+    preallocate: u64,
+    /// Sequence number of the next append
+    next_append: Cell<u64>,
+    /// Sequence numbers of the appends that have not been written yet
+    appending: RefCell<std::collections::BTreeSet<u64>>,
+    /// Wakers of the commits waiting for appends to be written
+    waiters: RefCell<Vec<std::task::Waker>>,
+    /// Set when an append or commit has failed
+    poisoned: Cell<bool>,
+}
+
+/// Registration of an append that has not been written yet, the log is
+/// poisoned if it is dropped before `written` is called
+struct PendingAppend<'a> {
+    log: &'a AppendLog,
+    seq: u64,
+    written: bool,
+}
+
+impl<'a> PendingAppend<'a> {
+    fn new(log: &'a AppendLog) -> Self {
+        let seq = log.next_append.get();
+        log.next_append.set(seq + 1);
+        log.appending.borrow_mut().insert(seq);
+        PendingAppend {
+            log,
+            seq,
+            written: false,
         }
     }
+
+    fn written(mut self) {
+        self.written = true;
+    }
+}
+
+impl<'a> Drop for PendingAppend<'a> {
+    fn drop(&mut self) {
+        if !self.written {
+            self.log.poisoned.set(true);
+        }
+        self.log.appending.borrow_mut().remove(&self.seq);
+        for waker in self.log.waiters.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Future waiting for all appends with a sequence number below `seq` to be written
+struct AppendsWritten<'a> {
+    log: &'a AppendLog,
+    seq: u64,
+}
+
+impl<'a> std::future::Future for AppendsWritten<'a> {
+    type Output = ();
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        context: &mut std::task::Context,
+    ) -> std::task::Poll<()> {
+        match self.log.appending.borrow().iter().next() {
+            Some(&seq) if seq < self.seq => {
+                self.log.waiters.borrow_mut().push(context.waker().clone());
+                std::task::Poll::Pending
+            }
+            _ => std::task::Poll::Ready(()),
+        }
+    }
+}
+
+impl AppendLog {
+    /// Open the log stored in the file at `path`, creating it if it does not exist
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<AppendLog> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .await?;
+        let size = unsafe {
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(file.fd.fd, &mut stat) < 0 {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            stat.st_size as u64
+        };
+        // Find the end of the last complete record with a valid checksum
+        let mut end = 0;
+        while end + RECORD_HEADER <= size {
+            let mut header = [0u8; RECORD_HEADER as usize];
+            file.read_exact_at(&mut header, end).await?;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+            if end + RECORD_HEADER + len > size {
+                break;
+            }
+            let mut record = vec![0; len as usize];
+            file.read_exact_at(&mut record, end + RECORD_HEADER).await?;
+            if record_header(&record) != header {
+                break;
+            }
+            end += RECORD_HEADER + len;
+        }
+        if end != size {
+            info!(
+                "Discarding {} bytes of torn or corrupt records at end of log",
+                size - end
+            );
+            if unsafe { libc::ftruncate(file.fd.fd, end as libc::off_t) } < 0 {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(AppendLog {
+            file,
+            end: Cell::new(end),
+            allocated: Cell::new(end),
+            preallocate: 0,
+            next_append: Cell::new(0),
+            appending: RefCell::new(std::collections::BTreeSet::new()),
+            waiters: RefCell::new(Vec::new()),
+            poisoned: Cell::new(false),
+        })
+    }
+
+    /// Preallocate disk space for the log `bytes` at a time, without changing
+    /// the size of the file (FALLOC_FL_KEEP_SIZE). This reduces fragmentation,
+    /// and the metadata updates that have to be flushed by `commit`
+    pub fn preallocate(&mut self, bytes: u64) -> &mut Self {
+        self.preallocate = bytes;
+        self
+    }
+
+    /// The offset the next record will be appended at
+    pub fn end(&self) -> u64 {
+        self.end.get()
+    }
+
+    /// Return an error if an earlier append or commit has failed
+    fn check_poisoned(&self) -> Result<()> {
+        if self.poisoned.get() {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "log poisoned by an earlier failed append or commit",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Append a record to the log, return the offset of it.
+    ///
+    /// The record is not durable before a following [`commit`] has returned.
+    /// Records are placed in the order `append` is called, also for concurrent
+    /// appends from several tasks. If the append fails or is dropped before it
+    /// returns, the log is poisoned.
+    ///
+    /// [`commit`]: #method.commit
+    pub async fn append(&self, record: &[u8]) -> Result<u64> {
+        if record.len() > u32::max_value() as usize {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "record too large",
+            )));
+        }
+        self.check_poisoned()?;
+        let offset = self.end.get();
+        let end = offset + RECORD_HEADER + record.len() as u64;
+        // Reserve the range before waiting for anything
+        self.end.set(end);
+        let pending = PendingAppend::new(self);
+        if self.preallocate != 0 && end > self.allocated.get() {
+            let start = self.allocated.get();
+            let len = std::cmp::max(self.preallocate, end - start);
+            self.allocated.set(start + len);
+            Fallocate::new(&self.file.fd, libc::FALLOC_FL_KEEP_SIZE, start, len).await?;
+        }
+        let mut data = Vec::with_capacity(RECORD_HEADER as usize + record.len());
+        data.extend_from_slice(&record_header(record));
+        data.extend_from_slice(record);
+        self.file.write_all(&data, offset).await?;
+        pending.written();
+        Ok(offset)
+    }
+
+    /// Wait for the appends started before the call to be written, and flush
+    /// them to the disk. Fails if the log is poisoned, and poisons the log if
+    /// the flush fails, as the records may then have been lost.
+    pub async fn commit(&self) -> Result<()> {
+        AppendsWritten {
+            log: self,
+            seq: self.next_append.get(),
+        }
+        .await;
+        self.check_poisoned()?;
+        if let Err(e) = self.file.sync_data().await {
+            self.poisoned.set(true);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Read the record appended at `offset`
+    pub async fn read_at(&self, offset: u64) -> Result<Vec<u8>> {
+        if offset
+            .checked_add(RECORD_HEADER)
+            .map_or(true, |o| o > self.end.get())
+        {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "offset past the end of the log",
+            )));
+        }
+        let mut header = [0u8; RECORD_HEADER as usize];
+        self.file.read_exact_at(&mut header, offset).await?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        // A corrupt length must not make us allocate past the end of the log
+        let within = (offset + RECORD_HEADER)
+            .checked_add(len as u64)
+            .map_or(false, |record_end| record_end <= self.end.get());
+        if !within {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "record extends past the end of the log",
+            )));
+        }
+        let mut record = vec![0; len as usize];
+        self.file
+            .read_exact_at(&mut record, offset + RECORD_HEADER)
+            .await?;
+        if record_header(&record) != header {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "record checksum mismatch",
+            )));
+        }
+        Ok(record)
+    }
+
+    /// Commit the log and close the file
+    pub async fn close(self) -> Result<()> {
+        self.file.sync_and_close().await
+    }
 }
 
 // impl Drop for File {
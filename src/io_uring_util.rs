@@ -1,7 +1,13 @@
-use crate::runtime::{io_uring_get_sqe_submit, waker_task, Error, Result, TaskRef, TaskState};
+use crate::runtime::{
+    current_task, interruption, io_uring_get_sqe_submit, Completion, Error, Result,
+    IGNORE_USER_DATA,
+};
 use crate::sys::{
-    io_uring_sqe, IORING_OP_ACCEPT, IORING_OP_CLOSE, IORING_OP_CONNECT, IORING_OP_OPENAT,
-    IORING_OP_READ, IORING_OP_WRITE,
+    io_uring, io_uring_sqe, io_uring_submit, IORING_OP_ACCEPT, IORING_OP_CLOSE, IORING_OP_CONNECT,
+    IORING_OP_EPOLL_CTL, IORING_OP_FALLOCATE, IORING_OP_FSYNC, IORING_OP_LINK_TIMEOUT,
+    IORING_OP_MADVISE, IORING_OP_NOP, IORING_OP_OPENAT, IORING_OP_POLL_ADD, IORING_OP_READ,
+    IORING_OP_RECV, IORING_OP_RECVMSG, IORING_OP_SEND, IORING_OP_SENDMSG,
+    IORING_OP_SYNC_FILE_RANGE, IORING_OP_TEE, IORING_OP_TIMEOUT, IORING_OP_WRITE,
 };
 use libc;
 use log::debug;
@@ -17,7 +23,7 @@ unsafe fn prep_rw(
     addr: *mut std::ffi::c_void,
     len: u32,
     offset: u64,
-    task: TaskRef,
+    user_data: u64,
 ) {
     sqe.opcode = op as u8;
     sqe.flags = 0;
@@ -27,7 +33,7 @@ unsafe fn prep_rw(
     sqe.__bindgen_anon_2.addr = addr as u64;
     sqe.len = len;
     sqe.__bindgen_anon_3.rw_flags = 0;
-    sqe.user_data = Rc::into_raw(task) as usize as u64;
+    sqe.user_data = user_data;
     sqe.__bindgen_anon_4.__pad2[0] = 0;
     sqe.__bindgen_anon_4.__pad2[1] = 0;
     sqe.__bindgen_anon_4.__pad2[2] = 0;
@@ -60,20 +66,68 @@ impl Fd {
 
 pub(super) trait IOUringMethod: std::marker::Unpin {
     type Output;
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()>;
-    fn result(&self, ret: i32) -> Result<Self::Output>;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()>;
+    fn result(&mut self, ret: i32) -> Result<Self::Output>;
+
+    /// Take the resources the kernel may still use for the operation, when its
+    /// future is dropped while the operation is in flight. Only operations owning
+    /// their buffers can be dropped in flight, for the others this returns None.
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        None
+    }
 }
 
-#[derive(Copy, Clone)]
 enum IOUringFutureState {
     Initial,
-    Sent,
+    Sent(Rc<Completion>),
     Done,
 }
 
+/// Link the next sqe to this one (IOSQE_IO_LINK)
+const IOSQE_IO_LINK: u8 = 1 << 2;
+/// Let the kernel select a buffer from the buffer group of the sqe (IOSQE_BUFFER_SELECT)
+const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+/// Keep a receive armed after each completion (IORING_RECV_MULTISHOT, in the ioprio field)
+const IORING_RECV_MULTISHOT: u16 = 1 << 1;
+
+/// Submit a multishot receive on `fd` into buffers selected from the buffer
+/// ring with the given group id. Each received chunk posts a cqe, with
+/// `IORING_CQE_F_MORE` set until the receive is no longer armed.
+pub(super) fn io_uring_recv_multishot(
+    ring: &mut io_uring,
+    fd: &Fd,
+    group_id: u16,
+    user_data: u64,
+) -> Result<()> {
+    unsafe {
+        let mut sqe = io_uring_get_sqe_submit(ring)?;
+        let sqe = sqe.as_mut();
+        prep_rw(
+            IORING_OP_RECV,
+            sqe,
+            fd.as_raw(),
+            std::ptr::null_mut(),
+            0,
+            0,
+            user_data,
+        );
+        sqe.flags |= IOSQE_BUFFER_SELECT;
+        sqe.ioprio = IORING_RECV_MULTISHOT;
+        sqe.__bindgen_anon_4
+            .__bindgen_anon_1
+            .__bindgen_anon_1
+            .buf_group = group_id;
+    }
+    Ok(())
+}
+
 pub(super) struct IOUringFeature<M: IOUringMethod> {
     state: IOUringFutureState,
     method: M,
+    /// Timeout of the operation, read by the kernel when the linked timeout is submitted
+    timeout: Option<libc::timespec>,
+    /// Io priority of the operation, in the encoding of ioprio_set(2)
+    ioprio: u16,
 }
 
 impl<M: IOUringMethod> IOUringFeature<M> {
@@ -81,14 +135,59 @@ impl<M: IOUringMethod> IOUringFeature<M> {
         Self {
             state: IOUringFutureState::Initial,
             method,
+            timeout: None,
+            ioprio: 0,
         }
     }
+
+    /// Set the io priority of the operation, in the encoding of ioprio_set(2)
+    pub(super) fn with_ioprio(mut self, ioprio: u16) -> Self {
+        self.ioprio = ioprio;
+        self
+    }
+
+    /// Fail the operation with `Error::Timeout` if it has not completed within
+    /// `timeout`. The operation is canceled by the kernel through a linked timeout
+    /// (IORING_OP_LINK_TIMEOUT), so unlike a timer in the reactor, the future is
+    /// not left with the operation in flight.
+    pub(super) fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = Some(libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        });
+        self
+    }
+}
+
+/// Number of free entries in the submission queue
+unsafe fn sq_space_left(ring: &io_uring) -> u32 {
+    *ring.sq.kring_entries - ring.sq.sqe_tail.wrapping_sub(ring.sq.sqe_head)
+}
+
+/// Prepare a IORING_OP_LINK_TIMEOUT sqe for the sqe before it
+unsafe fn prep_link_timeout(sqe: &mut io_uring_sqe, spec: &libc::timespec) {
+    prep_rw(
+        IORING_OP_LINK_TIMEOUT,
+        sqe,
+        -1,
+        spec as *const libc::timespec as *mut std::ffi::c_void,
+        1,
+        0,
+        IGNORE_USER_DATA,
+    );
+    sqe.__bindgen_anon_3.timeout_flags = 0;
 }
 
 impl<M: IOUringMethod> Drop for IOUringFeature<M> {
     fn drop(&mut self) {
-        if let IOUringFutureState::Sent = self.state {
-            panic!("io_uring future dropped while in progress");
+        if let IOUringFutureState::Sent(completion) = &self.state {
+            // Once the last cqe has arrived the kernel no longer uses the operation
+            if completion.result.get().is_none() || completion.more.get() {
+                match self.method.detach() {
+                    Some(resources) => completion.detach(resources),
+                    None => panic!("io_uring future dropped while in progress"),
+                }
+            }
         }
     }
 }
@@ -97,110 +196,123 @@ impl<M: IOUringMethod> Future for IOUringFeature<M> {
     type Output = Result<M::Output>;
     fn poll(
         mut self: Pin<&mut Self>,
-        context: &mut std::task::Context,
+        _context: &mut std::task::Context,
     ) -> std::task::Poll<Self::Output> {
-        let task = waker_task(context.waker().clone());
-        if let IOUringFutureState::Done = self.state {
-            return Poll::Ready(Err(Error::Internal("Done future polled")));
-        }
-        let (ts, s, r) = match task.state.get() {
-            TaskState::Inital => {
-                match io_uring_get_sqe_submit(
-                    &mut *task.as_ref().reactor.as_ref().ring.borrow_mut(),
-                ) {
-                    Err(e) => (
-                        TaskState::Inital,
-                        IOUringFutureState::Done,
-                        Poll::Ready(Err(e)),
-                    ),
-                    Ok(mut sqe) => unsafe {
-                        if let Err(e) = self.method.call(sqe.as_mut(), task.clone()) {
-                            (
-                                TaskState::Inital,
-                                IOUringFutureState::Done,
-                                Poll::Ready(Err(e)),
-                            )
-                        } else {
-                            (
-                                TaskState::UringWaiting,
-                                IOUringFutureState::Sent,
-                                Poll::Pending,
-                            )
-                        }
-                    },
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let res = match &self.state {
+            IOUringFutureState::Done => {
+                return Poll::Ready(Err(Error::Internal("Done future polled")));
+            }
+            IOUringFutureState::Initial => {
+                if let Some(e) = interruption(&task) {
+                    self.state = IOUringFutureState::Done;
+                    return Poll::Ready(Err(e));
+                }
+                // The sqe is only submitted when the reactor runs out of ready tasks,
+                // so all operations started by a poll are submitted together
+                let mut ring = task.as_ref().reactor.as_ref().ring.borrow_mut();
+                if self.timeout.is_some() && unsafe { sq_space_left(&ring) } < 2 {
+                    // The operation and its linked timeout must be submitted together
+                    let ret = unsafe { io_uring_submit(&mut *ring) };
+                    if ret < 0 {
+                        self.state = IOUringFutureState::Done;
+                        return Poll::Ready(Err(Error::from(std::io::Error::from_raw_os_error(
+                            -ret,
+                        ))));
+                    }
+                }
+                let mut sqe = match io_uring_get_sqe_submit(&mut *ring) {
+                    Ok(sqe) => sqe,
+                    Err(e) => {
+                        self.state = IOUringFutureState::Done;
+                        return Poll::Ready(Err(e));
+                    }
+                };
+                let completion = Rc::new(Completion::new(task.clone()));
+                let user_data = Rc::into_raw(completion.clone()) as usize as u64;
+                if let Err(e) = unsafe { self.method.call(sqe.as_mut(), user_data) } {
+                    unsafe { Rc::from_raw(user_data as usize as *const Completion) };
+                    self.state = IOUringFutureState::Done;
+                    return Poll::Ready(Err(e));
+                }
+                if self.ioprio != 0 {
+                    unsafe { sqe.as_mut().ioprio = self.ioprio };
                 }
+                if let Some(spec) = &self.timeout {
+                    unsafe {
+                        sqe.as_mut().flags |= IOSQE_IO_LINK;
+                        let mut timeout_sqe = io_uring_get_sqe_submit(&mut *ring)?;
+                        prep_link_timeout(timeout_sqe.as_mut(), spec);
+                    }
+                }
+                drop(ring);
+                task.in_flight.borrow_mut().push(user_data);
+                self.state = IOUringFutureState::Sent(completion);
+                return Poll::Pending;
             }
-            TaskState::Cancled => (
-                TaskState::Inital,
-                IOUringFutureState::Done,
-                Poll::Ready(Err(Error::Cancel)),
-            ),
-            TaskState::Timeouted => (
-                TaskState::Inital,
-                IOUringFutureState::Done,
-                Poll::Ready(Err(Error::Timeout)),
-            ),
-            TaskState::UringWaiting => (
-                TaskState::UringWaiting,
-                IOUringFutureState::Sent,
-                Poll::Pending,
-            ),
-            TaskState::UringDone(res) if res < 0 => (
-                TaskState::Inital,
-                IOUringFutureState::Done,
-                Poll::Ready(Err(Error::from(std::io::Error::from_raw_os_error(-res)))),
-            ),
-            TaskState::UringDone(res) => (
-                TaskState::Inital,
-                IOUringFutureState::Done,
-                Poll::Ready(self.method.result(res)),
-            ),
-            TaskState::UringCanceling => (
-                TaskState::UringCanceling,
-                IOUringFutureState::Sent,
-                Poll::Pending,
-            ),
-            TaskState::UringTimeouting => (
-                TaskState::UringTimeouting,
-                IOUringFutureState::Sent,
-                Poll::Pending,
-            ),
+            IOUringFutureState::Sent(completion) => match completion.result.get() {
+                Some(res) if !completion.more.get() => res,
+                _ => return Poll::Pending,
+            },
+        };
+        let canceled = match &self.state {
+            IOUringFutureState::Sent(completion) => completion.canceled.get(),
+            _ => false,
         };
-        task.state.set(ts);
-        self.state = s;
-        return r;
+        self.state = IOUringFutureState::Done;
+        if let Some(e) = interruption(&task) {
+            Poll::Ready(Err(e))
+        } else if res == -libc::ECANCELED && canceled {
+            // Canceled by `Reactor::cancel_all`
+            Poll::Ready(Err(Error::Cancel))
+        } else if res == -libc::ECANCELED && self.timeout.is_some() {
+            // Canceled by the linked timeout
+            Poll::Ready(Err(Error::Timeout))
+        } else if res < 0 {
+            Poll::Ready(Err(Error::from(std::io::Error::from_raw_os_error(-res))))
+        } else {
+            Poll::Ready(self.method.result(res))
+        }
     }
 }
 
 pub(super) struct Accept<'a> {
     fd: &'a Fd,
-    addr: libc::sockaddr_in,
-    addr_len: u64,
+    /// Address of the peer, large enough for any address family. Boxed, as the
+    /// kernel writes it and its length when the accept completes, and the future
+    /// may have been moved by then
+    addr: Box<libc::sockaddr_storage>,
+    addr_len: Box<libc::socklen_t>,
 }
 impl<'a> IOUringMethod for Accept<'a> {
-    type Output = (Fd, libc::sockaddr_in, u64);
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()> {
+    type Output = (Fd, libc::sockaddr_storage, libc::socklen_t);
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
         prep_rw(
             IORING_OP_ACCEPT,
             sqe,
             self.fd.as_raw(),
-            &mut self.addr as *mut libc::sockaddr_in as *mut core::ffi::c_void,
+            &mut *self.addr as *mut libc::sockaddr_storage as *mut core::ffi::c_void,
             0,
-            &mut self.addr_len as *mut u64 as usize as u64,
-            task,
+            &mut *self.addr_len as *mut libc::socklen_t as usize as u64,
+            user_data,
         );
+        // Do not leak accepted sockets into processes we exec
+        sqe.__bindgen_anon_3.accept_flags = libc::SOCK_CLOEXEC as u32;
         Ok(())
     }
-    fn result(&self, ret: i32) -> Result<Self::Output> {
-        Ok((Fd { fd: ret }, self.addr, self.addr_len))
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok((Fd { fd: ret }, *self.addr, *self.addr_len))
     }
 }
 impl<'a> Accept<'a> {
     pub(super) fn new(fd: &'a Fd) -> IOUringFeature<Self> {
         IOUringFeature::new(Self {
             fd,
-            addr: unsafe { std::mem::zeroed() },
-            addr_len: 0,
+            addr: Box::new(unsafe { std::mem::zeroed() }),
+            addr_len: Box::new(std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t),
         })
     }
 }
@@ -210,7 +322,7 @@ pub(super) struct Close {
 }
 impl IOUringMethod for Close {
     type Output = ();
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()> {
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
         let mut fd = None;
         std::mem::swap(&mut fd, &mut self.fd);
         if let Some(fd) = fd {
@@ -221,14 +333,14 @@ impl IOUringMethod for Close {
                 std::ptr::null_mut(),
                 0,
                 0,
-                task,
+                user_data,
             );
             Ok(())
         } else {
             Err(Error::Internal("internal error fd was none"))
         }
     }
-    fn result(&self, _: i32) -> Result<Self::Output> {
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
         Ok(())
     }
 }
@@ -242,10 +354,12 @@ pub(super) struct Write<'a> {
     fd: &'a Fd,
     data: &'a [u8],
     offset: u64,
+    /// RWF_* flags of the write
+    flags: i32,
 }
 impl<'a> IOUringMethod for Write<'a> {
     type Output = usize;
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()> {
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
         prep_rw(
             IORING_OP_WRITE,
             sqe,
@@ -253,17 +367,32 @@ impl<'a> IOUringMethod for Write<'a> {
             self.data.as_ptr() as *const core::ffi::c_void as *mut core::ffi::c_void,
             self.data.len() as u32,
             self.offset,
-            task,
+            user_data,
         );
+        sqe.__bindgen_anon_3.rw_flags = self.flags;
         Ok(())
     }
-    fn result(&self, ret: i32) -> Result<Self::Output> {
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
         Ok(ret as usize)
     }
 }
 impl<'a> Write<'a> {
     pub(super) fn new(fd: &'a Fd, data: &'a [u8], offset: u64) -> IOUringFeature<Self> {
-        IOUringFeature::new(Self { fd, data, offset })
+        Self::with_flags(fd, data, offset, 0)
+    }
+
+    pub(super) fn with_flags(
+        fd: &'a Fd,
+        data: &'a [u8],
+        offset: u64,
+        flags: i32,
+    ) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            data,
+            offset,
+            flags,
+        })
     }
 }
 
@@ -274,7 +403,7 @@ pub(super) struct Read<'a> {
 }
 impl<'a> IOUringMethod for Read<'a> {
     type Output = usize;
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()> {
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
         prep_rw(
             IORING_OP_READ,
             sqe,
@@ -282,11 +411,11 @@ impl<'a> IOUringMethod for Read<'a> {
             self.data.as_mut_ptr() as *mut core::ffi::c_void,
             self.data.len() as u32,
             self.offset,
-            task,
+            user_data,
         );
         Ok(())
     }
-    fn result(&self, ret: i32) -> Result<Self::Output> {
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
         Ok(ret as usize)
     }
 }
@@ -296,6 +425,79 @@ impl<'a> Read<'a> {
     }
 }
 
+/// Interpret the fd of the sqe as an index into the registered files (IOSQE_FIXED_FILE)
+const IOSQE_FIXED_FILE: u8 = 1 << 0;
+
+/// Read from the registered file at the given index
+pub(super) struct ReadFixed<'a> {
+    index: u32,
+    data: &'a mut [u8],
+    offset: u64,
+}
+impl<'a> IOUringMethod for ReadFixed<'a> {
+    type Output = usize;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_READ,
+            sqe,
+            self.index as i32,
+            self.data.as_mut_ptr() as *mut core::ffi::c_void,
+            self.data.len() as u32,
+            self.offset,
+            user_data,
+        );
+        sqe.flags |= IOSQE_FIXED_FILE;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok(ret as usize)
+    }
+}
+impl<'a> ReadFixed<'a> {
+    pub(super) fn new(index: u32, data: &'a mut [u8], offset: u64) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            index,
+            data,
+            offset,
+        })
+    }
+}
+
+/// Write to the registered file at the given index
+pub(super) struct WriteFixed<'a> {
+    index: u32,
+    data: &'a [u8],
+    offset: u64,
+}
+impl<'a> IOUringMethod for WriteFixed<'a> {
+    type Output = usize;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_WRITE,
+            sqe,
+            self.index as i32,
+            self.data.as_ptr() as *const core::ffi::c_void as *mut core::ffi::c_void,
+            self.data.len() as u32,
+            self.offset,
+            user_data,
+        );
+        sqe.flags |= IOSQE_FIXED_FILE;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok(ret as usize)
+    }
+}
+impl<'a> WriteFixed<'a> {
+    pub(super) fn new(index: u32, data: &'a [u8], offset: u64) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            index,
+            data,
+            offset,
+        })
+    }
+}
+
 pub(super) struct Connect<'a> {
     fd: &'a Fd,
     addr: *const libc::c_void,
@@ -304,7 +506,7 @@ pub(super) struct Connect<'a> {
 
 impl<'a> IOUringMethod for Connect<'a> {
     type Output = ();
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()> {
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
         prep_rw(
             IORING_OP_CONNECT,
             sqe,
@@ -312,11 +514,11 @@ impl<'a> IOUringMethod for Connect<'a> {
             self.addr as *mut libc::c_void,
             0,
             self.addr_size as u64,
-            task,
+            user_data,
         );
         Ok(())
     }
-    fn result(&self, _: i32) -> Result<Self::Output> {
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
         Ok(())
     }
 }
@@ -344,7 +546,7 @@ pub(super) struct OpenAt<'a> {
 
 impl<'a> IOUringMethod for OpenAt<'a> {
     type Output = Fd;
-    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, task: TaskRef) -> Result<()> {
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
         prep_rw(
             IORING_OP_OPENAT,
             sqe,
@@ -352,12 +554,12 @@ impl<'a> IOUringMethod for OpenAt<'a> {
             self.path.as_ptr() as *mut libc::c_void,
             self.mode,
             0,
-            task,
+            user_data,
         );
         sqe.__bindgen_anon_3.open_flags = self.flags;
         Ok(())
     }
-    fn result(&self, ret: i32) -> Result<Self::Output> {
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
         Ok(Fd { fd: ret })
     }
 }
@@ -376,3 +578,734 @@ impl<'a> OpenAt<'a> {
         })
     }
 }
+
+/// An operation that does nothing, but passes through the submission and completion queues
+pub(super) struct Nop {}
+
+impl IOUringMethod for Nop {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_NOP,
+            sqe,
+            -1,
+            std::ptr::null_mut(),
+            0,
+            0,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+}
+impl Nop {
+    pub(super) fn new() -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {})
+    }
+}
+
+/// A timeout of its own in the kernel, that completes with `ETIME` once `duration`
+/// has passed. The timespec is owned by the operation, so the future can be dropped
+/// while the timeout is armed, which then still completes when it expires.
+pub(super) struct SleepTimeout {
+    spec: Option<Box<libc::timespec>>,
+}
+
+impl IOUringMethod for SleepTimeout {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        let spec = self
+            .spec
+            .as_mut()
+            .ok_or(Error::Internal("Timeout without timespec"))?;
+        prep_rw(
+            IORING_OP_TIMEOUT,
+            sqe,
+            -1,
+            &mut **spec as *mut libc::timespec as *mut std::ffi::c_void,
+            1,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.timeout_flags = 0;
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        Some(Box::new(self.spec.take()))
+    }
+}
+
+impl SleepTimeout {
+    pub(super) fn new(duration: std::time::Duration) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            spec: Some(Box::new(libc::timespec {
+                tv_sec: duration.as_secs() as libc::time_t,
+                tv_nsec: duration.subsec_nanos() as libc::c_long,
+            })),
+        })
+    }
+}
+
+/// Send data on a socket with the given send(2) flags
+pub(super) struct SendFlags<'a> {
+    fd: &'a Fd,
+    data: &'a [u8],
+    flags: i32,
+}
+
+impl<'a> IOUringMethod for SendFlags<'a> {
+    type Output = usize;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_SEND,
+            sqe,
+            self.fd.as_raw(),
+            self.data.as_ptr() as *const core::ffi::c_void as *mut core::ffi::c_void,
+            self.data.len() as u32,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.msg_flags = (self.flags | libc::MSG_NOSIGNAL) as u32;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok(ret as usize)
+    }
+}
+
+impl<'a> SendFlags<'a> {
+    pub(super) fn new(fd: &'a Fd, data: &'a [u8], flags: i32) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self { fd, data, flags })
+    }
+}
+
+pub(super) struct SendMsg<'a> {
+    fd: &'a Fd,
+    data: &'a [u8],
+    control: &'a [u8],
+    /// Boxed, as the kernel reads the message header and the iovec it points
+    /// to after submission, and the future may have been moved by then
+    iov: Box<libc::iovec>,
+    msg: Box<libc::msghdr>,
+}
+
+impl<'a> IOUringMethod for SendMsg<'a> {
+    type Output = usize;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        self.iov.iov_base = self.data.as_ptr() as *mut libc::c_void;
+        self.iov.iov_len = self.data.len();
+        self.msg.msg_iov = &mut *self.iov;
+        self.msg.msg_iovlen = 1;
+        if !self.control.is_empty() {
+            self.msg.msg_control = self.control.as_ptr() as *mut libc::c_void;
+            self.msg.msg_controllen = self.control.len();
+        }
+        prep_rw(
+            IORING_OP_SENDMSG,
+            sqe,
+            self.fd.as_raw(),
+            &mut *self.msg as *mut libc::msghdr as *mut libc::c_void,
+            1,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.msg_flags = libc::MSG_NOSIGNAL as u32;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok(ret as usize)
+    }
+}
+
+impl<'a> SendMsg<'a> {
+    /// Send `data` along with the ancillary data in `control`
+    pub(super) fn new(fd: &'a Fd, data: &'a [u8], control: &'a [u8]) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            data,
+            control,
+            iov: Box::new(unsafe { std::mem::zeroed() }),
+            msg: Box::new(unsafe { std::mem::zeroed() }),
+        })
+    }
+}
+
+pub(super) struct RecvMsg<'a> {
+    fd: &'a Fd,
+    data: &'a mut [u8],
+    control: &'a mut [u8],
+    /// Boxed, as the kernel reads the iovec and writes the lengths and flags
+    /// of the message header when the receive completes, and the future may
+    /// have been moved by then
+    iov: Box<libc::iovec>,
+    msg: Box<libc::msghdr>,
+}
+
+impl<'a> IOUringMethod for RecvMsg<'a> {
+    /// The number of bytes read, the length of the received ancillary data and the message flags
+    type Output = (usize, usize, i32);
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        self.iov.iov_base = self.data.as_mut_ptr() as *mut libc::c_void;
+        self.iov.iov_len = self.data.len();
+        self.msg.msg_iov = &mut *self.iov;
+        self.msg.msg_iovlen = 1;
+        self.msg.msg_control = self.control.as_mut_ptr() as *mut libc::c_void;
+        self.msg.msg_controllen = self.control.len();
+        prep_rw(
+            IORING_OP_RECVMSG,
+            sqe,
+            self.fd.as_raw(),
+            &mut *self.msg as *mut libc::msghdr as *mut libc::c_void,
+            1,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.msg_flags = libc::MSG_CMSG_CLOEXEC as u32;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok((ret as usize, self.msg.msg_controllen, self.msg.msg_flags))
+    }
+}
+
+impl<'a> RecvMsg<'a> {
+    /// Receive into `data`, storing ancillary data in `control`
+    pub(super) fn new(
+        fd: &'a Fd,
+        data: &'a mut [u8],
+        control: &'a mut [u8],
+    ) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            data,
+            control,
+            iov: Box::new(unsafe { std::mem::zeroed() }),
+            msg: Box::new(unsafe { std::mem::zeroed() }),
+        })
+    }
+}
+
+pub(super) struct Fallocate<'a> {
+    fd: &'a Fd,
+    mode: i32,
+    offset: u64,
+    len: u64,
+}
+
+impl<'a> IOUringMethod for Fallocate<'a> {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_FALLOCATE,
+            sqe,
+            self.fd.as_raw(),
+            self.len as usize as *mut libc::c_void,
+            self.mode as u32,
+            self.offset,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+}
+
+impl<'a> Fallocate<'a> {
+    pub(super) fn new(fd: &'a Fd, mode: i32, offset: u64, len: u64) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            mode,
+            offset,
+            len,
+        })
+    }
+}
+
+/// Flag of IORING_OP_FSYNC to only flush data, like fdatasync
+const IORING_FSYNC_DATASYNC: u32 = 1;
+
+pub(super) struct Fsync<'a> {
+    fd: &'a Fd,
+    data_only: bool,
+}
+
+impl<'a> IOUringMethod for Fsync<'a> {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_FSYNC,
+            sqe,
+            self.fd.as_raw(),
+            std::ptr::null_mut(),
+            0,
+            0,
+            user_data,
+        );
+        if self.data_only {
+            sqe.__bindgen_anon_3.fsync_flags = IORING_FSYNC_DATASYNC;
+        }
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+}
+
+impl<'a> Fsync<'a> {
+    pub(super) fn new(fd: &'a Fd, data_only: bool) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self { fd, data_only })
+    }
+}
+
+pub(super) struct SyncFileRange<'a> {
+    fd: &'a Fd,
+    offset: u64,
+    len: u32,
+    flags: u32,
+}
+
+impl<'a> IOUringMethod for SyncFileRange<'a> {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_SYNC_FILE_RANGE,
+            sqe,
+            self.fd.as_raw(),
+            std::ptr::null_mut(),
+            self.len,
+            self.offset,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.sync_range_flags = self.flags;
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+}
+
+impl<'a> SyncFileRange<'a> {
+    pub(super) fn new(fd: &'a Fd, offset: u64, len: u32, flags: u32) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            offset,
+            len,
+            flags,
+        })
+    }
+}
+
+pub(super) struct EpollCtl {
+    epfd: i32,
+    op: i32,
+    fd: i32,
+    event: libc::epoll_event,
+}
+
+impl IOUringMethod for EpollCtl {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_EPOLL_CTL,
+            sqe,
+            self.epfd,
+            &mut self.event as *mut libc::epoll_event as *mut libc::c_void,
+            self.op as u32,
+            self.fd as u64,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+}
+
+impl EpollCtl {
+    pub(super) fn new(
+        epfd: i32,
+        op: i32,
+        fd: i32,
+        event: libc::epoll_event,
+    ) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            epfd,
+            op,
+            fd,
+            event,
+        })
+    }
+}
+
+/// Wait for poll events on a file descriptor
+pub(super) struct PollAdd {
+    fd: i32,
+    events: u16,
+}
+
+impl IOUringMethod for PollAdd {
+    /// The events that are ready
+    type Output = u16;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_POLL_ADD,
+            sqe,
+            self.fd,
+            std::ptr::null_mut(),
+            0,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.poll_events = self.events;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok(ret as u16)
+    }
+}
+
+impl PollAdd {
+    pub(super) fn new(fd: i32, events: u16) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self { fd, events })
+    }
+}
+
+/// Opcode of a zero-copy send, missing from older liburing headers (Linux 6.0)
+pub(super) const IORING_OP_SEND_ZC: u32 = 47;
+
+/// Send data on a socket without copying it to kernel buffers. The operation
+/// completes when the kernel notifies that the buffer is no longer used, and
+/// owns the buffer until then, also if the future is dropped before, in which
+/// case the send is canceled.
+pub(super) struct SendZc<'a> {
+    fd: &'a Fd,
+    data: Option<Vec<u8>>,
+}
+
+impl<'a> IOUringMethod for SendZc<'a> {
+    type Output = usize;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        let data = self
+            .data
+            .as_ref()
+            .ok_or(Error::Internal("Zero-copy send without buffer"))?;
+        prep_rw(
+            IORING_OP_SEND_ZC,
+            sqe,
+            self.fd.as_raw(),
+            data.as_ptr() as *const core::ffi::c_void as *mut core::ffi::c_void,
+            data.len() as u32,
+            0,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        self.data = None;
+        Ok(ret as usize)
+    }
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        Some(Box::new(self.data.take()))
+    }
+}
+
+impl<'a> SendZc<'a> {
+    pub(super) fn new(fd: &'a Fd, data: Vec<u8>) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            data: Some(data),
+        })
+    }
+}
+
+/// Write of a buffer owned by the operation, the result is the number of bytes
+/// written and the buffer. If the future is dropped while the write is in flight,
+/// the write is canceled and the buffer is freed once the kernel is done with it.
+pub(super) struct OwnedWrite<'a> {
+    fd: &'a Fd,
+    data: Option<Vec<u8>>,
+    offset: u64,
+}
+
+impl<'a> IOUringMethod for OwnedWrite<'a> {
+    type Output = (usize, Vec<u8>);
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        let data = self
+            .data
+            .as_ref()
+            .ok_or(Error::Internal("Owned write without buffer"))?;
+        prep_rw(
+            IORING_OP_WRITE,
+            sqe,
+            self.fd.as_raw(),
+            data.as_ptr() as *const core::ffi::c_void as *mut core::ffi::c_void,
+            data.len() as u32,
+            self.offset,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        let data = self
+            .data
+            .take()
+            .ok_or(Error::Internal("Owned write without buffer"))?;
+        Ok((ret as usize, data))
+    }
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        Some(Box::new(self.data.take()))
+    }
+}
+
+impl<'a> OwnedWrite<'a> {
+    pub(super) fn new(fd: &'a Fd, data: Vec<u8>, offset: u64) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            data: Some(data),
+            offset,
+        })
+    }
+}
+
+/// Read into the capacity of a buffer owned by the operation, the result is the
+/// number of bytes read and the buffer, with its length set to that number. If
+/// the future is dropped while the read is in flight, the read is canceled and
+/// the buffer is freed once the kernel is done with it.
+pub(super) struct OwnedRead<'a> {
+    fd: &'a Fd,
+    data: Option<Vec<u8>>,
+    offset: u64,
+}
+
+impl<'a> IOUringMethod for OwnedRead<'a> {
+    type Output = (usize, Vec<u8>);
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        let data = self
+            .data
+            .as_mut()
+            .ok_or(Error::Internal("Owned read without buffer"))?;
+        prep_rw(
+            IORING_OP_READ,
+            sqe,
+            self.fd.as_raw(),
+            data.as_mut_ptr() as *mut core::ffi::c_void,
+            std::cmp::min(data.capacity(), u32::max_value() as usize) as u32,
+            self.offset,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        let mut data = self
+            .data
+            .take()
+            .ok_or(Error::Internal("Owned read without buffer"))?;
+        // The kernel has initialized the first ret bytes
+        unsafe { data.set_len(ret as usize) };
+        Ok((ret as usize, data))
+    }
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        Some(Box::new(self.data.take()))
+    }
+}
+
+impl<'a> OwnedRead<'a> {
+    pub(super) fn new(fd: &'a Fd, mut data: Vec<u8>, offset: u64) -> IOUringFeature<Self> {
+        data.clear();
+        IOUringFeature::new(Self {
+            fd,
+            data: Some(data),
+            offset,
+        })
+    }
+}
+
+/// Opcodes of the extended attribute operations on file descriptors, missing
+/// from older liburing headers (Linux 5.19)
+pub(super) const IORING_OP_FSETXATTR: u32 = 41;
+pub(super) const IORING_OP_FGETXATTR: u32 = 43;
+
+/// Read the value of an extended attribute into `value`, the result is the
+/// value with the length of it. With an empty `value` only the length is returned.
+/// The name and value are owned by the operation, so the future can be dropped
+/// while the operation is in flight.
+pub(super) struct FGetXattr<'a> {
+    fd: &'a Fd,
+    name: std::ffi::CString,
+    value: Option<Vec<u8>>,
+}
+
+impl<'a> IOUringMethod for FGetXattr<'a> {
+    type Output = (Vec<u8>, usize);
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        let value = self
+            .value
+            .as_mut()
+            .ok_or(Error::Internal("Get xattr without buffer"))?;
+        prep_rw(
+            IORING_OP_FGETXATTR,
+            sqe,
+            self.fd.as_raw(),
+            self.name.as_ptr() as *mut core::ffi::c_void,
+            value.len() as u32,
+            value.as_mut_ptr() as usize as u64,
+            user_data,
+        );
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        let value = self
+            .value
+            .take()
+            .ok_or(Error::Internal("Get xattr without buffer"))?;
+        Ok((value, ret as usize))
+    }
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        Some(Box::new((
+            std::mem::take(&mut self.name),
+            self.value.take(),
+        )))
+    }
+}
+
+impl<'a> FGetXattr<'a> {
+    pub(super) fn new(fd: &'a Fd, name: std::ffi::CString, value: Vec<u8>) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            name,
+            value: Some(value),
+        })
+    }
+}
+
+/// Set the value of an extended attribute, with the flags of setxattr(2). The
+/// name and value are owned by the operation, so the future can be dropped
+/// while the operation is in flight.
+pub(super) struct FSetXattr<'a> {
+    fd: &'a Fd,
+    name: std::ffi::CString,
+    value: Vec<u8>,
+    flags: i32,
+}
+
+impl<'a> IOUringMethod for FSetXattr<'a> {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_FSETXATTR,
+            sqe,
+            self.fd.as_raw(),
+            self.name.as_ptr() as *mut core::ffi::c_void,
+            self.value.len() as u32,
+            self.value.as_ptr() as usize as u64,
+            user_data,
+        );
+        // xattr_flags shares its place in the sqe with rw_flags
+        sqe.__bindgen_anon_3.rw_flags = self.flags;
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+    fn detach(&mut self) -> Option<Box<dyn std::any::Any>> {
+        Some(Box::new((
+            std::mem::take(&mut self.name),
+            std::mem::take(&mut self.value),
+        )))
+    }
+}
+
+impl<'a> FSetXattr<'a> {
+    pub(super) fn new(
+        fd: &'a Fd,
+        name: std::ffi::CString,
+        value: Vec<u8>,
+        flags: i32,
+    ) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd,
+            name,
+            value,
+            flags,
+        })
+    }
+}
+
+/// Give advice about the use of a range of memory (madvise)
+pub(super) struct Madvise {
+    addr: *mut u8,
+    len: u32,
+    advice: u32,
+}
+
+impl IOUringMethod for Madvise {
+    type Output = ();
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_MADVISE,
+            sqe,
+            -1,
+            self.addr as *mut std::ffi::c_void,
+            self.len,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.fadvise_advice = self.advice;
+        Ok(())
+    }
+    fn result(&mut self, _: i32) -> Result<Self::Output> {
+        Ok(())
+    }
+}
+
+impl Madvise {
+    pub(super) fn new(addr: *mut u8, len: u32, advice: u32) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self { addr, len, advice })
+    }
+}
+
+/// Duplicate data from one pipe to another without consuming it
+pub(super) struct Tee {
+    fd_in: i32,
+    fd_out: i32,
+    len: u32,
+    flags: u32,
+}
+
+impl IOUringMethod for Tee {
+    type Output = usize;
+    unsafe fn call(&mut self, sqe: &mut io_uring_sqe, user_data: u64) -> Result<()> {
+        prep_rw(
+            IORING_OP_TEE,
+            sqe,
+            self.fd_out,
+            std::ptr::null_mut(),
+            self.len,
+            0,
+            user_data,
+        );
+        sqe.__bindgen_anon_3.splice_flags = self.flags;
+        sqe.__bindgen_anon_4.__bindgen_anon_1.splice_fd_in = self.fd_in;
+        Ok(())
+    }
+    fn result(&mut self, ret: i32) -> Result<Self::Output> {
+        Ok(ret as usize)
+    }
+}
+
+impl Tee {
+    pub(super) fn new(fd_in: i32, fd_out: i32, len: u32, flags: u32) -> IOUringFeature<Self> {
+        IOUringFeature::new(Self {
+            fd_in,
+            fd_out,
+            len,
+            flags,
+        })
+    }
+}
@@ -1,12 +1,20 @@
 mod io_uring_util;
 mod sys;
+mod timer_wheel;
 #[cfg(feature = "verbs")]
 pub mod verbs_util;
 
+/// Provides adapters to the io traits of the futures crate
+#[cfg(feature = "futures-compat")]
+pub mod compat;
 /// Provides filesystem access
 pub mod fs;
+/// Provides name resolution
+pub mod net;
 /// Provides tcp streams and listeners for the runtime
 pub mod tcp;
+/// Provides unix domain streams, including file descriptor passing
+pub mod unix;
 
 /// Defines the reactor
 pub mod runtime;
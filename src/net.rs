@@ -0,0 +1,29 @@
+use crate::runtime::Result;
+use crate::util::run_on_thread;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Resolve `host` to the socket addresses with the given port, without blocking the reactor
+///
+/// The lookup is made with the blocking libc resolver (`getaddrinfo`) on a
+/// separate thread, so `/etc/hosts`, `/etc/nsswitch.conf` and DNS are consulted as
+/// for any other program. Other tasks continue to run while the lookup is in progress.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::net::resolve;
+///
+/// for addr in resolve("localhost", 8080).await? {
+///     println!("{}", addr);
+/// }
+/// ```
+pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = host.to_string();
+    let addrs = run_on_thread(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>())
+    })
+    .await?;
+    Ok(addrs?)
+}
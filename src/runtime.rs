@@ -1,19 +1,133 @@
+use crate::io_uring_util::{EpollCtl, Fd, PollAdd, Read, ReadFixed, WriteFixed};
 use crate::sys::{
-    __io_uring_get_cqe, io_uring, io_uring_cqe, io_uring_get_sqe, io_uring_queue_exit,
-    io_uring_queue_init, io_uring_sqe, io_uring_submit, IORING_OP_ASYNC_CANCEL,
+    __io_uring_get_cqe, io_uring, io_uring_cqe, io_uring_get_sqe, io_uring_params, io_uring_probe,
+    io_uring_probe_op, io_uring_queue_exit, io_uring_queue_init_params, io_uring_register_probe,
+    io_uring_sqe, io_uring_submit, IORING_OP_ASYNC_CANCEL, IORING_OP_CONNECT, IORING_OP_TIMEOUT,
+    IORING_OP_TIMEOUT_REMOVE,
 };
+#[cfg(feature = "verbs")]
+use crate::sys::{ib_uverbs_comp_event_desc, IORING_OP_POLL_ADD, IORING_OP_READ};
+use crate::timer_wheel::TimerWheel;
 
-use log::info;
+use log::{debug, info, warn};
 use std::cell::{Cell, RefCell};
 use std::future::Future;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::Poll;
+use std::time::{Duration, Instant};
+
+// io_uring setup flags, defined here as they are missing from older liburing headers
+const IORING_SETUP_COOP_TASKRUN: u32 = 1 << 8;
+const IORING_SETUP_SINGLE_ISSUER: u32 = 1 << 12;
+const IORING_SETUP_DEFER_TASKRUN: u32 = 1 << 13;
+
+// io_uring cqe flags, missing from older liburing headers
+/// The operation selected a provided buffer, whose id is in the upper bits of the flags
+pub(super) const IORING_CQE_F_BUFFER: u32 = 1 << 0;
+/// More cqes follow for the same operation
+pub(super) const IORING_CQE_F_MORE: u32 = 1 << 1;
+/// The cqe is the notification of a zero-copy send that its buffer can be reused
+const IORING_CQE_F_NOTIF: u32 = 1 << 3;
+
+/// Shift of the buffer id in the flags of a cqe with `IORING_CQE_F_BUFFER`
+pub(super) const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+// io_uring_register opcodes, missing from older liburing headers
+const IORING_REGISTER_FILES: libc::c_uint = 2;
+const IORING_UNREGISTER_FILES: libc::c_uint = 3;
+const IORING_REGISTER_FILES_UPDATE: libc::c_uint = 6;
+const IORING_REGISTER_PBUF_RING: libc::c_uint = 22;
+const IORING_UNREGISTER_PBUF_RING: libc::c_uint = 23;
+/// Number of the io_uring_register system call, the same on all architectures,
+/// missing from older versions of libc
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+/// Number of the io_uring_enter system call, as for io_uring_register
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+/// Flag of io_uring_enter to wait for, and run the work of, completions
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+/// Call io_uring_register on the io_uring with the given fd
+unsafe fn io_uring_register(
+    ring_fd: i32,
+    opcode: libc::c_uint,
+    arg: *const libc::c_void,
+    nr_args: libc::c_uint,
+) -> std::io::Result<()> {
+    if libc::syscall(SYS_IO_URING_REGISTER, ring_fd, opcode, arg, nr_args) < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Flag of `io_uring_probe_op` set for opcodes supported by the kernel
+const IO_URING_OP_SUPPORTED: u16 = 1;
+/// Number of opcodes to probe for
+const PROBE_OPS: usize = 256;
+
+/// Buffer for IORING_REGISTER_PROBE, the probe header is followed by the opcodes
+#[repr(C)]
+struct Probe {
+    header: io_uring_probe,
+    ops: [io_uring_probe_op; PROBE_OPS],
+}
+
+/// Probe the opcodes supported by the running kernel
+fn probe_ops(ring: &mut io_uring) -> Vec<bool> {
+    let mut probe: Box<Probe> = Box::new(unsafe { std::mem::zeroed() });
+    let ret = unsafe {
+        io_uring_register_probe(
+            ring,
+            &mut probe.header as *mut io_uring_probe,
+            PROBE_OPS as u32,
+        )
+    };
+    if ret < 0 {
+        // Probing was added in Linux 5.6, before that only the
+        // opcodes up to IORING_OP_CONNECT were available
+        return (0..PROBE_OPS)
+            .map(|op| op <= IORING_OP_CONNECT as usize)
+            .collect();
+    }
+    let len = std::cmp::min(probe.header.ops_len as usize, PROBE_OPS);
+    probe.ops[..len]
+        .iter()
+        .map(|op| op.flags & IO_URING_OP_SUPPORTED != 0)
+        .collect()
+}
+
+/// Keep `resources`, typically a future being dropped, alive until the
+/// operations `ops` it started have completed, and cancel them
+///
+/// `ops` are the user_data of operations of the given tasks, those no longer
+/// in flight are skipped. If none are in flight `resources` is dropped right away.
+#[cfg(feature = "futures-compat")]
+pub(super) fn detach_in_flight(ops: &[(TaskRef, u64)], resources: Box<dyn std::any::Any>) {
+    let resources: Rc<dyn std::any::Any> = Rc::from(resources);
+    for (task, user_data) in ops {
+        if task.in_flight.borrow().contains(user_data) {
+            // The reactor holds a reference to the completion until its last cqe
+            let completion = unsafe { &*(*user_data as usize as *const Completion) };
+            completion.detach(Box::new(resources.clone()));
+        }
+    }
+}
+
+/// user_data of io_uring operations whose completion should be ignored, like cancels
+pub(super) const IGNORE_USER_DATA: u64 = 0;
+/// user_data of the read from the verbs completion channel
+#[cfg(feature = "verbs")]
+const VERBS_USER_DATA: u64 = 1;
+/// user_data of the timeout waking the reactor for the earliest timer
+const TIMER_USER_DATA: u64 = 2;
+/// user_data of the poll of the fd of the verbs asynchronous events
+#[cfg(feature = "verbs")]
+const VERBS_ASYNC_USER_DATA: u64 = 3;
 
-fn io_uring_cancel(task: &TaskRef) -> Result<()> {
+/// Submit a cancel of the operation with the given user_data
+pub(super) fn io_uring_cancel(reactor: &Reactor, user_data: u64) -> Result<()> {
     unsafe {
-        let mut sqep =
-            io_uring_get_sqe_submit(&mut *task.as_ref().reactor.as_ref().ring.borrow_mut())?;
+        let mut sqep = io_uring_get_sqe_submit(&mut *reactor.ring.borrow_mut())?;
         let mut sqe = sqep.as_mut();
 
         sqe.opcode = IORING_OP_ASYNC_CANCEL as u8;
@@ -21,10 +135,106 @@ fn io_uring_cancel(task: &TaskRef) -> Result<()> {
         sqe.ioprio = 0;
         sqe.fd = -1;
         sqe.__bindgen_anon_1.off = 0;
-        sqe.__bindgen_anon_2.addr = Rc::as_ptr(task) as usize as u64;
+        sqe.__bindgen_anon_2.addr = user_data;
+        sqe.len = 0;
+        sqe.__bindgen_anon_3.rw_flags = 0;
+        sqe.user_data = IGNORE_USER_DATA;
+        sqe.__bindgen_anon_4.__pad2[0] = 0;
+        sqe.__bindgen_anon_4.__pad2[1] = 0;
+        sqe.__bindgen_anon_4.__pad2[2] = 0;
+    }
+    Ok(())
+}
+
+/// Submit a timeout that completes after `duration`, waking the reactor
+fn io_uring_timer(reactor: &Reactor, duration: Duration) -> Result<()> {
+    let mut spec = reactor.timer_spec.borrow_mut();
+    spec.tv_sec = duration.as_secs() as libc::time_t;
+    spec.tv_nsec = duration.subsec_nanos() as libc::c_long;
+    unsafe {
+        let mut sqep = io_uring_get_sqe_submit(&mut *reactor.ring.borrow_mut())?;
+        let mut sqe = sqep.as_mut();
+
+        sqe.opcode = IORING_OP_TIMEOUT as u8;
+        sqe.flags = 0;
+        sqe.ioprio = 0;
+        sqe.fd = -1;
+        sqe.__bindgen_anon_1.off = 0;
+        sqe.__bindgen_anon_2.addr = &mut *spec as *mut libc::timespec as usize as u64;
+        sqe.len = 1;
+        sqe.__bindgen_anon_3.timeout_flags = 0;
+        sqe.user_data = TIMER_USER_DATA;
+        sqe.__bindgen_anon_4.__pad2[0] = 0;
+        sqe.__bindgen_anon_4.__pad2[1] = 0;
+        sqe.__bindgen_anon_4.__pad2[2] = 0;
+    }
+    Ok(())
+}
+
+/// Submit a removal of the timeout waking the reactor, its cqe fails with
+/// `ECANCELED` unless it has already expired
+fn io_uring_timer_remove(reactor: &Reactor) -> Result<()> {
+    unsafe {
+        let mut sqep = io_uring_get_sqe_submit(&mut *reactor.ring.borrow_mut())?;
+        let mut sqe = sqep.as_mut();
+
+        sqe.opcode = IORING_OP_TIMEOUT_REMOVE as u8;
+        sqe.flags = 0;
+        sqe.ioprio = 0;
+        sqe.fd = -1;
+        sqe.__bindgen_anon_1.off = 0;
+        sqe.__bindgen_anon_2.addr = TIMER_USER_DATA;
         sqe.len = 0;
+        sqe.__bindgen_anon_3.timeout_flags = 0;
+        sqe.user_data = IGNORE_USER_DATA;
+        sqe.__bindgen_anon_4.__pad2[0] = 0;
+        sqe.__bindgen_anon_4.__pad2[1] = 0;
+        sqe.__bindgen_anon_4.__pad2[2] = 0;
+    }
+    Ok(())
+}
+
+/// Submit a read of the next event on the verbs completion channel
+#[cfg(feature = "verbs")]
+fn io_uring_verbs_read(reactor: &Reactor) -> Result<()> {
+    let mut device = reactor.device.borrow_mut();
+    unsafe {
+        let mut sqep = io_uring_get_sqe_submit(&mut *reactor.ring.borrow_mut())?;
+        let mut sqe = sqep.as_mut();
+
+        sqe.opcode = IORING_OP_READ as u8;
+        sqe.flags = 0;
+        sqe.ioprio = 0;
+        sqe.fd = device.channel_fd();
+        sqe.__bindgen_anon_1.off = 0;
+        sqe.__bindgen_anon_2.addr = device.comp_event_ptr() as usize as u64;
+        sqe.len = std::mem::size_of::<ib_uverbs_comp_event_desc>() as u32;
         sqe.__bindgen_anon_3.rw_flags = 0;
-        sqe.user_data = 0;
+        sqe.user_data = VERBS_USER_DATA;
+        sqe.__bindgen_anon_4.__pad2[0] = 0;
+        sqe.__bindgen_anon_4.__pad2[1] = 0;
+        sqe.__bindgen_anon_4.__pad2[2] = 0;
+    }
+    Ok(())
+}
+
+/// Submit a poll for the next asynchronous event of the verbs device
+#[cfg(feature = "verbs")]
+fn io_uring_verbs_async_poll(reactor: &Reactor) -> Result<()> {
+    let device = reactor.device.borrow();
+    unsafe {
+        let mut sqep = io_uring_get_sqe_submit(&mut *reactor.ring.borrow_mut())?;
+        let mut sqe = sqep.as_mut();
+
+        sqe.opcode = IORING_OP_POLL_ADD as u8;
+        sqe.flags = 0;
+        sqe.ioprio = 0;
+        sqe.fd = device.async_fd();
+        sqe.__bindgen_anon_1.off = 0;
+        sqe.__bindgen_anon_2.addr = 0;
+        sqe.len = 0;
+        sqe.__bindgen_anon_3.poll_events = libc::POLLIN as u16;
+        sqe.user_data = VERBS_ASYNC_USER_DATA;
         sqe.__bindgen_anon_4.__pad2[0] = 0;
         sqe.__bindgen_anon_4.__pad2[1] = 0;
         sqe.__bindgen_anon_4.__pad2[2] = 0;
@@ -35,6 +245,39 @@ fn io_uring_cancel(task: &TaskRef) -> Result<()> {
 #[cfg(feature = "verbs")]
 use crate::verbs_util;
 
+/// Add, modify or remove the interest in `fd` of the epoll instance `epfd`,
+/// submitted through io_uring (IORING_OP_EPOLL_CTL). `op` is one of
+/// `EPOLL_CTL_ADD`, `EPOLL_CTL_MOD` or `EPOLL_CTL_DEL`, see epoll_ctl(2).
+pub async fn epoll_ctl(
+    epfd: std::os::unix::io::RawFd,
+    op: i32,
+    fd: std::os::unix::io::RawFd,
+    event: libc::epoll_event,
+) -> Result<()> {
+    EpollCtl::new(epfd, op, fd, event).await
+}
+
+/// Wait until the epoll instance `epfd` has events ready, without blocking the reactor.
+///
+/// This is how an epoll fd owned by a foreign library is driven by the reactor.
+/// io_uring reports completions while epoll reports readiness, so once this returns
+/// the caller must harvest the ready events with a non blocking `epoll_wait`
+/// (timeout 0) or the library's equivalent, perform the non blocking operations
+/// they signal, and then call this again.
+///
+/// # Examples
+///
+/// ```no_run
+/// loop {
+///     agrippa::runtime::epoll_ready(epfd).await?;
+///     library.process_events_nonblocking();
+/// }
+/// ```
+pub async fn epoll_ready(epfd: std::os::unix::io::RawFd) -> Result<()> {
+    PollAdd::new(epfd, libc::POLLIN as u16).await?;
+    Ok(())
+}
+
 #[derive(Copy, Clone)]
 pub enum Priority {
     High = 0,
@@ -42,8 +285,42 @@ pub enum Priority {
     Low = 2,
 }
 
+/// A task in the deadline lane of a `TaskQueue`, ordered so that the
+/// earliest deadline is at the top of the heap
+struct DeadlineEntry {
+    deadline: Instant,
+    seq: u64,
+    task: TaskRef,
+}
+
+impl PartialEq for DeadlineEntry {
+    fn eq(&self, o: &Self) -> bool {
+        self.deadline == o.deadline && self.seq == o.seq
+    }
+}
+
+impl Eq for DeadlineEntry {}
+
+impl PartialOrd for DeadlineEntry {
+    fn partial_cmp(&self, o: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(o))
+    }
+}
+
+impl Ord for DeadlineEntry {
+    fn cmp(&self, o: &Self) -> std::cmp::Ordering {
+        // Reversed, so that the max-heap yields the earliest deadline, and
+        // tasks with the same deadline in fifo order
+        (o.deadline, o.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
 struct TaskQueue {
     qs: [std::collections::VecDeque<TaskRef>; 3],
+    deadlines: std::collections::BinaryHeap<DeadlineEntry>,
+    seq: u64,
+    /// Record when tasks are pushed, to measure how long they wait
+    timed: bool,
 }
 
 impl TaskQueue {
@@ -54,15 +331,38 @@ impl TaskQueue {
                 std::collections::VecDeque::new(),
                 std::collections::VecDeque::new(),
             ],
+            deadlines: std::collections::BinaryHeap::new(),
+            seq: 0,
+            timed: false,
         }
     }
 
     fn push(&mut self, task: TaskRef) {
-        self.qs[task.priority as usize].push_back(task)
+        if self.timed {
+            task.enqueued.set(Some(Instant::now()));
+        }
+        if let Some(deadline) = task.deadline {
+            self.seq += 1;
+            self.deadlines.push(DeadlineEntry {
+                deadline,
+                seq: self.seq,
+                task,
+            });
+        } else {
+            self.qs[task.priority as usize].push_back(task)
+        }
     }
 
+    /// Pop the next task to run. High priority tasks run first, then
+    /// deadline tasks earliest deadline first, then normal and low priority tasks
     fn pop(&mut self) -> Option<TaskRef> {
-        for q in &mut self.qs {
+        if let Some(v) = self.qs[Priority::High as usize].pop_front() {
+            return Some(v);
+        }
+        if let Some(v) = self.deadlines.pop() {
+            return Some(v.task);
+        }
+        for q in &mut self.qs[Priority::Normal as usize..] {
             if let Some(v) = q.pop_front() {
                 return Some(v);
             }
@@ -78,10 +378,30 @@ pub enum Error {
     Timeout,
     Eof,
     Internal(&'static str),
+    /// An agrippa future was polled by something other than a task of an agrippa reactor
+    OutsideReactor,
     NulError(std::ffi::NulError),
     Boxed(Box<dyn std::error::Error>),
 }
 
+impl Error {
+    /// The kind of the underlying io error, if this is an io error
+    pub fn kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Error::Io(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+
+    /// The errno of the underlying io error, if this is an io error reported by the os
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Io(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Error::Io(error)
@@ -102,6 +422,7 @@ impl std::fmt::Display for Error {
             Error::Timeout => write!(f, "Timeout"),
             Error::Eof => write!(f, "Eof"),
             Error::Internal(s) => write!(f, "Internal error: {}", s),
+            Error::OutsideReactor => write!(f, "future awaited outside an agrippa reactor"),
             Error::NulError(e) => write!(f, "NulError: {}", e),
             Error::Boxed(e) => write!(f, "{}", e.as_ref()),
         }
@@ -121,21 +442,118 @@ pub(super) enum TaskState {
     Inital,
     Cancled,
     Timeouted,
-    UringWaiting,
-    UringCanceling,
-    UringTimeouting,
-    UringDone(i32),
+}
+
+/// Completion slot of a single io_uring operation. The user_data of the
+/// operation is a pointer to the slot, so that a task can have several
+/// operations in flight at the same time. Verbs sends use the slot the same
+/// way through the wr_id of their work request.
+///
+/// Some operations, like zero-copy sends, post more than one cqe. The slot then
+/// stays alive until the last cqe, which is the one without `IORING_CQE_F_MORE`.
+pub(super) struct Completion {
+    task: TaskRef,
+    /// Result of the operation, set when the cqe arrives
+    pub(super) result: Cell<Option<i32>>,
+    /// Set while more cqes are expected for the operation
+    pub(super) more: Cell<bool>,
+    /// Set when the operation is canceled by `Reactor::cancel_all`
+    pub(super) canceled: Cell<bool>,
+    /// Set for multishot operations selecting buffers from a buffer ring
+    pub(super) multishot: Option<Multishot>,
+    /// Resources of an operation whose future was dropped while it was in
+    /// flight, freed with the completion once the kernel is done with them
+    detached: RefCell<Option<Box<dyn std::any::Any>>>,
+}
+
+impl Completion {
+    pub(super) fn new(task: TaskRef) -> Self {
+        Completion {
+            task,
+            result: Cell::new(None),
+            more: Cell::new(false),
+            canceled: Cell::new(false),
+            multishot: None,
+            detached: RefCell::new(None),
+        }
+    }
+
+    /// Keep `resources` alive until the operation completes, and cancel it, as
+    /// its future has been dropped while it was in flight
+    pub(super) fn detach(&self, resources: Box<dyn std::any::Any>) {
+        *self.detached.borrow_mut() = Some(resources);
+        let user_data = self as *const Completion as usize as u64;
+        if let Err(e) = io_uring_cancel(&self.task.reactor, user_data) {
+            warn!("Failed to cancel dropped operation: {}", e);
+        }
+    }
+
+    /// Completion of a multishot operation selecting buffers from `ring`,
+    /// whose cqes are queued until the task consumes them
+    pub(super) fn multishot(task: TaskRef, ring: BufferRing) -> Self {
+        Completion {
+            multishot: Some(Multishot {
+                cqes: RefCell::new(std::collections::VecDeque::new()),
+                ring,
+                orphaned: Cell::new(false),
+            }),
+            ..Completion::new(task)
+        }
+    }
+
+    /// Make the task waiting for the operation ready, so that it is polled in
+    /// the current iteration of the reactor loop
+    #[cfg(feature = "verbs")]
+    pub(super) fn wake(&self) {
+        self.task.clone().wake();
+    }
+}
+
+/// State of a multishot operation, which can post any number of cqes
+/// before the task gets to consume them
+pub(super) struct Multishot {
+    /// Result and flags of the cqes not consumed yet
+    pub(super) cqes: RefCell<std::collections::VecDeque<(i32, u32)>>,
+    pub(super) ring: BufferRing,
+    /// Set once nobody consumes the cqes, the buffers selected by later
+    /// cqes are then given straight back to the ring
+    pub(super) orphaned: Cell<bool>,
 }
 
 pub(super) struct TaskContent {
-    future: RefCell<Pin<Box<dyn Future<Output = Result<()>> + 'static>>>,
+    /// The future of the task, None once the task has finished
+    future: RefCell<Option<Pin<Box<dyn Future<Output = Result<()>> + 'static>>>>,
+    /// Tasks waiting for this task to finish
+    join_waiters: RefCell<Vec<TaskRef>>,
     priority: Priority,
+    /// Set for tasks spawned with `Reactor::spawn_with_deadline`
+    deadline: Option<Instant>,
+    /// Name used to attribute log messages to the task
+    name: Option<&'static str>,
     pub(super) reactor: ReactorRef,
     pub(super) state: Cell<TaskState>,
+    /// user_data of the io_uring operations of the task that are in flight
+    pub(super) in_flight: RefCell<Vec<u64>>,
+    /// Number of entries of the task in the timer heap
+    timers: Cell<u32>,
+    /// When the task was last made ready, if wait statistics are collected
+    enqueued: Cell<Option<Instant>>,
 }
 
 pub(super) type TaskRef = Rc<TaskContent>;
 
+/// Return true if the task has not finished
+fn is_live(task: &std::rc::Weak<TaskContent>) -> bool {
+    match task.upgrade() {
+        Some(task) => match task.future.try_borrow() {
+            Ok(future) => future.is_some(),
+            // The task is being polled
+            Err(_) => true,
+        },
+        None => false,
+    }
+}
+
 impl TaskContent {
     fn wake(self: TaskRef) {
         let s = self.clone();
@@ -146,13 +564,21 @@ impl TaskContent {
     fn new<F: Future<Output = Result<()>> + 'static>(
         future: F,
         priority: Priority,
+        deadline: Option<Instant>,
+        name: Option<&'static str>,
         reactor: ReactorRef,
     ) -> Self {
         TaskContent {
-            future: RefCell::new(Box::pin(future)),
+            future: RefCell::new(Some(Box::pin(future))),
+            join_waiters: RefCell::new(Vec::new()),
             priority,
+            deadline,
+            name,
             reactor,
             state: Cell::new(TaskState::Inital),
+            in_flight: RefCell::new(Vec::new()),
+            timers: Cell::new(0),
+            enqueued: Cell::new(None),
         }
     }
 }
@@ -163,35 +589,75 @@ pub struct Task {
 }
 
 impl Task {
-    pub fn cancel(&self) -> Result<()> {
-        let s = match self.content.state.get() {
-            TaskState::Inital => TaskState::Cancled,
-            TaskState::UringWaiting => {
-                io_uring_cancel(&self.content)?;
-                TaskState::UringCanceling
-            }
-            TaskState::UringDone(_) => TaskState::Cancled,
-            v => v,
-        };
-        self.content.state.set(s);
+    /// Interrupt the task, cancelling its io_uring operations in flight
+    fn interrupt(&self, state: TaskState) -> Result<()> {
+        if let TaskState::Inital = self.content.state.get() {
+            for user_data in self.content.in_flight.borrow().iter() {
+                io_uring_cancel(&self.content.reactor, *user_data)?;
+            }
+            self.content.state.set(state);
+            // The task is woken right away to observe the interruption, also
+            // when it waits for something else than io or a timer, like a semaphore
+            if self.content.timers.get() != 0 {
+                self.content.reactor.remove_timers(&self.content, None);
+            }
+            self.content
+                .reactor
+                .ready
+                .borrow_mut()
+                .push(self.content.clone());
+        }
         Ok(())
     }
 
+    pub fn cancel(&self) -> Result<()> {
+        self.interrupt(TaskState::Cancled)
+    }
+
     pub fn timeout(&self) -> Result<()> {
-        let s = match self.content.state.get() {
-            TaskState::Inital => TaskState::Timeouted,
-            TaskState::UringWaiting => {
-                io_uring_cancel(&self.content)?;
-                TaskState::UringTimeouting
-            }
-            TaskState::UringDone(_) => TaskState::Timeouted,
-            v => v,
-        };
-        self.content.state.set(s);
-        Ok(())
+        self.interrupt(TaskState::Timeouted)
+    }
+
+    /// Return true if the task has run to completion
+    pub fn is_finished(&self) -> bool {
+        match self.content.future.try_borrow() {
+            Ok(future) => future.is_none(),
+            // The task is being polled
+            Err(_) => false,
+        }
+    }
+
+    /// Wait for the task to run to completion
+    pub async fn wait(&self) -> Result<()> {
+        Join {
+            task: self.content.clone(),
+        }
+        .await
     }
+}
+
+struct Join {
+    task: TaskRef,
+}
 
-    pub async fn wait(&self) {}
+impl Future for Join {
+    type Output = Result<()>;
+    fn poll(self: Pin<&mut Self>, _context: &mut std::task::Context) -> Poll<Self::Output> {
+        let current = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        match self.task.future.try_borrow() {
+            Ok(future) if future.is_none() => return Poll::Ready(Ok(())),
+            Ok(_) => (),
+            Err(_) => return Poll::Ready(Err(Error::Internal("Task waited for itself"))),
+        }
+        let mut waiters = self.task.join_waiters.borrow_mut();
+        if !waiters.iter().any(|w| Rc::ptr_eq(w, &current)) {
+            waiters.push(current);
+        }
+        Poll::Pending
+    }
 }
 
 unsafe fn waker_clone(data: *const ()) -> std::task::RawWaker {
@@ -219,37 +685,43 @@ unsafe fn waker_drop(data: *const ()) {
 const WAKER_VTABLE: std::task::RawWakerVTable =
     std::task::RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
 
-struct UnsafeRawWaker {
-    data: *const (),
-    _vtable: &'static std::task::RawWakerVTable,
+thread_local! {
+    /// The task being polled by the reactor running on this thread
+    static CURRENT_TASK: RefCell<Option<TaskRef>> = RefCell::new(None);
 }
 
-#[repr(transparent)]
-struct UnsafeWaker {
-    waker: UnsafeRawWaker,
+/// Return the task currently being polled by the reactor.
+///
+/// Returns `Error::OutsideReactor` if no task is being polled, as agrippa
+/// futures can only be driven by an agrippa reactor, not by another executor.
+pub(super) fn current_task() -> Result<TaskRef> {
+    CURRENT_TASK.with(|current| current.borrow().clone().ok_or(Error::OutsideReactor))
 }
 
-pub(super) fn waker_task(waker: std::task::Waker) -> TaskRef {
-    let a = unsafe {
-        TaskRef::from_raw(
-            (&waker as *const std::task::Waker as *const UnsafeWaker)
-                .as_ref()
-                .unwrap()
-                .waker
-                .data as *const TaskContent,
-        )
+/// Return the error to report if the task has been canceled or timed out.
+///
+/// The task is reset to the initial state once the last operation
+/// of the task in flight has observed the interruption.
+pub(super) fn interruption(task: &TaskRef) -> Option<Error> {
+    let e = match task.state.get() {
+        TaskState::Inital => return None,
+        TaskState::Cancled => Error::Cancel,
+        TaskState::Timeouted => Error::Timeout,
     };
-    std::mem::forget(waker);
-    a
+    if task.in_flight.borrow().is_empty() {
+        task.state.set(TaskState::Inital);
+    }
+    Some(e)
 }
 
 enum TimeEventType {
+    #[allow(dead_code)]
     Timeout,
     Wake,
 }
 
 struct TimeEvent {
-    when: u64, //Unix time in millisecond
+    when: Instant,
     task: TaskRef,
     event_type: TimeEventType,
 }
@@ -263,159 +735,1541 @@ impl PartialEq for TimeEvent {
 impl Eq for TimeEvent {}
 impl PartialOrd for TimeEvent {
     fn partial_cmp(&self, o: &Self) -> Option<std::cmp::Ordering> {
-        return Some(std::cmp::Ord::cmp(&self.when, &o.when));
+        return Some(self.cmp(o));
     }
 }
 
-pub struct Reactor {
-    ready: RefCell<TaskQueue>,
-    pub(super) ring: RefCell<io_uring>,
-    #[cfg(feature = "verbs")]
-    pub device: RefCell<verbs_util::Device>,
-    #[cfg(feature = "verbs")]
-    waiting_for_verbs_buffer: RefCell<TaskQueue>,
+impl Ord for TimeEvent {
+    fn cmp(&self, o: &Self) -> std::cmp::Ordering {
+        // Reversed, so that the max-heap yields the earliest event
+        o.when.cmp(&self.when)
+    }
 }
 
-pub(super) type ReactorRef = Rc<Reactor>;
+/// The pending timers of a reactor
+enum TimerStore {
+    Heap(std::collections::BinaryHeap<TimeEvent>),
+    Wheel(TimerWheel<TimeEvent>),
+}
 
-impl Reactor {
-    #[cfg(feature = "verbs")]
-    pub(super) fn wait_verbs_buffer(&self, t: TaskRef) {
-        self.waiting_for_verbs_buffer.borrow_mut().push(t)
-    }
+/// Statistics of how long tasks waited in the ready queue before being polled
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WaitStats {
+    /// Number of polls measured
+    pub count: u64,
+    /// Total time waited
+    pub total: Duration,
+    /// Longest time waited
+    pub max: Duration,
+}
 
-    #[cfg(feature = "verbs")]
-    pub(super) fn get_verbs_buffer(&self) -> Option<verbs_util::Buffer> {
-        self.device.borrow_mut().free_buffers.pop()
+impl WaitStats {
+    /// Average time waited
+    pub fn average(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+        }
     }
 
-    #[cfg(feature = "verbs")]
-    pub(super) fn put_verbs_buffer(&self, buffer: verbs_util::Buffer) {
-        self.device.borrow_mut().free_buffers.push(buffer)
+    fn record(&mut self, wait: Duration) {
+        self.count += 1;
+        self.total += wait;
+        if wait > self.max {
+            self.max = wait;
+        }
     }
+}
 
-    pub fn new(_size: u32) -> Result<ReactorRef> {
-        #[cfg(feature = "verbs")]
-        let mut device = verbs_util::Device::new(None, _size)?;
+/// Metrics collected by a [`Reactor`], see [`Reactor::metrics`].
+///
+/// [`Reactor`]: struct.Reactor.html
+/// [`Reactor::metrics`]: struct.Reactor.html#method.metrics
+#[derive(Clone, Debug, Default)]
+pub struct ReactorMetrics {
+    /// Ready queue wait statistics, indexed by `Priority as usize`.
+    /// Only collected when enabled with `ReactorConfig::wait_stats`.
+    pub ready_wait: [WaitStats; 3],
+}
 
-        let mut r = Rc::new(Reactor {
-            ready: RefCell::new(TaskQueue::new()),
-            ring: unsafe { std::mem::zeroed() },
+/// Options used to configure a [`Reactor`].
+///
+/// [`Reactor`]: struct.Reactor.html
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::runtime::{Reactor, ReactorConfig};
+///
+/// let reactor = Reactor::with_config(ReactorConfig::new().ring_entries(256))?;
+/// ```
+#[derive(Clone)]
+pub struct ReactorConfig {
+    ring_entries: u32,
+    setup_flags: u32,
+    slow_poll_threshold: Option<Duration>,
+    wait_stats: bool,
+    starvation_threshold: Option<Duration>,
+    cpu_affinity: Option<usize>,
+    timer_wheel: bool,
+    max_wait: Option<Duration>,
+    #[cfg(feature = "verbs")]
+    pub(super) verbs_buffer_size: u32,
+    #[cfg(feature = "verbs")]
+    pub(super) verbs_max_send_wr: u32,
+    #[cfg(feature = "verbs")]
+    pub(super) verbs_recv_sge: u32,
+    #[cfg(feature = "verbs")]
+    pub(super) verbs_send_sge: u32,
+    #[cfg(feature = "verbs")]
+    pub(super) verbs_cq_size: Option<u32>,
+}
+
+impl ReactorConfig {
+    /// Creates a new configuration with default values
+    pub fn new() -> Self {
+        ReactorConfig {
+            ring_entries: 128,
+            setup_flags: 0,
+            slow_poll_threshold: None,
+            wait_stats: false,
+            starvation_threshold: None,
+            cpu_affinity: None,
+            timer_wheel: false,
+            max_wait: None,
             #[cfg(feature = "verbs")]
-            device: RefCell::new(device),
+            verbs_buffer_size: 1024 * 1024,
             #[cfg(feature = "verbs")]
-            waiting_for_verbs_buffer: RefCell::new(TaskQueue::new()),
-        });
-
-        unsafe {
-            let ret =
-                io_uring_queue_init(128, &mut *Rc::get_mut(&mut r).unwrap().ring.borrow_mut(), 0);
-            if ret < 0 {
-                return Err(Error::from(std::io::Error::last_os_error()));
-            }
+            verbs_max_send_wr: 16,
+            #[cfg(feature = "verbs")]
+            verbs_recv_sge: 1,
+            #[cfg(feature = "verbs")]
+            verbs_send_sge: 4,
+            #[cfg(feature = "verbs")]
+            verbs_cq_size: None,
         }
-        Ok(r)
     }
 
-    pub fn spawn<F: Future<Output = Result<()>> + 'static>(
-        self: &ReactorRef,
-        priority: Priority,
-        future: F,
-    ) -> Task {
-        let task = TaskRef::new(TaskContent::new(future, priority, self.clone()));
-        self.ready.borrow_mut().push(task.clone());
-        Task { content: task }
+    /// Set the number of submission queue entries of the io_uring (default 128)
+    pub fn ring_entries(&mut self, ring_entries: u32) -> &mut Self {
+        self.ring_entries = ring_entries;
+        self
     }
 
-    pub fn run(self: &ReactorRef) -> Result<()> {
-        loop {
-            // TODO (jakobt) possible post verbs recieve here
-            // Poll verbs here
-            #[cfg(feature = "verbs")]
-            {
-                self.device.borrow_mut().process();
+    fn setup_flag(&mut self, flag: u32, enable: bool) -> &mut Self {
+        if enable {
+            self.setup_flags |= flag;
+        } else {
+            self.setup_flags &= !flag;
+        }
+        self
+    }
 
-                // Wake up a task waiting for free verbs buffers
-                if !self.device.borrow().free_buffers.is_empty() {
-                    if let Some(v) = self.waiting_for_verbs_buffer.borrow_mut().pop() {
-                        self.ready.borrow_mut().push(v)
-                    }
-                }
-            }
+    /// Tell the kernel that only the reactor thread submits to the ring (IORING_SETUP_SINGLE_ISSUER),
+    /// which is always the case for a reactor. Requires Linux 6.0.
+    pub fn single_issuer(&mut self, single_issuer: bool) -> &mut Self {
+        self.setup_flag(IORING_SETUP_SINGLE_ISSUER, single_issuer)
+    }
 
-            // Run ready tasks
-            let task = self.ready.borrow_mut().pop();
-            if let Some(task) = task {
-                let raw = std::task::RawWaker::new(
-                    Rc::into_raw(task.clone()) as *const (),
-                    &WAKER_VTABLE,
-                );
-                let waker = unsafe { std::task::Waker::from_raw(raw) };
-                let mut context = std::task::Context::from_waker(&waker);
-                match task
-                    .as_ref()
-                    .future
-                    .borrow_mut()
-                    .as_mut()
-                    .poll(&mut context)
-                {
-                    Poll::Pending => {}
-                    Poll::Ready(Ok(())) => {
-                        println!("Task finished successfully");
-                    }
-                    Poll::Ready(Err(e)) => {
-                        println!("TaskFailed {}", e);
-                    }
-                }
-                continue;
-            }
+    /// Do not interrupt the reactor thread to run completion work (IORING_SETUP_COOP_TASKRUN).
+    /// Completions are instead processed on the next transition into the kernel,
+    /// which the reactor does each time it waits for events, and each time
+    /// `poll_once` or `run_until_idle` find no completion. Requires Linux 5.19.
+    pub fn coop_taskrun(&mut self, coop_taskrun: bool) -> &mut Self {
+        self.setup_flag(IORING_SETUP_COOP_TASKRUN, coop_taskrun)
+    }
 
-            //TODO we should pool the queu and the verbs queues for a bit before handing over to the os for a wait
+    /// Defer completion work until the reactor waits for events (IORING_SETUP_DEFER_TASKRUN).
+    ///
+    /// Completions only become visible when the reactor runs out of ready tasks and
+    /// enters the kernel to wait, so they are processed in batches. This implies
+    /// `single_issuer(true)`. Requires Linux 6.1.
+    pub fn defer_taskrun(&mut self, defer_taskrun: bool) -> &mut Self {
+        if defer_taskrun {
+            self.single_issuer(true);
+        }
+        self.setup_flag(IORING_SETUP_DEFER_TASKRUN, defer_taskrun)
+    }
 
-            unsafe {
-                // TODO we should handle all entries here
-                let mut ring = self.ring.borrow_mut();
-                io_uring_submit(&mut *ring);
+    /// Warn when a single poll of a task takes longer than `threshold` (default disabled).
+    ///
+    /// A task that spends a long time in poll starves all other tasks of the reactor,
+    /// this helps finding such tasks. Enabling it costs two clock reads per poll.
+    pub fn slow_poll_threshold(&mut self, threshold: Duration) -> &mut Self {
+        self.slow_poll_threshold = Some(threshold);
+        self
+    }
 
-                let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
-                info!("Wait for event");
+    /// Collect statistics of how long tasks of each priority wait in the
+    /// ready queue before they are polled (default false). This costs a clock
+    /// read each time a task is made ready, and each time it is polled.
+    pub fn wait_stats(&mut self, wait_stats: bool) -> &mut Self {
+        self.wait_stats = wait_stats;
+        self
+    }
 
-                let ret = __io_uring_get_cqe(&mut *ring, &mut cqe, 0, 1, std::ptr::null_mut());
-                if ret < 0 {
-                    return Err(Error::from(std::io::Error::last_os_error()));
-                }
-                info!("Got event");
-                let cqe = cqe
-                    .as_mut()
-                    .ok_or(Error::Internal("Got null cqe pointer"))?;
+    /// Warn when a `Priority::Low` task has waited longer than `threshold` in the ready
+    /// queue (default disabled). As higher priority tasks always run first, this signals
+    /// that low priority tasks are starved. Implies `wait_stats(true)`.
+    pub fn starvation_threshold(&mut self, threshold: Duration) -> &mut Self {
+        self.wait_stats = true;
+        self.starvation_threshold = Some(threshold);
+        self
+    }
 
-                let task = TaskRef::from_raw(cqe.user_data as *const TaskContent);
+    /// Pin the thread running the reactor to the given cpu core (default not pinned).
+    ///
+    /// The affinity is set when `run` or `run_for` is called. Pinning reduces
+    /// latency jitter from migrations, and with verbs the core should preferably
+    /// be on the numa node local to the network device, so that completions and
+    /// buffers are not accessed across nodes. Reactor creation fails if the core is not online.
+    pub fn cpu_affinity(&mut self, cpu: usize) -> &mut Self {
+        self.cpu_affinity = Some(cpu);
+        self
+    }
 
-                match task.as_ref().state.get() {
-                    TaskState::UringWaiting => {
-                        task.as_ref().state.set(TaskState::UringDone(cqe.res))
-                    }
-                    TaskState::UringCanceling => task.as_ref().state.set(TaskState::Cancled),
-                    TaskState::UringTimeouting => task.as_ref().state.set(TaskState::Timeouted),
-                    v => panic!("Unexpected task state on uring result {:?}", v),
-                }
+    /// Bound the time the reactor blocks waiting for io_uring completions
+    /// (default unbounded).
+    ///
+    /// The reactor wakes at the latest after `max_wait`, or at the earliest timer
+    /// if that is sooner, and runs periodic maintenance, such as polling the verbs
+    /// completion queue, even when no completion arrives. This guards against work
+    /// that does not complete through io_uring going unnoticed. Costs a clock read
+    /// per iteration of the reactor.
+    pub fn max_wait(&mut self, max_wait: Duration) -> &mut Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
 
-                self.ready.borrow_mut().push(task);
-                std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
-                *ring.cq.khead.as_mut().unwrap() += 1;
-                std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
-            }
-        }
+    /// Keep timers in a hierarchical timing wheel instead of a binary heap (default false).
+    ///
+    /// Adding and cancelling a timer is O(1) in the wheel rather than O(log n) and O(n)
+    /// in the heap, which pays off with many short lived timers, such as a timeout per
+    /// request. Timers in the wheel have a resolution of one millisecond and may expire
+    /// up to a millisecond late. Either way a single io_uring timeout wakes the reactor
+    /// for the earliest timer.
+    pub fn timer_wheel(&mut self, timer_wheel: bool) -> &mut Self {
+        self.timer_wheel = timer_wheel;
+        self
     }
-}
 
-impl Drop for Reactor {
-    fn drop(&mut self) {
-        unsafe {
-            io_uring_queue_exit(self.ring.get_mut());
-        }
+    /// Set the size in bytes of each registered verbs buffer (default 1MB)
+    #[cfg(feature = "verbs")]
+    pub fn verbs_buffer_size(&mut self, verbs_buffer_size: u32) -> &mut Self {
+        self.verbs_buffer_size = verbs_buffer_size;
+        self
     }
-}
+
+    /// Set the maximal number of outstanding verbs sends per connection (default 16).
+    ///
+    /// Sends beyond this wait for an earlier send on the connection to complete.
+    /// Outstanding sends over all connections are also bounded by the room left
+    /// in the completion queue after the receives, see `verbs_cq_size`.
+    #[cfg(feature = "verbs")]
+    pub fn verbs_max_send_wr(&mut self, verbs_max_send_wr: u32) -> &mut Self {
+        self.verbs_max_send_wr = verbs_max_send_wr;
+        self
+    }
+
+    /// Set the number of buffers each verbs message is received into (default 1).
+    ///
+    /// A message larger than one buffer is scattered over the buffers,
+    /// use `Connection::recv_scatter` to receive all of them.
+    /// Must not exceed the maximal number of sges supported by the device.
+    #[cfg(feature = "verbs")]
+    pub fn verbs_recv_sge(&mut self, verbs_recv_sge: u32) -> &mut Self {
+        self.verbs_recv_sge = verbs_recv_sge;
+        self
+    }
+
+    /// Set the maximal number of segments a verbs send can gather (default 4).
+    ///
+    /// See `Connection::send_gather`. Limited to the maximal number of sges
+    /// supported by the device.
+    #[cfg(feature = "verbs")]
+    pub fn verbs_send_sge(&mut self, verbs_send_sge: u32) -> &mut Self {
+        self.verbs_send_sge = verbs_send_sge;
+        self
+    }
+
+    /// Set the number of entries of the verbs completion queue.
+    ///
+    /// The completion queue is shared by all connections, and must have room for
+    /// the completions of all posted receives and all outstanding sends. If it
+    /// overflowed, completions would be lost and the queue pairs would enter the
+    /// error state, so the outstanding sends over all connections are limited to
+    /// the entries left after the receive depth. Sends beyond this wait for a send
+    /// on any connection to complete.
+    ///
+    /// The default has room for the receives and `verbs_max_send_wr` sends, so
+    /// with several connections sending at the same time, only one of them can
+    /// pipeline a full window. Set it to the receive depth plus `verbs_max_send_wr`
+    /// times the number of connections to let all of them do so.
+    /// Reactor creation fails if it exceeds the maximum supported by the device,
+    /// or does not exceed the receive depth.
+    #[cfg(feature = "verbs")]
+    pub fn verbs_cq_size(&mut self, verbs_cq_size: u32) -> &mut Self {
+        self.verbs_cq_size = Some(verbs_cq_size);
+        self
+    }
+}
+
+impl Default for ReactorConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maximal number of scratch buffers kept in the pool of a reactor
+const MAX_POOLED_BUFFERS: usize = 32;
+
+/// A scratch buffer borrowed from the pool of a reactor.
+/// The buffer is returned to the pool when dropped.
+pub struct PooledBuf {
+    /// Initialized up to the largest length the buffer has had, which is kept
+    /// when it is reused, so that it does not have to be zeroed again
+    data: Vec<u8>,
+    len: usize,
+    reactor: ReactorRef,
+}
+
+impl PooledBuf {
+    /// Resize the buffer, new bytes are zeroed
+    pub fn resize(&mut self, new_len: usize) {
+        if new_len > self.data.len() {
+            self.data.resize(new_len, 0);
+        }
+        if new_len > self.len {
+            self.data[self.len..new_len].iter_mut().for_each(|b| *b = 0);
+        }
+        self.len = new_len;
+    }
+}
+
+impl std::ops::Deref for PooledBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl std::ops::DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut pool = self.reactor.scratch_buffers.borrow_mut();
+        if pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(std::mem::replace(&mut self.data, Vec::new()));
+        }
+    }
+}
+
+/// A descriptor in a buffer ring (struct io_uring_buf)
+#[repr(C)]
+struct IoUringBuf {
+    addr: u64,
+    len: u32,
+    bid: u16,
+    resv: u16,
+}
+
+/// Argument of IORING_REGISTER_PBUF_RING (struct io_uring_buf_reg)
+#[repr(C)]
+struct IoUringBufReg {
+    ring_addr: u64,
+    ring_entries: u32,
+    bgid: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+/// Offset of the tail in a buffer ring, it overlaps the `resv` field of the first descriptor
+const BUF_RING_TAIL_OFFSET: usize = 14;
+
+struct BufferRingContent {
+    /// Keeps the io_uring the ring is registered with open
+    reactor: ReactorRef,
+    ring_fd: i32,
+    group_id: u16,
+    /// The descriptors shared with the kernel, mapped with mmap
+    descriptors: *mut IoUringBuf,
+    mapped_len: usize,
+    /// Number of descriptors minus one, the number is a power of two
+    mask: u16,
+    tail: Cell<u16>,
+    /// Memory of the buffers, the buffer with id `i` starts at `i * buf_size`
+    memory: *mut [u8],
+    buf_size: usize,
+}
+
+impl Drop for BufferRingContent {
+    fn drop(&mut self) {
+        let mut reg: IoUringBufReg = unsafe { std::mem::zeroed() };
+        reg.bgid = self.group_id;
+        unsafe {
+            if let Err(e) = io_uring_register(
+                self.ring_fd,
+                IORING_UNREGISTER_PBUF_RING,
+                &reg as *const IoUringBufReg as *const libc::c_void,
+                1,
+            ) {
+                warn!("Failed to unregister buffer ring {}: {}", self.group_id, e);
+            }
+            libc::munmap(self.descriptors as *mut libc::c_void, self.mapped_len);
+            drop(Box::from_raw(self.memory));
+        }
+    }
+}
+
+/// A ring of buffers registered with the kernel (IORING_REGISTER_PBUF_RING), that
+/// operations like [`tcp::Socket::recv_multishot`] receive into.
+///
+/// Instead of the application passing a buffer when it submits a receive, the
+/// kernel picks a buffer from the ring when data arrives, so memory is only
+/// tied up by connections that actually receive. The kernel consumes buffers
+/// from the ring directly, and buffers are handed back by dropping the
+/// [`RingBuf`] they were received into, without any system call.
+///
+/// Buffer rings require Linux 5.19. Cloning a `BufferRing` gives another
+/// handle to the same ring, which is unregistered when the last handle and
+/// buffer are dropped.
+///
+/// [`tcp::Socket::recv_multishot`]: ../tcp/struct.Socket.html#method.recv_multishot
+/// [`RingBuf`]: struct.RingBuf.html
+#[derive(Clone)]
+pub struct BufferRing {
+    content: Rc<BufferRingContent>,
+}
+
+impl BufferRing {
+    /// The buffer group id the ring is registered as
+    pub fn group_id(&self) -> u16 {
+        self.content.group_id
+    }
+
+    /// The size of each buffer of the ring
+    pub fn buf_size(&self) -> usize {
+        self.content.buf_size
+    }
+
+    pub(super) fn reactor(&self) -> &ReactorRef {
+        &self.content.reactor
+    }
+
+    /// Give the buffer with id `bid` back to the kernel, by adding it at the
+    /// tail of the ring
+    pub(super) fn recycle(&self, bid: u16) {
+        let c = &*self.content;
+        let tail = c.tail.get();
+        unsafe {
+            let descriptor = &mut *c.descriptors.add((tail & c.mask) as usize);
+            descriptor.addr = (c.memory as *mut u8).add(bid as usize * c.buf_size) as u64;
+            descriptor.len = c.buf_size as u32;
+            descriptor.bid = bid;
+            // The kernel must see the descriptor before the new tail
+            let tail_ptr = (c.descriptors as *mut u8).add(BUF_RING_TAIL_OFFSET)
+                as *const std::sync::atomic::AtomicU16;
+            (*tail_ptr).store(tail.wrapping_add(1), std::sync::atomic::Ordering::Release);
+        }
+        c.tail.set(tail.wrapping_add(1));
+    }
+
+    /// Take ownership of the buffer with id `bid`, that the kernel has received `len` bytes into
+    pub(super) fn take(&self, bid: u16, len: usize) -> RingBuf {
+        RingBuf {
+            ring: self.clone(),
+            bid,
+            len: std::cmp::min(len, self.content.buf_size),
+        }
+    }
+}
+
+/// A buffer of a [`BufferRing`] holding received data.
+/// The buffer is given back to the ring when dropped.
+///
+/// [`BufferRing`]: struct.BufferRing.html
+pub struct RingBuf {
+    ring: BufferRing,
+    bid: u16,
+    len: usize,
+}
+
+impl std::ops::Deref for RingBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        let c = &*self.ring.content;
+        unsafe {
+            std::slice::from_raw_parts(
+                (c.memory as *const u8).add(self.bid as usize * c.buf_size),
+                self.len,
+            )
+        }
+    }
+}
+
+impl Drop for RingBuf {
+    fn drop(&mut self) {
+        self.ring.recycle(self.bid);
+    }
+}
+
+/// Argument of IORING_REGISTER_FILES_UPDATE (struct io_uring_files_update)
+#[repr(C)]
+struct IoUringFilesUpdate {
+    offset: u32,
+    resv: u32,
+    fds: u64,
+}
+
+struct FileSetContent {
+    /// Keeps the io_uring the files are registered with open
+    _reactor: ReactorRef,
+    ring_fd: i32,
+    len: u32,
+    /// Indices of the empty slots, the lowest last
+    free: RefCell<Vec<u32>>,
+}
+
+impl Drop for FileSetContent {
+    fn drop(&mut self) {
+        let ret = unsafe {
+            io_uring_register(self.ring_fd, IORING_UNREGISTER_FILES, std::ptr::null(), 0)
+        };
+        if let Err(e) = ret {
+            warn!("Failed to unregister files: {}", e);
+        }
+    }
+}
+
+/// The table of files registered with the io_uring of a reactor (IORING_REGISTER_FILES)
+///
+/// Operations on a registered file refer to it by its index in the table, which
+/// saves the kernel from looking up the file descriptor and taking a reference to
+/// the file for each operation. The table has a fixed number of slots, that are
+/// filled and emptied as connections come and go with [`insert`] and [`remove`],
+/// which update single slots (IORING_REGISTER_FILES_UPDATE).
+///
+/// The table holds a reference to each file in it, so a socket in the table is not
+/// closed by closing its file descriptor until it is removed from the table.
+///
+/// A reactor has at most one table, cloning a `FileSet` gives another handle to
+/// the same table, which is unregistered when the last handle is dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// let files = reactor.register_files(1024)?;
+/// let socket = listener.accept().await?;
+/// let index = files.insert(socket.as_raw_fd())?;
+/// let len = files.read(index, &mut data, 0).await?;
+/// files.remove(index)?;
+/// ```
+///
+/// [`insert`]: #method.insert
+/// [`remove`]: #method.remove
+#[derive(Clone)]
+pub struct FileSet {
+    content: Rc<FileSetContent>,
+}
+
+impl FileSet {
+    /// The number of slots of the table
+    pub fn len(&self) -> u32 {
+        self.content.len
+    }
+
+    /// Return true if the table has no slots
+    pub fn is_empty(&self) -> bool {
+        self.content.len == 0
+    }
+
+    /// Put `fd` in the slot at `index`, replacing the file in it, or empty
+    /// the slot if `fd` is -1
+    pub fn update(&self, index: u32, fd: std::os::unix::io::RawFd) -> Result<()> {
+        if index >= self.content.len {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "index out of range of the file set",
+            )));
+        }
+        let update = IoUringFilesUpdate {
+            offset: index,
+            resv: 0,
+            fds: &fd as *const std::os::unix::io::RawFd as u64,
+        };
+        unsafe {
+            io_uring_register(
+                self.content.ring_fd,
+                IORING_REGISTER_FILES_UPDATE,
+                &update as *const IoUringFilesUpdate as *const libc::c_void,
+                1,
+            )?;
+        }
+        let mut free = self.content.free.borrow_mut();
+        free.retain(|i| *i != index);
+        if fd == -1 {
+            // Keep the lowest free index last
+            let pos = free.iter().position(|i| *i < index).unwrap_or(free.len());
+            free.insert(pos, index);
+        }
+        Ok(())
+    }
+
+    /// Put `fd` in the lowest empty slot and return its index
+    ///
+    /// Fails with `ENFILE` if the table is full.
+    pub fn insert(&self, fd: std::os::unix::io::RawFd) -> Result<u32> {
+        let index = match self.content.free.borrow().last() {
+            Some(index) => *index,
+            None => return Err(Error::from(std::io::Error::from_raw_os_error(libc::ENFILE))),
+        };
+        self.update(index, fd)?;
+        Ok(index)
+    }
+
+    /// Empty the slot at `index`, so that it can be reused by `insert`
+    pub fn remove(&self, index: u32) -> Result<()> {
+        self.update(index, -1)
+    }
+
+    /// Read from the file at `index` into `data`, at `offset` for regular files,
+    /// return the number of bytes read
+    pub async fn read(&self, index: u32, data: &mut [u8], offset: u64) -> Result<usize> {
+        ReadFixed::new(index, data, offset).await
+    }
+
+    /// Write `data` to the file at `index`, at `offset` for regular files,
+    /// return the number of bytes written
+    pub async fn write(&self, index: u32, data: &[u8], offset: u64) -> Result<usize> {
+        WriteFixed::new(index, data, offset).await
+    }
+}
+
+pub struct Reactor {
+    ready: RefCell<TaskQueue>,
+    scratch_buffers: RefCell<Vec<Vec<u8>>>,
+    slow_poll_threshold: Option<Duration>,
+    starvation_threshold: Option<Duration>,
+    /// Cpu core to pin the thread running the reactor to
+    cpu_affinity: Option<usize>,
+    metrics: RefCell<ReactorMetrics>,
+    /// For each io_uring opcode, whether it is supported by the kernel
+    supported_ops: Vec<bool>,
+    /// Set if completion work is only run when the reactor enters the kernel,
+    /// with IORING_SETUP_COOP_TASKRUN or IORING_SETUP_DEFER_TASKRUN
+    deferred_taskrun: bool,
+    timers: RefCell<TimerStore>,
+    /// Duration of the submitted timer timeout, read by the kernel on submission
+    timer_spec: RefCell<libc::timespec>,
+    /// Deadline of the last submitted timer timeout, if it has not completed
+    timer_deadline: Cell<Option<Instant>>,
+    /// Maximal time to block waiting for completions
+    max_wait: Option<Duration>,
+    /// When periodic maintenance is next due, if `max_wait` is set
+    next_maintenance: Cell<Option<Instant>>,
+    /// Set by `stop` to make `run` return
+    stopped: Cell<bool>,
+    /// Number of spawned tasks that have not finished
+    live_tasks: Cell<usize>,
+    /// Spawned tasks, including some finished ones until they are pruned
+    tasks: RefCell<Vec<std::rc::Weak<TaskContent>>>,
+    /// Resources of dropped futures whose operations have completed, freed
+    /// once the ring is no longer borrowed
+    detached: RefCell<Vec<Box<dyn std::any::Any>>>,
+    pub(super) ring: RefCell<io_uring>,
+    #[cfg(feature = "verbs")]
+    pub device: RefCell<verbs_util::Device>,
+    #[cfg(feature = "verbs")]
+    waiting_for_verbs_buffer: RefCell<TaskQueue>,
+    /// Set when the verbs device should be processed in the next iteration of run
+    #[cfg(feature = "verbs")]
+    verbs_pending: Cell<bool>,
+    /// Set while a read of the verbs completion channel is submitted to io_uring
+    #[cfg(feature = "verbs")]
+    verbs_read_in_flight: Cell<bool>,
+    /// Set while a poll of the verbs asynchronous event fd is submitted to io_uring
+    #[cfg(feature = "verbs")]
+    verbs_async_poll_in_flight: Cell<bool>,
+}
+
+pub(super) type ReactorRef = Rc<Reactor>;
+
+impl Reactor {
+    #[cfg(feature = "verbs")]
+    pub(super) fn wait_verbs_buffer(&self, t: TaskRef) {
+        self.waiting_for_verbs_buffer.borrow_mut().push(t)
+    }
+
+    #[cfg(feature = "verbs")]
+    pub(super) fn get_verbs_buffer(&self) -> Option<verbs_util::Buffer> {
+        self.device.borrow_mut().free_buffers.pop()
+    }
+
+    #[cfg(feature = "verbs")]
+    pub(super) fn put_verbs_buffer(&self, buffer: verbs_util::Buffer) {
+        self.device.borrow_mut().free_buffers.push(buffer);
+        // The buffer may be posted as a receive buffer
+        self.verbs_pending.set(true);
+    }
+
+    /// Create a new reactor, using `size` as the size of verbs buffers
+    pub fn new(_size: u32) -> Result<ReactorRef> {
+        #[allow(unused_mut)]
+        let mut config = ReactorConfig::new();
+        #[cfg(feature = "verbs")]
+        config.verbs_buffer_size(_size);
+        Self::with_config(&config)
+    }
+
+    /// Create a new reactor with the given configuration
+    pub fn with_config(config: &ReactorConfig) -> Result<ReactorRef> {
+        if let Some(cpu) = config.cpu_affinity {
+            let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+            if cpu >= std::cmp::min(online as usize, libc::CPU_SETSIZE as usize) {
+                return Err(Error::from(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "cpu affinity is not an online cpu",
+                )));
+            }
+        }
+
+        #[cfg(feature = "verbs")]
+        let device = verbs_util::Device::new(None, config)?;
+
+        let mut r = Rc::new(Reactor {
+            ready: RefCell::new(TaskQueue {
+                timed: config.wait_stats,
+                ..TaskQueue::new()
+            }),
+            scratch_buffers: RefCell::new(Vec::new()),
+            slow_poll_threshold: config.slow_poll_threshold,
+            starvation_threshold: config.starvation_threshold,
+            cpu_affinity: config.cpu_affinity,
+            metrics: RefCell::new(ReactorMetrics::default()),
+            supported_ops: Vec::new(),
+            deferred_taskrun: config.setup_flags
+                & (IORING_SETUP_COOP_TASKRUN | IORING_SETUP_DEFER_TASKRUN)
+                != 0,
+            timers: RefCell::new(if config.timer_wheel {
+                TimerStore::Wheel(TimerWheel::new())
+            } else {
+                TimerStore::Heap(std::collections::BinaryHeap::new())
+            }),
+            timer_spec: RefCell::new(libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            }),
+            timer_deadline: Cell::new(None),
+            max_wait: config.max_wait,
+            next_maintenance: Cell::new(None),
+            stopped: Cell::new(false),
+            detached: RefCell::new(Vec::new()),
+            live_tasks: Cell::new(0),
+            tasks: RefCell::new(Vec::new()),
+            ring: unsafe { std::mem::zeroed() },
+            #[cfg(feature = "verbs")]
+            device: RefCell::new(device),
+            #[cfg(feature = "verbs")]
+            waiting_for_verbs_buffer: RefCell::new(TaskQueue::new()),
+            #[cfg(feature = "verbs")]
+            verbs_pending: Cell::new(true),
+            #[cfg(feature = "verbs")]
+            verbs_read_in_flight: Cell::new(false),
+            #[cfg(feature = "verbs")]
+            verbs_async_poll_in_flight: Cell::new(false),
+        });
+
+        unsafe {
+            let mut params: io_uring_params = std::mem::zeroed();
+            params.flags = config.setup_flags;
+            let ret = io_uring_queue_init_params(
+                config.ring_entries,
+                &mut *Rc::get_mut(&mut r).unwrap().ring.borrow_mut(),
+                &mut params,
+            );
+            if ret < 0 {
+                return Err(Error::from(std::io::Error::from_raw_os_error(-ret)));
+            }
+        }
+        let reactor = Rc::get_mut(&mut r).unwrap();
+        reactor.supported_ops = probe_ops(reactor.ring.get_mut());
+        Ok(r)
+    }
+
+    /// Return true if the running kernel supports the io_uring opcode `op`,
+    /// one of the `IORING_OP_*` values from the kernel headers.
+    ///
+    /// Operations that are not supported fail with `EINVAL`. Where possible the
+    /// high level apis fall back to blocking system calls for unsupported operations.
+    pub fn supports(&self, op: u8) -> bool {
+        self.supported_ops
+            .get(op as usize)
+            .cloned()
+            .unwrap_or(false)
+    }
+
+    /// Register a ring of `entries` buffers of `buf_size` bytes with the kernel,
+    /// as the buffer group `group_id`, see [`BufferRing`].
+    ///
+    /// `entries` must be a power of two of at most 32768, and the group id must
+    /// not be in use by another ring of the reactor. Requires Linux 5.19, older
+    /// kernels fail with `EINVAL`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let ring = reactor.register_buffer_ring(0, 256, 4096)?;
+    /// let mut recv = socket.recv_multishot(&ring);
+    /// while let Some(data) = recv.next().await? {
+    ///     handle(&data);
+    /// }
+    /// ```
+    ///
+    /// [`BufferRing`]: struct.BufferRing.html
+    pub fn register_buffer_ring(
+        self: &ReactorRef,
+        group_id: u16,
+        entries: u16,
+        buf_size: usize,
+    ) -> Result<BufferRing> {
+        if !entries.is_power_of_two() || entries > 32768 {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "entries must be a power of two of at most 32768",
+            )));
+        }
+        if buf_size == 0 || buf_size > u32::max_value() as usize {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid buffer size",
+            )));
+        }
+        // The descriptors must be page aligned, which mmap guarantees
+        let mapped_len = entries as usize * std::mem::size_of::<IoUringBuf>();
+        let descriptors = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        if descriptors == libc::MAP_FAILED {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        let ring_fd = self.ring.borrow().ring_fd;
+        let mut reg: IoUringBufReg = unsafe { std::mem::zeroed() };
+        reg.ring_addr = descriptors as u64;
+        reg.ring_entries = entries as u32;
+        reg.bgid = group_id;
+        let ret = unsafe {
+            io_uring_register(
+                ring_fd,
+                IORING_REGISTER_PBUF_RING,
+                &reg as *const IoUringBufReg as *const libc::c_void,
+                1,
+            )
+        };
+        if let Err(e) = ret {
+            unsafe { libc::munmap(descriptors, mapped_len) };
+            return Err(Error::from(e));
+        }
+        let memory = vec![0u8; entries as usize * buf_size].into_boxed_slice();
+        let ring = BufferRing {
+            content: Rc::new(BufferRingContent {
+                reactor: self.clone(),
+                ring_fd,
+                group_id,
+                descriptors: descriptors as *mut IoUringBuf,
+                mapped_len,
+                mask: entries - 1,
+                tail: Cell::new(0),
+                memory: Box::into_raw(memory),
+                buf_size,
+            }),
+        };
+        for bid in 0..entries {
+            ring.recycle(bid);
+        }
+        Ok(ring)
+    }
+
+    /// Register a table of `len` empty slots for files with the kernel, see [`FileSet`]
+    ///
+    /// Empty slots require Linux 5.5. Fails with `EBUSY` if the reactor already has a table.
+    ///
+    /// [`FileSet`]: struct.FileSet.html
+    pub fn register_files(self: &ReactorRef, len: u32) -> Result<FileSet> {
+        let fds = vec![-1 as libc::c_int; len as usize];
+        let ring_fd = self.ring.borrow().ring_fd;
+        unsafe {
+            io_uring_register(
+                ring_fd,
+                IORING_REGISTER_FILES,
+                fds.as_ptr() as *const libc::c_void,
+                len,
+            )?;
+        }
+        Ok(FileSet {
+            content: Rc::new(FileSetContent {
+                _reactor: self.clone(),
+                ring_fd,
+                len,
+                free: RefCell::new((0..len).rev().collect()),
+            }),
+        })
+    }
+
+    /// Borrow a scratch buffer of `min_size` bytes from the pool of the reactor,
+    /// allocating a new one if no large enough buffer is available.
+    ///
+    /// Reusing buffers avoids allocator churn in hot io paths. A new buffer is
+    /// zeroed, but a reused one is not, and still holds the data of its previous use.
+    pub fn acquire_buffer(self: &ReactorRef, min_size: usize) -> PooledBuf {
+        let mut pool = self.scratch_buffers.borrow_mut();
+        let mut data = match pool.iter().position(|b| b.capacity() >= min_size) {
+            Some(i) => pool.swap_remove(i),
+            None => Vec::with_capacity(min_size),
+        };
+        if data.len() < min_size {
+            data.resize(min_size, 0);
+        }
+        PooledBuf {
+            data,
+            len: min_size,
+            reactor: self.clone(),
+        }
+    }
+
+    /// Return a snapshot of the metrics collected by the reactor
+    pub fn metrics(&self) -> ReactorMetrics {
+        self.metrics.borrow().clone()
+    }
+
+    /// Return a snapshot of the state of the verbs device
+    #[cfg(feature = "verbs")]
+    pub fn verbs_stats(&self) -> verbs_util::VerbsStats {
+        self.device.borrow().stats()
+    }
+
+    /// Wake `task` when `when` is reached
+    pub(super) fn add_timer(&self, when: Instant, task: TaskRef) {
+        task.timers.set(task.timers.get() + 1);
+        let e = TimeEvent {
+            when,
+            task,
+            event_type: TimeEventType::Wake,
+        };
+        match &mut *self.timers.borrow_mut() {
+            TimerStore::Heap(heap) => heap.push(e),
+            TimerStore::Wheel(wheel) => wheel.insert(when, e),
+        }
+    }
+
+    /// Remove the timers of `task`, only the ones at `when` if given
+    pub(super) fn remove_timers(&self, task: &TaskRef, when: Option<Instant>) {
+        if task.timers.get() == 0 {
+            return;
+        }
+        match &mut *self.timers.borrow_mut() {
+            TimerStore::Heap(heap) => heap.retain(|e| {
+                let remove = Rc::ptr_eq(&e.task, task) && when.map_or(true, |w| w == e.when);
+                if remove {
+                    task.timers.set(task.timers.get() - 1);
+                }
+                !remove
+            }),
+            TimerStore::Wheel(wheel) => {
+                let removed = wheel.remove(when, |e| Rc::ptr_eq(&e.task, task));
+                task.timers.set(task.timers.get() - removed as u32);
+            }
+        }
+    }
+
+    /// Make the tasks whose timers have expired ready
+    fn expire_timers(&self) {
+        let mut expired = Vec::new();
+        match &mut *self.timers.borrow_mut() {
+            TimerStore::Heap(heap) => {
+                if heap.is_empty() {
+                    return;
+                }
+                let now = Instant::now();
+                while heap.peek().map_or(false, |e| e.when <= now) {
+                    expired.push(heap.pop().unwrap());
+                }
+            }
+            TimerStore::Wheel(wheel) => {
+                if wheel.is_empty() {
+                    return;
+                }
+                wheel.expire(Instant::now(), &mut expired);
+            }
+        }
+        for e in expired {
+            e.task.timers.set(e.task.timers.get() - 1);
+            match e.event_type {
+                TimeEventType::Wake => self.ready.borrow_mut().push(e.task),
+                TimeEventType::Timeout => {}
+            }
+        }
+    }
+
+    /// Run periodic maintenance if it is due, and return when it is next due,
+    /// None if `max_wait` is not configured
+    fn maintain(&self) -> Option<Instant> {
+        let interval = self.max_wait?;
+        let now = Instant::now();
+        let next = match self.next_maintenance.get() {
+            Some(next) if next > now => next,
+            _ => {
+                // Poll the completion queue even if no event arrived on the
+                // completion channel
+                #[cfg(feature = "verbs")]
+                self.verbs_pending.set(true);
+                now + interval
+            }
+        };
+        self.next_maintenance.set(Some(next));
+        Some(next)
+    }
+
+    /// Make sure a timeout is submitted that wakes the reactor for the earliest timer,
+    /// or at `limit` if that is earlier
+    fn arm_timer(&self, limit: Option<Instant>) -> Result<()> {
+        let next = match &*self.timers.borrow() {
+            TimerStore::Heap(heap) => heap.peek().map(|e| e.when),
+            TimerStore::Wheel(wheel) => wheel.next_deadline(),
+        };
+        let next = match (next, limit) {
+            (Some(next), Some(limit)) => Some(std::cmp::min(next, limit)),
+            (next, limit) => next.or(limit),
+        };
+        let when = match next {
+            Some(when) => when,
+            None => return Ok(()),
+        };
+        let deadline = self.timer_deadline.get();
+        if deadline.map_or(true, |d| when < d) {
+            // Replace the later timeout in flight, so that the kernel only tracks
+            // a single timeout for the reactor. The removal is submitted before
+            // the new timeout, so it can not remove that instead
+            if deadline.is_some() {
+                io_uring_timer_remove(self)?;
+            }
+            io_uring_timer(self, when.saturating_duration_since(Instant::now()))?;
+            self.timer_deadline.set(Some(when));
+        }
+        Ok(())
+    }
+
+    fn spawn_task<F: Future<Output = Result<()>> + 'static>(
+        self: &ReactorRef,
+        priority: Priority,
+        deadline: Option<Instant>,
+        name: Option<&'static str>,
+        future: F,
+    ) -> Task {
+        let task = TaskRef::new(TaskContent::new(
+            future,
+            priority,
+            deadline,
+            name,
+            self.clone(),
+        ));
+        self.ready.borrow_mut().push(task.clone());
+        self.live_tasks.set(self.live_tasks.get() + 1);
+        let mut tasks = self.tasks.borrow_mut();
+        // Prune finished tasks once they make up half of the list
+        if tasks.len() >= 2 * self.live_tasks.get() + 64 {
+            tasks.retain(is_live);
+        }
+        tasks.push(Rc::downgrade(&task));
+        drop(tasks);
+        Task { content: task }
+    }
+
+    pub fn spawn<F: Future<Output = Result<()>> + 'static>(
+        self: &ReactorRef,
+        priority: Priority,
+        future: F,
+    ) -> Task {
+        self.spawn_task(priority, None, None, future)
+    }
+
+    /// Return a builder to configure and spawn a task
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use agrippa::runtime::Priority;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let task = reactor
+    ///     .task()
+    ///     .name("flush")
+    ///     .priority(Priority::High)
+    ///     .deadline(Instant::now() + Duration::from_millis(5))
+    ///     .spawn(flush());
+    /// ```
+    pub fn task(self: &ReactorRef) -> TaskBuilder {
+        TaskBuilder {
+            reactor: self.clone(),
+            priority: Priority::Normal,
+            deadline: None,
+            name: None,
+        }
+    }
+
+    /// Spawn a task with a name, that is used to attribute log messages to the task
+    pub fn spawn_named<F: Future<Output = Result<()>> + 'static>(
+        self: &ReactorRef,
+        name: &'static str,
+        priority: Priority,
+        future: F,
+    ) -> Task {
+        self.spawn_task(priority, None, Some(name), future)
+    }
+
+    /// Spawn a task that is scheduled earliest deadline first.
+    ///
+    /// Whenever the task is ready, it is placed in a deadline lane ordered by `deadline`.
+    /// The deadline lane is serviced after [`Priority::High`] tasks, but before
+    /// [`Priority::Normal`] and [`Priority::Low`] tasks. The deadline is only a
+    /// scheduling hint, the task is not cancelled when the deadline passes.
+    ///
+    /// [`Priority::High`]: enum.Priority.html
+    /// [`Priority::Normal`]: enum.Priority.html
+    /// [`Priority::Low`]: enum.Priority.html
+    pub fn spawn_with_deadline<F: Future<Output = Result<()>> + 'static>(
+        self: &ReactorRef,
+        deadline: Instant,
+        future: F,
+    ) -> Task {
+        self.spawn_task(Priority::Normal, Some(deadline), None, future)
+    }
+
+    /// Poll a task that has been popped from the ready queue
+    fn poll_task(self: &ReactorRef, task: TaskRef) {
+        if task.future.borrow().is_none() {
+            // The task was woken after it finished
+            return;
+        }
+        if let Some(enqueued) = task.enqueued.take() {
+            let wait = enqueued.elapsed();
+            self.metrics.borrow_mut().ready_wait[task.priority as usize].record(wait);
+            if let (Priority::Low, Some(threshold)) = (task.priority, self.starvation_threshold) {
+                if wait > threshold {
+                    warn!(
+                        "Low priority task {} starved for {:?}",
+                        task.name.unwrap_or("<unnamed>"),
+                        wait
+                    );
+                }
+            }
+        }
+        let raw = std::task::RawWaker::new(Rc::into_raw(task.clone()) as *const (), &WAKER_VTABLE);
+        let waker = unsafe { std::task::Waker::from_raw(raw) };
+        let mut context = std::task::Context::from_waker(&waker);
+        let start = self.slow_poll_threshold.map(|_| Instant::now());
+        let previous = CURRENT_TASK.with(|current| current.replace(Some(task.clone())));
+        let result = task
+            .as_ref()
+            .future
+            .borrow_mut()
+            .as_mut()
+            .unwrap()
+            .as_mut()
+            .poll(&mut context);
+        CURRENT_TASK.with(|current| current.replace(previous));
+        if let (Some(start), Some(threshold)) = (start, self.slow_poll_threshold) {
+            let elapsed = start.elapsed();
+            if elapsed > threshold {
+                warn!(
+                    "Task {} spent {:?} in a single poll",
+                    task.name.unwrap_or("<unnamed>"),
+                    elapsed
+                );
+            }
+        }
+        if result.is_ready() {
+            // Drop the future of the finished task, and wake the tasks waiting for it
+            let future = task.future.borrow_mut().take();
+            drop(future);
+            self.live_tasks.set(self.live_tasks.get() - 1);
+            let waiters: Vec<TaskRef> = task.join_waiters.borrow_mut().drain(..).collect();
+            for waiter in waiters {
+                self.ready.borrow_mut().push(waiter);
+            }
+        }
+        match result {
+            Poll::Pending => {}
+            Poll::Ready(Ok(())) => {
+                info!(
+                    "Task {} finished successfully",
+                    task.name.unwrap_or("<unnamed>")
+                );
+            }
+            Poll::Ready(Err(e)) => {
+                warn!("Task {} failed {}", task.name.unwrap_or("<unnamed>"), e);
+            }
+        }
+    }
+
+    /// Run a single iteration of the reactor loop: poll one ready task, or
+    /// else handle one io_uring completion. If `wait` is true the reactor
+    /// blocks until a completion arrives, or at the latest until `deadline`.
+    /// Returns false if there was nothing to do.
+    fn step(self: &ReactorRef, wait: bool, deadline: Option<Instant>) -> Result<bool> {
+        let maintenance = self.maintain();
+
+        // Only process the verbs device when the completion channel has signaled
+        // a completion, or when we have new buffers to post. Otherwise we rely on
+        // the read of the completion channel to wake us up from io_uring
+        #[cfg(feature = "verbs")]
+        {
+            if self.verbs_pending.replace(false) {
+                self.device.borrow_mut().process()?;
+            }
+            // The cq is armed after process, so make sure that we will be woken
+            // by the completion channel when we block in io_uring below
+            if !self.verbs_read_in_flight.get() {
+                io_uring_verbs_read(self)?;
+                self.verbs_read_in_flight.set(true);
+            }
+            // Queue pairs failing without a completion are only reported by
+            // asynchronous events, which fail the receives waiting on them
+            if !self.verbs_async_poll_in_flight.get() {
+                io_uring_verbs_async_poll(self)?;
+                self.verbs_async_poll_in_flight.set(true);
+            }
+
+            // Wake up a task waiting for free verbs buffers
+            if !self.device.borrow().free_buffers.is_empty() {
+                if let Some(v) = self.waiting_for_verbs_buffer.borrow_mut().pop() {
+                    self.ready.borrow_mut().push(v)
+                }
+            }
+        }
+
+        self.expire_timers();
+
+        // Run ready tasks
+        let task = self.ready.borrow_mut().pop();
+        if let Some(task) = task {
+            self.poll_task(task);
+            return Ok(true);
+        }
+
+        //TODO we should pool the queu and the verbs queues for a bit before handing over to the os for a wait
+
+        let deadline = match (deadline, maintenance) {
+            (Some(deadline), Some(maintenance)) => Some(std::cmp::min(deadline, maintenance)),
+            (deadline, maintenance) => deadline.or(maintenance),
+        };
+        self.arm_timer(deadline)?;
+
+        unsafe {
+            // TODO we should handle all entries here
+            let mut ring = self.ring.borrow_mut();
+            io_uring_submit(&mut *ring);
+
+            let mut cqe: *mut io_uring_cqe = std::ptr::null_mut();
+            info!("Wait for event");
+
+            // When waiting, an empty completion queue makes liburing enter the kernel
+            // with IORING_ENTER_GETEVENTS, which also runs the completion work deferred
+            // by IORING_SETUP_COOP_TASKRUN (Linux 5.19) or IORING_SETUP_DEFER_TASKRUN
+            // (Linux 6.1). Without waiting liburing only enters the kernel if the ring
+            // flags pending work, which requires IORING_SETUP_TASKRUN_FLAG, so enter
+            // it here to have the deferred completions posted.
+            if !wait && self.deferred_taskrun {
+                let ret = libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    ring.ring_fd,
+                    0,
+                    0,
+                    IORING_ENTER_GETEVENTS,
+                    std::ptr::null::<libc::c_void>(),
+                    0,
+                );
+                if ret < 0 {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(Error::from(e));
+                    }
+                }
+            }
+
+            let ret = __io_uring_get_cqe(
+                &mut *ring,
+                &mut cqe,
+                0,
+                if wait { 1 } else { 0 },
+                std::ptr::null_mut(),
+            );
+            if ret == -libc::EAGAIN && !wait {
+                return Ok(false);
+            }
+            if ret < 0 {
+                return Err(Error::from(std::io::Error::from_raw_os_error(-ret)));
+            }
+            info!("Got event");
+            let cqe = cqe
+                .as_mut()
+                .ok_or(Error::Internal("Got null cqe pointer"))?;
+
+            match cqe.user_data {
+                IGNORE_USER_DATA => {}
+                // Removed by arm_timer, which has already armed its replacement
+                TIMER_USER_DATA if cqe.res == -libc::ECANCELED => {}
+                TIMER_USER_DATA => {
+                    // Expired timers are handled at the start of the next iteration
+                    self.timer_deadline.set(None);
+                }
+                // The read is submitted again by the next iteration
+                #[cfg(feature = "verbs")]
+                VERBS_USER_DATA if cqe.res == -libc::ECANCELED => {
+                    self.verbs_read_in_flight.set(false);
+                    self.verbs_pending.set(true);
+                }
+                #[cfg(feature = "verbs")]
+                VERBS_USER_DATA => {
+                    self.verbs_read_in_flight.set(false);
+                    self.verbs_pending.set(true);
+                    self.device.borrow_mut().handle_comp_event(cqe.res)?;
+                }
+                // The poll is submitted again by the next iteration
+                #[cfg(feature = "verbs")]
+                VERBS_ASYNC_USER_DATA => {
+                    self.verbs_async_poll_in_flight.set(false);
+                    if cqe.res >= 0 {
+                        self.device.borrow_mut().handle_async_events()?;
+                    }
+                    self.verbs_pending.set(true);
+                }
+                user_data => {
+                    let completion = Rc::from_raw(user_data as usize as *const Completion);
+                    match &completion.multishot {
+                        Some(multishot) if multishot.orphaned.get() => {
+                            if cqe.flags & IORING_CQE_F_BUFFER != 0 {
+                                multishot
+                                    .ring
+                                    .recycle((cqe.flags >> IORING_CQE_BUFFER_SHIFT) as u16);
+                            }
+                        }
+                        Some(multishot) => {
+                            multishot.cqes.borrow_mut().push_back((cqe.res, cqe.flags))
+                        }
+                        None if cqe.flags & IORING_CQE_F_NOTIF == 0 => {
+                            completion.result.set(Some(cqe.res));
+                        }
+                        None => {}
+                    }
+                    let more = cqe.flags & IORING_CQE_F_MORE != 0;
+                    completion.more.set(more);
+                    let task = completion.task.clone();
+                    if more {
+                        // The user_data keeps its reference until the last cqe
+                        std::mem::forget(completion);
+                    } else {
+                        task.in_flight.borrow_mut().retain(|v| *v != user_data);
+                        if let Some(resources) = completion.detached.borrow_mut().take() {
+                            self.detached.borrow_mut().push(resources);
+                        }
+                    }
+                    self.ready.borrow_mut().push(task);
+                }
+            }
+            std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
+            *ring.cq.khead.as_mut().unwrap() += 1;
+            std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+        }
+        // Detached futures may submit operations when dropped
+        let detached: Vec<_> = self.detached.borrow_mut().drain(..).collect();
+        drop(detached);
+        Ok(true)
+    }
+
+    /// Run a single iteration of the reactor loop without blocking: poll one
+    /// ready task, or else handle one available io_uring completion.
+    /// Returns false if there was nothing to do.
+    pub fn poll_once(self: &ReactorRef) -> Result<bool> {
+        self.step(false, None)
+    }
+
+    /// Run tasks and handle io completions until no task is ready and no
+    /// completion is available, then return.
+    ///
+    /// io that is still in flight is not waited for, so this is mostly useful to
+    /// deterministically advance tasks in tests, and to integrate with other event loops.
+    pub fn run_until_idle(self: &ReactorRef) -> Result<()> {
+        while self.step(false, None)? {}
+        Ok(())
+    }
+
+    /// Submit the operations queued in the submission queue to the kernel right
+    /// away, and return the number of operations submitted.
+    ///
+    /// Normally queued operations are submitted when the reactor runs out of ready
+    /// tasks. A task that has started several operations can call this to have the
+    /// kernel start on them before the task yields. This does not wait for any completion.
+    pub fn flush_submissions(&self) -> Result<usize> {
+        let ret = unsafe { io_uring_submit(&mut *self.ring.borrow_mut()) };
+        if ret < 0 {
+            return Err(Error::from(std::io::Error::from_raw_os_error(-ret)));
+        }
+        Ok(ret as usize)
+    }
+
+    /// Pin the calling thread to the configured cpu core, if any
+    fn set_affinity(&self) -> Result<()> {
+        if let Some(cpu) = self.cpu_affinity {
+            unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                libc::CPU_SET(cpu, &mut set);
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                    return Err(Error::from(std::io::Error::last_os_error()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run tasks and handle io completions until all spawned tasks have
+    /// finished, or `stop` is called.
+    ///
+    /// A reactor without unfinished tasks has nothing that could ever make progress,
+    /// so `run` returns right away, also when no task was spawned at all, rather
+    /// than blocking forever. io still in flight, for instance of dropped futures,
+    /// is not waited for.
+    pub fn run(self: &ReactorRef) -> Result<()> {
+        self.set_affinity()?;
+        loop {
+            if self.stopped.replace(false) {
+                break;
+            }
+            if self.live_tasks.get() == 0 {
+                debug!("reactor has no work, returning");
+                break;
+            }
+            self.step(true, None)?;
+        }
+        Ok(())
+    }
+
+    /// Cancel every io_uring operation in flight of the tasks of the reactor.
+    ///
+    /// The canceled operations fail with `Error::Cancel`, which unblocks all tasks
+    /// waiting for io, for instance to shut down quickly. Unlike [`Task::cancel`]
+    /// the tasks are not interrupted, so they can go on to start new io, like
+    /// closing their sockets. Tasks sleeping or waiting for other tasks are not
+    /// affected, and neither is the internal io of the reactor. Operations that
+    /// are already being completed by the kernel may still complete normally.
+    ///
+    /// [`Task::cancel`]: struct.Task.html#method.cancel
+    pub fn cancel_all(&self) -> Result<()> {
+        let mut tasks = self.tasks.borrow_mut();
+        tasks.retain(is_live);
+        for task in tasks.iter().filter_map(|task| task.upgrade()) {
+            for user_data in task.in_flight.borrow().iter() {
+                // The reactor holds a reference to the completion until its last cqe
+                let completion = unsafe { &*(*user_data as usize as *const Completion) };
+                completion.canceled.set(true);
+                io_uring_cancel(self, *user_data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make `run` return once the current task has been polled, leaving the
+    /// remaining tasks and io in flight in place for a later `run`
+    pub fn stop(&self) {
+        self.stopped.set(true);
+    }
+
+    /// Run tasks and handle io completions for at most `duration`, then return,
+    /// even if tasks are still ready.
+    ///
+    /// This lets the reactor be driven from an outer loop that is not async, like
+    /// a game or gui event loop. io that is still in flight when this returns is
+    /// not canceled, it completes in a later call. A single poll of a task is not
+    /// interrupted, so a task spending a long time in poll can overrun the duration.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// loop {
+    ///     handle_input();
+    ///     reactor.run_for(Duration::from_millis(5))?;
+    ///     render();
+    /// }
+    /// ```
+    pub fn run_for(self: &ReactorRef, duration: Duration) -> Result<()> {
+        self.set_affinity()?;
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.step(true, Some(deadline))?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for configuring how a task is spawned, see [`Reactor::task`]
+///
+/// [`Reactor::task`]: struct.Reactor.html#method.task
+pub struct TaskBuilder {
+    reactor: ReactorRef,
+    priority: Priority,
+    deadline: Option<Instant>,
+    name: Option<&'static str>,
+}
+
+impl TaskBuilder {
+    /// Set the priority of the task (default `Priority::Normal`)
+    pub fn priority(&mut self, priority: Priority) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set the name used to attribute log messages to the task
+    pub fn name(&mut self, name: &'static str) -> &mut Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Schedule the task earliest deadline first, see [`Reactor::spawn_with_deadline`].
+    /// A task with a deadline is placed in the deadline lane whatever its priority.
+    ///
+    /// [`Reactor::spawn_with_deadline`]: struct.Reactor.html#method.spawn_with_deadline
+    pub fn deadline(&mut self, deadline: Instant) -> &mut Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Spawn the task with the configured options
+    pub fn spawn<F: Future<Output = Result<()>> + 'static>(&self, future: F) -> Task {
+        self.reactor
+            .spawn_task(self.priority, self.deadline, self.name, future)
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe {
+            io_uring_queue_exit(self.ring.get_mut());
+        }
+    }
+}
 
 pub(super) fn io_uring_get_sqe_submit(
     ring: *mut io_uring,
@@ -431,3 +2285,142 @@ pub(super) fn io_uring_get_sqe_submit(
         }
     }
 }
+
+/// An error that can be sent between threads, with boxed errors converted to io errors
+struct SendError(Error);
+
+// All variants other than `Error::Boxed` are Send
+unsafe impl Send for SendError {}
+
+impl SendError {
+    fn new(e: Error) -> Self {
+        match e {
+            Error::Boxed(e) => SendError(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ))),
+            e => SendError(e),
+        }
+    }
+}
+
+/// Wake the stop tasks of all shards by writing to their eventfds
+fn stop_shards(eventfds: &[i32]) {
+    for fd in eventfds {
+        let one: u64 = 1;
+        unsafe { libc::write(*fd, &one as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+/// Run a shard, a reactor set up by `setup`, until it is stopped
+fn run_shard<F>(index: usize, config: &ReactorConfig, setup: &F, eventfd: i32) -> Result<()>
+where
+    F: Fn(usize, &ReactorRef) -> Result<()>,
+{
+    let reactor = Reactor::with_config(config)?;
+    let r = reactor.clone();
+    reactor.spawn_named("shard_stop", Priority::High, async move {
+        // The eventfds are owned by `run_sharded`, which closes them once all shards are done
+        let fd = Fd { fd: eventfd };
+        let mut buf = [0u8; 8];
+        let res = loop {
+            match Read::new(&fd, &mut buf, 0).await {
+                // Canceled by `Reactor::cancel_all`, the shard is still to be stopped
+                Err(Error::Cancel) => continue,
+                res => break res,
+            }
+        };
+        fd.into_raw();
+        res?;
+        r.stop();
+        Ok(())
+    });
+    setup(index, &reactor)?;
+    reactor.run()
+}
+
+/// Run `shards` reactors, each on its own thread, and wait for all of them to stop.
+///
+/// As the reactor is single threaded, the way to use more cores is to run a reactor
+/// per core that serves its share of the work, for instance each with its own
+/// listener bound with `SO_REUSEPORT`, see [`ListenOptions::reuse_port`], so that the
+/// kernel spreads connections over the shards. Tasks are never moved between shards.
+///
+/// Each shard creates a reactor with `config`, and calls `setup` with the index of the
+/// shard and the reactor to spawn its tasks, before running the reactor. When any shard
+/// stops, because a task calls [`Reactor::stop`], `setup` fails or the reactor fails,
+/// all other shards are stopped as well. The first error of the shards, in the order of
+/// their index, is returned. Note that a cpu affinity in `config` applies to all shards.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::runtime::{run_sharded, Priority, ReactorConfig};
+/// use agrippa::tcp::ListenOptions;
+///
+/// run_sharded(4, &ReactorConfig::new(), |_, reactor| {
+///     reactor.spawn(Priority::Normal, async {
+///         let listener = ListenOptions::new().reuse_port(true).listen("0.0.0.0:8080").await?;
+///         serve(listener).await
+///     });
+///     Ok(())
+/// })?;
+/// ```
+///
+/// [`ListenOptions::reuse_port`]: ../tcp/struct.ListenOptions.html#method.reuse_port
+/// [`Reactor::stop`]: struct.Reactor.html#method.stop
+pub fn run_sharded<F>(shards: usize, config: &ReactorConfig, setup: F) -> Result<()>
+where
+    F: Fn(usize, &ReactorRef) -> Result<()> + Send + Sync + 'static,
+{
+    let mut eventfds = Vec::with_capacity(shards);
+    for _ in 0..shards {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            let e = std::io::Error::last_os_error();
+            for fd in eventfds {
+                unsafe { libc::close(fd) };
+            }
+            return Err(Error::from(e));
+        }
+        eventfds.push(fd);
+    }
+    let eventfds = std::sync::Arc::new(eventfds);
+    let setup = std::sync::Arc::new(setup);
+    let mut threads = Vec::with_capacity(shards);
+    let mut result = Ok(());
+    for index in 0..shards {
+        let config = config.clone();
+        let setup = setup.clone();
+        let fds = eventfds.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("agrippa-shard-{}", index))
+            .spawn(move || {
+                let res = run_shard(index, &config, &*setup, fds[index]);
+                stop_shards(&fds);
+                res.map_err(SendError::new)
+            });
+        match thread {
+            Ok(thread) => threads.push(thread),
+            Err(e) => {
+                // Stop the shards already started
+                stop_shards(&eventfds[..index]);
+                result = Err(Error::from(e));
+                break;
+            }
+        }
+    }
+    for thread in threads {
+        let res = match thread.join() {
+            Ok(res) => res.map_err(|e| e.0),
+            Err(_) => Err(Error::Internal("Shard panicked")),
+        };
+        if result.is_ok() {
+            result = res;
+        }
+    }
+    for fd in eventfds.iter() {
+        unsafe { libc::close(*fd) };
+    }
+    result
+}
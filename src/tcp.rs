@@ -1,24 +1,518 @@
-use crate::io_uring_util::{Accept, Close, Connect, Fd, Read, Write};
-use crate::runtime::{Error, Result};
+use crate::io_uring_util::{
+    io_uring_recv_multishot, Accept, Close, Connect, Fd, OwnedRead, OwnedWrite, PollAdd, Read,
+    SendFlags, SendZc, Write, IORING_OP_SEND_ZC,
+};
+use crate::net::resolve;
+use crate::runtime::{
+    current_task, interruption, io_uring_cancel, BufferRing, Completion, Error, PooledBuf,
+    Priority, Result, RingBuf, IORING_CQE_BUFFER_SHIFT, IORING_CQE_F_BUFFER, IORING_CQE_F_MORE,
+};
+use crate::util::{
+    acquire_buffer, catch_unwind, current_reactor, sleep, AsyncRead, AsyncWrite, Permit, Pod,
+    Semaphore,
+};
 use libc;
-use std::net::TcpListener;
+use log::warn;
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 /// Listening socket that can be used to accept connections
 pub struct ListenSocket {
     fd: Fd,
+    /// How long to wait before accepting again when out of file descriptors
+    emfile_backoff: Option<Duration>,
+    /// Descriptor reserved to be able to reject a connection when out of file descriptors
+    spare_fd: RefCell<Option<Fd>>,
+    /// Permits held by the accepted connections, see `limit_connections`
+    connection_limit: Option<Semaphore>,
+}
+
+fn is_out_of_fds(e: &Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => true,
+        _ => false,
+    }
+}
+
+fn open_spare_fd() -> Result<Fd> {
+    let fd = unsafe {
+        libc::open(
+            b"/dev/null\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+    Ok(Fd { fd })
 }
 
 impl ListenSocket {
+    fn new(fd: Fd) -> ListenSocket {
+        ListenSocket {
+            fd,
+            emfile_backoff: None,
+            spare_fd: RefCell::new(None),
+            connection_limit: None,
+        }
+    }
+
     /// Accept a new connection from the socket
+    ///
+    /// By default running out of file descriptors fails the accept with the
+    /// `EMFILE` or `ENFILE` error, see [`emfile_backoff`] to wait and retry instead.
+    ///
+    /// [`emfile_backoff`]: #method.emfile_backoff
     pub async fn accept(&self) -> Result<Socket> {
-        let (fd, _address, _len) = Accept::new(&self.fd).await?;
-        Ok(Socket { fd })
+        let (socket, _address) = self.accept_with_addr().await?;
+        Ok(socket)
+    }
+
+    /// Accept a new connection from the socket, and return it with the address of the peer
+    ///
+    /// IPv4 peers connecting to a dual-stack IPv6 listener, see [`ListenOptions::only_v6`],
+    /// are returned as `SocketAddr::V4` rather than as IPv4-mapped IPv6 addresses.
+    ///
+    /// [`ListenOptions::only_v6`]: struct.ListenOptions.html#method.only_v6
+    pub async fn accept_with_addr(&self) -> Result<(Socket, SocketAddr)> {
+        let permit = match &self.connection_limit {
+            Some(semaphore) => Some(semaphore.acquire().await?),
+            None => None,
+        };
+        loop {
+            match Accept::new(&self.fd).await {
+                Ok((fd, address, len)) => {
+                    let address = decode_addr(&address, len)
+                        .ok_or(Error::Internal("Accepted connection of unknown family"))?;
+                    return Ok((
+                        Socket {
+                            fd,
+                            _permit: permit,
+                        },
+                        address,
+                    ));
+                }
+                Err(e) if is_out_of_fds(&e) && self.emfile_backoff.is_some() => {
+                    warn!("Out of file descriptors in accept: {}", e);
+                    self.reject_pending().await;
+                    sleep(self.emfile_backoff.unwrap()).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Use the spare descriptor to accept and close the pending connection,
+    /// so that the client sees the connection reset instead of waiting in the
+    /// accept queue while we are out of file descriptors
+    async fn reject_pending(&self) {
+        let spare = self.spare_fd.borrow_mut().take();
+        if let Some(spare) = spare {
+            drop(spare);
+            // Only reject a connection that is already pending, waiting for the next
+            // one would keep us from accepting again once descriptors are available
+            let mut pending = libc::pollfd {
+                fd: self.fd.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pending, 1, 0) } == 1 {
+                if let Ok((fd, _address, _len)) = Accept::new(&self.fd).await {
+                    drop(fd);
+                }
+            }
+            match open_spare_fd() {
+                Ok(fd) => *self.spare_fd.borrow_mut() = Some(fd),
+                Err(e) => warn!("Unable to reopen spare file descriptor: {}", e),
+            }
+        }
+    }
+
+    /// When `accept` fails because the process or system is out of file
+    /// descriptors (`EMFILE` or `ENFILE`), log a warning and wait for `backoff`
+    /// before accepting again, instead of failing. This keeps an accept loop from
+    /// spinning while the condition lasts.
+    ///
+    /// When `reserve_fd` is true a file descriptor is reserved up front. While out
+    /// of descriptors it is closed to accept the pending connection and close it
+    /// right away, and is then reserved again. Otherwise the pending connections
+    /// wait in the accept queue until descriptors are available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut listener = listen("127.0.0.1:1234").await?;
+    /// listener.emfile_backoff(Duration::from_millis(100), true)?;
+    /// ```
+    pub fn emfile_backoff(&mut self, backoff: Duration, reserve_fd: bool) -> Result<&mut Self> {
+        self.emfile_backoff = Some(backoff);
+        let spare = if reserve_fd {
+            Some(open_spare_fd()?)
+        } else {
+            None
+        };
+        *self.spare_fd.borrow_mut() = spare;
+        Ok(self)
+    }
+
+    /// Limit the number of open connections accepted from the listener to the
+    /// permits of `semaphore`
+    ///
+    /// `accept` waits for a permit before accepting, and the permit is held by
+    /// the returned socket until it is closed or dropped. While the limit is
+    /// reached new connections wait in the accept queue of the kernel, and once
+    /// it is full further connection attempts are dropped or refused, so a flood
+    /// of connections can not exhaust memory or file descriptors. The semaphore
+    /// can be shared by several listeners to limit their total.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut listener = listen("127.0.0.1:1234").await?;
+    /// listener.limit_connections(Semaphore::new(1000));
+    /// ```
+    pub fn limit_connections(&mut self, semaphore: Semaphore) -> &mut Self {
+        self.connection_limit = Some(semaphore);
+        self
     }
 
     /// Close the listener
     pub async fn close(self) -> Result<()> {
         Close::new(self.fd).await
     }
+
+    /// Take ownership of a std listener, so that connections can be accepted
+    /// using the reactor. The listener is closed when the `ListenSocket` is dropped.
+    pub fn from_std(listener: TcpListener) -> ListenSocket {
+        ListenSocket::new(Fd {
+            fd: std::os::unix::io::IntoRawFd::into_raw_fd(listener),
+        })
+    }
+}
+
+/// Decode a socket address returned by the kernel, with IPv4-mapped IPv6
+/// addresses decoded as IPv4 addresses
+fn decode_addr(addr: &libc::sockaddr_storage, len: libc::socklen_t) -> Option<SocketAddr> {
+    match addr.ss_family as i32 {
+        libc::AF_INET if len as usize >= std::mem::size_of::<libc::sockaddr_in>() => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 if len as usize >= std::mem::size_of::<libc::sockaddr_in6>() => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = addr.sin6_addr.s6_addr;
+            let port = u16::from_be(addr.sin6_port);
+            if ip[..10] == [0; 10] && ip[10..12] == [0xff, 0xff] {
+                return Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]),
+                    port,
+                )));
+            }
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(ip),
+                port,
+                u32::from_be(addr.sin6_flowinfo),
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Return the address family, and the raw socket address of `addr` with its length
+fn raw_addr(addr: &SocketAddr) -> (libc::c_int, libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    match addr {
+        SocketAddr::V4(addr) => {
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            (
+                libc::AF_INET,
+                storage,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        }
+        SocketAddr::V6(addr) => {
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_flowinfo = addr.flowinfo().to_be();
+            raw.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            raw.sin6_scope_id = addr.scope_id();
+            (
+                libc::AF_INET6,
+                storage,
+                std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        }
+    }
+}
+
+fn set_int_option(
+    fd: &Fd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn get_int_option(fd: &Fd, level: libc::c_int, name: libc::c_int) -> Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+    Ok(value)
+}
+
+/// Bind the socket to the network interface named `device` (SO_BINDTODEVICE)
+fn bind_to_device(fd: &Fd, device: &str) -> Result<()> {
+    if device.len() >= libc::IFNAMSIZ || device.contains('\0') {
+        return Err(Error::from(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid network interface name",
+        )));
+    }
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EPERM) {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "binding a socket to a network interface requires CAP_NET_RAW",
+            )));
+        }
+        return Err(Error::from(e));
+    }
+    Ok(())
+}
+
+fn buffer_size_option(size: usize) -> libc::c_int {
+    std::cmp::min(size, libc::c_int::max_value() as usize) as libc::c_int
+}
+
+/// Options which can be used to configure how a listening socket is created
+///
+/// # Examples
+///
+/// Accept both IPv6 and IPv4 connections on all interfaces, regardless of the
+/// system default:
+///
+/// ```no_run
+/// use agrippa::tcp::ListenOptions;
+///
+/// let listener = ListenOptions::new().only_v6(false).listen("[::]:1234").await?;
+/// ```
+pub struct ListenOptions {
+    backlog: i32,
+    only_v6: Option<bool>,
+    reuse_port: bool,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    device: Option<String>,
+}
+
+impl ListenOptions {
+    /// Creates a new set of options with the defaults of [`listen`]
+    ///
+    /// [`listen`]: fn.listen.html
+    pub fn new() -> Self {
+        ListenOptions {
+            backlog: 128,
+            only_v6: None,
+            reuse_port: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            device: None,
+        }
+    }
+
+    /// Set the maximal number of pending connections that have not been accepted yet
+    /// (default 128).
+    ///
+    /// The kernel silently clamps the backlog to `/proc/sys/net/core/somaxconn`.
+    pub fn backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set whether a socket listening on an IPv6 address only accepts IPv6
+    /// connections (IPV6_V6ONLY).
+    ///
+    /// By default the system default is used, which varies between systems
+    /// (`/proc/sys/net/ipv6/bindv6only`). With `only_v6(false)` a socket listening
+    /// on `[::]` also accepts IPv4 connections. The option is ignored for IPv4 addresses.
+    pub fn only_v6(&mut self, only_v6: bool) -> &mut Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Allow several sockets to listen to the same address (SO_REUSEPORT, default false).
+    ///
+    /// The kernel spreads incoming connections over the sockets, which lets a
+    /// reactor per thread accept its share of the connections, see
+    /// [`runtime::run_sharded`]. All the sockets must set the option.
+    ///
+    /// [`runtime::run_sharded`]: ../runtime/fn.run_sharded.html
+    pub fn reuse_port(&mut self, reuse_port: bool) -> &mut Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Set the receive buffer size (SO_RCVBUF) of the listener, which accepted
+    /// sockets inherit.
+    ///
+    /// Setting it on the listener, before connections are established, also lets the
+    /// kernel advertise a tcp window scale large enough for the buffer. See
+    /// [`Socket::set_recv_buffer_size`] for how the kernel adjusts the size.
+    ///
+    /// [`Socket::set_recv_buffer_size`]: struct.Socket.html#method.set_recv_buffer_size
+    pub fn recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the send buffer size (SO_SNDBUF) of the listener, which accepted
+    /// sockets inherit.
+    ///
+    /// See [`Socket::set_send_buffer_size`] for how the kernel adjusts the size.
+    ///
+    /// [`Socket::set_send_buffer_size`]: struct.Socket.html#method.set_send_buffer_size
+    pub fn send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Only accept connections arriving on the network interface named `device`,
+    /// such as `eth1` (SO_BINDTODEVICE)
+    ///
+    /// This lets for instance a management service listen on one interface of a
+    /// multi-homed host only, even on the wildcard address. Before Linux 5.7 this
+    /// requires the CAP_NET_RAW capability, without it `listen` fails with
+    /// `ErrorKind::PermissionDenied`.
+    pub fn bind_to_device(&mut self, device: &str) -> &mut Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Listen to the given tcp address with these options
+    ///
+    /// Each address `address` resolves to is tried in turn, if none can be
+    /// listened to the error of the last attempt is returned.
+    pub async fn listen<A: std::net::ToSocketAddrs>(&self, address: A) -> Result<ListenSocket> {
+        if self.backlog <= 0 {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "backlog must be positive",
+            )));
+        }
+        let mut last_error = None;
+        for addr in address.to_socket_addrs()? {
+            match self.bind(&addr) {
+                Ok(fd) => return Ok(ListenSocket::new(fd)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or(Error::Internal("Address resolved to no addresses")))
+    }
+
+    fn bind(&self, addr: &SocketAddr) -> Result<Fd> {
+        let (domain, raw, len) = raw_addr(addr);
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        let fd = Fd { fd };
+        // Like std, allow binding while connections of a previous listener linger
+        set_int_option(&fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        if self.reuse_port {
+            set_int_option(&fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            set_int_option(
+                &fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                buffer_size_option(size),
+            )?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            set_int_option(
+                &fd,
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                buffer_size_option(size),
+            )?;
+        }
+        if let Some(device) = &self.device {
+            bind_to_device(&fd, device)?;
+        }
+        if let (SocketAddr::V6(_), Some(only_v6)) = (addr, self.only_v6) {
+            set_int_option(
+                &fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                only_v6 as libc::c_int,
+            )?;
+        }
+        if unsafe { libc::bind(fd.fd, &raw as *const _ as *const libc::sockaddr, len) } < 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        if unsafe { libc::listen(fd.fd, self.backlog) } < 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        Ok(fd)
+    }
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Listen to the given tcp address
@@ -31,21 +525,425 @@ impl ListenSocket {
 pub async fn listen<A: std::net::ToSocketAddrs>(address: A) -> Result<ListenSocket> {
     let listener = TcpListener::bind(address)?;
     //info!("Listening on {}", address);
-    Ok(ListenSocket {
-        fd: Fd {
-            fd: std::os::unix::io::IntoRawFd::into_raw_fd(listener),
-        },
-    })
+    Ok(ListenSocket::from_std(listener))
+}
+
+/// Listen to the given tcp address with an explicit backlog, the maximal number
+/// of pending connections that have not been accepted yet.
+///
+/// The kernel silently clamps the backlog to `/proc/sys/net/core/somaxconn`.
+///
+/// # Example
+///
+/// ```
+/// listen_with_backlog("127.0.0.1:1234", 4096).await?
+/// ```
+pub async fn listen_with_backlog<A: std::net::ToSocketAddrs>(
+    address: A,
+    backlog: i32,
+) -> Result<ListenSocket> {
+    if backlog <= 0 {
+        return Err(Error::from(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "backlog must be positive",
+        )));
+    }
+    let listener = TcpListener::bind(address)?;
+    // Calling listen again on a listening socket updates the backlog
+    if unsafe { libc::listen(std::os::unix::io::AsRawFd::as_raw_fd(&listener), backlog) } < 0 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+    Ok(ListenSocket::from_std(listener))
+}
+
+/// Map the result of a non-blocking send or recv, with `EAGAIN` mapped to `None`
+fn would_block(ret: isize) -> Result<Option<usize>> {
+    if ret >= 0 {
+        return Ok(Some(ret as usize));
+    }
+    let e = std::io::Error::last_os_error();
+    if e.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(Error::from(e))
+    }
+}
+
+/// Stream of data received on a socket into the buffers of a [`BufferRing`],
+/// returned by [`Socket::recv_multishot`]
+///
+/// The receive is submitted on the first call to [`next`], and is then kept armed
+/// by the kernel, so data is received while the task handles earlier data. If the
+/// ring runs out of buffers, because the received buffers are not dropped fast
+/// enough, `next` fails with `ENOBUFS` once the data received before has been
+/// returned, and the receive is submitted again by the next call to `next`.
+///
+/// A receive that is still armed when this is dropped is canceled.
+///
+/// [`BufferRing`]: ../runtime/struct.BufferRing.html
+/// [`Socket::recv_multishot`]: struct.Socket.html#method.recv_multishot
+/// [`next`]: #method.next
+pub struct RecvMultishot<'a> {
+    fd: &'a Fd,
+    ring: BufferRing,
+    /// The completion and user_data of the armed receive
+    armed: Option<(Rc<Completion>, u64)>,
+    eof: bool,
+}
+
+impl<'a> RecvMultishot<'a> {
+    /// Return the next chunk of data received, or None once the peer has
+    /// closed the connection
+    pub async fn next(&mut self) -> Result<Option<RingBuf>> {
+        NextRecv { recv: self }.await
+    }
+
+    fn poll_next(&mut self) -> Poll<Result<Option<RingBuf>>> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let (res, flags, canceled) = match &self.armed {
+            None if self.eof => return Poll::Ready(Ok(None)),
+            None => {
+                if let Some(e) = interruption(&task) {
+                    return Poll::Ready(Err(e));
+                }
+                let completion = Rc::new(Completion::multishot(task.clone(), self.ring.clone()));
+                let user_data = Rc::into_raw(completion.clone()) as usize as u64;
+                let res = io_uring_recv_multishot(
+                    &mut *task.reactor.ring.borrow_mut(),
+                    self.fd,
+                    self.ring.group_id(),
+                    user_data,
+                );
+                if let Err(e) = res {
+                    unsafe { Rc::from_raw(user_data as usize as *const Completion) };
+                    return Poll::Ready(Err(e));
+                }
+                task.in_flight.borrow_mut().push(user_data);
+                self.armed = Some((completion, user_data));
+                return Poll::Pending;
+            }
+            Some((completion, _)) => {
+                let multishot = completion.multishot.as_ref().unwrap();
+                match multishot.cqes.borrow_mut().pop_front() {
+                    Some((res, flags)) => (res, flags, completion.canceled.get()),
+                    None => return Poll::Pending,
+                }
+            }
+        };
+        if flags & IORING_CQE_F_MORE == 0 {
+            self.armed = None;
+        }
+        // A selected buffer goes back to the ring when dropped, also on errors
+        let buffer = if flags & IORING_CQE_F_BUFFER != 0 {
+            let bid = (flags >> IORING_CQE_BUFFER_SHIFT) as u16;
+            Some(self.ring.take(bid, std::cmp::max(res, 0) as usize))
+        } else {
+            None
+        };
+        if self.armed.is_none() {
+            if let Some(e) = interruption(&task) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        if res == -libc::ECANCELED && canceled {
+            // Canceled by `Reactor::cancel_all`
+            Poll::Ready(Err(Error::Cancel))
+        } else if res < 0 {
+            Poll::Ready(Err(Error::from(std::io::Error::from_raw_os_error(-res))))
+        } else if res == 0 {
+            self.eof = true;
+            Poll::Ready(Ok(None))
+        } else {
+            Poll::Ready(
+                buffer
+                    .ok_or(Error::Internal("Receive selected no buffer"))
+                    .map(Some),
+            )
+        }
+    }
+}
+
+impl<'a> Drop for RecvMultishot<'a> {
+    fn drop(&mut self) {
+        if let Some((completion, user_data)) = self.armed.take() {
+            let multishot = completion.multishot.as_ref().unwrap();
+            multishot.orphaned.set(true);
+            for (_, flags) in multishot.cqes.borrow_mut().drain(..) {
+                if flags & IORING_CQE_F_BUFFER != 0 {
+                    multishot
+                        .ring
+                        .recycle((flags >> IORING_CQE_BUFFER_SHIFT) as u16);
+                }
+            }
+            // The kernel keeps the completion alive until the final cqe
+            if let Err(e) = io_uring_cancel(self.ring.reactor(), user_data) {
+                warn!("Failed to cancel multishot receive: {}", e);
+            }
+        }
+    }
+}
+
+struct NextRecv<'a, 'b> {
+    recv: &'b mut RecvMultishot<'a>,
+}
+
+impl<'a, 'b> Future for NextRecv<'a, 'b> {
+    type Output = Result<Option<RingBuf>>;
+    fn poll(mut self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
+        self.recv.poll_next()
+    }
 }
 
 /// Regular tcp socket
 pub struct Socket {
     fd: Fd,
+    /// Permit of the connection limit of the listener the socket was accepted from
+    _permit: Option<Permit>,
 }
 
 impl Socket {
-    /// Write bytes to socket
-    pub async fn write(&self, data: &[u8]) -> Result<()> {
+    /// Take ownership of a connected std stream, so that it can be used with the reactor.
+    /// The stream is closed when the `Socket` is closed or dropped.
+    pub fn from_std(stream: std::net::TcpStream) -> Socket {
+        Socket {
+            fd: Fd {
+                fd: std::os::unix::io::IntoRawFd::into_raw_fd(stream),
+            },
+            _permit: None,
+        }
+    }
+
+    /// Set the receive buffer size of the socket (SO_RCVBUF)
+    ///
+    /// The kernel doubles the requested size to make room for its bookkeeping,
+    /// and clamps the request to `/proc/sys/net/core/rmem_max`, so
+    /// [`recv_buffer_size`] reports a different number than requested.
+    /// Setting the size disables the automatic tuning of the buffer by the kernel.
+    ///
+    /// [`recv_buffer_size`]: #method.recv_buffer_size
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        set_int_option(
+            &self.fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            buffer_size_option(size),
+        )
+    }
+
+    /// Return the receive buffer size of the socket, as adjusted by the kernel
+    pub fn recv_buffer_size(&self) -> Result<usize> {
+        Ok(get_int_option(&self.fd, libc::SOL_SOCKET, libc::SO_RCVBUF)? as usize)
+    }
+
+    /// Set the send buffer size of the socket (SO_SNDBUF)
+    ///
+    /// The kernel doubles the requested size to make room for its bookkeeping,
+    /// and clamps the request to `/proc/sys/net/core/wmem_max`, so
+    /// [`send_buffer_size`] reports a different number than requested.
+    /// Setting the size disables the automatic tuning of the buffer by the kernel.
+    ///
+    /// [`send_buffer_size`]: #method.send_buffer_size
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        set_int_option(
+            &self.fd,
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            buffer_size_option(size),
+        )
+    }
+
+    /// Return the send buffer size of the socket, as adjusted by the kernel
+    pub fn send_buffer_size(&self) -> Result<usize> {
+        Ok(get_int_option(&self.fd, libc::SOL_SOCKET, libc::SO_SNDBUF)? as usize)
+    }
+
+    /// Write bytes to socket, return the number of bytes written
+    ///
+    /// This may write fewer bytes than in `data`, use [`write_all`]
+    /// to write all of it.
+    ///
+    /// [`write_all`]: #method.write_all
+    pub async fn write(&self, data: &[u8]) -> Result<usize> {
+        Write::new(&self.fd, data, 0).await
+    }
+
+    /// Read bytes from the socket into the capacity of `data`, taking ownership
+    /// of it, and return the number of bytes read together with `data`, whose
+    /// length is set to that number, 0 at the end of the stream
+    ///
+    /// As the future owns the buffer, dropping it while the read is in flight
+    /// is safe, the read is canceled and the buffer is freed once the kernel is
+    /// done with it. See `File::read_owned`.
+    pub async fn read_owned(&self, data: Vec<u8>) -> Result<(usize, Vec<u8>)> {
+        OwnedRead::new(&self.fd, data, 0).await
+    }
+
+    /// Write bytes to the socket, taking ownership of `data`, and return the
+    /// number of bytes written together with `data` for reuse
+    ///
+    /// As the future owns the buffer, dropping it while the write is in flight
+    /// is safe, the write is canceled and the buffer is freed once the kernel is
+    /// done with it. See `File::write_owned`.
+    pub async fn write_owned(&self, data: Vec<u8>) -> Result<(usize, Vec<u8>)> {
+        OwnedWrite::new(&self.fd, data, 0).await
+    }
+
+    /// Send bytes on the socket without copying them into kernel buffers
+    /// (IORING_OP_SEND_ZC), return the number of bytes sent
+    ///
+    /// The kernel sends directly from `data`, so this only completes, and frees
+    /// `data`, once the kernel notifies that it no longer uses the buffer, which
+    /// for tcp is when the data has been acknowledged. This pays off for large
+    /// writes, for small ones the copy of [`write`] is cheaper.
+    ///
+    /// As the future owns `data`, dropping it before the send completes is
+    /// safe, the send is canceled and `data` is freed once the kernel is done
+    /// with it.
+    ///
+    /// Zero-copy sends require Linux 6.0, on older kernels this falls back
+    /// to a regular [`write`].
+    ///
+    /// [`write`]: #method.write
+    pub async fn send_zc(&self, data: Vec<u8>) -> Result<usize> {
+        if !current_reactor().await?.supports(IORING_OP_SEND_ZC as u8) {
+            let (written, _) = OwnedWrite::new(&self.fd, data, 0).await?;
+            return Ok(written);
+        }
+        SendZc::new(&self.fd, data).await
+    }
+
+    /// Write bytes to the socket if it can be done without blocking, return the
+    /// number of bytes written, or `None` if the socket buffer is full.
+    ///
+    /// This bypasses io_uring and sends synchronously on the socket with
+    /// `MSG_DONTWAIT`, so it neither waits nor is subject to cancellation.
+    pub fn try_write(&self, data: &[u8]) -> Result<Option<usize>> {
+        let ret = unsafe {
+            libc::send(
+                self.fd.fd,
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+                libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+            )
+        };
+        would_block(ret)
+    }
+
+    /// Wait until the socket can be written to without blocking, because there
+    /// is room in its send buffer
+    ///
+    /// This also resolves when the connection has failed or been closed, in which
+    /// case the next write reports the error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// loop {
+    ///     socket.writable().await?;
+    ///     let chunk = source.read(&mut buf).await?;
+    ///     socket.write_all(&buf[..chunk]).await?;
+    /// }
+    /// ```
+    pub async fn writable(&self) -> Result<()> {
+        PollAdd::new(self.fd.fd, libc::POLLOUT as u16).await?;
+        Ok(())
+    }
+
+    /// Wait until data is available to be read from the socket
+    ///
+    /// This also resolves when the peer has closed the connection, or the
+    /// connection has failed, in which case the next read reports it. Together with
+    /// [`try_read`] this allows readiness based code in the style of epoll.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// loop {
+    ///     socket.readable().await?;
+    ///     while let Some(n) = socket.try_read(&mut buf)? {
+    ///         if n == 0 {
+    ///             return Ok(());
+    ///         }
+    ///         parser.feed(&buf[..n]);
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// [`try_read`]: #method.try_read
+    pub async fn readable(&self) -> Result<()> {
+        PollAdd::new(self.fd.fd, libc::POLLIN as u16).await?;
+        Ok(())
+    }
+
+    /// Read bytes from the socket if any are available without blocking, return
+    /// the number of bytes read, or `None` if no data is available.
+    /// `Some(0)` means that the peer has closed the connection.
+    ///
+    /// This bypasses io_uring and receives synchronously from the socket with
+    /// `MSG_DONTWAIT`, so it neither waits nor is subject to cancellation.
+    pub fn try_read(&self, data: &mut [u8]) -> Result<Option<usize>> {
+        let ret = unsafe {
+            libc::recv(
+                self.fd.fd,
+                data.as_mut_ptr() as *mut libc::c_void,
+                data.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        would_block(ret)
+    }
+
+    /// Read bytes from the socket until `data` is full, the peer closes the
+    /// connection, or `timeout` has passed, and return the number of bytes read.
+    ///
+    /// Unlike a read with a timeout, the bytes that arrived before the timeout are
+    /// returned rather than lost, and a timeout with nothing read returns 0 rather
+    /// than an error. An io_uring read either completes with data or is canceled
+    /// with none, so this is done in two steps: wait for the socket to become
+    /// readable with a linked timeout, then read whatever is buffered without
+    /// blocking, until the timeout fires.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// let n = socket.read_timeout(&mut buf, Duration::from_millis(50)).await?;
+    /// parser.feed(&buf[..n]);
+    /// ```
+    pub async fn read_timeout(&self, data: &mut [u8], timeout: Duration) -> Result<usize> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut start = 0;
+        while start != data.len() {
+            match self.try_read(&mut data[start..])? {
+                Some(0) => break,
+                Some(n) => {
+                    start += n;
+                    continue;
+                }
+                None => {}
+            }
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            match PollAdd::new(self.fd.fd, libc::POLLIN as u16)
+                .with_timeout(remaining)
+                .await
+            {
+                Ok(_) => {}
+                Err(Error::Timeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(start)
+    }
+
+    /// Write all bytes of data to the socket
+    pub async fn write_all(&self, data: &[u8]) -> Result<()> {
         let mut start = 0;
         while start != data.len() {
             //TODO Handle EINTR and EAGAIN
@@ -58,9 +956,88 @@ impl Socket {
         Ok(())
     }
 
+    /// Write bytes to the socket, telling the kernel that more data follows
+    /// (MSG_MORE), return the number of bytes written
+    ///
+    /// The kernel holds the data back until a later write without the flag, so
+    /// that for instance a header and a body written separately go out in the
+    /// same segment, without copying them together first as a [`BufWriter`] does.
+    /// This holds even if `TCP_NODELAY` is set on the socket. Data held back is
+    /// sent anyway after about 200ms. Like [`write`], this may write fewer bytes
+    /// than in `data`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// socket.write_all_more(&header).await?;
+    /// socket.write_all(&body).await?;
+    /// ```
+    ///
+    /// [`BufWriter`]: ../util/struct.BufWriter.html
+    /// [`write`]: #method.write
+    pub async fn write_more(&self, data: &[u8]) -> Result<usize> {
+        SendFlags::new(&self.fd, data, libc::MSG_MORE).await
+    }
+
+    /// Write all bytes of data to the socket, telling the kernel that more
+    /// data follows, see [`write_more`]
+    ///
+    /// [`write_more`]: #method.write_more
+    pub async fn write_all_more(&self, data: &[u8]) -> Result<()> {
+        let mut start = 0;
+        while start != data.len() {
+            let written = SendFlags::new(&self.fd, &data[start..], libc::MSG_MORE).await?;
+            if written == 0 {
+                return Err(Error::Eof);
+            }
+            start += written;
+        }
+        Ok(())
+    }
+
+    /// Hold back partial segments until [`uncork`] is called (TCP_CORK)
+    ///
+    /// While the socket is corked, all writes are coalesced into full segments,
+    /// which suits a response assembled from many small writes. Unlike
+    /// [`write_more`] this spans any number of writes, at the cost of two system
+    /// calls. Corking takes precedence over `TCP_NODELAY`, and data held back is
+    /// sent anyway after about 200ms.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// socket.cork()?;
+    /// for part in parts {
+    ///     socket.write_all(part).await?;
+    /// }
+    /// socket.uncork()?;
+    /// ```
+    ///
+    /// [`uncork`]: #method.uncork
+    /// [`write_more`]: #method.write_more
+    pub fn cork(&self) -> Result<()> {
+        set_int_option(&self.fd, libc::IPPROTO_TCP, libc::TCP_CORK, 1)
+    }
+
+    /// Send the data held back since [`cork`] was called, and stop holding
+    /// back partial segments
+    ///
+    /// [`cork`]: #method.cork
+    pub fn uncork(&self) -> Result<()> {
+        set_int_option(&self.fd, libc::IPPROTO_TCP, libc::TCP_CORK, 0)
+    }
+
+    /// Write the raw bytes of item to the socket
+    ///
+    /// # Note
+    ///
+    /// Any padding bytes in `T` are uninitialized, and reading them is undefined
+    /// behaviour. Prefer [`write_struct`] which only accepts types without padding.
+    ///
+    /// [`write_struct`]: #method.write_struct
     pub async fn write_item<T: Copy>(&self, item: &T) -> Result<()> {
         unsafe {
-            self.write(std::slice::from_raw_parts(
+            self.write_all(std::slice::from_raw_parts(
                 item as *const T as *const u8,
                 std::mem::size_of::<T>(),
             ))
@@ -69,10 +1046,56 @@ impl Socket {
     }
 
     /// Read data from socket into data, return number of bytes read
+    ///
+    /// A return value of `Ok(0)` for a non empty `data` means that the peer
+    /// has closed the connection (eof), any other value is a partial read.
     pub async fn read(&self, data: &mut [u8]) -> Result<usize> {
         Read::new(&self.fd, data, 0).await
     }
 
+    /// Read the next chunk of data available on the socket, at most 64KB,
+    /// returning `None` when the peer has closed the connection.
+    ///
+    /// The chunk is read into a scratch buffer of the reactor, which is returned
+    /// to the pool when the chunk is dropped, see [`acquire_buffer`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// while let Some(chunk) = socket.read_buf().await? {
+    ///     handle(&chunk);
+    /// }
+    /// ```
+    ///
+    /// [`acquire_buffer`]: ../util/fn.acquire_buffer.html
+    pub async fn read_buf(&self) -> Result<Option<PooledBuf>> {
+        let mut data = acquire_buffer(64 * 1024).await?;
+        let read = Read::new(&self.fd, &mut data, 0).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        data.resize(read);
+        Ok(Some(data))
+    }
+
+    /// Receive from the socket into buffers of `ring`, with a single multishot
+    /// receive (IORING_OP_RECV with IORING_RECV_MULTISHOT) that stays armed
+    /// across completions, see [`RecvMultishot`].
+    ///
+    /// Requires Linux 6.0.
+    ///
+    /// [`RecvMultishot`]: struct.RecvMultishot.html
+    pub fn recv_multishot(&self, ring: &BufferRing) -> RecvMultishot<'_> {
+        RecvMultishot {
+            fd: &self.fd,
+            ring: ring.clone(),
+            armed: None,
+            eof: false,
+        }
+    }
+
+    /// Fill all of data from the socket, failing with `Error::Eof`
+    /// if the peer closes the connection before it is filled
     pub async fn read_all(&self, data: &mut [u8]) -> Result<()> {
         let mut start = 0;
         while start != data.len() {
@@ -85,6 +1108,38 @@ impl Socket {
         Ok(())
     }
 
+    /// Read and discard bytes from the socket until the peer closes the
+    /// connection or `max` bytes have been discarded, return the number
+    /// of bytes discarded
+    ///
+    /// The limit bounds the work a peer can make the server do, bytes after
+    /// the first `max` are left for the next read.
+    pub async fn drain(&self, max: usize) -> Result<usize> {
+        let mut scratch = vec![0; std::cmp::min(max, 64 * 1024)];
+        let mut drained = 0;
+        while drained != max {
+            let len = std::cmp::min(max - drained, scratch.len());
+            let read = Read::new(&self.fd, &mut scratch[..len], 0).await?;
+            if read == 0 {
+                break;
+            }
+            drained += read;
+        }
+        Ok(drained)
+    }
+
+    /// Read the raw bytes of an item of type `T` from the socket
+    ///
+    /// # Safety
+    ///
+    /// The bytes read from the socket are reinterpreted as a `T`. This is
+    /// undefined behaviour unless every bit pattern is a valid `T`, so it
+    /// must never be used with types containing references, pointers, `bool`,
+    /// `char` or enums. Prefer [`read_struct`] which enforces this through the
+    /// [`Pod`] trait.
+    ///
+    /// [`read_struct`]: #method.read_struct
+    /// [`Pod`]: ../util/trait.Pod.html
     pub async unsafe fn read_item<T: Copy>(&self) -> Result<T> {
         let mut item: T = std::mem::zeroed();
         self.read_all(std::slice::from_raw_parts_mut(
@@ -95,48 +1150,446 @@ impl Socket {
         Ok(item)
     }
 
+    /// Try to write the bytes of item to the socket without waiting.
+    ///
+    /// Return the number of bytes written, which may be fewer than the size of `T`,
+    /// or `None` if the socket would block. It is up to the caller to write the
+    /// remaining bytes, this allows callers to implement their own backpressure.
+    ///
+    /// # Note
+    ///
+    /// This bypasses io_uring and performs a non-blocking send directly on the socket.
+    pub fn try_write_item<T: Pod>(&self, item: &T) -> Result<Option<usize>> {
+        let ret = unsafe {
+            libc::send(
+                self.fd.fd,
+                item as *const T as *const libc::c_void,
+                std::mem::size_of::<T>(),
+                libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+            )
+        };
+        would_block(ret)
+    }
+
+    /// Write the bytes of a plain old data item to the socket
+    pub async fn write_struct<T: Pod>(&self, item: &T) -> Result<()> {
+        self.write_item(item).await
+    }
+
+    /// Read a plain old data item from the socket
+    pub async fn read_struct<T: Pod>(&self) -> Result<T> {
+        // Safe since T is valid for any bit pattern
+        unsafe { self.read_item().await }
+    }
+
+    /// Write a u16 to the socket in big endian byte order
+    pub async fn write_u16_be(&self, v: u16) -> Result<()> {
+        self.write_all(&v.to_be_bytes()).await
+    }
+
+    /// Read a u16 in big endian byte order from the socket
+    pub async fn read_u16_be(&self) -> Result<u16> {
+        let mut buf = [0; 2];
+        self.read_all(&mut buf).await?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Write a u16 to the socket in little endian byte order
+    pub async fn write_u16_le(&self, v: u16) -> Result<()> {
+        self.write_all(&v.to_le_bytes()).await
+    }
+
+    /// Read a u16 in little endian byte order from the socket
+    pub async fn read_u16_le(&self) -> Result<u16> {
+        let mut buf = [0; 2];
+        self.read_all(&mut buf).await?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Write a u32 to the socket in big endian byte order
+    pub async fn write_u32_be(&self, v: u32) -> Result<()> {
+        self.write_all(&v.to_be_bytes()).await
+    }
+
+    /// Read a u32 in big endian byte order from the socket
+    pub async fn read_u32_be(&self) -> Result<u32> {
+        let mut buf = [0; 4];
+        self.read_all(&mut buf).await?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Write a u32 to the socket in little endian byte order
+    pub async fn write_u32_le(&self, v: u32) -> Result<()> {
+        self.write_all(&v.to_le_bytes()).await
+    }
+
+    /// Read a u32 in little endian byte order from the socket
+    pub async fn read_u32_le(&self) -> Result<u32> {
+        let mut buf = [0; 4];
+        self.read_all(&mut buf).await?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Write a u64 to the socket in big endian byte order
+    pub async fn write_u64_be(&self, v: u64) -> Result<()> {
+        self.write_all(&v.to_be_bytes()).await
+    }
+
+    /// Read a u64 in big endian byte order from the socket
+    pub async fn read_u64_be(&self) -> Result<u64> {
+        let mut buf = [0; 8];
+        self.read_all(&mut buf).await?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Write a u64 to the socket in little endian byte order
+    pub async fn write_u64_le(&self, v: u64) -> Result<()> {
+        self.write_all(&v.to_le_bytes()).await
+    }
+
+    /// Read a u64 in little endian byte order from the socket
+    pub async fn read_u64_le(&self) -> Result<u64> {
+        let mut buf = [0; 8];
+        self.read_all(&mut buf).await?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
     /// Close this socket for reading and writing
     pub async fn close(self) -> Result<()> {
         Close::new(self.fd).await
     }
 }
 
-struct SocketAddrV4Copy {
-    inner: libc::sockaddr_in,
+impl std::os::unix::io::AsRawFd for ListenSocket {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd.fd
+    }
+}
+
+impl std::os::unix::io::FromRawFd for ListenSocket {
+    /// Take ownership of a raw listening socket, it is closed when the listener is dropped
+    unsafe fn from_raw_fd(fd: std::os::unix::io::RawFd) -> ListenSocket {
+        ListenSocket::new(Fd { fd })
+    }
+}
+
+impl std::os::unix::io::IntoRawFd for ListenSocket {
+    /// Consume the listener without closing it, returning the raw file descriptor
+    fn into_raw_fd(self) -> std::os::unix::io::RawFd {
+        self.fd.into_raw()
+    }
+}
+
+impl AsyncRead for Socket {
+    fn read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<usize>> + 'a>> {
+        Box::pin(Socket::read(self, data))
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn write<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<usize>> + 'a>> {
+        Box::pin(Socket::write(self, data))
+    }
+}
+
+impl std::os::unix::io::AsRawFd for Socket {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd.fd
+    }
+}
+
+impl std::os::unix::io::FromRawFd for Socket {
+    /// Take ownership of a raw connected socket, for instance one passed by socket
+    /// activation. It is closed when the socket is dropped.
+    ///
+    /// io_uring does not require the socket to be non-blocking. Blocking and
+    /// non-blocking sockets both work, but a non-blocking socket may make
+    /// operations be retried internally by the kernel rather than wait in place.
+    unsafe fn from_raw_fd(fd: std::os::unix::io::RawFd) -> Socket {
+        Socket {
+            fd: Fd { fd },
+            _permit: None,
+        }
+    }
+}
+
+impl std::os::unix::io::IntoRawFd for Socket {
+    /// Consume the socket without closing it, returning the raw file descriptor
+    fn into_raw_fd(self) -> std::os::unix::io::RawFd {
+        self.fd.into_raw()
+    }
+}
+
+/// Socket whose reads fail with `Error::Timeout` when the connection has been idle
+/// for too long, see [`with_idle_timeout`]
+///
+/// [`with_idle_timeout`]: fn.with_idle_timeout.html
+pub struct IdleSocket {
+    socket: Socket,
+    idle_timeout: Duration,
+    /// When data was last read or written
+    last_activity: std::cell::Cell<std::time::Instant>,
+}
+
+impl IdleSocket {
+    /// Read bytes from the socket, return the number of bytes read, 0 at eof.
+    ///
+    /// Fails with `Error::Timeout` if no data has been read or written for the
+    /// idle timeout by the time the read completes. The read is canceled by the
+    /// kernel when the timeout expires.
+    pub async fn read(&self, data: &mut [u8]) -> Result<usize> {
+        let idle = self.last_activity.get().elapsed();
+        if idle >= self.idle_timeout {
+            return Err(Error::Timeout);
+        }
+        let read = Read::new(&self.socket.fd, data, 0)
+            .with_timeout(self.idle_timeout - idle)
+            .await?;
+        self.last_activity.set(std::time::Instant::now());
+        Ok(read)
+    }
+
+    /// Write bytes to the socket, return the number of bytes written.
+    /// Writing counts as activity, and resets the idle timer.
+    pub async fn write(&self, data: &[u8]) -> Result<usize> {
+        let written = self.socket.write(data).await?;
+        self.last_activity.set(std::time::Instant::now());
+        Ok(written)
+    }
+
+    /// Write all bytes of data to the socket, resetting the idle timer
+    pub async fn write_all(&self, data: &[u8]) -> Result<()> {
+        self.socket.write_all(data).await?;
+        self.last_activity.set(std::time::Instant::now());
+        Ok(())
+    }
+
+    /// The wrapped socket, using it directly does not reset the idle timer
+    pub fn get_ref(&self) -> &Socket {
+        &self.socket
+    }
+
+    /// Return the wrapped socket
+    pub fn into_inner(self) -> Socket {
+        self.socket
+    }
+
+    /// Close the socket
+    pub async fn close(self) -> Result<()> {
+        self.socket.close().await
+    }
 }
 
-struct SocketAddrV6Copy {
-    inner: libc::sockaddr_in6,
+/// Wrap `socket`, so that reads fail with `Error::Timeout` once nothing has been
+/// read from or written to it for `idle_timeout`. The caller would then
+/// typically close the connection.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::runtime::Error;
+/// use agrippa::tcp::with_idle_timeout;
+/// use std::time::Duration;
+///
+/// let socket = with_idle_timeout(listener.accept().await?, Duration::from_secs(60));
+/// loop {
+///     match socket.read(&mut buf).await {
+///         Ok(0) | Err(Error::Timeout) => break,
+///         Ok(n) => socket.write_all(&buf[..n]).await?,
+///         Err(e) => return Err(e),
+///     }
+/// }
+/// socket.close().await?;
+/// ```
+pub fn with_idle_timeout(socket: Socket, idle_timeout: Duration) -> IdleSocket {
+    IdleSocket {
+        socket,
+        idle_timeout,
+        last_activity: std::cell::Cell::new(std::time::Instant::now()),
+    }
 }
 
 /**
  * Connect to a remove service
+ *
+ * Each address `address` resolves to is tried in turn. If none can be connected
+ * to, the error of the last attempt is returned. Errors reported by the kernel keep
+ * their errno, so `Error::kind` can be used to match for instance
+ * `std::io::ErrorKind::ConnectionRefused`.
+ *
+ * Resolving a host name blocks the reactor while the lookup is in progress,
+ * use `connect_host` to connect by host name.
  */
 pub async fn connect<A: std::net::ToSocketAddrs>(address: A) -> Result<Socket> {
-    let iter = address.to_socket_addrs()?;
-    for addr in iter {
-        let (domain, addr, addr_size) = match &addr {
-            std::net::SocketAddr::V4(addr) => (
-                libc::AF_INET,
-                &unsafe { &*(addr as *const std::net::SocketAddrV4 as *const SocketAddrV4Copy) }
-                    .inner as *const libc::sockaddr_in as *const libc::c_void,
-                std::mem::size_of::<libc::sockaddr_in>(),
-            ),
-            std::net::SocketAddr::V6(addr) => (
-                libc::AF_INET6,
-                &unsafe { &*(addr as *const std::net::SocketAddrV6 as *const SocketAddrV6Copy) }
-                    .inner as *const libc::sockaddr_in6 as *const libc::c_void,
-                std::mem::size_of::<libc::sockaddr_in6>(),
-            ),
-        };
+    ConnectOptions::new().connect(address).await
+}
+
+/**
+ * Connect to the given port of a host given by name
+ *
+ * The host name is resolved with `net::resolve`, which does not block the reactor,
+ * after which the addresses are tried in turn as by `connect`.
+ */
+pub async fn connect_host(host: &str, port: u16) -> Result<Socket> {
+    ConnectOptions::new().connect_host(host, port).await
+}
+
+/// Accept connections on `listener`, and handle each of them with `handler`
+/// in a task of its own, spawned with `priority`.
+///
+/// The handler is given the socket of the connection. Once the handler returns,
+/// its error if any is logged, and the socket is closed. A handler that panics
+/// is treated as failed, its io is canceled and the socket is closed, and the
+/// other connections are not affected. If the handler keeps clones of the socket,
+/// for instance in tasks it spawned, the socket is closed when the last of them
+/// is dropped instead.
+///
+/// Only returns when accepting fails, see [`ListenSocket::emfile_backoff`] for
+/// riding out a shortage of file descriptors. Connections being handled are
+/// not affected by the return.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::runtime::Priority;
+/// use agrippa::tcp::{listen, serve};
+///
+/// let listener = listen("127.0.0.1:1234").await?;
+/// serve(&listener, Priority::Normal, |socket| async move {
+///     let mut buf = [0u8; 1024];
+///     let n = socket.read(&mut buf).await?;
+///     socket.write_all(&buf[..n]).await
+/// })
+/// .await?;
+/// ```
+///
+/// [`ListenSocket::emfile_backoff`]: struct.ListenSocket.html#method.emfile_backoff
+pub async fn serve<F, Fut>(listener: &ListenSocket, priority: Priority, handler: F) -> Result<()>
+where
+    F: Fn(Rc<Socket>) -> Fut,
+    Fut: Future<Output = Result<()>> + 'static,
+{
+    let reactor = current_reactor().await?;
+    loop {
+        let (socket, address) = listener.accept_with_addr().await?;
+        let socket = Rc::new(socket);
+        // The handler may also panic before returning its future
+        let handling = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            catch_unwind(handler(socket.clone()))
+        }));
+        reactor.spawn_named("serve_connection", priority, async move {
+            let res = match handling {
+                Ok(handling) => handling.await,
+                Err(_) => Err(Error::Internal("Future panicked")),
+            };
+            if let Err(e) = res {
+                warn!("Handling connection from {} failed: {}", address, e);
+            }
+            if let Ok(socket) = Rc::try_unwrap(socket) {
+                if let Err(e) = socket.close().await {
+                    warn!("Closing connection from {} failed: {}", address, e);
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Options which can be used to configure how a socket is connected
+///
+/// # Examples
+///
+/// Connect through a specific network interface of a multi-homed host:
+///
+/// ```no_run
+/// use agrippa::tcp::ConnectOptions;
+///
+/// let socket = ConnectOptions::new().bind_to_device("eth1").connect("10.0.0.2:1234").await?;
+/// ```
+pub struct ConnectOptions {
+    device: Option<String>,
+}
+
+impl ConnectOptions {
+    /// Creates a new set of options with the defaults of [`connect`]
+    ///
+    /// [`connect`]: fn.connect.html
+    pub fn new() -> Self {
+        ConnectOptions { device: None }
+    }
+
+    /// Only send and receive through the network interface named `device`,
+    /// such as `eth1` (SO_BINDTODEVICE)
+    ///
+    /// Before Linux 5.7 this requires the CAP_NET_RAW capability, without it
+    /// connecting fails with `ErrorKind::PermissionDenied`.
+    pub fn bind_to_device(&mut self, device: &str) -> &mut Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Connect to the given address with these options, see [`connect`]
+    ///
+    /// [`connect`]: fn.connect.html
+    pub async fn connect<A: std::net::ToSocketAddrs>(&self, address: A) -> Result<Socket> {
+        self.connect_addrs(address.to_socket_addrs()?).await
+    }
+
+    /// Connect to the given port of a host given by name with these options,
+    /// see [`connect_host`]
+    ///
+    /// [`connect_host`]: fn.connect_host.html
+    pub async fn connect_host(&self, host: &str, port: u16) -> Result<Socket> {
+        self.connect_addrs(resolve(host, port).await?).await
+    }
 
-        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    /// Try each of `addrs` in turn, returning the error of the last attempt
+    /// if none can be connected to
+    async fn connect_addrs<I: IntoIterator<Item = std::net::SocketAddr>>(
+        &self,
+        addrs: I,
+    ) -> Result<Socket> {
+        let mut last_error = None;
+        for addr in addrs {
+            match self.connect_addr(&addr).await {
+                Ok(socket) => return Ok(socket),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or(Error::Internal("Unable to connect")))
+    }
+
+    async fn connect_addr(&self, addr: &SocketAddr) -> Result<Socket> {
+        let (domain, addr, addr_size) = raw_addr(addr);
+
+        // The socket is left blocking, io_uring does not need it to be non-blocking,
+        // and try_read and try_write pass MSG_DONTWAIT
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
         if fd == -1 {
             return Err(Error::from(std::io::Error::last_os_error()));
         }
         let fd = Fd { fd };
-        Connect::new(&fd, addr, addr_size).await?;
-        return Ok(Socket { fd });
+        if let Some(device) = &self.device {
+            bind_to_device(&fd, device)?;
+        }
+        Connect::new(
+            &fd,
+            &addr as *const _ as *const libc::c_void,
+            addr_size as usize,
+        )
+        .await?;
+        Ok(Socket { fd, _permit: None })
+    }
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self::new()
     }
-    Err(Error::Internal("Unable to connect"))
 }
@@ -0,0 +1,271 @@
+use std::time::{Duration, Instant};
+
+/// Number of slots in each level of the wheel
+const SLOTS: usize = 64;
+/// log2 of `SLOTS`
+const SLOT_BITS: u32 = 6;
+/// Number of levels, covering 64^4 ticks
+const LEVELS: usize = 4;
+/// Resolution of the wheel
+const TICK: Duration = Duration::from_millis(1);
+
+struct Entry<T> {
+    tick: u64,
+    when: Instant,
+    value: T,
+}
+
+/// Hierarchical timing wheel with a resolution of one millisecond.
+///
+/// An entry is placed in the level given by the highest bits in which its tick
+/// differs from the current tick, so level 0 holds the entries due within the
+/// current block of 64 ticks, level 1 the entries due within the current block
+/// of 64^2 ticks and so on. Inserting and removing an entry with a known deadline
+/// is O(1). When the current tick enters a new block, the entries of the
+/// matching slots in the levels above are moved down. Entries too far in the
+/// future for the wheel are kept in an overflow list.
+///
+/// Entries expire at the first tick at or after their deadline, so never early
+/// but up to a tick late.
+pub(crate) struct TimerWheel<T> {
+    start: Instant,
+    /// The last tick that has been processed
+    current: u64,
+    levels: Vec<Vec<Vec<Entry<T>>>>,
+    /// Entries beyond the range of the wheel
+    overflow: Vec<Entry<T>>,
+    /// Entries that are due but have not been expired yet
+    due: Vec<Entry<T>>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub(crate) fn new() -> Self {
+        TimerWheel {
+            start: Instant::now(),
+            current: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            overflow: Vec::new(),
+            due: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The first tick at or after `when`
+    fn tick_ceil(&self, when: Instant) -> u64 {
+        let tick = TICK.as_nanos();
+        ((when.saturating_duration_since(self.start).as_nanos() + tick - 1) / tick) as u64
+    }
+
+    /// The last tick at or before `when`
+    fn tick_floor(&self, when: Instant) -> u64 {
+        (when.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn level(&self, tick: u64) -> Option<usize> {
+        let masked = (tick ^ self.current) | (SLOTS as u64 - 1);
+        let level = ((63 - masked.leading_zeros()) / SLOT_BITS) as usize;
+        if level < LEVELS {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    fn slot(tick: u64, level: usize) -> usize {
+        ((tick >> (SLOT_BITS * level as u32)) & (SLOTS as u64 - 1)) as usize
+    }
+
+    fn place(&mut self, entry: Entry<T>) {
+        if entry.tick <= self.current {
+            self.due.push(entry);
+            return;
+        }
+        match self.level(entry.tick) {
+            Some(level) => self.levels[level][Self::slot(entry.tick, level)].push(entry),
+            None => self.overflow.push(entry),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, when: Instant, value: T) {
+        let tick = self.tick_ceil(when);
+        self.len += 1;
+        self.place(Entry { tick, when, value });
+    }
+
+    /// Remove the entries for which `f` returns true, only the ones at `when` if
+    /// given. Returns the number of entries removed.
+    pub(crate) fn remove<F: FnMut(&T) -> bool>(
+        &mut self,
+        when: Option<Instant>,
+        mut f: F,
+    ) -> usize {
+        let tick = when.map(|w| self.tick_ceil(w));
+        let mut removed = 0;
+        let mut keep = |e: &Entry<T>| {
+            let remove = when.map_or(true, |w| w == e.when) && f(&e.value);
+            if remove {
+                removed += 1;
+            }
+            !remove
+        };
+        for (level, slots) in self.levels.iter_mut().enumerate() {
+            match tick {
+                // At each level the entry can only be in the slot of its tick
+                Some(tick) => slots[Self::slot(tick, level)].retain(&mut keep),
+                None => {
+                    for slot in slots.iter_mut() {
+                        slot.retain(&mut keep);
+                    }
+                }
+            }
+        }
+        self.overflow.retain(&mut keep);
+        self.due.retain(&mut keep);
+        self.len -= removed;
+        removed
+    }
+
+    /// The earliest tick of an entry not yet due
+    fn next_tick(&self) -> Option<u64> {
+        let mut best = self.overflow.iter().map(|e| e.tick).min();
+        for (level, slots) in self.levels.iter().enumerate() {
+            // Entries of a level are all in the slots after the one of the current tick,
+            // and the first non-empty one holds the earliest
+            let first = Self::slot(self.current, level);
+            if let Some(slot) = slots[first..].iter().find(|s| !s.is_empty()) {
+                let t = slot.iter().map(|e| e.tick).min().unwrap();
+                best = Some(best.map_or(t, |b| std::cmp::min(b, t)));
+            }
+        }
+        best
+    }
+
+    /// The deadline of the earliest entry, if any
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        if let Some(e) = self.due.iter().min_by_key(|e| e.when) {
+            return Some(e.when);
+        }
+        self.next_tick()
+            .map(|t| self.start + Duration::from_nanos(t * TICK.as_nanos() as u64))
+    }
+
+    /// Move the entries that are due at `now` to `out`
+    pub(crate) fn expire(&mut self, now: Instant, out: &mut Vec<T>) {
+        let target = self.tick_floor(now);
+        loop {
+            self.len -= self.due.len();
+            out.extend(self.due.drain(..).map(|e| e.value));
+            let next = match self.next_tick() {
+                Some(next) if next <= target => next,
+                _ => break,
+            };
+            // Jump to the next tick with an entry, moving the entries of the blocks
+            // it enters down the levels
+            self.current = next;
+            let mut entries = std::mem::replace(&mut self.overflow, Vec::new());
+            for level in 0..LEVELS {
+                entries.append(&mut self.levels[level][Self::slot(next, level)]);
+            }
+            for entry in entries {
+                self.place(entry);
+            }
+        }
+        if target > self.current {
+            self.current = target;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(wheel: &TimerWheel<u32>, ms: u64) -> Instant {
+        wheel.start + Duration::from_millis(ms)
+    }
+
+    fn expire(wheel: &mut TimerWheel<u32>, ms: u64) -> Vec<u32> {
+        let mut out = Vec::new();
+        wheel.expire(at(wheel, ms), &mut out);
+        out.sort_unstable();
+        out
+    }
+
+    #[test]
+    fn entries_cascade_down_the_levels() {
+        let mut wheel = TimerWheel::new();
+        // One entry in each level
+        for &ms in &[10, 100, 5_000, 300_000] {
+            wheel.insert(at(&wheel, ms), ms as u32);
+        }
+        for (level, &ms) in [10u64, 100, 5_000, 300_000].iter().enumerate() {
+            assert_eq!(
+                wheel.levels[level][TimerWheel::<u32>::slot(ms, level)].len(),
+                1
+            );
+        }
+        assert_eq!(expire(&mut wheel, 9), Vec::<u32>::new());
+        assert_eq!(expire(&mut wheel, 10), vec![10]);
+        assert_eq!(expire(&mut wheel, 99), Vec::<u32>::new());
+        assert_eq!(expire(&mut wheel, 100), vec![100]);
+        assert_eq!(expire(&mut wheel, 4_999), Vec::<u32>::new());
+        assert_eq!(expire(&mut wheel, 5_000), vec![5_000]);
+        assert_eq!(expire(&mut wheel, 299_999), Vec::<u32>::new());
+        assert_eq!(expire(&mut wheel, 300_000), vec![300_000]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn entries_beyond_the_wheel_overflow() {
+        let mut wheel = TimerWheel::new();
+        let range = (SLOTS as u64).pow(LEVELS as u32);
+        wheel.insert(at(&wheel, range - 1), 1);
+        wheel.insert(at(&wheel, range), 2);
+        assert_eq!(wheel.overflow.len(), 1);
+        assert_eq!(wheel.overflow[0].value, 2);
+        assert_eq!(expire(&mut wheel, range - 2), Vec::<u32>::new());
+        assert_eq!(expire(&mut wheel, range - 1), vec![1]);
+        assert_eq!(expire(&mut wheel, range), vec![2]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn removed_entries_do_not_fire() {
+        let mut wheel = TimerWheel::new();
+        let when = at(&wheel, 200);
+        wheel.insert(when, 1);
+        wheel.insert(when, 2);
+        wheel.insert(at(&wheel, 300), 1);
+        // Only the entry at the given deadline is removed
+        assert_eq!(wheel.remove(Some(when), |&v| v == 1), 1);
+        assert_eq!(expire(&mut wheel, 1_000), vec![1, 2]);
+        wheel.insert(at(&wheel, 2_000), 3);
+        assert_eq!(wheel.remove(None, |&v| v == 3), 1);
+        assert!(wheel.is_empty());
+        assert_eq!(expire(&mut wheel, 3_000), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn next_deadline_is_the_nearest() {
+        let mut wheel = TimerWheel::new();
+        assert_eq!(wheel.next_deadline(), None);
+        for &ms in &[300, 20, 5_000] {
+            wheel.insert(at(&wheel, ms), ms as u32);
+        }
+        assert_eq!(wheel.next_deadline(), Some(at(&wheel, 20)));
+        assert_eq!(expire(&mut wheel, 20), vec![20]);
+        assert_eq!(wheel.next_deadline(), Some(at(&wheel, 300)));
+        // An entry that is already due is the nearest
+        wheel.insert(at(&wheel, 10), 10);
+        assert_eq!(wheel.next_deadline(), Some(at(&wheel, 10)));
+        assert_eq!(expire(&mut wheel, 20), vec![10]);
+        assert_eq!(wheel.next_deadline(), Some(at(&wheel, 300)));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::io_uring_util::{Close, Connect, Fd, Read, RecvMsg, SendMsg, Write};
+use crate::runtime::{Error, Result};
+use crate::util::{AsyncRead, AsyncWrite};
+use libc;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// Size of a control message buffer holding a single file descriptor,
+/// in units of u64 to get the alignment of `cmsghdr`
+const FD_CONTROL_LEN: usize = 4;
+
+/// Connected unix domain stream socket
+pub struct UnixStream {
+    fd: Fd,
+}
+
+impl UnixStream {
+    /// Create a pair of connected unix sockets
+    pub fn pair() -> Result<(UnixStream, UnixStream)> {
+        let mut fds = [0; 2];
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        Ok((
+            UnixStream {
+                fd: Fd { fd: fds[0] },
+            },
+            UnixStream {
+                fd: Fd { fd: fds[1] },
+            },
+        ))
+    }
+
+    /// Take ownership of a connected std unix stream, so that it can be used with the reactor
+    pub fn from_std(stream: std::os::unix::net::UnixStream) -> UnixStream {
+        UnixStream {
+            fd: Fd {
+                fd: std::os::unix::io::IntoRawFd::into_raw_fd(stream),
+            },
+        }
+    }
+
+    /// Write bytes to the socket, return the number of bytes written
+    pub async fn write(&self, data: &[u8]) -> Result<usize> {
+        Write::new(&self.fd, data, 0).await
+    }
+
+    /// Read bytes from the socket, return the number of bytes read or 0 on eof
+    pub async fn read(&self, data: &mut [u8]) -> Result<usize> {
+        Read::new(&self.fd, data, 0).await
+    }
+
+    /// Send a file descriptor to the peer using a SCM_RIGHTS control message.
+    ///
+    /// The peer receives a duplicate of `fd`, that refers to the same open file.
+    /// `fd` is not closed by this method.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let socket = listener.accept().await?;
+    /// worker.send_fd(socket.as_raw_fd()).await?;
+    /// ```
+    pub async fn send_fd(&self, fd: RawFd) -> Result<()> {
+        let mut control = [0u64; FD_CONTROL_LEN];
+        let control_len = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+        unsafe {
+            let cmsg = control.as_mut_ptr() as *mut libc::cmsghdr;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as usize;
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+        let control =
+            unsafe { std::slice::from_raw_parts(control.as_ptr() as *const u8, control_len) };
+        // A control message must be accompanied by at least one byte of data
+        let sent = SendMsg::new(&self.fd, &[0], control).await?;
+        if sent == 0 {
+            return Err(Error::Eof);
+        }
+        Ok(())
+    }
+
+    /// Receive a file descriptor sent by the peer with [`send_fd`].
+    ///
+    /// The received file descriptor has close on exec set, and is owned by the caller.
+    /// Returns `Error::Eof` if the peer has closed the connection, and an error if
+    /// the peer sent data without a file descriptor.
+    ///
+    /// [`send_fd`]: #method.send_fd
+    pub async fn recv_fd(&self) -> Result<RawFd> {
+        let mut data = [0u8; 1];
+        let mut control = [0u64; FD_CONTROL_LEN];
+        let control_bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                control.as_mut_ptr() as *mut u8,
+                FD_CONTROL_LEN * std::mem::size_of::<u64>(),
+            )
+        };
+        let (read, control_len, flags) = RecvMsg::new(&self.fd, &mut data, control_bytes).await?;
+        if read == 0 {
+            return Err(Error::Eof);
+        }
+        if flags & libc::MSG_CTRUNC != 0 {
+            return Err(Error::Internal("Control message truncated"));
+        }
+        if control_len < std::mem::size_of::<libc::cmsghdr>() {
+            return Err(Error::Internal("No file descriptor received"));
+        }
+        unsafe {
+            let cmsg = control.as_ptr() as *const libc::cmsghdr;
+            if (*cmsg).cmsg_level != libc::SOL_SOCKET
+                || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+                || (*cmsg).cmsg_len < libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as usize
+            {
+                return Err(Error::Internal("No file descriptor received"));
+            }
+            Ok(std::ptr::read_unaligned(
+                libc::CMSG_DATA(cmsg) as *const RawFd
+            ))
+        }
+    }
+
+    /// Close the socket
+    pub async fn close(self) -> Result<()> {
+        Close::new(self.fd).await
+    }
+}
+
+impl AsyncRead for UnixStream {
+    fn read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<usize>> + 'a>> {
+        Box::pin(UnixStream::read(self, data))
+    }
+}
+
+impl AsyncWrite for UnixStream {
+    fn write<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<usize>> + 'a>> {
+        Box::pin(UnixStream::write(self, data))
+    }
+}
+
+impl std::os::unix::io::AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.fd
+    }
+}
+
+impl std::os::unix::io::IntoRawFd for UnixStream {
+    /// Consume the socket without closing it, returning the raw file descriptor
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw()
+    }
+}
+
+impl std::os::unix::io::FromRawFd for UnixStream {
+    /// Take ownership of a raw connected unix socket, it is closed when the socket is dropped
+    unsafe fn from_raw_fd(fd: RawFd) -> UnixStream {
+        UnixStream { fd: Fd { fd } }
+    }
+}
+
+/// Connect to the unix domain socket at `path`
+pub async fn connect<P: AsRef<Path>>(path: P) -> Result<UnixStream> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path.as_ref().as_os_str().as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if path.len() >= addr.sun_path.len() {
+        return Err(Error::Internal("Unix socket path too long"));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (d, s) in addr.sun_path.iter_mut().zip(path) {
+        *d = *s as libc::c_char;
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+    let fd = Fd { fd };
+    Connect::new(
+        &fd,
+        &addr as *const libc::sockaddr_un as *const libc::c_void,
+        std::mem::size_of::<libc::sockaddr_un>(),
+    )
+    .await?;
+    Ok(UnixStream { fd })
+}
@@ -1,7 +1,55 @@
-use crate::runtime::{waker_task, Error, Priority, Result, Task};
+use crate::io_uring_util::{Fd, Madvise, Nop, Read, SleepTimeout, Tee};
+use crate::runtime::{
+    current_task, interruption, io_uring_cancel, Error, PooledBuf, Priority, ReactorRef, Result,
+    Task, TaskRef,
+};
+use crate::sys::IORING_OP_MADVISE;
+use log::warn;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::future::Future;
+use std::os::unix::io::AsRawFd;
 use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Marker for plain old data types, that can be safely transmitted as raw bytes.
+///
+/// # Safety
+///
+/// The type must be valid for any bit pattern, and must not contain any padding,
+/// pointers or references. Types implementing it should be `#[repr(C)]`
+/// or `#[repr(C, packed)]`.
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for u128 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for i128 {}
+unsafe impl Pod for isize {}
+unsafe impl Pod for f32 {}
+unsafe impl Pod for f64 {}
+
+/// Byte streams that can be read from, such as sockets
+pub trait AsyncRead {
+    /// Read bytes into `data`, return the number of bytes read, 0 at the end of the stream
+    fn read<'a>(&'a self, data: &'a mut [u8]) -> Pin<Box<dyn Future<Output = Result<usize>> + 'a>>;
+}
+
+/// Byte streams that can be written to, such as sockets
+pub trait AsyncWrite {
+    /// Write bytes from `data`, return the number of bytes written
+    fn write<'a>(&'a self, data: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<usize>> + 'a>>;
+}
 
 pub struct Delay {
     first: bool,
@@ -30,15 +78,23 @@ pub fn delay() -> Delay {
 struct SpawnTaskFuture<F: Future<Output = Result<()>> + 'static> {
     future: Option<F>,
     priority: Priority,
+    name: Option<&'static str>,
 }
 
 impl<F: Future<Output = Result<()>> + 'static> Future for SpawnTaskFuture<F> {
     type Output = Result<Task>;
-    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
         let p = self.priority;
+        let name = self.name;
         if let Some(f) = unsafe { self.get_unchecked_mut() }.future.take() {
-            let task = waker_task(context.waker().clone());
-            Poll::Ready(Ok(task.reactor.spawn(p, f)))
+            let task = match current_task() {
+                Ok(task) => task,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            Poll::Ready(Ok(match name {
+                Some(name) => task.reactor.spawn_named(name, p, f),
+                None => task.reactor.spawn(p, f),
+            }))
         } else {
             Poll::Ready(Err(Error::Internal("Poll called on done future")))
         }
@@ -54,6 +110,1904 @@ pub async fn spawn_task<F: Future<Output = Result<()>> + 'static>(
     SpawnTaskFuture {
         future: Some(future),
         priority,
+        name: None,
+    }
+    .await
+}
+
+/// Spawn a new named task with the given priority. The task will be spawned
+/// in reactor of the task that awaits the resulting future.
+pub async fn spawn_named_task<F: Future<Output = Result<()>> + 'static>(
+    name: &'static str,
+    priority: Priority,
+    future: F,
+) -> Result<Task> {
+    SpawnTaskFuture {
+        future: Some(future),
+        priority,
+        name: Some(name),
     }
     .await
 }
+
+struct AcquireBufferFuture {
+    min_size: usize,
+}
+
+impl Future for AcquireBufferFuture {
+    type Output = Result<PooledBuf>;
+    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        Poll::Ready(Ok(task.reactor.acquire_buffer(self.min_size)))
+    }
+}
+
+/// Borrow a scratch buffer of at least `min_size` bytes from the pool of the
+/// reactor of the task that awaits the resulting future.
+pub async fn acquire_buffer(min_size: usize) -> Result<PooledBuf> {
+    AcquireBufferFuture { min_size }.await
+}
+
+/// Submit a no-op to io_uring and wait for its completion.
+///
+/// This exercises the full submit/complete path of the reactor without touching
+/// any file descriptor, which makes it useful for testing cancellation and
+/// for measuring the raw submission throughput.
+pub async fn nop() -> Result<()> {
+    Nop::new().await
+}
+
+/// Duplicate up to `len` bytes of data from the pipe `fd_in` to the pipe `fd_out`,
+/// without consuming it from `fd_in`. Return the number of bytes duplicated,
+/// 0 if there is no data to duplicate because the write end of `fd_in` is closed.
+///
+/// Both file descriptors must refer to pipes. `flags` are `SPLICE_F_*` flags as
+/// for tee(2). The data can subsequently be read from `fd_in` as usual, which
+/// allows a byte stream to be forwarded to two destinations.
+///
+/// Requires the kernel to support `IORING_OP_TEE`, see [`supports`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::tee;
+///
+/// let n = tee(&input, &log_pipe, 64 * 1024, 0).await?;
+/// ```
+///
+/// [`supports`]: fn.supports.html
+pub async fn tee<I: AsRawFd, O: AsRawFd>(
+    fd_in: &I,
+    fd_out: &O,
+    len: u32,
+    flags: u32,
+) -> Result<usize> {
+    Tee::new(fd_in.as_raw_fd(), fd_out.as_raw_fd(), len, flags).await
+}
+
+/// Advice about how a range of memory will be used, see madvise(2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MadvAdvice {
+    /// No special treatment (MADV_NORMAL)
+    Normal,
+    /// The memory will be accessed in random order, so read ahead is less useful (MADV_RANDOM)
+    Random,
+    /// The memory will be accessed sequentially, so read ahead aggressively (MADV_SEQUENTIAL)
+    Sequential,
+    /// The memory will be accessed soon, so read it in ahead (MADV_WILLNEED)
+    WillNeed,
+    /// The memory will not be accessed soon, so its pages can be freed (MADV_DONTNEED).
+    /// Private anonymous memory reads back as zeros afterwards
+    DontNeed,
+}
+
+impl MadvAdvice {
+    fn bits(self) -> u32 {
+        (match self {
+            MadvAdvice::Normal => libc::MADV_NORMAL,
+            MadvAdvice::Random => libc::MADV_RANDOM,
+            MadvAdvice::Sequential => libc::MADV_SEQUENTIAL,
+            MadvAdvice::WillNeed => libc::MADV_WILLNEED,
+            MadvAdvice::DontNeed => libc::MADV_DONTNEED,
+        }) as u32
+    }
+}
+
+/// Advise the kernel about how `len` bytes of memory starting at `addr` will be
+/// used, without blocking the reactor while the kernel acts on it, for instance
+/// while `MadvAdvice::WillNeed` reads in the pages of a memory mapped file.
+///
+/// `addr` must be page aligned. Falls back to a blocking madvise(2) on kernels
+/// without `IORING_OP_MADVISE` (before Linux 5.6).
+///
+/// # Safety
+///
+/// The memory must stay mapped until the advice completes, and with
+/// `MadvAdvice::DontNeed` the contents of the memory may be discarded, so
+/// nothing may rely on them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::{madvise, MadvAdvice};
+///
+/// unsafe { madvise(map.as_mut_ptr(), map.len(), MadvAdvice::WillNeed).await? };
+/// ```
+pub async unsafe fn madvise(addr: *mut u8, len: usize, advice: MadvAdvice) -> Result<()> {
+    if len > u32::max_value() as usize {
+        return Err(Error::from(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "madvise length does not fit in 32 bits",
+        )));
+    }
+    if !current_reactor().await?.supports(IORING_OP_MADVISE as u8) {
+        if libc::madvise(addr as *mut libc::c_void, len, advice.bits() as i32) < 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+        return Ok(());
+    }
+    Madvise::new(addr, len as u32, advice.bits()).await
+}
+
+/// Run `f` on a new thread, and wait for its result without blocking the reactor.
+///
+/// The thread holds the write end of a pipe that it closes once the result has
+/// been stored, the read of the read end completes with eof at that point.
+pub(crate) async fn run_on_thread<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+    let read_end = Fd { fd: fds[0] };
+    let write_end = fds[1];
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = result.clone();
+    let spawned = std::thread::Builder::new()
+        .name("agrippa-blocking".to_string())
+        .spawn(move || {
+            let v = f();
+            *thread_result.lock().unwrap() = Some(v);
+            unsafe {
+                libc::close(write_end);
+            }
+        });
+    if let Err(e) = spawned {
+        unsafe {
+            libc::close(write_end);
+        }
+        return Err(Error::from(e));
+    }
+    let mut buf = [0u8; 1];
+    Read::new(&read_end, &mut buf, 0).await?;
+    let v = result.lock().unwrap().take();
+    v.ok_or(Error::Internal("Blocking thread did not produce a result"))
+}
+
+enum JoinSlot<F: Future> {
+    Pending(Pin<Box<F>>),
+    Done(F::Output),
+    Taken,
+}
+
+/// Future returned by [`join_all`]
+///
+/// [`join_all`]: fn.join_all.html
+pub struct JoinAll<F: Future> {
+    slots: Vec<JoinSlot<F>>,
+}
+
+// The outputs are never pinned
+impl<F: Future> Unpin for JoinAll<F> {}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let slots = &mut self.get_mut().slots;
+        let mut done = true;
+        for slot in slots.iter_mut() {
+            if let JoinSlot::Pending(f) = slot {
+                match f.as_mut().poll(context) {
+                    Poll::Ready(v) => *slot = JoinSlot::Done(v),
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+        if !done {
+            return Poll::Pending;
+        }
+        Poll::Ready(
+            slots
+                .iter_mut()
+                .map(|slot| match std::mem::replace(slot, JoinSlot::Taken) {
+                    JoinSlot::Done(v) => v,
+                    _ => panic!("join_all polled after completion"),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Run all the futures concurrently within the current task, and return their
+/// outputs in order once all of them are done.
+///
+/// Every future runs to completion, so for fallible futures the result holds
+/// the outcome of each of them, even if some fail. Use [`try_join_all`] to stop
+/// at the first error instead.
+///
+/// All the futures are polled in the same poll of the task, so the io_uring
+/// operations they start are submitted to the kernel together in one system call,
+/// instead of one system call per operation when awaited in sequence.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::join_all;
+///
+/// let writes = chunks.iter().enumerate().map(|(i, c)| file.write_all(c, i as u64 * 4096));
+/// for r in join_all(writes).await {
+///     r?;
+/// }
+/// ```
+///
+/// [`try_join_all`]: fn.try_join_all.html
+pub fn join_all<I>(futures: I) -> JoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    JoinAll {
+        slots: futures
+            .into_iter()
+            .map(|f| JoinSlot::Pending(Box::pin(f)))
+            .collect(),
+    }
+}
+
+/// Future returned by [`try_join_all`]
+///
+/// [`try_join_all`]: fn.try_join_all.html
+pub struct TryJoinAll<F: Future> {
+    slots: Vec<JoinSlot<F>>,
+    /// user_data of the io_uring operations started by each future that may be in flight
+    ops: Vec<Vec<u64>>,
+    /// The first error, set once a future has failed
+    error: Option<Error>,
+}
+
+// The outputs are never pinned
+impl<F: Future> Unpin for TryJoinAll<F> {}
+
+impl<T, F: Future<Output = Result<T>>> Future for TryJoinAll<F> {
+    type Output = Result<Vec<T>>;
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let this = self.get_mut();
+        for ops in this.ops.iter_mut() {
+            ops.retain(|op| task.in_flight.borrow().contains(op));
+        }
+        if this.error.is_none() {
+            let mut done = true;
+            for (slot, ops) in this.slots.iter_mut().zip(this.ops.iter_mut()) {
+                if let JoinSlot::Pending(f) = slot {
+                    // Operations are only added to the end of the operations of the task
+                    // in flight while it is polled, so the new ones are those of the future
+                    let before = task.in_flight.borrow().len();
+                    let res = f.as_mut().poll(context);
+                    ops.extend_from_slice(&task.in_flight.borrow()[before..]);
+                    match res {
+                        Poll::Ready(Ok(v)) => *slot = JoinSlot::Done(Ok(v)),
+                        Poll::Ready(Err(e)) => {
+                            *slot = JoinSlot::Taken;
+                            this.error = Some(e);
+                            break;
+                        }
+                        Poll::Pending => done = false,
+                    }
+                }
+            }
+            if this.error.is_none() {
+                if !done {
+                    return Poll::Pending;
+                }
+                return Poll::Ready(Ok(this
+                    .slots
+                    .iter_mut()
+                    .map(|slot| match std::mem::replace(slot, JoinSlot::Taken) {
+                        JoinSlot::Done(Ok(v)) => v,
+                        _ => panic!("try_join_all polled after completion"),
+                    })
+                    .collect()));
+            }
+            for (slot, ops) in this.slots.iter().zip(this.ops.iter()) {
+                if let JoinSlot::Pending(_) = slot {
+                    for op in ops {
+                        if let Err(e) = io_uring_cancel(&task.reactor, *op) {
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+            }
+        }
+        // A future can only be dropped once the kernel is done with its operations
+        let mut done = true;
+        for (slot, ops) in this.slots.iter_mut().zip(this.ops.iter()) {
+            if ops.is_empty() {
+                *slot = JoinSlot::Taken;
+            } else {
+                done = false;
+            }
+        }
+        if !done {
+            return Poll::Pending;
+        }
+        Poll::Ready(Err(this.error.take().unwrap_or(Error::Cancel)))
+    }
+}
+
+/// Run all the futures concurrently within the current task like [`join_all`],
+/// but fail with the first error any of them returns.
+///
+/// When a future fails, the io_uring operations the remaining futures have in
+/// flight are canceled, and once they have completed the futures are dropped
+/// without being polled again, so the error is returned only once the kernel
+/// is done with their buffers. If all the futures succeed their outputs are
+/// returned in order.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::try_join_all;
+///
+/// let reads = sockets.iter().map(|s| s.read_u32_be());
+/// let values = try_join_all(reads).await?;
+/// ```
+///
+/// [`join_all`]: fn.join_all.html
+pub fn try_join_all<I, T>(futures: I) -> TryJoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = Result<T>>,
+{
+    let slots: Vec<_> = futures
+        .into_iter()
+        .map(|f| JoinSlot::Pending(Box::pin(f)))
+        .collect();
+    TryJoinAll {
+        ops: slots.iter().map(|_| Vec::new()).collect(),
+        slots,
+        error: None,
+    }
+}
+
+struct CurrentReactorFuture {}
+
+impl Future for CurrentReactorFuture {
+    type Output = Result<ReactorRef>;
+    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
+        Poll::Ready(current_task().map(|task| task.reactor.clone()))
+    }
+}
+
+/// Return the reactor of the task that awaits the resulting future
+pub(crate) async fn current_reactor() -> Result<ReactorRef> {
+    CurrentReactorFuture {}.await
+}
+
+/// Return true if the kernel supports the io_uring opcode `op`,
+/// see [`Reactor::supports`].
+///
+/// [`Reactor::supports`]: ../runtime/struct.Reactor.html#method.supports
+pub async fn supports(op: u8) -> Result<bool> {
+    Ok(current_reactor().await?.supports(op))
+}
+
+/// Future returned by [`sleep`] and [`sleep_until`]
+///
+/// [`sleep`]: fn.sleep.html
+/// [`sleep_until`]: fn.sleep_until.html
+pub struct Sleep {
+    when: Instant,
+    /// The task waiting for the timer, once registered in the timer heap
+    task: Option<TaskRef>,
+}
+
+impl Future for Sleep {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let res = if let Some(e) = interruption(&task) {
+            Err(e)
+        } else if Instant::now() >= self.when {
+            Ok(())
+        } else {
+            if self.task.is_none() {
+                task.reactor.add_timer(self.when, task.clone());
+                self.task = Some(task);
+            }
+            return Poll::Pending;
+        };
+        if let Some(task) = self.task.take() {
+            task.reactor.remove_timers(&task, Some(self.when));
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.reactor.remove_timers(&task, Some(self.when));
+        }
+    }
+}
+
+/// Sleep for the given duration.
+///
+/// The sleep fails with `Error::Cancel` or `Error::Timeout` right away,
+/// if the task is canceled or timed out while sleeping.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::sleep;
+/// use std::time::Duration;
+///
+/// sleep(Duration::from_millis(100)).await?;
+/// ```
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Sleep until the given instant, see [`sleep`]
+///
+/// [`sleep`]: fn.sleep.html
+pub fn sleep_until(when: Instant) -> Sleep {
+    Sleep { when, task: None }
+}
+
+/// Sleep for the given duration with an io_uring timeout of its own
+/// (IORING_OP_TIMEOUT), rather than with a timer of the reactor as [`sleep`].
+///
+/// Each such sleep is a timer tracked by the kernel. This costs a submission
+/// per sleep, but no bookkeeping in the reactor, see `benches/timers.rs` for a
+/// comparison with the timer heap and the timing wheel of the reactor. If the
+/// future is dropped before the duration has passed, the timeout stays armed in
+/// the kernel until it expires.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::uring_sleep;
+/// use std::time::Duration;
+///
+/// uring_sleep(Duration::from_millis(100)).await?;
+/// ```
+///
+/// [`sleep`]: fn.sleep.html
+pub async fn uring_sleep(duration: Duration) -> Result<()> {
+    match SleepTimeout::new(duration).await {
+        // The timeout reports its expiry as an error
+        Err(e) if e.raw_os_error() == Some(libc::ETIME) => Ok(()),
+        res => res,
+    }
+}
+
+/// Future returned by [`timeout`]
+///
+/// [`timeout`]: fn.timeout.html
+pub struct Timeout<F: Future> {
+    /// The wrapped future, None once it has been dropped
+    future: Option<Pin<Box<F>>>,
+    sleep: Sleep,
+    /// user_data of the io_uring operations started by the future that may be in flight
+    ops: Vec<u64>,
+    /// The error to fail with, set when the timeout has expired
+    error: Option<Error>,
+}
+
+impl<T, F: Future<Output = Result<T>>> Future for Timeout<F> {
+    type Output = Result<T>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let this = &mut *self;
+        this.ops.retain(|op| task.in_flight.borrow().contains(op));
+        if this.error.is_none() {
+            let future = match this.future.as_mut() {
+                Some(future) => future,
+                None => return Poll::Ready(Err(Error::Internal("Done future polled"))),
+            };
+            // Operations are only added to the end of the operations of the task
+            // in flight while it is polled, so the new ones are those of the future
+            let before = task.in_flight.borrow().len();
+            let res = future.as_mut().poll(context);
+            this.ops
+                .extend_from_slice(&task.in_flight.borrow()[before..]);
+            if let Poll::Ready(res) = res {
+                this.future = None;
+                return Poll::Ready(res);
+            }
+            // The sleep fails if the task is interrupted, in which case the
+            // operations of the future must be waited for as well
+            this.error = match Pin::new(&mut this.sleep).poll(context) {
+                Poll::Ready(Ok(())) => Some(Error::Timeout),
+                Poll::Ready(Err(e)) => Some(e),
+                Poll::Pending => return Poll::Pending,
+            };
+            for op in &this.ops {
+                if let Err(e) = io_uring_cancel(&task.reactor, *op) {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        // The future can only be dropped once the kernel is done with its operations
+        if !this.ops.is_empty() {
+            return Poll::Pending;
+        }
+        this.future = None;
+        Poll::Ready(Err(this.error.take().unwrap_or(Error::Timeout)))
+    }
+}
+
+/// Run `future`, failing with `Error::Timeout` if it has not completed within `duration`
+///
+/// This works for any future, including futures joining several operations,
+/// channel receives and verbs receives. When the timeout expires, the io_uring
+/// operations the future has in flight are canceled, and once they have completed
+/// the future is dropped without being polled again.
+///
+/// For a single io_uring operation a kernel linked timeout, as used by for instance
+/// `tcp::with_idle_timeout`, is cheaper, as this registers a timer with the
+/// reactor and tracks the operations of the future.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::runtime::Error;
+/// use agrippa::util::timeout;
+/// use std::time::Duration;
+///
+/// match timeout(Duration::from_secs(1), connection.recv()).await {
+///     Ok(buffer) => handle(buffer),
+///     Err(Error::Timeout) => println!("No message within a second"),
+///     Err(e) => return Err(e),
+/// }
+/// ```
+pub fn timeout<T, F: Future<Output = Result<T>>>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Some(Box::pin(future)),
+        sleep: sleep(duration),
+        ops: Vec::new(),
+        error: None,
+    }
+}
+
+struct TokenState {
+    cancelled: Cell<bool>,
+    /// When the token is cancelled by itself, if it has a timeout
+    deadline: Option<Instant>,
+    /// Wakers of the tasks waiting for the token to be cancelled
+    wakers: RefCell<Vec<std::task::Waker>>,
+    /// Tokens cancelled along with this one
+    children: RefCell<Vec<std::rc::Weak<TokenState>>>,
+}
+
+impl TokenState {
+    fn new(deadline: Option<Instant>) -> Rc<Self> {
+        Rc::new(TokenState {
+            cancelled: Cell::new(false),
+            deadline,
+            wakers: RefCell::new(Vec::new()),
+            children: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn is_cancelled(&self) -> bool {
+        if !self.cancelled.get() && self.deadline.map_or(false, |d| Instant::now() >= d) {
+            self.cancel();
+        }
+        self.cancelled.get()
+    }
+
+    fn cancel(&self) {
+        if self.cancelled.replace(true) {
+            return;
+        }
+        for waker in self.wakers.borrow_mut().drain(..) {
+            waker.wake();
+        }
+        let children: Vec<_> = self.children.borrow_mut().drain(..).collect();
+        for child in children.iter().filter_map(|c| c.upgrade()) {
+            child.cancel();
+        }
+    }
+}
+
+/// Cancellation signal shared by a call tree, such as the handling of a request
+///
+/// Clones of a token share its state, so a token passed down through the calls
+/// can be checked with [`is_cancelled`], awaited with [`cancelled`], or used to
+/// make io fail with [`run`], wherever it ends up. Cancelling any clone cancels
+/// them all, as well as the tokens derived with [`child_token`] and
+/// [`with_timeout`], while cancelling a derived token leaves its parent alone.
+///
+/// Unlike `Task::cancel`, which interrupts all io of a single task, a token
+/// only affects the calls it is passed to, which may span several tasks or
+/// part of one.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::CancellationToken;
+/// use std::time::Duration;
+///
+/// let token = CancellationToken::new().with_timeout(Duration::from_secs(5));
+/// let request = token.run(socket.read_u32_be()).await?;
+/// handle(request, token.child_token()).await?;
+/// ```
+///
+/// [`is_cancelled`]: #method.is_cancelled
+/// [`cancelled`]: #method.cancelled
+/// [`run`]: #method.run
+/// [`child_token`]: #method.child_token
+/// [`with_timeout`]: #method.with_timeout
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Rc<TokenState>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            state: TokenState::new(None),
+        }
+    }
+
+    /// Cancel the token, its clones and the tokens derived from it, and wake
+    /// the tasks waiting for it
+    pub fn cancel(&self) {
+        self.state.cancel();
+    }
+
+    /// Return true if the token has been cancelled, or its deadline has passed
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled()
+    }
+
+    /// The instant at which the token is cancelled by itself, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.deadline
+    }
+
+    /// Wait until the token is cancelled
+    ///
+    /// Fails with `Error::Cancel` or `Error::Timeout` if the waiting task is
+    /// interrupted while the token has a deadline.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            state: self.state.clone(),
+            sleep: self.state.deadline.map(sleep_until),
+            waker: None,
+        }
+    }
+
+    /// Return a new token that is cancelled along with this one, but can also
+    /// be cancelled by itself
+    pub fn child_token(&self) -> CancellationToken {
+        self.derive(self.state.deadline)
+    }
+
+    /// Return a new token that is cancelled along with this one, or once
+    /// `duration` has passed, whichever comes first
+    pub fn with_timeout(&self, duration: Duration) -> CancellationToken {
+        let deadline = Instant::now() + duration;
+        self.derive(Some(
+            self.state
+                .deadline
+                .map_or(deadline, |d| std::cmp::min(d, deadline)),
+        ))
+    }
+
+    fn derive(&self, deadline: Option<Instant>) -> CancellationToken {
+        let state = TokenState::new(deadline);
+        if self.is_cancelled() {
+            state.cancelled.set(true);
+        } else {
+            let mut children = self.state.children.borrow_mut();
+            children.retain(|c| c.strong_count() != 0);
+            children.push(Rc::downgrade(&state));
+        }
+        CancellationToken { state }
+    }
+
+    /// Run `future`, failing with `Error::Cancel` if the token is cancelled
+    /// before it completes
+    ///
+    /// As with [`timeout`], the io_uring operations the future has in flight
+    /// are canceled on cancellation, and once they have completed the future is
+    /// dropped without being polled again.
+    ///
+    /// [`timeout`]: fn.timeout.html
+    pub fn run<T, F: Future<Output = Result<T>>>(&self, future: F) -> Cancellable<F> {
+        Cancellable {
+            future: Some(Box::pin(future)),
+            cancelled: self.cancelled(),
+            ops: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by [`CancellationToken::cancelled`]
+///
+/// [`CancellationToken::cancelled`]: struct.CancellationToken.html#method.cancelled
+pub struct Cancelled {
+    state: Rc<TokenState>,
+    /// Sleep until the deadline of the token, if it has one
+    sleep: Option<Sleep>,
+    /// The waker registered with the token
+    waker: Option<std::task::Waker>,
+}
+
+impl Future for Cancelled {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if self.state.is_cancelled() {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(sleep) = self.sleep.as_mut() {
+            match Pin::new(sleep).poll(context) {
+                Poll::Ready(Ok(())) => {
+                    self.state.cancel();
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+        }
+        let waker = context.waker().clone();
+        let mut wakers = self.state.wakers.borrow_mut();
+        wakers.retain(|w| !w.will_wake(&waker));
+        wakers.push(waker.clone());
+        drop(wakers);
+        self.waker = Some(waker);
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            self.state
+                .wakers
+                .borrow_mut()
+                .retain(|w| !w.will_wake(&waker));
+        }
+    }
+}
+
+/// Future returned by [`CancellationToken::run`]
+///
+/// [`CancellationToken::run`]: struct.CancellationToken.html#method.run
+pub struct Cancellable<F: Future> {
+    /// The wrapped future, None once it has been dropped
+    future: Option<Pin<Box<F>>>,
+    cancelled: Cancelled,
+    /// user_data of the io_uring operations started by the future that may be in flight
+    ops: Vec<u64>,
+    /// The error to fail with, set when the token has been cancelled
+    error: Option<Error>,
+}
+
+impl<T, F: Future<Output = Result<T>>> Future for Cancellable<F> {
+    type Output = Result<T>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let this = &mut *self;
+        this.ops.retain(|op| task.in_flight.borrow().contains(op));
+        if this.error.is_none() {
+            let future = match this.future.as_mut() {
+                Some(future) => future,
+                None => return Poll::Ready(Err(Error::Internal("Done future polled"))),
+            };
+            let before = task.in_flight.borrow().len();
+            let res = future.as_mut().poll(context);
+            this.ops
+                .extend_from_slice(&task.in_flight.borrow()[before..]);
+            if let Poll::Ready(res) = res {
+                this.future = None;
+                return Poll::Ready(res);
+            }
+            this.error = match Pin::new(&mut this.cancelled).poll(context) {
+                Poll::Ready(Ok(())) => Some(Error::Cancel),
+                Poll::Ready(Err(e)) => Some(e),
+                Poll::Pending => return Poll::Pending,
+            };
+            for op in &this.ops {
+                if let Err(e) = io_uring_cancel(&task.reactor, *op) {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        // The future can only be dropped once the kernel is done with its operations
+        if !this.ops.is_empty() {
+            return Poll::Pending;
+        }
+        this.future = None;
+        Poll::Ready(Err(this.error.take().unwrap_or(Error::Cancel)))
+    }
+}
+
+struct SemaphoreState {
+    /// Number of permits not handed out
+    available: Cell<usize>,
+    /// Id given to the next waiter
+    next_id: Cell<u64>,
+    /// Tasks waiting for a permit, in the order they started waiting
+    waiters: RefCell<VecDeque<(u64, std::task::Waker)>>,
+}
+
+impl SemaphoreState {
+    /// Wake the first waiter if there is a permit for it
+    fn wake_first(&self) {
+        if self.available.get() == 0 {
+            return;
+        }
+        if let Some((_, waker)) = self.waiters.borrow().front() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+/// Counting semaphore limiting how many tasks hold a permit at the same time,
+/// for instance to bound the number of concurrent connections or requests
+///
+/// Permits are handed out in the order the tasks started waiting for them, and
+/// are returned to the semaphore when the [`Permit`] is dropped. Clones of a
+/// semaphore share its permits.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::Semaphore;
+///
+/// let semaphore = Semaphore::new(16);
+/// let permit = semaphore.acquire().await?;
+/// let response = backend.request(id, &payload).await?;
+/// drop(permit);
+/// ```
+///
+/// [`Permit`]: struct.Permit.html
+#[derive(Clone)]
+pub struct Semaphore {
+    state: Rc<SemaphoreState>,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `permits` permits
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Rc::new(SemaphoreState {
+                available: Cell::new(permits),
+                next_id: Cell::new(0),
+                waiters: RefCell::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    /// Wait for a permit
+    ///
+    /// Fails with `Error::Cancel` or `Error::Timeout` if the task is canceled
+    /// or timed out. Waiting for a permit is not io, so use [`timeout`] or
+    /// [`CancellationToken::run`] to bound the wait.
+    ///
+    /// [`timeout`]: fn.timeout.html
+    /// [`CancellationToken::run`]: struct.CancellationToken.html#method.run
+    pub fn acquire(&self) -> Acquire {
+        Acquire {
+            state: self.state.clone(),
+            id: None,
+        }
+    }
+
+    /// Take a permit if one is available and no task is waiting for one
+    pub fn try_acquire(&self) -> Option<Permit> {
+        if self.state.available.get() == 0 || !self.state.waiters.borrow().is_empty() {
+            return None;
+        }
+        self.state.available.set(self.state.available.get() - 1);
+        Some(Permit {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Return the number of permits not currently handed out
+    pub fn available_permits(&self) -> usize {
+        self.state.available.get()
+    }
+
+    /// Add `permits` permits to the semaphore, waking waiting tasks
+    pub fn add_permits(&self, permits: usize) {
+        self.state
+            .available
+            .set(self.state.available.get() + permits);
+        self.state.wake_first();
+    }
+}
+
+/// Future returned by [`Semaphore::acquire`]
+///
+/// [`Semaphore::acquire`]: struct.Semaphore.html#method.acquire
+pub struct Acquire {
+    state: Rc<SemaphoreState>,
+    /// Id of the entry in the waiters of the semaphore, once registered
+    id: Option<u64>,
+}
+
+impl Future for Acquire {
+    type Output = Result<Permit>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Ok(task) = current_task() {
+            if let Some(e) = interruption(&task) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        let this = &mut *self;
+        let state = &this.state;
+        let first = match this.id {
+            None => state.waiters.borrow().is_empty(),
+            Some(id) => state.waiters.borrow().front().map_or(false, |w| w.0 == id),
+        };
+        if first && state.available.get() != 0 {
+            state.available.set(state.available.get() - 1);
+            if this.id.take().is_some() {
+                state.waiters.borrow_mut().pop_front();
+            }
+            state.wake_first();
+            return Poll::Ready(Ok(Permit {
+                state: state.clone(),
+            }));
+        }
+        let waker = context.waker().clone();
+        match this.id {
+            Some(id) => {
+                if let Some(w) = state.waiters.borrow_mut().iter_mut().find(|w| w.0 == id) {
+                    w.1 = waker;
+                }
+            }
+            None => {
+                let id = state.next_id.get();
+                state.next_id.set(id + 1);
+                state.waiters.borrow_mut().push_back((id, waker));
+                this.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.state.waiters.borrow_mut().retain(|w| w.0 != id);
+            // The dropped waiter may have been woken for a permit, pass it on
+            self.state.wake_first();
+        }
+    }
+}
+
+/// Permit of a [`Semaphore`], returned to the semaphore when dropped
+///
+/// [`Semaphore`]: struct.Semaphore.html
+pub struct Permit {
+    state: Rc<SemaphoreState>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.state.available.set(self.state.available.get() + 1);
+        self.state.wake_first();
+    }
+}
+
+/// Future returned by [`catch_unwind`]
+///
+/// [`catch_unwind`]: fn.catch_unwind.html
+pub(crate) struct CatchUnwind<F: Future> {
+    /// The wrapped future, None once it has been dropped
+    future: Option<Pin<Box<F>>>,
+    /// user_data of the io_uring operations started by the future that may be in flight
+    ops: Vec<u64>,
+    panicked: bool,
+}
+
+impl<T, F: Future<Output = Result<T>>> Future for CatchUnwind<F> {
+    type Output = Result<T>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let this = &mut *self;
+        this.ops.retain(|op| task.in_flight.borrow().contains(op));
+        if !this.panicked {
+            let future = match this.future.as_mut() {
+                Some(future) => future,
+                None => return Poll::Ready(Err(Error::Internal("Done future polled"))),
+            };
+            let before = task.in_flight.borrow().len();
+            let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                future.as_mut().poll(context)
+            }));
+            this.ops
+                .extend_from_slice(&task.in_flight.borrow()[before..]);
+            match res {
+                Ok(Poll::Ready(res)) => {
+                    this.future = None;
+                    return Poll::Ready(res);
+                }
+                Ok(Poll::Pending) => return Poll::Pending,
+                Err(_) => this.panicked = true,
+            }
+            for op in &this.ops {
+                if let Err(e) = io_uring_cancel(&task.reactor, *op) {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        // The future can only be dropped once the kernel is done with its operations
+        if !this.ops.is_empty() {
+            return Poll::Pending;
+        }
+        this.future = None;
+        Poll::Ready(Err(Error::Internal("Future panicked")))
+    }
+}
+
+/// Run `future`, failing with `Error::Internal` if it panics instead of
+/// unwinding through the reactor
+///
+/// The io_uring operations the future has in flight when it panics are
+/// canceled, and once they have completed the future is dropped.
+pub(crate) fn catch_unwind<T, F: Future<Output = Result<T>>>(future: F) -> CatchUnwind<F> {
+    CatchUnwind {
+        future: Some(Box::pin(future)),
+        ops: Vec::new(),
+        panicked: false,
+    }
+}
+
+/// Handle used to spawn child tasks of a [`scope`]
+///
+/// [`scope`]: fn.scope.html
+#[derive(Clone)]
+pub struct Scope {
+    reactor: ReactorRef,
+    children: Rc<RefCell<Vec<Task>>>,
+}
+
+impl Scope {
+    /// Spawn a child task, that the scope waits for before it returns
+    pub fn spawn<F: Future<Output = Result<()>> + 'static>(
+        &self,
+        priority: Priority,
+        future: F,
+    ) -> Task {
+        let task = self.reactor.spawn(priority, future);
+        self.children.borrow_mut().push(task.clone());
+        task
+    }
+}
+
+/// Run `body` with a [`Scope`] that child tasks can be spawned in. The scope
+/// does not return before all its children have finished, so no child outlives it.
+///
+/// If `body` returns an error, including when the task running the scope is
+/// canceled while in `body`, the children that are still running are canceled
+/// in the order they were spawned. The children are then waited for in the same
+/// order. Cancellation makes the next or current io operation of a child fail with
+/// `Error::Cancel`. A child that ignores this, or does not perform io, is still
+/// waited for, and delays the return of the scope until it finishes.
+///
+/// If the future returned by `scope` is dropped before it completes, such as
+/// when it loses a `select` or a `timeout` expires, the children that are still
+/// running are canceled in the same way, but can not be waited for. A child that
+/// ignores the cancellation keeps running after the scope is gone, like a task
+/// spawned outside of it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::runtime::Priority;
+/// use agrippa::util::scope;
+///
+/// scope(|s| async move {
+///     s.spawn(Priority::Normal, prefetch());
+///     handle_request().await
+/// })
+/// .await?;
+/// ```
+///
+/// [`Scope`]: struct.Scope.html
+pub async fn scope<T, F, Fut>(body: F) -> Result<T>
+where
+    F: FnOnce(Scope) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let scope = Scope {
+        reactor: current_reactor().await?,
+        children: Rc::new(RefCell::new(Vec::new())),
+    };
+    let guard = ScopeGuard {
+        children: scope.children.clone(),
+    };
+    let res = body(scope.clone()).await;
+    let children: Vec<Task> = guard.children.borrow().clone();
+    if res.is_err() {
+        for child in children.iter().filter(|c| !c.is_finished()) {
+            child.cancel()?;
+        }
+    }
+    for child in children {
+        child.wait().await?;
+    }
+    guard.children.borrow_mut().clear();
+    res
+}
+
+/// Children of a [`scope`], canceled if the scope future is dropped before
+/// it has waited for them
+///
+/// [`scope`]: fn.scope.html
+struct ScopeGuard {
+    children: Rc<RefCell<Vec<Task>>>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        for child in self.children.borrow_mut().drain(..) {
+            if !child.is_finished() {
+                // There is nothing to report the failure to
+                let _ = child.cancel();
+            }
+        }
+    }
+}
+
+/// Policy for [`retry`]: how many attempts are made, and how long to sleep
+/// between them
+///
+/// [`retry`]: fn.retry.html
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    retryable: Option<Box<dyn Fn(&Error) -> bool>>,
+}
+
+impl RetryPolicy {
+    /// Make at most `max_attempts` attempts. The delay after the first failed
+    /// attempt is `base_delay`, and it doubles after each following failure,
+    /// up to `max_delay`
+    pub fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts,
+            base_delay,
+            max_delay,
+            retryable: None,
+        }
+    }
+
+    /// Only retry errors for which `retryable` returns true, by default all
+    /// errors except `Error::Cancel` and `Error::Timeout` are retried
+    pub fn retry_if<P: Fn(&Error) -> bool + 'static>(&mut self, retryable: P) -> &mut Self {
+        self.retryable = Some(Box::new(retryable));
+        self
+    }
+
+    fn should_retry(&self, error: &Error) -> bool {
+        match error {
+            Error::Cancel | Error::Timeout => false,
+            e => self.retryable.as_ref().map_or(true, |r| r(e)),
+        }
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        std::cmp::min(
+            self.base_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        )
+    }
+}
+
+/// Run the future produced by `operation` until it succeeds, sleeping with
+/// exponential backoff between the attempts as specified by `policy`.
+///
+/// The error of the last attempt is returned when the attempts are exhausted,
+/// or right away if the error is not retryable. A canceled or timed out
+/// task is never retried.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::{retry, RetryPolicy};
+/// use std::io::ErrorKind;
+/// use std::time::Duration;
+///
+/// let mut policy = RetryPolicy::new(5, Duration::from_millis(10), Duration::from_secs(1));
+/// policy.retry_if(|e| e.kind() == Some(ErrorKind::ConnectionRefused));
+/// let socket = retry(&policy, || agrippa::tcp::connect("127.0.0.1:8080")).await?;
+/// ```
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut failures = 0;
+    loop {
+        match operation().await {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                failures += 1;
+                if failures >= policy.max_attempts || !policy.should_retry(&e) {
+                    return Err(e);
+                }
+                sleep(policy.delay(failures)).await?;
+            }
+        }
+    }
+}
+
+/// Buffers small writes to an [`AsyncWrite`], and writes them in larger chunks
+///
+/// The buffer is written when it fills up, and when [`flush`] is called. The
+/// buffer can not be written when the `BufWriter` is dropped, since that would
+/// require waiting for io, so it must be flushed explicitly. Data that has not been
+/// flushed when the writer is dropped is lost, and a warning is logged.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::BufWriter;
+///
+/// let mut writer = BufWriter::new(socket, 4096);
+/// for field in fields {
+///     writer.write_all(field).await?;
+/// }
+/// writer.flush().await?;
+/// ```
+///
+/// [`AsyncWrite`]: trait.AsyncWrite.html
+/// [`flush`]: #method.flush
+pub struct BufWriter<W: AsyncWrite> {
+    inner: W,
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl<W: AsyncWrite> BufWriter<W> {
+    /// Create a writer buffering up to `capacity` bytes before writing to `inner`
+    pub fn new(inner: W, capacity: usize) -> Self {
+        BufWriter {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The number of bytes buffered, that have not been written yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Write all of `data`, buffering it if it fits in the buffer
+    ///
+    /// Data that does not fit in the buffer causes the buffer to be flushed,
+    /// and data larger than the buffer is written directly.
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        if self.buf.len() + data.len() > self.capacity {
+            self.flush().await?;
+        }
+        if data.len() >= self.capacity {
+            return write_all_to(&self.inner, data).await;
+        }
+        self.buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Write all buffered data to the underlying writer
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let res = write_all_to(&self.inner, &self.buf).await;
+        // On errors it is unknown how much was written, so the data is discarded
+        self.buf.clear();
+        res
+    }
+
+    /// Flush the buffer and return the underlying writer
+    pub async fn into_inner(mut self) -> Result<W> {
+        self.flush().await?;
+        let inner = unsafe { std::ptr::read(&self.inner) };
+        let buf = unsafe { std::ptr::read(&self.buf) };
+        std::mem::forget(self);
+        drop(buf);
+        Ok(inner)
+    }
+}
+
+impl<W: AsyncWrite> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            warn!(
+                "BufWriter dropped with {} bytes that were not flushed",
+                self.buf.len()
+            );
+        }
+    }
+}
+
+async fn write_all_to<W: AsyncWrite>(writer: &W, data: &[u8]) -> Result<()> {
+    let mut start = 0;
+    while start != data.len() {
+        let written = writer.write(&data[start..]).await?;
+        if written == 0 {
+            return Err(Error::Eof);
+        }
+        start += written;
+    }
+    Ok(())
+}
+
+/// Token bucket limiting the bytes transferred per second, allowing bursts of
+/// up to one second worth of bytes
+struct TokenBucket {
+    rate: u64,
+    tokens: Cell<u64>,
+    last_refill: Cell<Instant>,
+}
+
+impl TokenBucket {
+    fn refill(&self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_refill.get());
+        let add = (elapsed.as_nanos() * self.rate as u128 / 1_000_000_000) as u64;
+        if add == 0 {
+            return;
+        }
+        let tokens = self.tokens.get().saturating_add(add);
+        if tokens >= self.rate {
+            self.tokens.set(self.rate);
+            self.last_refill.set(now);
+        } else {
+            // Only account for the time of the whole tokens added, to not lose the rest
+            self.tokens.set(tokens);
+            let used =
+                Duration::from_nanos((add as u128 * 1_000_000_000 / self.rate as u128) as u64);
+            self.last_refill.set(self.last_refill.get() + used);
+        }
+    }
+
+    /// Wait until tokens are available, and return how many of `want` bytes may be transferred
+    async fn acquire(&self, want: usize) -> Result<usize> {
+        loop {
+            self.refill();
+            let tokens = self.tokens.get();
+            if tokens > 0 || want == 0 {
+                return Ok(std::cmp::min(want as u64, tokens) as usize);
+            }
+            // Wait for enough tokens for the whole transfer, or a full bucket,
+            // rather than for a single byte at a time
+            let needed = std::cmp::min(want as u64, self.rate);
+            sleep(Duration::from_nanos(
+                (needed as u128 * 1_000_000_000 / self.rate as u128) as u64,
+            ))
+            .await?;
+        }
+    }
+
+    fn consume(&self, used: usize) {
+        self.tokens
+            .set(self.tokens.get().saturating_sub(used as u64));
+    }
+}
+
+/// Counts the bytes read from and written to a stream, and optionally limits
+/// the rate at which they are transferred
+///
+/// With a limit, reads and writes are shortened to the bytes available in a token
+/// bucket refilled at `limit` bytes per second, and wait for the bucket to refill
+/// when it is empty. The bucket holds at most one second worth of bytes, so a
+/// stream that has been idle can burst up to that before being throttled.
+///
+/// The counts can be shared between streams with [`counters`], for instance to
+/// account the traffic of all connections of a tenant.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::util::Metered;
+///
+/// // Throttle the stream to 1MB per second
+/// let socket = Metered::new(socket, Some(1024 * 1024));
+/// let n = socket.read(&mut buf).await?;
+/// socket.write_all(&buf[..n]).await?;
+/// println!("{} bytes in, {} bytes out", socket.bytes_read(), socket.bytes_written());
+/// ```
+///
+/// [`counters`]: #method.counters
+pub struct Metered<S> {
+    inner: S,
+    read: Rc<Cell<u64>>,
+    written: Rc<Cell<u64>>,
+    limit: Option<TokenBucket>,
+}
+
+impl<S> Metered<S> {
+    /// Wrap `inner`, limiting the bytes read and written to `limit` bytes per
+    /// second in total if given
+    pub fn new(inner: S, limit: Option<u64>) -> Self {
+        Metered {
+            inner,
+            read: Rc::new(Cell::new(0)),
+            written: Rc::new(Cell::new(0)),
+            limit: limit.filter(|rate| *rate > 0).map(|rate| TokenBucket {
+                rate,
+                tokens: Cell::new(rate),
+                last_refill: Cell::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Add the bytes read and written to the given counters, instead of to counters
+    /// of this stream only
+    pub fn counters(&mut self, read: Rc<Cell<u64>>, written: Rc<Cell<u64>>) -> &mut Self {
+        self.read = read;
+        self.written = written;
+        self
+    }
+
+    /// The number of bytes read, as counted by the read counter
+    pub fn bytes_read(&self) -> u64 {
+        self.read.get()
+    }
+
+    /// The number of bytes written, as counted by the write counter
+    pub fn bytes_written(&self) -> u64 {
+        self.written.get()
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return the underlying stream
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    async fn acquire(&self, want: usize) -> Result<usize> {
+        match &self.limit {
+            Some(limit) => limit.acquire(want).await,
+            None => Ok(want),
+        }
+    }
+
+    fn consume(&self, used: usize) {
+        if let Some(limit) = &self.limit {
+            limit.consume(used);
+        }
+    }
+}
+
+impl<S: AsyncRead> Metered<S> {
+    /// Read bytes into `data`, return the number of bytes read, 0 at the end of the stream
+    pub async fn read(&self, data: &mut [u8]) -> Result<usize> {
+        let len = self.acquire(data.len()).await?;
+        let read = self.inner.read(&mut data[..len]).await?;
+        self.consume(read);
+        self.read.set(self.read.get() + read as u64);
+        Ok(read)
+    }
+}
+
+impl<S: AsyncWrite> Metered<S> {
+    /// Write bytes from `data`, return the number of bytes written
+    pub async fn write(&self, data: &[u8]) -> Result<usize> {
+        let len = self.acquire(data.len()).await?;
+        let written = self.inner.write(&data[..len]).await?;
+        self.consume(written);
+        self.written.set(self.written.get() + written as u64);
+        Ok(written)
+    }
+
+    /// Write all of `data`
+    pub async fn write_all(&self, data: &[u8]) -> Result<()> {
+        write_all_to(self, data).await
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Metered<S> {
+    fn read<'a>(&'a self, data: &'a mut [u8]) -> Pin<Box<dyn Future<Output = Result<usize>> + 'a>> {
+        Box::pin(Metered::read(self, data))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Metered<S> {
+    fn write<'a>(&'a self, data: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<usize>> + 'a>> {
+        Box::pin(Metered::write(self, data))
+    }
+}
+
+/// Largest frame payload accepted by a [`Multiplexer`]
+///
+/// [`Multiplexer`]: struct.Multiplexer.html
+const MULTIPLEXER_MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Encode a frame of `payload` tagged with `id`
+fn encode_frame(id: u64, payload: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    if payload.len() > u32::max_value() as usize {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "frame payload too large",
+        )));
+    }
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Read until `data` is full or the stream ends, return the number of bytes read
+async fn read_full_from<R: AsyncRead>(reader: &R, data: &mut [u8]) -> Result<usize> {
+    let mut start = 0;
+    while start != data.len() {
+        let read = reader.read(&mut data[start..]).await?;
+        if read == 0 {
+            break;
+        }
+        start += read;
+    }
+    Ok(start)
+}
+
+/// Write a frame holding `payload` tagged with `id`, in the format read by
+/// [`read_frame`] and used by [`Multiplexer`]: the id as a big endian u64,
+/// the length of the payload as a big endian u32, then the payload.
+///
+/// [`read_frame`]: fn.read_frame.html
+/// [`Multiplexer`]: struct.Multiplexer.html
+pub async fn write_frame<W: AsyncWrite>(writer: &W, id: u64, payload: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(12 + payload.len());
+    encode_frame(id, payload, &mut frame)?;
+    write_all_to(writer, &frame).await
+}
+
+/// Read a frame written by [`write_frame`], and return its id and payload, or
+/// None if the stream ended before the frame started.
+///
+/// Fails with `Error::Eof` if the stream ends within the frame, and with
+/// `ErrorKind::InvalidData` if the payload is longer than `max_len`.
+///
+/// # Examples
+///
+/// A server answering the requests of a [`Multiplexer`]:
+///
+/// ```no_run
+/// use agrippa::util::{read_frame, write_frame};
+///
+/// while let Some((id, request)) = read_frame(&socket, 1024 * 1024).await? {
+///     write_frame(&socket, id, &handle(&request)).await?;
+/// }
+/// ```
+///
+/// [`write_frame`]: fn.write_frame.html
+/// [`Multiplexer`]: struct.Multiplexer.html
+pub async fn read_frame<R: AsyncRead>(
+    reader: &R,
+    max_len: usize,
+) -> Result<Option<(u64, Vec<u8>)>> {
+    let mut header = [0u8; 12];
+    match read_full_from(reader, &mut header).await? {
+        0 => return Ok(None),
+        12 => {}
+        _ => return Err(Error::Eof),
+    }
+    let mut id = [0u8; 8];
+    let mut len = [0u8; 4];
+    id.copy_from_slice(&header[..8]);
+    len.copy_from_slice(&header[8..]);
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame payload too large",
+        )));
+    }
+    let mut payload = vec![0u8; len];
+    if read_full_from(reader, &mut payload).await? != len {
+        return Err(Error::Eof);
+    }
+    Ok(Some((u64::from_be_bytes(id), payload)))
+}
+
+/// Why the connection of a [`Multiplexer`] is no longer usable
+enum MultiplexerClosed {
+    /// The peer closed the connection
+    Eof,
+    /// Reading or writing failed, or the multiplexer was dropped
+    Failed(String),
+}
+
+impl MultiplexerClosed {
+    fn to_error(&self) -> Error {
+        match self {
+            MultiplexerClosed::Eof => Error::Eof,
+            MultiplexerClosed::Failed(reason) => Error::Io(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                reason.clone(),
+            )),
+        }
+    }
+}
+
+/// Response of an outstanding request, and the task waiting for it
+#[derive(Default)]
+struct PendingResponse {
+    response: Option<Result<Vec<u8>>>,
+    waker: Option<std::task::Waker>,
+}
+
+struct MultiplexerShared<S> {
+    stream: S,
+    next_id: Cell<u64>,
+    /// Outstanding requests by id
+    pending: RefCell<std::collections::HashMap<u64, PendingResponse>>,
+    /// Encoded frames waiting to be written by the writer task
+    outbox: RefCell<Vec<u8>>,
+    writer_waker: RefCell<Option<std::task::Waker>>,
+    closed: RefCell<Option<MultiplexerClosed>>,
+}
+
+impl<S> MultiplexerShared<S> {
+    /// Hand a received response to the request waiting for it. Responses to
+    /// unknown ids, for instance of requests that were dropped, are discarded
+    fn deliver(&self, id: u64, payload: Vec<u8>) {
+        if let Some(pending) = self.pending.borrow_mut().get_mut(&id) {
+            pending.response = Some(Ok(payload));
+            if let Some(waker) = pending.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Fail all outstanding requests, and stop the writer task
+    fn close(&self, closed: MultiplexerClosed) {
+        if self.closed.borrow().is_some() {
+            return;
+        }
+        for pending in self.pending.borrow_mut().values_mut() {
+            pending.response = Some(Err(closed.to_error()));
+            if let Some(waker) = pending.waker.take() {
+                waker.wake();
+            }
+        }
+        *self.closed.borrow_mut() = Some(closed);
+        if let Some(waker) = self.writer_waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves when there are frames to write, or the connection is closed
+struct OutboxReady<'a, S> {
+    shared: &'a MultiplexerShared<S>,
+}
+
+impl<'a, S> Future for OutboxReady<'a, S> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if self.shared.outbox.borrow().is_empty() && self.shared.closed.borrow().is_none() {
+            *self.shared.writer_waker.borrow_mut() = Some(context.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Resolves to the response of the request with the given id
+struct ResponseFuture<'a, S> {
+    shared: &'a MultiplexerShared<S>,
+    id: u64,
+}
+
+impl<'a, S> Future for ResponseFuture<'a, S> {
+    type Output = Result<Vec<u8>>;
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut pending = self.shared.pending.borrow_mut();
+        let entry = match pending.get_mut(&self.id) {
+            Some(entry) => entry,
+            None => return Poll::Ready(Err(Error::Internal("Poll called on done future"))),
+        };
+        match entry.response.take() {
+            Some(response) => {
+                pending.remove(&self.id);
+                Poll::Ready(response)
+            }
+            None => {
+                entry.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, S> Drop for ResponseFuture<'a, S> {
+    fn drop(&mut self) {
+        self.shared.pending.borrow_mut().remove(&self.id);
+    }
+}
+
+async fn multiplexer_read<S: AsyncRead>(shared: Rc<MultiplexerShared<S>>) -> Result<()> {
+    let closed = loop {
+        match read_frame(&shared.stream, MULTIPLEXER_MAX_FRAME).await {
+            Ok(Some((id, payload))) => shared.deliver(id, payload),
+            Ok(None) => break MultiplexerClosed::Eof,
+            Err(e) => break MultiplexerClosed::Failed(e.to_string()),
+        }
+    };
+    shared.close(closed);
+    Ok(())
+}
+
+async fn multiplexer_write<S: AsyncWrite>(shared: Rc<MultiplexerShared<S>>) -> Result<()> {
+    loop {
+        OutboxReady { shared: &shared }.await;
+        if shared.closed.borrow().is_some() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut *shared.outbox.borrow_mut(), Vec::new());
+        if let Err(e) = write_all_to(&shared.stream, &data).await {
+            shared.close(MultiplexerClosed::Failed(e.to_string()));
+            return Ok(());
+        }
+    }
+}
+
+/// Client side of a request/response protocol over a stream, with many
+/// requests in flight at the same time
+///
+/// Each request is sent as a frame tagged with a fresh id, in the format of
+/// [`write_frame`], and the peer answers with a frame tagged with the same id,
+/// in any order. A background task reads the responses and hands each one to
+/// the request waiting for it, and another writes the requests, so a request
+/// is never interleaved with another on the stream.
+///
+/// When the peer closes the connection, all outstanding and later requests fail
+/// with `Error::Eof`. When reading or writing fails they fail with
+/// `ErrorKind::ConnectionAborted`. Dropping the multiplexer stops the background
+/// tasks.
+///
+/// # Examples
+///
+/// ```no_run
+/// use agrippa::tcp::connect;
+/// use agrippa::util::{join_all, Multiplexer};
+///
+/// let client = Multiplexer::new(connect("127.0.0.1:1234").await?).await?;
+/// let (a, b) = (client.request(b"get a"), client.request(b"get b"));
+/// let responses = join_all(vec![a, b]).await;
+/// ```
+///
+/// [`write_frame`]: fn.write_frame.html
+pub struct Multiplexer<S: AsyncRead + AsyncWrite + 'static> {
+    shared: Rc<MultiplexerShared<S>>,
+    reader: Task,
+}
+
+impl<S: AsyncRead + AsyncWrite + 'static> Multiplexer<S> {
+    /// Start multiplexing requests over `stream`, spawning the background
+    /// tasks on the reactor of the current task
+    pub async fn new(stream: S) -> Result<Self> {
+        let reactor = current_reactor().await?;
+        let shared = Rc::new(MultiplexerShared {
+            stream,
+            next_id: Cell::new(0),
+            pending: RefCell::new(std::collections::HashMap::new()),
+            outbox: RefCell::new(Vec::new()),
+            writer_waker: RefCell::new(None),
+            closed: RefCell::new(None),
+        });
+        let reader = reactor.spawn_named(
+            "multiplexer_read",
+            Priority::Normal,
+            multiplexer_read(shared.clone()),
+        );
+        reactor.spawn_named(
+            "multiplexer_write",
+            Priority::Normal,
+            multiplexer_write(shared.clone()),
+        );
+        Ok(Multiplexer { shared, reader })
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.shared.stream
+    }
+
+    /// The number of requests waiting for their response
+    pub fn outstanding(&self) -> usize {
+        self.shared.pending.borrow().len()
+    }
+
+    /// Send a request and wait for the response to it
+    ///
+    /// If the future is dropped, the request may still be sent, and its response
+    /// is discarded when it arrives.
+    pub async fn request(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        if let Some(closed) = &*self.shared.closed.borrow() {
+            return Err(closed.to_error());
+        }
+        let id = self.shared.next_id.get();
+        self.shared.next_id.set(id.wrapping_add(1));
+        encode_frame(id, payload, &mut self.shared.outbox.borrow_mut())?;
+        self.shared
+            .pending
+            .borrow_mut()
+            .insert(id, PendingResponse::default());
+        if let Some(waker) = self.shared.writer_waker.borrow_mut().take() {
+            waker.wake();
+        }
+        ResponseFuture {
+            shared: &self.shared,
+            id,
+        }
+        .await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + 'static> Drop for Multiplexer<S> {
+    fn drop(&mut self) {
+        self.shared
+            .close(MultiplexerClosed::Failed("multiplexer dropped".to_string()));
+        // The writer stops once woken by the close, the reader is blocked in a read
+        if !self.reader.is_finished() {
+            let _ = self.reader.cancel();
+        }
+    }
+}
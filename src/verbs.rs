@@ -1,93 +1,693 @@
-use crate::runtime::{waker_task, Error, ReactorRef, Result, TaskRef, NOT_DONE};
+use crate::runtime::{
+    current_task, interruption, Completion, Error, Priority, ReactorRef, Result, TaskRef,
+};
+use crate::sys::ibv_qp_state;
+use crate::util::{sleep, sleep_until, Sleep};
 use crate::verbs_util::QueuePair;
-pub use crate::verbs_util::{Buffer, VerbsAddr};
+pub use crate::verbs_util::{Buffer, MemoryRegion, VerbsAddr, VerbsStats};
+use log::info;
 use std::future::Future;
 use std::pin::Pin;
-use std::task::Poll;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Duration;
+
+fn poll_recv(
+    qp: &QueuePair,
+    waiting: &mut Option<ReactorRef>,
+    context: &mut std::task::Context,
+) -> Poll<Result<Vec<Buffer>>> {
+    let reactor = match current_task() {
+        Ok(task) => task.reactor.clone(),
+        Err(e) => return Poll::Ready(Err(e)),
+    };
+    let mut device = reactor.device.borrow_mut();
+    if let Some(buffers) = device.take_received(qp.qp_num()) {
+        *waiting = None;
+        Poll::Ready(Ok(buffers))
+    } else if device.is_failed(qp.qp_num()) {
+        *waiting = None;
+        Poll::Ready(Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "verbs connection failed",
+        ))))
+    } else if waiting.is_some() {
+        // Still registered from an earlier poll
+        Poll::Pending
+    } else if let Err(e) = device.wait_recv(qp.qp_num(), context.waker().clone()) {
+        Poll::Ready(Err(Error::Io(e)))
+    } else {
+        drop(device);
+        *waiting = Some(reactor);
+        Poll::Pending
+    }
+}
+
+/// Deregister a receive future that is dropped while waiting for a message,
+/// so that the next message does not wake a stale task
+fn cancel_recv(qp: &QueuePair, waiting: &mut Option<ReactorRef>) {
+    if let Some(reactor) = waiting.take() {
+        if let Ok(mut device) = reactor.device.try_borrow_mut() {
+            device.cancel_recv(qp.qp_num());
+        }
+    }
+}
 
 pub struct Recv<'a> {
     qp: &'a QueuePair,
+    /// Set while the task is registered to be woken by the next message
+    waiting: Option<ReactorRef>,
 }
 
 impl<'a> Future for Recv<'a> {
     type Output = Result<Buffer>;
     fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
-        if let Some(buffer) = self.qp.read() {
-            Poll::Ready(Ok(buffer))
-        } else if let Err(e) = self.qp.wait(waker_task(context.waker().clone())) {
-            Poll::Ready(Err(Error::Io(e)))
-        } else {
-            Poll::Pending
+        let this = &mut *self;
+        match poll_recv(this.qp, &mut this.waiting, context) {
+            Poll::Ready(Ok(mut buffers)) => {
+                // Return the buffers we did not receive into to the pool
+                let reactor = match current_task() {
+                    Ok(task) => task.reactor.clone(),
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                for buffer in buffers.drain(1..) {
+                    reactor.put_verbs_buffer(buffer);
+                }
+                Poll::Ready(Ok(buffers.pop().unwrap()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a> Drop for Recv<'a> {
+    fn drop(&mut self) {
+        cancel_recv(self.qp, &mut self.waiting);
+    }
+}
+
+pub struct RecvScatter<'a> {
+    qp: &'a QueuePair,
+    /// Set while the task is registered to be woken by the next message
+    waiting: Option<ReactorRef>,
+}
+
+impl<'a> Future for RecvScatter<'a> {
+    type Output = Result<Vec<Buffer>>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        poll_recv(this.qp, &mut this.waiting, context)
+    }
+}
+
+impl<'a> Drop for RecvScatter<'a> {
+    fn drop(&mut self) {
+        cancel_recv(self.qp, &mut self.waiting);
+    }
+}
+
+pub struct RecvBatch<'a> {
+    qp: &'a QueuePair,
+    max: usize,
+    /// Set while the task is registered to be woken by the next message
+    waiting: Option<ReactorRef>,
+}
+
+impl<'a> Future for RecvBatch<'a> {
+    type Output = Result<Vec<Buffer>>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut first = match poll_recv(this.qp, &mut this.waiting, context) {
+            Poll::Ready(Ok(buffers)) => buffers,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let reactor = match current_task() {
+            Ok(task) => task.reactor.clone(),
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let mut messages = Vec::new();
+        messages.push(first.drain(..1).next().unwrap());
+        let mut spare = first;
+        {
+            let mut device = reactor.device.borrow_mut();
+            while messages.len() < this.max {
+                match device.take_received(this.qp.qp_num()) {
+                    Some(mut buffers) => {
+                        messages.push(buffers.drain(..1).next().unwrap());
+                        spare.append(&mut buffers);
+                    }
+                    None => break,
+                }
+            }
+        }
+        // Return the buffers we did not receive into to the pool
+        for buffer in spare {
+            reactor.put_verbs_buffer(buffer);
         }
+        Poll::Ready(Ok(messages))
+    }
+}
+
+impl<'a> Drop for RecvBatch<'a> {
+    fn drop(&mut self) {
+        cancel_recv(self.qp, &mut self.waiting);
     }
 }
 
-#[derive(Copy, Clone)]
 enum SendState {
     Initial,
-    Sent,
+    /// Waiting for room in the send queue, with the waker that was registered
+    WaitingCredit(ReactorRef, Waker),
+    /// The work request with the given wr_id has been posted
+    Sent(ReactorRef, Rc<Completion>, u64),
     Done,
 }
 
 pub struct Send<'a> {
     qp: &'a QueuePair,
+    /// The buffer sent from, returned to the pool once the send has completed
     buffer: Option<Buffer>,
+    /// Address, length and lkey of each segment of memory to send, or the reason
+    /// they are invalid
+    segments: std::result::Result<Vec<(u64, u32, u32)>, &'static str>,
+    /// The region sent from, borrowed until the send has completed
+    _region: Option<&'a MemoryRegion>,
+    /// Immediate data to send with the message
+    imm: Option<u32>,
     state: SendState,
 }
 
 impl<'a> Future for Send<'a> {
     type Output = Result<()>;
     fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
-        let task = waker_task(context.waker().clone());
-        match self.state {
-            SendState::Initial => {
-                task.ring_result.set(NOT_DONE);
-                match unsafe { self.qp.send(task, self.buffer.as_ref().unwrap()) } {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let this = &mut *self;
+        match std::mem::replace(&mut this.state, SendState::Done) {
+            SendState::Initial | SendState::WaitingCredit(..) => {
+                let reactor = task.reactor.clone();
+                let mut device = reactor.device.borrow_mut();
+                // Wait until there is room in the send queue
+                if !device.send_credit_available(this.qp.qp_num()) {
+                    let waker = context.waker().clone();
+                    device.wait_send_credit(this.qp.qp_num(), waker.clone());
+                    drop(device);
+                    this.state = SendState::WaitingCredit(reactor, waker);
+                    return Poll::Pending;
+                }
+                let invalid = match &this.segments {
+                    Ok(segments) => device.check_gather(segments),
+                    Err(e) => Some(*e),
+                };
+                if let Some(e) = invalid {
+                    if let Some(buffer) = this.buffer.take() {
+                        device.free_buffers.push(buffer);
+                    }
+                    return Poll::Ready(Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        e,
+                    ))));
+                }
+                let segments = this.segments.as_ref().unwrap();
+                let completion = Rc::new(Completion::new(task));
+                match unsafe {
+                    this.qp
+                        .send(&mut device, completion.clone(), segments, this.imm)
+                } {
                     Err(e) => {
-                        self.state = SendState::Done;
-                        put_buffer(self.buffer.take().unwrap());
+                        if let Some(buffer) = this.buffer.take() {
+                            device.free_buffers.push(buffer);
+                        }
                         Poll::Ready(Err(Error::Io(e)))
                     }
-                    Ok(e) => {
-                        self.state = SendState::Sent;
+                    Ok(wr_id) => {
+                        drop(device);
+                        this.state = SendState::Sent(reactor, completion, wr_id);
                         Poll::Pending
                     }
                 }
             }
-            SendState::Sent => {
-                let res = task.ring_result.get();
-                if res == NOT_DONE {
+            SendState::Sent(reactor, completion, wr_id) => match completion.result.get() {
+                None => {
+                    this.state = SendState::Sent(reactor, completion, wr_id);
                     Poll::Pending
-                } else if res != 0 {
-                    self.state = SendState::Done;
-                    put_buffer(self.buffer.take().unwrap());
-                    Poll::Ready(Err(Error::Internal("verbs error"))) //TODO (jakobt) this should be some kind of verbs error
-                } else {
-                    self.state = SendState::Done;
-                    put_buffer(self.buffer.take().unwrap());
+                }
+                Some(0) => {
+                    if let Some(buffer) = this.buffer.take() {
+                        reactor.put_verbs_buffer(buffer);
+                    }
                     Poll::Ready(Ok(()))
                 }
-            }
+                Some(_) => {
+                    if let Some(buffer) = this.buffer.take() {
+                        reactor.put_verbs_buffer(buffer);
+                    }
+                    Poll::Ready(Err(Error::Internal("verbs error"))) //TODO (jakobt) this should be some kind of verbs error
+                }
+            },
             SendState::Done => Poll::Ready(Err(Error::Internal("Poll called on done future"))),
         }
     }
 }
 
+impl<'a> Drop for Send<'a> {
+    fn drop(&mut self) {
+        match std::mem::replace(&mut self.state, SendState::Done) {
+            SendState::WaitingCredit(reactor, waker) => {
+                if let Ok(mut device) = reactor.device.try_borrow_mut() {
+                    device.cancel_send_credit(self.qp.qp_num(), &waker);
+                }
+            }
+            SendState::Sent(reactor, completion, wr_id) => {
+                // The device may still read from the buffer, so it must be kept
+                // alive until the send completes
+                if let Some(buffer) = self.buffer.take() {
+                    if completion.result.get().is_some() {
+                        reactor.put_verbs_buffer(buffer);
+                    } else {
+                        reactor.device.borrow_mut().orphan_send(wr_id, buffer);
+                    }
+                }
+            }
+            SendState::Initial | SendState::Done => {}
+        }
+    }
+}
+
+pub struct SendTimeout<'a> {
+    send: Send<'a>,
+    sleep: Sleep,
+}
+
+impl<'a> Future for SendTimeout<'a> {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
+        if let Poll::Ready(res) = Pin::new(&mut self.send).poll(context) {
+            return Poll::Ready(res);
+        }
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(Ok(())) => Poll::Ready(Err(Error::Timeout)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// State of the queue pair of a verbs connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QpState {
+    /// Created or reset, not usable yet
+    Reset,
+    /// Initialized, receives can be posted but nothing is received
+    Init,
+    /// Ready to receive, connected to the remote queue pair
+    ReadyToReceive,
+    /// Ready to send, fully connected
+    ReadyToSend,
+    /// Send queue drained
+    SendQueueDrained,
+    /// Send queue error, sends fail but receives still work
+    SendQueueError,
+    /// Failed, all work requests complete with an error
+    Error,
+    /// Unknown state reported by the device
+    Unknown,
+}
+
+impl From<ibv_qp_state::Type> for QpState {
+    fn from(state: ibv_qp_state::Type) -> Self {
+        match state {
+            ibv_qp_state::IBV_QPS_RESET => QpState::Reset,
+            ibv_qp_state::IBV_QPS_INIT => QpState::Init,
+            ibv_qp_state::IBV_QPS_RTR => QpState::ReadyToReceive,
+            ibv_qp_state::IBV_QPS_RTS => QpState::ReadyToSend,
+            ibv_qp_state::IBV_QPS_SQD => QpState::SendQueueDrained,
+            ibv_qp_state::IBV_QPS_SQE => QpState::SendQueueError,
+            ibv_qp_state::IBV_QPS_ERR => QpState::Error,
+            _ => QpState::Unknown,
+        }
+    }
+}
+
+/// How often `Connection::wait_until_rts` queries the state of the queue pair
+const RTS_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long to wait for `IBV_EVENT_QP_LAST_WQE_REACHED` when draining a queue
+/// pair, as the event is not reported by all devices
+const LAST_WQE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Future of `drain`, ready once all sends posted on the queue pair have
+/// completed and `IBV_EVENT_QP_LAST_WQE_REACHED` has been handled for it
+struct Drain {
+    reactor: ReactorRef,
+    qp_num: u32,
+    last_wqe_timeout: Sleep,
+}
+
+impl Future for Drain {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
+        let task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if let Some(e) = interruption(&task) {
+            return Poll::Ready(Err(e));
+        }
+        let this = &mut *self;
+        let mut device = this.reactor.device.borrow_mut();
+        if device.sends_in_flight(this.qp_num) != 0 {
+            // The flushed sends complete through the completion channel, which
+            // the reactor reads while we wait
+            device.wait_drain(this.qp_num, context.waker().clone());
+            return Poll::Pending;
+        }
+        if device.take_last_wqe(this.qp_num) {
+            device.cancel_drain(this.qp_num);
+            return Poll::Ready(Ok(()));
+        }
+        device.wait_drain(this.qp_num, context.waker().clone());
+        drop(device);
+        match Pin::new(&mut this.last_wqe_timeout).poll(context) {
+            Poll::Ready(Ok(())) => {
+                info!("No last WQE event for queue pair {}", this.qp_num);
+                this.reactor.device.borrow_mut().cancel_drain(this.qp_num);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Drain {
+    fn drop(&mut self) {
+        if let Ok(mut device) = self.reactor.device.try_borrow_mut() {
+            device.cancel_drain(self.qp_num);
+        }
+    }
+}
+
+/// Move the queue pair to the error state and wait until it is drained: all
+/// sends posted on it have completed, so that the completions held by their
+/// work requests are released, and its asynchronous events have been handled.
+///
+/// Destroying the queue pair blocks until its asynchronous events have been
+/// acknowledged, and entering the error state generates one as it uses the srq.
+async fn drain(reactor: &ReactorRef, qp: &QueuePair) -> Result<()> {
+    qp.set_error()?;
+    Drain {
+        reactor: reactor.clone(),
+        qp_num: qp.qp_num(),
+        last_wqe_timeout: sleep_until(std::time::Instant::now() + LAST_WQE_TIMEOUT),
+    }
+    .await
+}
+
+/// Drain and destroy the queue pair, unless it has been closed already
+async fn close_qp(reactor: &ReactorRef, qp: &mut QueuePair) -> Result<()> {
+    if qp.is_closed() {
+        return Ok(());
+    }
+    drain(reactor, qp).await?;
+    qp.destroy(&mut reactor.device.borrow_mut())?;
+    Ok(())
+}
+
 pub struct Connection {
+    reactor: ReactorRef,
     qp: QueuePair,
 }
 
 impl Connection {
+    /// Close the connection.
+    ///
+    /// Outstanding operations are flushed and their completions drained before
+    /// the underlying queue pair is destroyed, while the reactor goes on running
+    /// other tasks. If the connection is dropped without calling this method,
+    /// the same is done on a best effort basis by a task spawned for it.
+    pub async fn close(mut self) -> Result<()> {
+        close_qp(&self.reactor, &mut self.qp).await
+    }
+
+    /// Return false if the connection has failed, because the queue pair has
+    /// entered the error state, for instance because the remote end went away.
+    ///
+    /// This queries the state of the queue pair from the device. Receives on a
+    /// connection fail with `ErrorKind::ConnectionAborted` instead of waiting
+    /// forever, once a send on it has failed or the device has reported that
+    /// the queue pair entered the error state.
+    pub fn is_alive(&self) -> Result<bool> {
+        let state = self.qp.state()?;
+        Ok(state != ibv_qp_state::IBV_QPS_ERR)
+    }
+
+    /// Query the current state of the queue pair from the device, a closed
+    /// connection is reported to be in the error state
+    pub fn qp_state(&self) -> Result<QpState> {
+        Ok(QpState::from(self.qp.state()?))
+    }
+
+    /// Wait until the queue pair is ready to send, failing with
+    /// `ErrorKind::ConnectionAborted` if it enters the error state instead
+    ///
+    /// The state is polled every millisecond, wrap the call in `util::timeout`
+    /// to bound the wait. A connection returned by `ConnectionBuilder::connect`
+    /// is normally ready to send already, this helps diagnosing connections that
+    /// were set up but do not carry data, or that are reconnected.
+    pub async fn wait_until_rts(&self) -> Result<()> {
+        loop {
+            match self.qp_state()? {
+                QpState::ReadyToSend => return Ok(()),
+                QpState::Error => {
+                    return Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        "queue pair entered the error state",
+                    )))
+                }
+                _ => sleep(RTS_POLL_INTERVAL).await?,
+            }
+        }
+    }
+
+    /// Reconnect a failed connection to the given remote address, which may be
+    /// the address of a new queue pair on the remote host.
+    ///
+    /// The queue pair is reset and connected again, rather than recreated, so the
+    /// buffers and memory registrations of the device are reused. Sends still in
+    /// flight are flushed and their completions drained first, as by [`close`],
+    /// and messages that have been received but not read are discarded. The remote
+    /// host must connect its side to our [`local_address`], which is unchanged by
+    /// the reconnect.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// if !connection.is_alive()? {
+    ///     let remote = exchange_addresses(connection.local_address()).await?;
+    ///     connection.reconnect(&remote).await?;
+    /// }
+    /// ```
+    ///
+    /// [`close`]: #method.close
+    /// [`local_address`]: #method.local_address
+    pub async fn reconnect(&mut self, remote_address: &VerbsAddr) -> Result<()> {
+        if self.qp.is_closed() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "The queue pair has been closed",
+            )));
+        }
+        drain(&self.reactor, &self.qp).await?;
+        let mut device = self.reactor.device.borrow_mut();
+        self.qp.reset(&mut device)?;
+        self.qp.connect(&device, remote_address)?;
+        Ok(())
+    }
+
+    /// Return our local address, the address the remote host connects to
+    pub fn local_address(&self) -> VerbsAddr {
+        self.qp.local_address(&self.reactor.device.borrow())
+    }
+
     pub fn send(&self, buffer: Buffer) -> Send {
         Send {
             qp: &self.qp,
+            segments: Ok(vec![buffer.sge()]),
             buffer: Some(buffer),
+            _region: None,
+            imm: None,
             state: SendState::Initial,
         }
     }
 
+    /// Send the buffer together with 32 bits of immediate data (IBV_WR_SEND_WITH_IMM)
+    ///
+    /// The immediate data is delivered in the completion of the receive, not in the
+    /// payload, and is returned by [`Buffer::imm`] on the received buffer. This can tag
+    /// messages or signal the peer without a separate control message.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let buffer = agrippa::verbs::get_buffer().await?;
+    /// connection.send_with_imm(buffer, 42).await?;
+    /// ```
+    ///
+    /// [`Buffer::imm`]: struct.Buffer.html#method.imm
+    pub fn send_with_imm(&self, buffer: Buffer, imm: u32) -> Send {
+        let mut send = self.send(buffer);
+        send.imm = Some(imm);
+        send
+    }
+
+    /// Send the buffer, failing with `Error::Timeout` if the send has not
+    /// completed within `timeout`
+    ///
+    /// A send to a receiver that has no receive posted is retried by the device,
+    /// up to the retry count of the queue pair, after which the send fails and the
+    /// connection enters the error state. Depending on the timeout, this may happen
+    /// before or after the timeout expires. A posted send can not be withdrawn, so
+    /// on timeout the buffer is kept until the device completes the send, and is then
+    /// returned to the pool. The connection should usually be considered broken.
+    pub fn send_timeout(&self, buffer: Buffer, timeout: Duration) -> SendTimeout {
+        SendTimeout {
+            send: self.send(buffer),
+            sleep: sleep(timeout),
+        }
+    }
+
+    /// Send `len` bytes at `offset` in a region of application memory, registered
+    /// with `Device::register_memory`, without copying it.
+    ///
+    /// The region is borrowed until the send has completed. If the future is
+    /// dropped before, the memory may still be read by the device until the
+    /// region is dropped.
+    pub fn send_region<'a>(
+        &'a self,
+        region: &'a MemoryRegion,
+        offset: usize,
+        len: usize,
+    ) -> Send<'a> {
+        Send {
+            qp: &self.qp,
+            buffer: None,
+            segments: region
+                .sge(offset, len)
+                .map(|sge| vec![sge])
+                .ok_or("range is not within the memory region"),
+            _region: Some(region),
+            imm: None,
+            state: SendState::Initial,
+        }
+    }
+
+    /// Send one message gathered from `(buffer, offset, len)` segments, without
+    /// copying them into one buffer first
+    ///
+    /// The segments may lie in the same buffer or in different buffers, and
+    /// must be within the used part of their buffer. The number of segments is
+    /// limited by `ReactorConfig::verbs_send_sge`, and the total length by the
+    /// maximal message size of the port, otherwise the send fails with
+    /// `ErrorKind::InvalidInput`. The receiver gets the concatenation of the
+    /// segments as a single message.
+    ///
+    /// The buffers are borrowed until the send has completed, and remain owned
+    /// by the caller. If the future is dropped before, the memory may still be
+    /// read by the device until the buffers are dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let header = connection.recv().await?;
+    /// let body = connection.recv().await?;
+    /// connection
+    ///     .send_gather(&[(&header, 0, 16), (&body, 0, body_len)])
+    ///     .await?;
+    /// ```
+    pub fn send_gather<'a>(&'a self, segments: &[(&'a Buffer, usize, usize)]) -> Send<'a> {
+        Send {
+            qp: &self.qp,
+            buffer: None,
+            segments: segments
+                .iter()
+                .map(|&(buffer, offset, len)| buffer.segment(offset, len))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("range is not within the used part of the buffer"),
+            _region: None,
+            imm: None,
+            state: SendState::Initial,
+        }
+    }
+
+    /// Receive the next message on the connection
+    ///
+    /// If the reactor is configured to receive into more than one buffer
+    /// per message, only the first buffer is returned, use [`recv_scatter`]
+    /// to receive all of them.
+    ///
+    /// [`recv_scatter`]: #method.recv_scatter
     pub fn recv(&self) -> Recv {
-        Recv { qp: &self.qp }
+        Recv {
+            qp: &self.qp,
+            waiting: None,
+        }
+    }
+
+    /// Receive all messages that have arrived on the connection, up to `max`,
+    /// waiting for the first one if none has arrived yet
+    ///
+    /// As with [`recv`], only the first buffer of each message is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// for buffer in connection.recv_batch(32).await? {
+    ///     handle(&buffer);
+    ///     agrippa::verbs::put_buffer(buffer).await?;
+    /// }
+    /// ```
+    ///
+    /// [`recv`]: #method.recv
+    pub fn recv_batch(&self, max: usize) -> RecvBatch {
+        RecvBatch {
+            qp: &self.qp,
+            max: std::cmp::max(max, 1),
+            waiting: None,
+        }
+    }
+
+    /// Receive the next message on the connection, scattered over the
+    /// configured number of buffers. Buffers the message did not reach
+    /// are returned empty.
+    pub fn recv_scatter(&self) -> RecvScatter {
+        RecvScatter {
+            qp: &self.qp,
+            waiting: None,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.qp.is_closed() {
+            return;
+        }
+        // Draining waits for the device, so it is left to a task rather than
+        // blocking the drop
+        let mut qp = self.qp.take();
+        let reactor = self.reactor.clone();
+        self.reactor
+            .spawn_named("close_verbs_connection", Priority::Low, async move {
+                if let Err(e) = close_qp(&reactor, &mut qp).await {
+                    info!("Failed to close verbs connection: {}", e);
+                }
+                Ok(())
+            });
     }
 }
 
@@ -105,7 +705,10 @@ impl ConnectionBuilder {
     pub fn connect(self, remote_address: &VerbsAddr) -> Result<Connection> {
         let mut qp = self.qp;
         qp.connect(&mut self.reactor.device.borrow_mut(), remote_address)?;
-        Ok(Connection { qp })
+        Ok(Connection {
+            reactor: self.reactor,
+            qp,
+        })
     }
 
     /**
@@ -115,13 +718,22 @@ impl ConnectionBuilder {
     pub fn local_address(&self) -> VerbsAddr {
         self.qp.local_address(&self.reactor.device.borrow())
     }
+
+    /// Query the current state of the queue pair from the device, which is
+    /// `QpState::Init` until `connect` is called
+    pub fn qp_state(&self) -> Result<QpState> {
+        Ok(QpState::from(self.qp.state()?))
+    }
 }
 
 pub struct Connect {}
 impl Future for Connect {
     type Output = Result<ConnectionBuilder>;
-    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
-        let mut task = waker_task(context.waker().clone());
+    fn poll(mut self: Pin<&mut Self>, _context: &mut std::task::Context) -> Poll<Self::Output> {
+        let mut task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
         let reactor = task.as_ref().reactor.clone();
         let ans = QueuePair::new(&mut reactor.device.borrow_mut());
         match ans {
@@ -141,8 +753,11 @@ pub fn connect() -> Connect {
 pub struct GetBuffer {}
 impl Future for GetBuffer {
     type Output = Result<Buffer>;
-    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
-        let mut task = waker_task(context.waker().clone());
+    fn poll(mut self: Pin<&mut Self>, _context: &mut std::task::Context) -> Poll<Self::Output> {
+        let mut task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
         let t2 = task.clone();
         match task.as_ref().reactor.get_verbs_buffer() {
             Some(b) => Poll::Ready(Ok(b)),
@@ -163,8 +778,11 @@ pub struct PutBuffer {
 }
 impl Future for PutBuffer {
     type Output = Result<()>;
-    fn poll(mut self: Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Self::Output> {
-        let mut task = waker_task(context.waker().clone());
+    fn poll(mut self: Pin<&mut Self>, _context: &mut std::task::Context) -> Poll<Self::Output> {
+        let mut task = match current_task() {
+            Ok(task) => task,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
         task.as_ref()
             .reactor
             .put_verbs_buffer(self.buffer.take().unwrap());
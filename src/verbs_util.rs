@@ -1,35 +1,40 @@
 use crate::sys::{
-    _compat_ibv_port_attr, ib_uverbs_comp_event_desc, ibv_access_flags, ibv_ack_cq_events,
-    ibv_alloc_pd, ibv_close_device, ibv_comp_channel, ibv_context, ibv_cq, ibv_create_comp_channel,
-    ibv_create_cq, ibv_create_qp, ibv_create_srq, ibv_dealloc_pd, ibv_dereg_mr,
-    ibv_destroy_comp_channel, ibv_destroy_cq, ibv_destroy_qp, ibv_destroy_srq, ibv_device,
-    ibv_free_device_list, ibv_get_cq_event, ibv_get_device_list, ibv_get_device_name,
-    ibv_modify_qp, ibv_mr, ibv_mtu_IBV_MTU_1024, ibv_open_device, ibv_pd, ibv_port_attr, ibv_qp,
-    ibv_qp_attr,
+    _compat_ibv_port_attr, ib_uverbs_comp_event_desc, ibv_access_flags, ibv_ack_async_event,
+    ibv_ack_cq_events, ibv_alloc_pd, ibv_async_event, ibv_close_device, ibv_comp_channel,
+    ibv_context, ibv_cq, ibv_create_comp_channel, ibv_create_cq, ibv_create_qp, ibv_create_srq,
+    ibv_dealloc_pd, ibv_dereg_mr, ibv_destroy_comp_channel, ibv_destroy_cq, ibv_destroy_qp,
+    ibv_destroy_srq, ibv_device, ibv_device_attr, ibv_event_type_IBV_EVENT_DEVICE_FATAL,
+    ibv_event_type_IBV_EVENT_QP_ACCESS_ERR, ibv_event_type_IBV_EVENT_QP_FATAL,
+    ibv_event_type_IBV_EVENT_QP_LAST_WQE_REACHED, ibv_event_type_IBV_EVENT_QP_REQ_ERR,
+    ibv_free_device_list, ibv_get_async_event, ibv_get_cq_event, ibv_get_device_list,
+    ibv_get_device_name, ibv_modify_qp, ibv_mr, ibv_mtu_IBV_MTU_1024, ibv_open_device, ibv_pd,
+    ibv_port_attr, ibv_qp, ibv_qp_attr,
     ibv_qp_attr_mask::{
         IBV_QP_ACCESS_FLAGS, IBV_QP_AV, IBV_QP_CAP, IBV_QP_DEST_QPN, IBV_QP_MAX_DEST_RD_ATOMIC,
         IBV_QP_MAX_QP_RD_ATOMIC, IBV_QP_MIN_RNR_TIMER, IBV_QP_PATH_MTU, IBV_QP_PKEY_INDEX,
         IBV_QP_PORT, IBV_QP_RETRY_CNT, IBV_QP_RNR_RETRY, IBV_QP_RQ_PSN, IBV_QP_SQ_PSN,
         IBV_QP_STATE, IBV_QP_TIMEOUT,
     },
-    ibv_qp_init_attr, ibv_qp_state, ibv_qp_type, ibv_query_port, ibv_query_qp, ibv_recv_wr,
-    ibv_reg_mr,
+    ibv_qp_init_attr, ibv_qp_state, ibv_qp_type, ibv_query_device, ibv_query_port, ibv_query_qp,
+    ibv_recv_wr, ibv_reg_mr,
     ibv_send_flags::IBV_SEND_SIGNALED,
-    ibv_send_wr, ibv_sge, ibv_srq, ibv_srq_init_attr, ibv_wc,
+    ibv_send_wr, ibv_sge, ibv_srq, ibv_srq_init_attr, ibv_wc, ibv_wc_flags_IBV_WC_WITH_IMM,
     ibv_wc_opcode::{IBV_WC_RECV, IBV_WC_SEND},
-    ibv_wr_opcode::IBV_WR_SEND,
+    ibv_wc_status_IBV_WC_SUCCESS,
+    ibv_wr_opcode::{IBV_WR_SEND, IBV_WR_SEND_WITH_IMM},
     IBV_LINK_LAYER_ETHERNET,
 };
 
 use crate::io_uring_util::{Fd, Read};
-use crate::runtime::{Task, TaskRef};
+use crate::runtime::{Completion, ReactorConfig};
+use crate::util::Pod;
 use libc;
 use libc::c_int;
-use log::info;
-use std::cell::RefCell;
+use log::{info, warn};
 use std::ffi::{c_void, CStr};
 use std::ptr::null_mut;
 use std::rc::Rc;
+use std::task::Waker;
 
 unsafe fn ibv_req_notify_cq(cq: *mut ibv_cq, solicited_only: c_int) -> c_int {
     let fp = (*(*cq).context).ops.req_notify_cq.unwrap();
@@ -98,6 +103,8 @@ pub struct Buffer {
     used: usize,
     capacity: usize,
     mr: *mut ibv_mr,
+    /// Immediate data of the received message the buffer holds the start of
+    imm: Option<u32>,
 }
 
 impl Drop for Buffer {
@@ -119,6 +126,31 @@ impl Drop for Buffer {
 }
 
 impl Buffer {
+    /// Address, length and lkey of the used part of the buffer
+    pub(super) fn sge(&self) -> (u64, u32, u32) {
+        unsafe { (self.buf as u64, self.used as u32, (*self.mr).lkey) } //TODO add offset
+    }
+
+    /// Address, length and lkey of `len` bytes at `offset` in the used part
+    /// of the buffer, None if the range is not within it
+    pub(super) fn segment(&self, offset: usize, len: usize) -> Option<(u64, u32, u32)> {
+        if offset.checked_add(len)? > self.used {
+            return None;
+        }
+        Some((self.buf as u64 + offset as u64, len as u32, unsafe {
+            (*self.mr).lkey
+        }))
+    }
+
+    /// The immediate data the received message was sent with, see
+    /// [`Connection::send_with_imm`]. None if the message carried no immediate data,
+    /// and for buffers that do not hold the start of a received message.
+    ///
+    /// [`Connection::send_with_imm`]: ../verbs/struct.Connection.html#method.send_with_imm
+    pub fn imm(&self) -> Option<u32> {
+        self.imm
+    }
+
     pub(super) fn new(d: &Device) -> std::io::Result<Buffer> {
         unsafe {
             let mut r = Buffer {
@@ -127,6 +159,7 @@ impl Buffer {
                 used: 0,
                 capacity: d.size,
                 mr: null_mut(),
+                imm: None,
             };
             r.buf = libc::memalign(4 * 1024, r.capacity);
             if r.buf.is_null() {
@@ -175,14 +208,19 @@ pub struct VerbsAddr {
     lid: u16,
 }
 
+// VerbsAddr is packed so it has no padding, and all its fields are integers
+unsafe impl Pod for VerbsAddr {}
+
 pub(super) struct QueuePair {
     qp: *mut ibv_qp,
     psn: u32,
-    received: RefCell<std::collections::VecDeque<Buffer>>,
-    waiting: RefCell<Option<TaskRef>>,
 }
 
 impl QueuePair {
+    pub(super) fn qp_num(&self) -> u32 {
+        unsafe { (*self.qp).qp_num }
+    }
+
     pub(super) fn local_address(&self, device: &Device) -> VerbsAddr {
         VerbsAddr {
             qpn: unsafe { (*self.qp).qp_num },
@@ -192,44 +230,118 @@ impl QueuePair {
         }
     }
 
-    pub(super) fn read(&self) -> Option<Buffer> {
-        self.received.borrow_mut().pop_front()
-    }
-
-    pub(super) fn wait(&self, task: TaskRef) -> std::io::Result<()> {
-        let mut w = self.waiting.borrow_mut();
-        if w.is_some() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Two concurent reads are not supported",
-            ));
-        }
-        *w = Some(task);
-        Ok(())
-    }
-
-    pub(super) unsafe fn send(&self, task: TaskRef, buffer: &Buffer) -> std::io::Result<()> {
-        let mut list: ibv_sge = std::mem::zeroed();
-        list.addr = buffer.buf as u64; //TODO add offset
-        list.length = buffer.used as u32;
-        list.lkey = (*buffer.mr).lkey;
+    /// Post a send of the memory given by the address, length and lkey of each
+    /// segment, gathered into one message, with the immediate data `imm` if given,
+    /// the result is delivered in `completion`.
+    /// Returns the wr_id of the posted work request.
+    pub(super) unsafe fn send(
+        &self,
+        device: &mut Device,
+        completion: Rc<Completion>,
+        segments: &[(u64, u32, u32)],
+        imm: Option<u32>,
+    ) -> std::io::Result<u64> {
+        let mut list: Vec<ibv_sge> = segments
+            .iter()
+            .map(|&(addr, length, lkey)| {
+                let mut sge: ibv_sge = std::mem::zeroed();
+                sge.addr = addr;
+                sge.length = length;
+                sge.lkey = lkey;
+                sge
+            })
+            .collect();
 
         let mut wr: ibv_send_wr = std::mem::zeroed();
-        wr.wr_id = Rc::into_raw(task) as usize as u64;
-        wr.sg_list = &mut list;
-        wr.num_sge = 1;
-        wr.opcode = IBV_WR_SEND;
+        wr.wr_id = Rc::into_raw(completion) as usize as u64;
+        wr.sg_list = list.as_mut_ptr();
+        wr.num_sge = list.len() as i32;
+        match imm {
+            Some(imm) => {
+                wr.opcode = IBV_WR_SEND_WITH_IMM;
+                // The immediate data is sent in network byte order
+                wr.__bindgen_anon_1.imm_data = imm.to_be();
+            }
+            None => wr.opcode = IBV_WR_SEND,
+        }
         wr.send_flags = IBV_SEND_SIGNALED;
 
         let mut bad_wr: *mut ibv_send_wr = std::ptr::null_mut();
 
         info!("Sending buffer");
         if ibv_post_send(self.qp, &mut wr, &mut bad_wr) != 0 {
-            Rc::from_raw(wr.wr_id as *mut Task);
+            // Reclaim the reference leaked into wr_id above
+            Rc::from_raw(wr.wr_id as usize as *const Completion);
             Err(std::io::Error::last_os_error())
         } else {
-            Ok(())
+            *device.sends_in_flight.entry((*self.qp).qp_num).or_insert(0) += 1;
+            device.total_sends_in_flight += 1;
+            Ok(wr.wr_id)
+        }
+    }
+
+    /// Query the current state of the queue pair, a closed queue pair is reported
+    /// to be in the error state
+    pub(super) fn state(&self) -> std::io::Result<ibv_qp_state::Type> {
+        if self.qp.is_null() {
+            return Ok(ibv_qp_state::IBV_QPS_ERR);
+        }
+        unsafe {
+            let mut attr: ibv_qp_attr = std::mem::zeroed();
+            let mut init_attr: ibv_qp_init_attr = std::mem::zeroed();
+            if ibv_query_qp(self.qp, &mut attr, IBV_QP_STATE as i32, &mut init_attr) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(attr.qp_state)
+        }
+    }
+
+    /// Return true once the queue pair has been destroyed
+    pub(super) fn is_closed(&self) -> bool {
+        self.qp.is_null()
+    }
+
+    /// Move the ownership of the queue pair to the returned value, leaving
+    /// this one closed
+    pub(super) fn take(&mut self) -> QueuePair {
+        QueuePair {
+            qp: std::mem::replace(&mut self.qp, null_mut()),
+            psn: self.psn,
+        }
+    }
+
+    /// Move the queue pair to the error state, this flushes all outstanding
+    /// work requests to the cq with an error status
+    pub(super) fn set_error(&self) -> std::io::Result<()> {
+        unsafe {
+            let mut attr: ibv_qp_attr = std::mem::zeroed();
+            attr.qp_state = ibv_qp_state::IBV_QPS_ERR;
+            if ibv_modify_qp(self.qp, &mut attr, IBV_QP_STATE as i32) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Destroy the queue pair.
+    ///
+    /// The queue pair must have been moved to the error state and drained first,
+    /// so that the completions held by the work requests of its sends have been
+    /// released, and its asynchronous events acknowledged, see `verbs::drain`.
+    pub(super) fn destroy(&mut self, device: &mut Device) -> std::io::Result<()> {
+        if self.qp.is_null() {
+            return Ok(());
+        }
+        let qp_num = unsafe { (*self.qp).qp_num };
+        device.discard_received(qp_num);
+        device.queue_pairs.remove(&qp_num);
+        unsafe {
+            if ibv_destroy_qp(self.qp) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
         }
+        self.qp = null_mut();
+        Ok(())
     }
 
     pub(super) fn connect(&mut self, device: &Device, remote: &VerbsAddr) -> std::io::Result<()> {
@@ -292,22 +404,22 @@ impl QueuePair {
         Ok(())
     }
 
-    pub fn new(c: &Device) -> std::io::Result<Self> {
+    /// Create a queue pair sharing the cq and srq of the device, and register it
+    /// with the device so that its completions are routed to it
+    pub fn new(c: &mut Device) -> std::io::Result<Self> {
         unsafe {
             let mut r = QueuePair {
                 qp: std::ptr::null_mut(),
                 psn: rand::random::<u32>() & 0xFFFFFF,
-                received: RefCell::new(std::collections::VecDeque::new()),
-                waiting: RefCell::new(None),
             };
 
             let mut init_attr: ibv_qp_init_attr = std::mem::zeroed();
             init_attr.send_cq = c.cq;
             init_attr.recv_cq = c.cq;
             init_attr.srq = c.srq;
-            init_attr.cap.max_send_wr = 1;
+            init_attr.cap.max_send_wr = c.max_send_wr;
             init_attr.cap.max_recv_wr = c.rx_depth;
-            init_attr.cap.max_send_sge = 1;
+            init_attr.cap.max_send_sge = c.send_sge;
             init_attr.cap.max_recv_sge = 1;
             init_attr.qp_type = ibv_qp_type::IBV_QPT_RC;
 
@@ -316,23 +428,49 @@ impl QueuePair {
                 return Err(std::io::Error::last_os_error());
             }
 
+            r.init(c)?;
+            c.queue_pairs.insert((*r.qp).qp_num);
+            Ok(r)
+        }
+    }
+
+    /// Move the queue pair from the reset state to the init state
+    fn init(&self, device: &Device) -> std::io::Result<()> {
+        unsafe {
             let mut attr: ibv_qp_attr = std::mem::zeroed();
             attr.qp_state = ibv_qp_state::IBV_QPS_INIT;
             attr.pkey_index = 0;
-            attr.port_num = c.ib_port;
+            attr.port_num = device.ib_port;
             attr.qp_access_flags = 0;
 
             if ibv_modify_qp(
-                r.qp,
+                self.qp,
                 &mut attr,
                 (IBV_QP_STATE | IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_ACCESS_FLAGS) as i32,
             ) != 0
             {
                 return Err(std::io::Error::last_os_error());
             }
+        }
+        Ok(())
+    }
 
-            Ok(r)
+    /// Reset the queue pair to the init state, so that it can be connected again.
+    ///
+    /// As when destroying it, the queue pair must have been moved to the error
+    /// state and drained first, see `verbs::drain`. Unread messages are discarded
+    /// before the queue pair is reset.
+    pub(super) fn reset(&mut self, device: &mut Device) -> std::io::Result<()> {
+        let qp_num = self.qp_num();
+        device.discard_received(qp_num);
+        unsafe {
+            let mut attr: ibv_qp_attr = std::mem::zeroed();
+            attr.qp_state = ibv_qp_state::IBV_QPS_RESET;
+            if ibv_modify_qp(self.qp, &mut attr, IBV_QP_STATE as i32) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
         }
+        self.init(device)
     }
 }
 
@@ -348,8 +486,63 @@ impl Drop for QueuePair {
     }
 }
 
+/// Memory owned by the application, that is registered with the device so
+/// that it can be sent from without copying it into a `Buffer`
+///
+/// The registration is removed when the region is dropped.
+pub struct MemoryRegion {
+    mr: *mut ibv_mr,
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl MemoryRegion {
+    /// Start of the registered memory
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Length of the registered memory
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return true if the registered memory is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Address, length and lkey of `len` bytes at `offset` in the region,
+    /// None if the range is not within the region
+    pub(super) fn sge(&self, offset: usize, len: usize) -> Option<(u64, u32, u32)> {
+        if offset.checked_add(len)? > self.len || len > u32::max_value() as usize {
+            return None;
+        }
+        Some((self.ptr as u64 + offset as u64, len as u32, unsafe {
+            (*self.mr).lkey
+        }))
+    }
+}
+
+impl Drop for MemoryRegion {
+    fn drop(&mut self) {
+        unsafe {
+            if ibv_dereg_mr(self.mr) != 0 {
+                info!(
+                    "Failed to deregister memory region: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+/// Extra completion queue entries beyond the receive depth and send window
+const CQ_SLACK: u32 = 16;
+
 pub struct Device {
     rx_depth: u32,
+    max_send_wr: u32,
     size: usize,
     device_list: *mut *mut ibv_device,
     context: *mut ibv_context,
@@ -363,15 +556,83 @@ pub struct Device {
 
     notify_enabled: bool,
     events_pending: usize,
-
-    read_slot: Vec<Option<Buffer>>,
+    comp_event: ib_uverbs_comp_event_desc,
+    /// Number of sends posted but not yet completed for each queue pair number
+    sends_in_flight: std::collections::HashMap<u32, usize>,
+    /// Number of sends posted but not yet completed over all queue pairs
+    total_sends_in_flight: usize,
+    /// Maximal number of sends in flight over all queue pairs, the entries of
+    /// the shared cq that are not needed for the completions of the receives
+    send_budget: usize,
+    /// Tasks waiting for a send to complete on a queue pair, so they can post theirs
+    send_waiters: std::collections::HashMap<u32, std::collections::VecDeque<Waker>>,
+    /// Buffers of sends whose future was dropped before the send completed,
+    /// by wr_id. They are returned to the pool when the send completes
+    orphaned_sends: std::collections::HashMap<u64, Buffer>,
+
+    /// Number of buffers posted for each receive
+    recv_sge: u32,
+    /// Maximal number of segments gathered by a send
+    send_sge: u32,
+    device_attr: ibv_device_attr,
+    /// The buffers posted for each receive slot
+    read_slot: Vec<Vec<Buffer>>,
+    /// Received messages that have not been read yet, for each queue pair number
+    received: std::collections::HashMap<u32, std::collections::VecDeque<Vec<Buffer>>>,
+    /// Numbers of the live queue pairs created on the device. All queue pairs
+    /// share the cq and srq, so completions are routed by the qp_num of the work
+    /// completion, to `received` for receives
+    queue_pairs: std::collections::HashSet<u32>,
+    /// Task waiting for a message to be received on a queue pair
+    recv_waiters: std::collections::HashMap<u32, Waker>,
+    /// Queue pairs on which a work request has completed with an error, or
+    /// reported by an asynchronous event to have entered the error state
+    failed: std::collections::HashSet<u32>,
+    /// Queue pairs for which `IBV_EVENT_QP_LAST_WQE_REACHED` has been handled
+    last_wqe_reached: std::collections::HashSet<u32>,
+    /// Task draining a queue pair, woken when a send on it completes and when
+    /// `IBV_EVENT_QP_LAST_WQE_REACHED` is handled for it
+    drain_waiters: std::collections::HashMap<u32, Waker>,
     empty_read_slots: Vec<usize>,
     pub(super) free_buffers: Vec<Buffer>,
+    /// Number of successful receive and send completions processed
+    recv_completions: u64,
+    send_completions: u64,
+    /// Number of work requests completed with an error
+    failed_completions: u64,
+}
+
+/// Snapshot of the state of the verbs device, see [`Device::stats`]
+///
+/// [`Device::stats`]: struct.Device.html#method.stats
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VerbsStats {
+    /// Number of receive work requests currently posted to the srq
+    pub recvs_posted: usize,
+    /// Number of buffers available for receives and sends. When this stays
+    /// below the number of buffers per receive, receives can not be reposted
+    pub free_buffers: usize,
+    /// Number of sends posted but not yet completed, over all queue pairs
+    pub sends_in_flight: usize,
+    /// Number of messages received but not read yet, over all queue pairs
+    pub unread_messages: usize,
+    /// Number of successful receive completions processed
+    pub recv_completions: u64,
+    /// Number of successful send completions processed
+    pub send_completions: u64,
+    /// Number of work requests completed with an error
+    pub failed_completions: u64,
+    /// Number of live queue pairs in the error state
+    pub failed_queue_pairs: usize,
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
         unsafe {
+            if self.events_pending != 0 && !self.cq.is_null() {
+                ibv_ack_cq_events(self.cq, self.events_pending as u32);
+                self.events_pending = 0;
+            }
             if !self.srq.is_null() {
                 ibv_destroy_srq(self.srq);
                 self.srq = null_mut();
@@ -401,6 +662,27 @@ impl Drop for Device {
 }
 
 impl Device {
+    /// Register `len` bytes of application memory at `ptr` with the device, with
+    /// the given `ibv_access_flags`, so that it can be sent from with
+    /// `Connection::send_region`
+    ///
+    /// # Safety
+    ///
+    /// The memory must stay valid until the returned region has been dropped,
+    /// and must not be modified while a send from it is in flight.
+    pub unsafe fn register_memory(
+        &self,
+        ptr: *mut u8,
+        len: usize,
+        access: ibv_access_flags::Type,
+    ) -> std::io::Result<MemoryRegion> {
+        let mr = ibv_reg_mr(self.pd, ptr as *mut c_void, len as u64, access as i32);
+        if mr.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(MemoryRegion { mr, ptr, len })
+    }
+
     /*pub fn addr(&self) -> VerbsAddr {
         VerbsAddr {
             qpn: self.qpn,
@@ -473,26 +755,36 @@ impl Device {
         }
     }*/
 
+    /// Post receive buffers, arm the completion notification and drain the completion queue.
+    ///
+    /// Returns true if the notification was (re)armed. After this call the cq is
+    /// always armed, so the caller must make sure that a read of the completion
+    /// channel fd is in flight before blocking, see `handle_comp_event`.
     pub fn process(&mut self) -> std::io::Result<bool> {
         let mut add_notify_read = false;
 
         unsafe {
             info!("PROCESS");
-            while !self.empty_read_slots.is_empty() && !self.free_buffers.is_empty() {
+            while !self.empty_read_slots.is_empty()
+                && self.free_buffers.len() >= self.recv_sge as usize
+            {
                 let slot = self.empty_read_slots.pop().unwrap();
-                let buf = self.free_buffers.pop().unwrap();
 
-                let mut list: ibv_sge = std::mem::zeroed();
-                list.addr = buf.buf as u64;
-                list.length = buf.capacity as u32;
-                list.lkey = (*buf.mr).lkey;
+                let mut list: Vec<ibv_sge> = Vec::with_capacity(self.recv_sge as usize);
+                for _ in 0..self.recv_sge {
+                    let buf = self.free_buffers.pop().unwrap();
+                    let mut sge: ibv_sge = std::mem::zeroed();
+                    sge.addr = buf.buf as u64;
+                    sge.length = buf.capacity as u32;
+                    sge.lkey = (*buf.mr).lkey;
+                    list.push(sge);
+                    self.read_slot[slot].push(buf);
+                }
 
                 let mut wr: ibv_recv_wr = std::mem::zeroed();
                 wr.wr_id = slot as u64;
-                wr.sg_list = &mut list;
-                wr.num_sge = 1;
-
-                self.read_slot[slot] = Some(buf);
+                wr.sg_list = list.as_mut_ptr();
+                wr.num_sge = list.len() as i32;
 
                 let mut bad_wr: *mut ibv_recv_wr = std::ptr::null_mut();
                 if ibv_post_srq_recv(self.srq, &mut wr, &mut bad_wr) != 0 {
@@ -520,14 +812,32 @@ impl Device {
                     break;
                 }
                 for w in &wc[0..ne as usize] {
+                    // The opcode is only valid for successful completions, for failed ones
+                    // we tell receives from sends by the wr_id, which is a slot for receives
+                    // and a completion pointer for sends
+                    if w.status != ibv_wc_status_IBV_WC_SUCCESS {
+                        self.failed_completions += 1;
+                        if (w.wr_id as usize) < self.read_slot.len() {
+                            info!("Receive failed {}", w.status);
+                            let slot = w.wr_id as usize;
+                            self.free_buffers.append(&mut self.read_slot[slot]);
+                            self.empty_read_slots.push(slot);
+                        } else {
+                            info!("Send failed {}", w.status);
+                            self.fail_qp(w.qp_num);
+                            self.complete_send(w);
+                        }
+                        continue;
+                    }
                     match w.opcode {
-                        IBV_WC_RECV => {}
+                        IBV_WC_RECV => {
+                            self.recv_completions += 1;
+                            self.complete_recv(w);
+                        }
                         IBV_WC_SEND => {
                             info!("Send finished {}", w.wr_id);
-
-                            let task = TaskRef::from_raw(w.wr_id as *const Task);
-                            task.as_ref().ring_result.set(w.status as i32);
-                            //self.ready.borrow_mut().push(task);
+                            self.send_completions += 1;
+                            self.complete_send(w);
                         }
                         other => info!("Unhandled verbs opcode {}", other),
                     }
@@ -539,6 +849,323 @@ impl Device {
         }
     }
 
+    /// Return a snapshot of the receives posted, the buffers available and
+    /// the completions processed, for diagnosing buffer starvation
+    pub fn stats(&self) -> VerbsStats {
+        VerbsStats {
+            recvs_posted: self.read_slot.len() - self.empty_read_slots.len(),
+            free_buffers: self.free_buffers.len(),
+            sends_in_flight: self.total_sends_in_flight,
+            unread_messages: self.received.values().map(|v| v.len()).sum(),
+            recv_completions: self.recv_completions,
+            send_completions: self.send_completions,
+            failed_completions: self.failed_completions,
+            failed_queue_pairs: self.failed.len(),
+        }
+    }
+
+    fn complete_recv(&mut self, w: &ibv_wc) {
+        let slot = w.wr_id as usize;
+        let mut buffers = std::mem::replace(&mut self.read_slot[slot], Vec::new());
+        self.empty_read_slots.push(slot);
+
+        // The message is scattered over the buffers in the order they were posted
+        let mut remaining = w.byte_len as usize;
+        for buffer in &mut buffers {
+            buffer.offset = 0;
+            buffer.used = std::cmp::min(remaining, buffer.capacity);
+            buffer.imm = None;
+            remaining -= buffer.used;
+        }
+        if w.wc_flags & ibv_wc_flags_IBV_WC_WITH_IMM != 0 {
+            if let Some(first) = buffers.first_mut() {
+                first.imm = Some(u32::from_be(unsafe { w.__bindgen_anon_1.imm_data }));
+            }
+        }
+
+        if !self.queue_pairs.contains(&w.qp_num) {
+            // The queue pair has been closed, nobody will read the message
+            info!("Dropping message for unknown queue pair {}", w.qp_num);
+            self.free_buffers.append(&mut buffers);
+            return;
+        }
+
+        self.received
+            .entry(w.qp_num)
+            .or_insert_with(std::collections::VecDeque::new)
+            .push_back(buffers);
+        if let Some(waker) = self.recv_waiters.remove(&w.qp_num) {
+            waker.wake();
+        }
+    }
+
+    /// Return the buffers of the next message received on the given queue pair
+    pub(super) fn take_received(&mut self, qp_num: u32) -> Option<Vec<Buffer>> {
+        let received = self.received.get_mut(&qp_num)?;
+        let buffers = received.pop_front();
+        if received.is_empty() {
+            self.received.remove(&qp_num);
+        }
+        buffers
+    }
+
+    /// Wake the given waker when a message is received on the given queue pair
+    pub(super) fn wait_recv(&mut self, qp_num: u32, waker: Waker) -> std::io::Result<()> {
+        if self.recv_waiters.contains_key(&qp_num) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Two concurent reads are not supported",
+            ));
+        }
+        self.recv_waiters.insert(qp_num, waker);
+        Ok(())
+    }
+
+    /// Mark the queue pair as failed, and wake the task waiting to receive on it
+    fn fail_qp(&mut self, qp_num: u32) {
+        self.failed.insert(qp_num);
+        if let Some(waker) = self.recv_waiters.remove(&qp_num) {
+            waker.wake();
+        }
+    }
+
+    /// Return true if a work request on the given queue pair has failed
+    pub(super) fn is_failed(&self, qp_num: u32) -> bool {
+        self.failed.contains(&qp_num)
+    }
+
+    /// Return the buffers of all unread messages on the given queue pair to the pool
+    pub(super) fn discard_received(&mut self, qp_num: u32) {
+        if let Some(received) = self.received.remove(&qp_num) {
+            for mut buffers in received {
+                self.free_buffers.append(&mut buffers);
+            }
+        }
+        self.recv_waiters.remove(&qp_num);
+        // The number may be reused by a later queue pair
+        self.failed.remove(&qp_num);
+    }
+
+    /// Stop waiting for a message on the given queue pair
+    pub(super) fn cancel_recv(&mut self, qp_num: u32) {
+        self.recv_waiters.remove(&qp_num);
+    }
+
+    unsafe fn complete_send(&mut self, w: &ibv_wc) {
+        if let Some(cnt) = self.sends_in_flight.get_mut(&w.qp_num) {
+            *cnt -= 1;
+            if *cnt == 0 {
+                self.sends_in_flight.remove(&w.qp_num);
+            }
+        }
+        let budget_was_full = self.total_sends_in_flight >= self.send_budget;
+        self.total_sends_in_flight = self.total_sends_in_flight.saturating_sub(1);
+        if budget_was_full {
+            // Senders on other queue pairs may be waiting for room in the cq,
+            // wake the first of each, they check their credit again
+            for waiters in self.send_waiters.values_mut() {
+                if let Some(waker) = waiters.pop_front() {
+                    waker.wake();
+                }
+            }
+            self.send_waiters.retain(|_, waiters| !waiters.is_empty());
+        } else if let Some(waiters) = self.send_waiters.get_mut(&w.qp_num) {
+            // A slot in the send queue was freed, let the next waiting sender have it
+            if let Some(waker) = waiters.pop_front() {
+                waker.wake();
+            }
+            if waiters.is_empty() {
+                self.send_waiters.remove(&w.qp_num);
+            }
+        }
+        if let Some(waker) = self.drain_waiters.remove(&w.qp_num) {
+            waker.wake();
+        }
+        let completion = Rc::from_raw(w.wr_id as usize as *const Completion);
+        completion.result.set(Some(w.status as i32));
+        if let Some(buffer) = self.orphaned_sends.remove(&w.wr_id) {
+            self.free_buffers.push(buffer);
+        }
+        completion.wake();
+    }
+
+    /// Number of sends posted on the given queue pair that have not yet completed
+    pub(super) fn sends_in_flight(&self, qp_num: u32) -> usize {
+        self.sends_in_flight.get(&qp_num).cloned().unwrap_or(0)
+    }
+
+    /// Check that a send gathering `segments` can be posted, returning
+    /// a description of the problem if not
+    pub(super) fn check_gather(&self, segments: &[(u64, u32, u32)]) -> Option<&'static str> {
+        if segments.is_empty() {
+            return Some("no segments to send");
+        }
+        if segments.len() > self.send_sge as usize {
+            return Some("more segments than the send sges of the device");
+        }
+        let total: u64 = segments.iter().map(|&(_, len, _)| len as u64).sum();
+        if total > self.port_info.max_msg_sz as u64 {
+            return Some("message larger than the maximal message size of the port");
+        }
+        None
+    }
+
+    /// Return true if another send can be posted on the given queue pair, without
+    /// exceeding the send queue of the queue pair or the room for send completions
+    /// in the shared cq
+    pub(super) fn send_credit_available(&self, qp_num: u32) -> bool {
+        self.sends_in_flight(qp_num) < self.max_send_wr as usize
+            && self.total_sends_in_flight < self.send_budget
+    }
+
+    /// Wake the given waker when a send completes on the given queue pair
+    pub(super) fn wait_send_credit(&mut self, qp_num: u32, waker: Waker) {
+        self.send_waiters
+            .entry(qp_num)
+            .or_insert_with(std::collections::VecDeque::new)
+            .push_back(waker);
+    }
+
+    /// Remove a waker registered with `wait_send_credit`
+    pub(super) fn cancel_send_credit(&mut self, qp_num: u32, waker: &Waker) {
+        if let Some(waiters) = self.send_waiters.get_mut(&qp_num) {
+            if let Some(i) = waiters.iter().position(|w| w.will_wake(waker)) {
+                waiters.remove(i);
+            }
+            if waiters.is_empty() {
+                self.send_waiters.remove(&qp_num);
+            }
+        }
+    }
+
+    /// Keep the buffer of a send that is still in flight, until the send completes
+    pub(super) fn orphan_send(&mut self, wr_id: u64, buffer: Buffer) {
+        self.orphaned_sends.insert(wr_id, buffer);
+    }
+
+    /// The fd of the completion channel, readable when the cq has been notified
+    pub(super) fn channel_fd(&self) -> i32 {
+        unsafe { (*self.channel).fd }
+    }
+
+    /// The fd of the asynchronous events of the device, readable when an event
+    /// is pending, see `handle_async_events`
+    pub(super) fn async_fd(&self) -> i32 {
+        unsafe { (*self.context).async_fd }
+    }
+
+    /// Handle the pending asynchronous events of the device.
+    ///
+    /// Receives of all queue pairs complete through the shared srq, so a queue
+    /// pair that enters the error state without a send posted on it is only
+    /// reported by these events. Such queue pairs are marked failed, which fails
+    /// the pending and later receives on them.
+    pub(super) fn handle_async_events(&mut self) -> std::io::Result<()> {
+        loop {
+            let mut event: ibv_async_event = unsafe { std::mem::zeroed() };
+            if unsafe { ibv_get_async_event(self.context, &mut event) } != 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+            match event.event_type {
+                ibv_event_type_IBV_EVENT_QP_FATAL
+                | ibv_event_type_IBV_EVENT_QP_REQ_ERR
+                | ibv_event_type_IBV_EVENT_QP_ACCESS_ERR => {
+                    let qp_num = unsafe { (*event.element.qp).qp_num };
+                    info!("Queue pair {} failed, event {}", qp_num, event.event_type);
+                    if self.queue_pairs.contains(&qp_num) {
+                        self.fail_qp(qp_num);
+                    }
+                }
+                // Reported when a queue pair using the srq has entered the error state,
+                // which we do ourselves when closing or resetting it
+                ibv_event_type_IBV_EVENT_QP_LAST_WQE_REACHED => {
+                    let qp_num = unsafe { (*event.element.qp).qp_num };
+                    info!("Last WQE reached on queue pair {}", qp_num);
+                    self.last_wqe_reached.insert(qp_num);
+                    if let Some(waker) = self.drain_waiters.remove(&qp_num) {
+                        waker.wake();
+                    }
+                }
+                ibv_event_type_IBV_EVENT_DEVICE_FATAL => {
+                    warn!("Verbs device {} failed", self.name);
+                    let queue_pairs: Vec<u32> = self.queue_pairs.iter().cloned().collect();
+                    for qp_num in queue_pairs {
+                        self.fail_qp(qp_num);
+                    }
+                }
+                other => info!("Verbs asynchronous event {}", other),
+            }
+            unsafe { ibv_ack_async_event(&mut event) };
+        }
+    }
+
+    /// Return true, once, after `IBV_EVENT_QP_LAST_WQE_REACHED` has been handled
+    /// for the queue pair
+    pub(super) fn take_last_wqe(&mut self, qp_num: u32) -> bool {
+        self.last_wqe_reached.remove(&qp_num)
+    }
+
+    /// Wake the given waker when a send completes on the given queue pair, or
+    /// `IBV_EVENT_QP_LAST_WQE_REACHED` is handled for it
+    pub(super) fn wait_drain(&mut self, qp_num: u32, waker: Waker) {
+        self.drain_waiters.insert(qp_num, waker);
+    }
+
+    /// Remove a waker registered with `wait_drain`
+    pub(super) fn cancel_drain(&mut self, qp_num: u32) {
+        self.drain_waiters.remove(&qp_num);
+    }
+
+    /// Address that the completion channel event should be read into
+    pub(super) fn comp_event_ptr(&mut self) -> *mut c_void {
+        &mut self.comp_event as *mut ib_uverbs_comp_event_desc as *mut c_void
+    }
+
+    /// Handle a completion channel event read by io_uring into `comp_event`.
+    ///
+    /// `ibv_req_notify_cq` arms the cq to generate exactly one event on the channel
+    /// for the next completion. Once that event has been read we must acknowledge it
+    /// and re-arm the cq before polling it again, otherwise completions arriving
+    /// between the last poll and the re-arm would never generate an event.
+    /// We therefore only clear `notify_enabled` here, and leave the re-arm and
+    /// the drain of the cq to the next call to `process`.
+    pub(super) fn handle_comp_event(&mut self, res: i32) -> std::io::Result<()> {
+        if res < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res));
+        }
+        if res as usize != std::mem::size_of::<ib_uverbs_comp_event_desc>() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Short read from completion channel",
+            ));
+        }
+        unsafe {
+            let cq = self.comp_event.cq_handle as *mut ibv_cq;
+            if cq != self.cq {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Completion event for unknown cq",
+                ));
+            }
+            let fp: Option<unsafe extern "C" fn(cq: *mut ibv_cq)> =
+                std::mem::transmute((*(*cq).context).ops._compat_cq_event);
+            (fp.unwrap())(cq);
+
+            // Acknowledging events takes a mutex, so do it in batches
+            self.events_pending += 1;
+            if self.events_pending >= 16 {
+                ibv_ack_cq_events(self.cq, self.events_pending as u32);
+                self.events_pending = 0;
+            }
+        }
+        self.notify_enabled = false;
+        Ok(())
+    }
+
     //         info!("recv_ping 2");
     //         //TODO use recv_cq when we have set it up
     //
@@ -739,11 +1366,11 @@ impl Device {
     //     }
     // }
 
-    pub(super) fn new(name: Option<&str>, size: u32) -> std::io::Result<Self> {
+    pub(super) fn new(name: Option<&str>, config: &ReactorConfig) -> std::io::Result<Self> {
         unsafe {
             let mut c = Device {
                 //buf: null_mut(),
-                size: size as usize, //: 4096, //1024 * 1024 * 10,
+                size: config.verbs_buffer_size as usize, //: 4096, //1024 * 1024 * 10,
                 device_list: null_mut(),
                 context: null_mut(),
                 name: String::new(),
@@ -755,15 +1382,34 @@ impl Device {
                 port_info: std::mem::zeroed(),
                 srq: null_mut(),
                 rx_depth: 30,
+                max_send_wr: config.verbs_max_send_wr,
+                recv_sge: config.verbs_recv_sge,
+                send_sge: config.verbs_send_sge,
+                device_attr: std::mem::zeroed(),
+                received: std::collections::HashMap::new(),
+                queue_pairs: std::collections::HashSet::new(),
+                recv_waiters: std::collections::HashMap::new(),
+                failed: std::collections::HashSet::new(),
+                last_wqe_reached: std::collections::HashSet::new(),
+                drain_waiters: std::collections::HashMap::new(),
                 events_pending: 0,
                 notify_enabled: false,
+                comp_event: std::mem::zeroed(),
+                sends_in_flight: std::collections::HashMap::new(),
+                total_sends_in_flight: 0,
+                send_budget: 0,
+                send_waiters: std::collections::HashMap::new(),
+                orphaned_sends: std::collections::HashMap::new(),
                 empty_read_slots: Vec::new(),
                 free_buffers: Vec::new(),
                 read_slot: Vec::new(),
+                recv_completions: 0,
+                send_completions: 0,
+                failed_completions: 0,
             };
 
             for n in 0..c.rx_depth {
-                c.read_slot.push(None);
+                c.read_slot.push(Vec::new());
                 c.empty_read_slots.push(n as usize);
             }
 
@@ -807,6 +1453,13 @@ impl Device {
                 ));
             }
 
+            // Asynchronous events are read when io_uring reports the fd readable
+            let async_fd = (*c.context).async_fd;
+            let flags = libc::fcntl(async_fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(async_fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
             c.channel = ibv_create_comp_channel(c.context);
             if c.channel.is_null() {
                 return Err(std::io::Error::last_os_error());
@@ -817,7 +1470,26 @@ impl Device {
                 return Err(std::io::Error::last_os_error());
             }
 
-            for n in 0..(c.rx_depth * 2) {
+            if ibv_query_device(c.context, &mut c.device_attr) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            if c.recv_sge == 0 || c.recv_sge as i32 > c.device_attr.max_srq_sge {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Number of receive sges not supported by device",
+                ));
+            }
+            if c.send_sge == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Number of send sges must be positive",
+                ));
+            }
+            c.send_sge = std::cmp::min(c.send_sge, c.device_attr.max_sge as u32);
+
+            // Enough buffers to fill all receive slots, and as many again for sending
+            for _ in 0..(c.rx_depth * (c.recv_sge + 1)) {
                 c.free_buffers.push(Buffer::new(&c)?)
             }
 
@@ -838,14 +1510,33 @@ impl Device {
                 return Err(std::io::Error::last_os_error());
             }*/
 
-            c.cq = ibv_create_cq(c.context, (c.rx_depth + 1) as i32, null_mut(), c.channel, 0);
+            // Room for a completion for each posted receive and outstanding send
+            let cq_size = config
+                .verbs_cq_size
+                .unwrap_or(c.rx_depth + c.max_send_wr + CQ_SLACK);
+            if cq_size == 0 || cq_size as i32 > c.device_attr.max_cqe {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Completion queue size not supported by device",
+                ));
+            }
+            c.cq = ibv_create_cq(c.context, cq_size as i32, null_mut(), c.channel, 0);
             if c.cq.is_null() {
                 return Err(std::io::Error::last_os_error());
             }
+            // The device may round the size up
+            let cqe = (*c.cq).cqe.max(0) as u32;
+            if cqe <= c.rx_depth {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Completion queue size leaves no room for sends",
+                ));
+            }
+            c.send_budget = (cqe - c.rx_depth) as usize;
 
             let mut attr: ibv_srq_init_attr = std::mem::zeroed();
             attr.attr.max_wr = c.rx_depth;
-            attr.attr.max_sge = 1;
+            attr.attr.max_sge = c.recv_sge;
 
             c.srq = ibv_create_srq(c.pd, &mut attr);
             if c.srq.is_null() {
@@ -0,0 +1,80 @@
+#![allow(dead_code)]
+
+use agrippa::runtime::{Priority, Reactor, Result};
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::sync::{Mutex, Once};
+
+/// Run `future` as a task of a new reactor until the reactor runs out of work,
+/// and return the output of the future
+pub fn block_on<T: 'static, F: Future<Output = Result<T>> + 'static>(future: F) -> Result<T> {
+    let reactor = Reactor::new(1024 * 1024)?;
+    let output = Rc::new(RefCell::new(None));
+    let o = output.clone();
+    reactor.spawn(Priority::Normal, async move {
+        *o.borrow_mut() = Some(future.await);
+        Ok(())
+    });
+    reactor.run()?;
+    let output = output.borrow_mut().take();
+    output.expect("test task did not finish")
+}
+
+/// Bind a listener to an unused port of the loopback interface
+pub fn local_listener() -> Result<(agrippa::tcp::ListenSocket, std::net::SocketAddr)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    Ok((agrippa::tcp::ListenSocket::from_std(listener), address))
+}
+
+/// Return a connected pair of sockets, the client side first
+pub async fn socket_pair() -> Result<(agrippa::tcp::Socket, agrippa::tcp::Socket)> {
+    let (listener, address) = local_listener()?;
+    let client = agrippa::tcp::connect(address).await?;
+    let server = listener.accept().await?;
+    Ok((client, server))
+}
+
+/// Return a path in a new temporary directory, removed when the test process exits
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "agrippa-test-{}-{}",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGGED.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Capture the log messages of the test process from now on. The tests of a
+/// process share the log, so the messages checked for should be unique to a test
+pub fn capture_logs() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&CaptureLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// Return true if a captured log message contains `pattern`
+pub fn logged(pattern: &str) -> bool {
+    LOGGED.lock().unwrap().iter().any(|m| m.contains(pattern))
+}
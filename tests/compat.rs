@@ -0,0 +1,67 @@
+mod common;
+
+use agrippa::compat::Compat;
+use agrippa::runtime::Error;
+use agrippa::util::{nop, sleep, timeout};
+use common::{block_on, socket_pair};
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+#[test]
+fn round_trip() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        let mut client = Compat::new(client);
+        let mut server = BufReader::new(Compat::new(server));
+        client.write_all(b"hello\nworld\n").await?;
+        client.flush().await?;
+        let mut line = String::new();
+        server.read_line(&mut line).await?;
+        assert_eq!(line, "hello\n");
+        line.clear();
+        server.read_line(&mut line).await?;
+        assert_eq!(line, "world\n");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn drop_with_read_in_flight() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        let mut server = Compat::new(server);
+        let mut buf = [0u8; 16];
+        // Nothing is sent, so the read started by the poll stays in flight
+        let poll =
+            poll_fn(|context| Poll::Ready(Pin::new(&mut server).poll_read(context, &mut buf)))
+                .await;
+        assert!(poll.is_pending());
+        drop(server);
+        // The reactor keeps working, and the socket is closed once the read is canceled
+        let mut client = Compat::new(client);
+        let n = timeout(Duration::from_secs(5), async {
+            client.read(&mut buf).await.map_err(Error::from)
+        })
+        .await?;
+        assert_eq!(n, 0);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn foreign_executor_gets_outside_reactor() {
+    // No agrippa task is polled, so the futures fail rather than touch a task
+    assert!(matches!(
+        futures::executor::block_on(nop()),
+        Err(Error::OutsideReactor)
+    ));
+    assert!(matches!(
+        futures::executor::block_on(sleep(Duration::from_millis(10))),
+        Err(Error::OutsideReactor)
+    ));
+}
@@ -0,0 +1,74 @@
+//! Accepting while out of file descriptors. This is a test binary of its own,
+//! as it lowers the file descriptor limit of the whole process.
+
+mod common;
+
+use agrippa::runtime::{Priority, Reactor};
+use agrippa::util::sleep;
+use std::cell::RefCell;
+use std::io::Read;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Lower the file descriptor limit, and open descriptors until it is reached
+fn exhaust_fds() -> Vec<std::fs::File> {
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+        0
+    );
+    limit.rlim_cur = std::cmp::min(limit.rlim_cur, 256);
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+    let mut files = Vec::new();
+    loop {
+        match std::fs::File::open("/dev/null") {
+            Ok(file) => files.push(file),
+            Err(e) if e.raw_os_error() == Some(libc::EMFILE) => return files,
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+}
+
+#[test]
+fn accept_backs_off_and_rejects_while_out_of_fds() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let (mut listener, address) = common::local_listener().unwrap();
+    listener
+        .emfile_backoff(Duration::from_millis(20), true)
+        .unwrap();
+    // Connected before running out, so the connection waits in the accept queue
+    let mut first = std::net::TcpStream::connect(address).unwrap();
+    let fillers = exhaust_fds();
+
+    let accepted = Rc::new(RefCell::new(None));
+    let a = accepted.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (_socket, peer) = listener.accept_with_addr().await?;
+        *a.borrow_mut() = Some(peer);
+        Ok(())
+    });
+    let second = Rc::new(RefCell::new(None));
+    let s = second.clone();
+    reactor.spawn(Priority::Normal, async move {
+        sleep(Duration::from_millis(50)).await?;
+        // The first connection was accepted with the spare descriptor and closed
+        first.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut buf = [0u8; 1];
+        match first.read(&mut buf) {
+            Ok(0) => (),
+            Err(e) if e.raw_os_error() == Some(libc::ECONNRESET) => (),
+            res => panic!("first connection not rejected: {:?}", res),
+        }
+        // Once descriptors are available again, the next attempt to accept succeeds
+        drop(fillers);
+        let stream = std::net::TcpStream::connect(address)?;
+        *s.borrow_mut() = Some(stream.local_addr()?);
+        sleep(Duration::from_millis(50)).await?;
+        drop(stream);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    let accepted = accepted.borrow_mut().take();
+    assert_eq!(accepted, second.borrow_mut().take());
+    assert!(accepted.is_some());
+}
@@ -0,0 +1,497 @@
+mod common;
+
+use agrippa::fs::{AppendLog, File, IoPriority, OpenOptions, RwFlags};
+use agrippa::runtime::Error;
+use common::{block_on, temp_path};
+use std::io::Write;
+
+#[test]
+fn append_log_reopen_discards_torn_and_zero_tails() {
+    let path = temp_path("append.log");
+    let p = path.clone();
+    let (a, b) = block_on(async move {
+        let log = AppendLog::open(&p).await?;
+        let a = log.append(b"first").await?;
+        let b = log.append(b"second").await?;
+        log.commit().await?;
+        log.close().await?;
+        Ok((a, b))
+    })
+    .unwrap();
+    let end = std::fs::metadata(&path).unwrap().len();
+
+    // A torn record followed by zeroes, as left by a crash during an append
+    // into a preallocated range
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, b'x']).unwrap();
+    file.write_all(&[0; 64]).unwrap();
+    drop(file);
+
+    let p = path.clone();
+    block_on(async move {
+        let log = AppendLog::open(&p).await?;
+        assert_eq!(log.end(), end);
+        assert_eq!(log.read_at(a).await?, b"first");
+        assert_eq!(log.read_at(b).await?, b"second");
+        let c = log.append(b"third").await?;
+        assert_eq!(c, end);
+        log.commit().await?;
+        Ok(())
+    })
+    .unwrap();
+
+    // Only zeroes after the records, which would parse as empty records
+    // without the checksum
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(&[0; 64]).unwrap();
+    drop(file);
+
+    block_on(async move {
+        let log = AppendLog::open(&path).await?;
+        assert_eq!(log.read_at(end).await?, b"third");
+        assert_eq!(log.end(), end + 8 + 5);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn append_log_rejects_length_past_the_end() {
+    let path = temp_path("append.log");
+    block_on(async move {
+        let log = AppendLog::open(&path).await?;
+        let a = log.append(&[0xff; 16]).await?;
+        log.commit().await?;
+        // Inside the record, the payload parses as a length of 4GB
+        match log.read_at(a + 8).await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            res => panic!("unexpected {:?}", res),
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn user_xattr_round_trip() {
+    let path = temp_path("xattr");
+    block_on(async move {
+        let file = File::create(&path).await?;
+        match file.set_xattr("user.agrippa.test", b"value").await {
+            // tmpfs before Linux 6.6 and some other filesystems have no user xattrs
+            Err(Error::Io(e)) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(()),
+            res => res?,
+        }
+        assert_eq!(file.get_xattr("user.agrippa.test").await?, b"value");
+        match file.get_xattr("user.agrippa.missing").await {
+            Err(Error::Io(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENODATA)),
+            res => panic!("unexpected {:?}", res),
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn directory_open_fails_on_regular_file() {
+    let path = temp_path("regular");
+    std::fs::write(&path, b"data").unwrap();
+    block_on(async move {
+        let dir = path.parent().unwrap().to_path_buf();
+        OpenOptions::new()
+            .read(true)
+            .directory(true)
+            .open(&dir)
+            .await?;
+        match OpenOptions::new()
+            .read(true)
+            .directory(true)
+            .open(&path)
+            .await
+        {
+            Err(Error::Io(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR)),
+            res => panic!("unexpected {:?}", res.map(|_| ())),
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn nonblock_fifo_open_does_not_hang() {
+    let path = temp_path("fifo");
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+    // Without a writer, a blocking open of the read end would wait forever
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let res = block_on(async move {
+            OpenOptions::new()
+                .read(true)
+                .nonblock(true)
+                .open(&path)
+                .await?;
+            Ok(())
+        });
+        sender.send(res.is_ok()).unwrap();
+    });
+    let opened = receiver
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("open of the fifo hangs");
+    assert!(opened);
+}
+
+/// The permission bits of the file at `path`, read with statx(2)
+fn statx_permissions(path: &std::path::Path) -> u32 {
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let mut stat: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            0,
+            libc::STATX_MODE,
+            &mut stat,
+        )
+    };
+    assert_eq!(ret, 0, "statx failed: {}", std::io::Error::last_os_error());
+    stat.stx_mode as u32 & 0o7777
+}
+
+#[test]
+fn create_mode_sets_exact_permissions() {
+    let path = temp_path("secret");
+    let p = path.clone();
+    block_on(async move {
+        // Group and other write are cleared by the usual umask, unlike with create_mode
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .exclusive(true)
+            .create_mode(0o622)
+            .open(&p)
+            .await?;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(statx_permissions(&path), 0o622);
+}
+
+#[test]
+fn read_scatter_reads_eight_regions() {
+    let path = temp_path("scatter");
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i * 7) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+    block_on(async move {
+        let file = File::open(&path).await?;
+        let mut buffers: Vec<Vec<u8>> = (0..8).map(|i| vec![0; 100 + i]).collect();
+        let offsets: Vec<u64> = (0..8).map(|i| i * 8000 + 13).collect();
+        let mut requests: Vec<(u64, &mut [u8])> = offsets
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|(&offset, buffer)| (offset, &mut buffer[..]))
+            .collect();
+        let lengths = file.read_scatter(&mut requests).await?;
+        assert_eq!(lengths, (0..8).map(|i| 100 + i).collect::<Vec<_>>());
+        for (offset, buffer) in offsets.iter().zip(&buffers) {
+            let offset = *offset as usize;
+            assert_eq!(&buffer[..], &data[offset..offset + buffer.len()]);
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn punch_hole_and_zero_range_read_back_as_zeros() {
+    use std::os::unix::fs::MetadataExt;
+    const SIZE: usize = 1024 * 1024;
+    let path = temp_path("sparse");
+    std::fs::write(&path, vec![0xaa; SIZE]).unwrap();
+    let blocks = std::fs::metadata(&path).unwrap().blocks();
+    let std_file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let res = block_on(async move {
+        let file = File::from_std(std_file);
+        file.punch_hole(64 * 1024, 512 * 1024).await?;
+        file.zero_range(0, 4096).await?;
+        Ok(())
+    });
+    match res {
+        Ok(()) => (),
+        // Not every filesystem supports both
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+        Err(e) => panic!("failed: {:?}", e),
+    }
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data.len(), SIZE);
+    assert!(data[..4096].iter().all(|&b| b == 0));
+    assert!(data[4096..64 * 1024].iter().all(|&b| b == 0xaa));
+    assert!(data[64 * 1024..576 * 1024].iter().all(|&b| b == 0));
+    assert!(data[576 * 1024..].iter().all(|&b| b == 0xaa));
+    // The punched range is deallocated
+    assert!(std::fs::metadata(&path).unwrap().blocks() < blocks);
+}
+
+#[test]
+fn dsync_write_completes() {
+    let path = temp_path("dsync");
+    let p = path.clone();
+    block_on(async move {
+        let file = File::create(&p).await?;
+        let written = file
+            .write_with_flags(b"record\n", 0, RwFlags::DSYNC)
+            .await?;
+        assert_eq!(written, 7);
+        let written = file
+            .write_with_flags(b"second\n", 7, RwFlags::DSYNC | RwFlags::SYNC)
+            .await?;
+        assert_eq!(written, 7);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"record\nsecond\n");
+}
+
+#[test]
+fn read_into_reuses_the_buffer() {
+    let first = temp_path("first");
+    let second = temp_path("second");
+    std::fs::write(&first, vec![b'a'; 100]).unwrap();
+    std::fs::write(&second, vec![b'b'; 50]).unwrap();
+    block_on(async move {
+        let mut buf = Vec::with_capacity(64 * 1024);
+        let len = File::open(&first).await?.read_into(&mut buf).await?;
+        assert_eq!(len, 100);
+        assert_eq!(buf, vec![b'a'; 100]);
+        let (ptr, capacity) = (buf.as_ptr(), buf.capacity());
+
+        // The previous contents are replaced, and the allocation is kept
+        let len = File::open(&second).await?.read_into(&mut buf).await?;
+        assert_eq!(len, 50);
+        assert_eq!(buf, vec![b'b'; 50]);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.capacity(), capacity);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn streaming_reads_advance_through_a_pipe() {
+    use std::os::unix::io::FromRawFd;
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let read_end = unsafe { File::from_raw_fd(fds[0]) };
+    let mut write_end = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+    write_end.write_all(b"first second").unwrap();
+    drop(write_end);
+    block_on(async move {
+        let mut buf = [0u8; 6];
+        assert_eq!(read_end.read_streaming(&mut buf).await?, 6);
+        assert_eq!(&buf, b"first ");
+        assert_eq!(read_end.read_streaming(&mut buf).await?, 6);
+        assert_eq!(&buf, b"second");
+        // The write end is closed
+        assert_eq!(read_end.read_streaming(&mut buf).await?, 0);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn detached_writes_land_after_the_caller_moves_on() {
+    let path = temp_path("detached");
+    let p = path.clone();
+    block_on(async move {
+        let file = File::create(&p).await?;
+        file.write_detached(b"first ".to_vec(), 0)?;
+        file.write_detached(b"second".to_vec(), 6)?;
+        // The file is dropped, and the task finishes, before the writes complete
+        Ok(())
+    })
+    .unwrap();
+    // The reactor only returned once the detached writes were done
+    assert_eq!(std::fs::read(&path).unwrap(), b"first second");
+
+    let p = path.clone();
+    let contents = block_on(async move {
+        let file = OpenOptions::new().write(true).open(&p).await?;
+        file.write_detached(b"FIRST".to_vec(), 0)?;
+        file.flush_and_close().await?;
+        File::open(&p).await?.read_all().await
+    })
+    .unwrap();
+    assert_eq!(contents, b"FIRST second");
+}
+
+#[test]
+fn prioritized_reads_and_writes_complete() {
+    let path = temp_path("ioprio");
+    block_on(async move {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .await?;
+        let written = file
+            .write_with_priority(b"background", 0, IoPriority::Idle)
+            .await?;
+        assert_eq!(written, 10);
+        let written = file
+            .write_with_priority(b" foreground", 10, IoPriority::BestEffort(0))
+            .await?;
+        assert_eq!(written, 11);
+        let mut buf = [0u8; 21];
+        // Levels above 7 are clamped
+        let read = file
+            .read_with_priority(&mut buf, 0, IoPriority::BestEffort(42))
+            .await?;
+        assert_eq!(&buf[..read], b"background foreground");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn flush_and_close_waits_for_detached_writes() {
+    const CHUNKS: usize = 100;
+    const CHUNK: usize = 4096;
+    let path = temp_path("flush_and_close");
+    block_on(async move {
+        let file = File::create(&path).await?;
+        for i in 0..CHUNKS {
+            file.write_detached(vec![i as u8; CHUNK], (i * CHUNK) as u64)?;
+        }
+        file.flush_and_close().await?;
+        // Every write has landed before the close, without the reactor going idle
+        let data = std::fs::read(&path)?;
+        assert_eq!(data.len(), CHUNKS * CHUNK);
+        for (i, chunk) in data.chunks(CHUNK).enumerate() {
+            assert!(chunk.iter().all(|&b| b == i as u8));
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn owned_write_moves_into_a_spawned_task() {
+    use agrippa::runtime::Priority;
+    use agrippa::util::{sleep, spawn_task};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let path = temp_path("write_owned");
+    let p = path.clone();
+    block_on(async move {
+        let file = Rc::new(File::create(&p).await?);
+        let returned = Rc::new(RefCell::new(None));
+        let data = b"owned data".to_vec();
+        let address = data.as_ptr();
+        let (f, r) = (file.clone(), returned.clone());
+        // The buffer and the file are moved into the task, nothing is borrowed
+        let task = spawn_task(Priority::Normal, async move {
+            *r.borrow_mut() = Some(f.write_owned(data, 0).await?);
+            Ok(())
+        })
+        .await?;
+        while !task.is_finished() {
+            sleep(std::time::Duration::from_millis(1)).await?;
+        }
+        let (written, mut data) = returned.borrow_mut().take().unwrap();
+        assert_eq!(written, 10);
+        // The same allocation comes back, and can be reused for the next write
+        assert_eq!(data.as_ptr(), address);
+        data.clear();
+        data.extend_from_slice(b"!");
+        let (written, _) = file.write_owned(data, 10).await?;
+        assert_eq!(written, 1);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"owned data!");
+}
+
+#[test]
+fn owned_read_fills_the_capacity_of_the_vec() {
+    use agrippa::util::timeout;
+    use std::os::unix::io::FromRawFd;
+    use std::time::Duration;
+
+    let path = temp_path("read_owned");
+    std::fs::write(&path, b"hello world").unwrap();
+    let p = path.clone();
+    block_on(async move {
+        let file = File::open(&p).await?;
+        let (n, data) = file.read_owned(Vec::with_capacity(5), 6).await?;
+        assert_eq!(n, 5);
+        assert_eq!(data, b"world");
+        // Previous content is discarded, and the length set to the bytes read
+        let mut data = data;
+        data.reserve(64);
+        let (n, data) = file.read_owned(data, 0).await?;
+        assert_eq!(n, 11);
+        assert_eq!(data, b"hello world");
+        let (n, data) = file.read_owned(data, 11).await?;
+        assert_eq!(n, 0);
+        assert!(data.is_empty());
+        Ok(())
+    })
+    .unwrap();
+
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let read_end = unsafe { File::from_raw_fd(fds[0]) };
+    let mut write_end = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+    block_on(async move {
+        // Dropping the read while it is in flight cancels it, and frees the buffer
+        let res = timeout(
+            Duration::from_millis(20),
+            read_end.read_owned(Vec::with_capacity(64), 0),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        write_end.write_all(b"after").unwrap();
+        let (n, data) = read_end.read_owned(Vec::with_capacity(64), 0).await?;
+        assert_eq!(n, 5);
+        assert_eq!(data, b"after");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn sync_range_writes_back_ranges_of_any_length() {
+    use agrippa::fs::SyncRangeFlags;
+    let path = temp_path("sync_range");
+    let p = path.clone();
+    block_on(async move {
+        let file = File::create(&p).await?;
+        file.write_all(&vec![b'x'; 65536], 0).await?;
+        file.sync_range(0, 4096, SyncRangeFlags::all()).await?;
+        // Up to the end of the file, starting writeback without waiting
+        file.sync_range(4096, 0, SyncRangeFlags::WRITE).await?;
+        file.sync_range(0, 65536, SyncRangeFlags::empty()).await?;
+        // Longer than a single operation, and past the end of the file
+        file.sync_range(0, 5 << 30, SyncRangeFlags::all()).await?;
+        let res = file.sync_range(1, u64::MAX, SyncRangeFlags::all()).await;
+        assert_eq!(
+            res.unwrap_err().kind(),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), vec![b'x'; 65536]);
+}
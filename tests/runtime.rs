@@ -0,0 +1,788 @@
+mod common;
+
+use agrippa::fs::File;
+use agrippa::runtime::{Error, Priority, Reactor, ReactorConfig};
+use agrippa::util::{
+    acquire_buffer, join_all, madvise, nop, retry, scope, sleep, supports, tee, timeout,
+    try_join_all, AsyncWrite, BufWriter, CancellationToken, MadvAdvice, Metered, RetryPolicy,
+    Semaphore,
+};
+use std::cell::{Cell, RefCell};
+use std::os::unix::io::FromRawFd;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn cancel_all_cancels_reads() {
+    const READERS: usize = 64;
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    // Nothing is ever written to the pipe
+    let read_end = Rc::new(unsafe { File::from_raw_fd(fds[0]) });
+    let write_end = unsafe { File::from_raw_fd(fds[1]) };
+    let canceled = Rc::new(Cell::new(0));
+    for _ in 0..READERS {
+        let read_end = read_end.clone();
+        let canceled = canceled.clone();
+        reactor.spawn(Priority::Normal, async move {
+            let mut buf = [0u8; 8];
+            match read_end.read(&mut buf, 0).await {
+                Err(Error::Cancel) => canceled.set(canceled.get() + 1),
+                res => panic!("read was not canceled: {:?}", res),
+            }
+            // The task itself is not interrupted
+            nop().await
+        });
+    }
+    let r = reactor.clone();
+    reactor.spawn(Priority::Normal, async move {
+        sleep(Duration::from_millis(10)).await?;
+        r.cancel_all()?;
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert_eq!(canceled.get(), READERS);
+    drop(write_end);
+}
+
+#[test]
+fn run_until_idle_reaps_deferred_completions() {
+    let mut config = ReactorConfig::new();
+    config.single_issuer(true).defer_taskrun(true);
+    let reactor = match Reactor::with_config(&config) {
+        Ok(reactor) => reactor,
+        // IORING_SETUP_DEFER_TASKRUN requires Linux 6.1
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+        Err(e) => panic!("failed to create reactor: {:?}", e),
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        nop().await?;
+        d.set(true);
+        Ok(())
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done.get() {
+        assert!(
+            Instant::now() < deadline,
+            "deferred completion never reaped"
+        );
+        reactor.run_until_idle().unwrap();
+    }
+}
+
+#[test]
+fn ten_thousand_nops_complete() {
+    const NOPS: usize = 10_000;
+    let completed = common::block_on(async {
+        let results = join_all((0..NOPS).map(|_| nop())).await;
+        Ok(results.into_iter().filter(|r| r.is_ok()).count())
+    })
+    .unwrap();
+    assert_eq!(completed, NOPS);
+}
+
+#[test]
+fn cancel_ends_sleep_promptly() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let result = Rc::new(Cell::new(None));
+    let r = result.clone();
+    let sleeper = reactor.spawn(Priority::Normal, async move {
+        let start = Instant::now();
+        let res = sleep(Duration::from_secs(10)).await;
+        r.set(Some((
+            res.map_err(|e| matches!(e, Error::Cancel)),
+            start.elapsed(),
+        )));
+        Ok(())
+    });
+    reactor.spawn(Priority::Normal, async move {
+        sleep(Duration::from_millis(10)).await?;
+        sleeper.cancel()
+    });
+    reactor.run().unwrap();
+    let (res, elapsed) = result.take().expect("sleeper did not finish");
+    assert_eq!(res, Err(true));
+    assert!(elapsed < Duration::from_secs(1), "slept for {:?}", elapsed);
+}
+
+#[test]
+fn poll_once_shows_intermediate_progress() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let progress = Rc::new(Cell::new(0));
+    let p = progress.clone();
+    reactor.spawn(Priority::Normal, async move {
+        for step in 1..=3 {
+            nop().await?;
+            p.set(step);
+        }
+        Ok(())
+    });
+    let mut seen = vec![progress.get()];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while progress.get() != 3 {
+        assert!(Instant::now() < deadline, "task did not finish");
+        reactor.poll_once().unwrap();
+        if *seen.last().unwrap() != progress.get() {
+            seen.push(progress.get());
+        }
+    }
+    // Every step is observed on its own, as an iteration polls the task at most once
+    assert_eq!(seen, vec![0, 1, 2, 3]);
+    reactor.run_until_idle().unwrap();
+}
+
+/// Succeed with `i` after a sleep of `delay`, except for 2 which fails right away
+async fn one_failure(i: u32, delay: Duration) -> agrippa::runtime::Result<u32> {
+    if i == 2 {
+        return Err(Error::Internal("failed"));
+    }
+    sleep(delay).await?;
+    Ok(i)
+}
+
+#[test]
+fn join_all_collects_every_outcome() {
+    let results = common::block_on(async {
+        Ok(join_all((0..5).map(|i| one_failure(i, Duration::from_millis(10)))).await)
+    })
+    .unwrap();
+    assert_eq!(results.len(), 5);
+    for (i, res) in results.into_iter().enumerate() {
+        match res {
+            Ok(v) => assert_eq!(v, i as u32),
+            Err(e) => assert!(i == 2 && matches!(e, Error::Internal("failed"))),
+        }
+    }
+}
+
+#[test]
+fn try_join_all_stops_at_first_failure() {
+    let start = Instant::now();
+    let res = common::block_on(async {
+        try_join_all((0..5).map(|i| one_failure(i, Duration::from_secs(10)))).await
+    });
+    assert!(matches!(res, Err(Error::Internal("failed"))));
+    // The other futures were dropped rather than waited for
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn run_without_tasks_returns() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let reactor = Reactor::new(1024 * 1024).unwrap();
+        sender.send(reactor.run().is_ok()).unwrap();
+    });
+    let ok = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("run of an empty reactor did not return");
+    assert!(ok);
+}
+
+#[test]
+fn third_task_waits_for_a_permit() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let semaphore = Rc::new(Semaphore::new(2));
+    let released = Rc::new(Cell::new(0));
+    let acquired = Rc::new(Cell::new(0));
+    for _ in 0..2 {
+        let (semaphore, released, acquired) =
+            (semaphore.clone(), released.clone(), acquired.clone());
+        reactor.spawn(Priority::Normal, async move {
+            let permit = semaphore.acquire().await?;
+            acquired.set(acquired.get() + 1);
+            sleep(Duration::from_millis(20)).await?;
+            released.set(released.get() + 1);
+            drop(permit);
+            Ok(())
+        });
+    }
+    let s = semaphore.clone();
+    let r = released.clone();
+    let a = acquired.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let _permit = s.acquire().await?;
+        // Only once one of the first two has released its permit
+        assert_eq!(a.get(), 2);
+        assert!(r.get() >= 1);
+        a.set(a.get() + 1);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert_eq!(acquired.get(), 3);
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test]
+fn cancel_wakes_task_blocked_on_semaphore() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let semaphore = Rc::new(Semaphore::new(0));
+    let canceled = Rc::new(Cell::new(false));
+    let (s, c) = (semaphore.clone(), canceled.clone());
+    let waiter = reactor.spawn(Priority::Normal, async move {
+        // No permit is ever released, only the cancellation ends the wait
+        let res = s.acquire().await;
+        c.set(matches!(res, Err(Error::Cancel)));
+        Ok(())
+    });
+    reactor.spawn(Priority::Normal, async move {
+        sleep(Duration::from_millis(10)).await?;
+        waiter.cancel()
+    });
+    reactor.run().unwrap();
+    assert!(canceled.get());
+    assert_eq!(semaphore.available_permits(), 0);
+}
+
+#[test]
+fn scope_error_cancels_blocked_child() {
+    let semaphore = Rc::new(Semaphore::new(0));
+    let s = semaphore.clone();
+    let start = Instant::now();
+    let res: agrippa::runtime::Result<()> = common::block_on(async move {
+        scope(|sc| async move {
+            // The child waits for a permit that is never released
+            sc.spawn(Priority::Normal, async move {
+                let _permit = s.acquire().await?;
+                Ok(())
+            });
+            nop().await?;
+            Err(Error::Internal("early return"))
+        })
+        .await
+    });
+    assert!(matches!(res, Err(Error::Internal("early return"))));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn pooled_buffer_is_reused_without_zeroing() {
+    common::block_on(async {
+        let mut buf = acquire_buffer(16).await?;
+        assert_eq!(&buf[..], &[0; 16]);
+        buf.iter_mut().for_each(|b| *b = 0xaa);
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        let mut buf = acquire_buffer(8).await?;
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(&buf[..], &[0xaa; 8]);
+        // Growing the buffer zeroes the new bytes, also within the pooled allocation
+        buf.resize(16);
+        assert_eq!(&buf[8..], &[0; 8]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn lone_timer_wakes_blocked_reactor() {
+    // Without and with periodic maintenance wakeups in between
+    for max_wait in [None, Some(Duration::from_millis(20))] {
+        let mut config = ReactorConfig::new();
+        if let Some(max_wait) = max_wait {
+            config.max_wait(max_wait);
+        }
+        let reactor = Reactor::with_config(&config).unwrap();
+        let woken = Rc::new(Cell::new(None));
+        let w = woken.clone();
+        let start = Instant::now();
+        // Nothing but the timer is pending, so the reactor blocks in the completion wait
+        reactor.spawn(Priority::Normal, async move {
+            sleep(Duration::from_millis(50)).await?;
+            w.set(Some(start.elapsed()));
+            Ok(())
+        });
+        reactor.run().unwrap();
+        let elapsed = woken.get().expect("sleeper did not finish");
+        assert!(
+            elapsed >= Duration::from_millis(50),
+            "woke after {:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < Duration::from_millis(500),
+            "woke after {:?}",
+            elapsed
+        );
+    }
+}
+
+#[test]
+fn failed_task_is_logged_with_its_name() {
+    common::capture_logs();
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    reactor.spawn_named("failing_named_task", Priority::Normal, async {
+        Err(Error::Internal("failed"))
+    });
+    reactor.run().unwrap();
+    assert!(common::logged("Task failing_named_task failed"));
+}
+
+#[test]
+fn slow_poll_is_logged() {
+    common::capture_logs();
+    let mut config = ReactorConfig::new();
+    config.slow_poll_threshold(Duration::from_millis(10));
+    let reactor = Reactor::with_config(&config).unwrap();
+    reactor.spawn_named("slow_poll_task", Priority::Normal, async {
+        // Block the reactor thread within a single poll
+        std::thread::sleep(Duration::from_millis(50));
+        Ok(())
+    });
+    reactor.spawn_named("fast_poll_task", Priority::Normal, async { nop().await });
+    reactor.run().unwrap();
+    assert!(common::logged("Task slow_poll_task spent"));
+    assert!(!common::logged("Task fast_poll_task spent"));
+}
+
+#[test]
+fn starved_low_priority_task_raises_alarm() {
+    common::capture_logs();
+    let mut config = ReactorConfig::new();
+    config.starvation_threshold(Duration::from_millis(10));
+    let reactor = Reactor::with_config(&config).unwrap();
+    reactor.spawn_named("starved_low_task", Priority::Low, async { Ok(()) });
+    // The high priority task is polled first, and holds the reactor meanwhile
+    reactor.spawn(Priority::High, async {
+        std::thread::sleep(Duration::from_millis(50));
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(common::logged("Low priority task starved_low_task starved"));
+    let low = &reactor.metrics().ready_wait[Priority::Low as usize];
+    assert_eq!(low.count, 1);
+    assert!(low.max >= Duration::from_millis(50));
+}
+
+#[test]
+fn retry_backs_off_until_success() {
+    let attempts = Rc::new(Cell::new(0));
+    let a = attempts.clone();
+    let start = Instant::now();
+    let res = common::block_on(async move {
+        let policy = RetryPolicy::new(5, Duration::from_millis(20), Duration::from_secs(1));
+        retry(&policy, || {
+            a.set(a.get() + 1);
+            let attempt = a.get();
+            async move {
+                if attempt <= 2 {
+                    Err(Error::Internal("not yet"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await
+    });
+    assert_eq!(res.unwrap(), 3);
+    assert_eq!(attempts.get(), 3);
+    // Slept 20ms after the first failure, and 40ms after the second
+    assert!(start.elapsed() >= Duration::from_millis(60));
+}
+
+#[test]
+fn retry_gives_up_on_unretryable_error() {
+    let attempts = Rc::new(Cell::new(0));
+    let a = attempts.clone();
+    let res: agrippa::runtime::Result<()> = common::block_on(async move {
+        let mut policy = RetryPolicy::new(5, Duration::from_millis(1), Duration::from_secs(1));
+        policy.retry_if(|e| e.kind() == Some(std::io::ErrorKind::ConnectionRefused));
+        retry(&policy, || {
+            a.set(a.get() + 1);
+            async { Err(Error::Internal("not retryable")) }
+        })
+        .await
+    });
+    assert!(matches!(res, Err(Error::Internal("not retryable"))));
+    assert_eq!(attempts.get(), 1);
+}
+
+/// Return the read and write ends of a new pipe
+fn pipe() -> (File, File) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+}
+
+#[test]
+fn tee_feeds_two_readers() {
+    // Opcode of IORING_OP_TEE
+    const IORING_OP_TEE: u8 = 33;
+    common::block_on(async {
+        if !supports(IORING_OP_TEE).await? {
+            return Ok(());
+        }
+        let (input_read, input_write) = pipe();
+        let (copy_read, copy_write) = pipe();
+        input_write.write_all(b"stream", 0).await?;
+        assert_eq!(tee(&input_read, &copy_write, 64, 0).await?, 6);
+        // Both the original and the duplicate can be read
+        let mut first = [0u8; 6];
+        assert_eq!(input_read.read(&mut first, 0).await?, 6);
+        let mut second = [0u8; 6];
+        assert_eq!(copy_read.read(&mut second, 0).await?, 6);
+        assert_eq!(&first, b"stream");
+        assert_eq!(&second, b"stream");
+        Ok(())
+    })
+    .unwrap();
+}
+
+/// Writer recording every write made to it
+#[derive(Default)]
+struct RecordingWriter {
+    writes: RefCell<Vec<Vec<u8>>>,
+}
+
+impl AsyncWrite for RecordingWriter {
+    fn write<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = agrippa::runtime::Result<usize>> + 'a>>
+    {
+        self.writes.borrow_mut().push(data.to_vec());
+        Box::pin(async move { Ok(data.len()) })
+    }
+}
+
+#[test]
+fn buf_writer_coalesces_small_writes() {
+    common::block_on(async {
+        let mut writer = BufWriter::new(RecordingWriter::default(), 4096);
+        for i in 0..100u8 {
+            writer.write_all(&[i; 10]).await?;
+        }
+        assert!(writer.get_ref().writes.borrow().is_empty());
+        assert_eq!(writer.buffered(), 1000);
+        writer.flush().await?;
+        {
+            let writes = writer.get_ref().writes.borrow();
+            assert_eq!(writes.len(), 1);
+            assert_eq!(writes[0].len(), 1000);
+            assert_eq!(&writes[0][990..], &[99; 10]);
+        }
+        // Larger than the buffer, so written directly
+        writer.write_all(&[7; 8192]).await?;
+        writer.write_all(b"tail").await?;
+        let inner = writer.into_inner().await?;
+        let writes = inner.writes.into_inner();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[1].len(), 8192);
+        assert_eq!(writes[2], b"tail");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn buf_writer_dropped_dirty_warns() {
+    common::capture_logs();
+    let mut writer = BufWriter::new(RecordingWriter::default(), 4096);
+    common::block_on(async move {
+        writer.write_all(&[1; 1234]).await?;
+        Ok(())
+    })
+    .unwrap();
+    assert!(common::logged("BufWriter dropped with 1234 bytes"));
+}
+
+#[test]
+fn task_builder_configures_spawn() {
+    common::capture_logs();
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let record = |name: &'static str| {
+        let order = order.clone();
+        async move {
+            order.borrow_mut().push(name);
+            Ok(())
+        }
+    };
+    reactor.task().priority(Priority::Low).spawn(record("low"));
+    reactor.task().spawn(record("normal"));
+    // A deadline places the task in the deadline lane, after high priority tasks
+    reactor
+        .task()
+        .name("builder_deadline_task")
+        .priority(Priority::High)
+        .deadline(Instant::now() + Duration::from_secs(1))
+        .spawn(record("deadline"));
+    let o = order.clone();
+    reactor
+        .task()
+        .name("builder_high_task")
+        .priority(Priority::High)
+        .spawn(async move {
+            o.borrow_mut().push("high");
+            Err(Error::Internal("failed"))
+        });
+    reactor.run().unwrap();
+    assert_eq!(*order.borrow(), vec!["high", "deadline", "normal", "low"]);
+    assert!(common::logged("Task builder_high_task failed"));
+    assert!(common::logged("Task builder_deadline_task finished"));
+}
+
+/// Return the cpus the calling thread may run on
+fn allowed_cpus() -> Vec<usize> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let ret =
+        unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    assert_eq!(ret, 0);
+    (0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect()
+}
+
+#[test]
+fn run_pins_the_reactor_thread() {
+    let cpu = *allowed_cpus().last().unwrap();
+    // On a thread of its own, as the affinity stays set after the reactor returns
+    let pinned = std::thread::spawn(move || {
+        let mut config = ReactorConfig::new();
+        config.cpu_affinity(cpu);
+        let reactor = Reactor::with_config(&config).unwrap();
+        let pinned = Rc::new(RefCell::new(Vec::new()));
+        let p = pinned.clone();
+        reactor.spawn(Priority::Normal, async move {
+            *p.borrow_mut() = allowed_cpus();
+            Ok(())
+        });
+        reactor.run().unwrap();
+        pinned.take()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(pinned, vec![cpu]);
+
+    let mut config = ReactorConfig::new();
+    config.cpu_affinity(libc::CPU_SETSIZE as usize);
+    match Reactor::with_config(&config) {
+        Err(e) => assert_eq!(e.kind(), Some(std::io::ErrorKind::InvalidInput)),
+        Ok(_) => panic!("reactor created with an invalid cpu"),
+    }
+}
+
+#[test]
+fn flushed_submissions_complete() {
+    const NOPS: usize = 8;
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let completed = Rc::new(Cell::new(0));
+    let (c, r) = (completed.clone(), reactor.clone());
+    reactor.spawn(Priority::Normal, async move {
+        let mut nops: Vec<_> = (0..NOPS).map(|_| Box::pin(nop())).collect();
+        // Polling each nop once queues its submission
+        std::future::poll_fn(|context| {
+            for nop in &mut nops {
+                assert!(std::future::Future::poll(nop.as_mut(), context).is_pending());
+            }
+            std::task::Poll::Ready(())
+        })
+        .await;
+        assert!(r.flush_submissions()? >= NOPS);
+        // Nothing is left to submit
+        assert_eq!(r.flush_submissions()?, 0);
+        for nop in nops {
+            nop.await?;
+            c.set(c.get() + 1);
+        }
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert_eq!(completed.get(), NOPS);
+}
+
+#[test]
+fn metered_writes_are_throttled_to_the_rate() {
+    const RATE: u64 = 200_000;
+    let written = Rc::new(Cell::new(0));
+    let w = written.clone();
+    let start = Instant::now();
+    common::block_on(async move {
+        let mut sink = Metered::new(RecordingWriter::default(), Some(RATE));
+        sink.counters(Rc::new(Cell::new(0)), w);
+        let chunk = [0u8; 10_000];
+        for _ in 0..40 {
+            sink.write_all(&chunk).await?;
+        }
+        Ok(())
+    })
+    .unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(written.get(), 400_000);
+    // A second worth of bytes goes out as a burst, the rest at the rate
+    assert!(elapsed >= Duration::from_millis(900), "took {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+}
+
+#[test]
+fn timeout_ends_waits_of_any_kind() {
+    common::block_on(async {
+        // A wait that is not an io_uring operation
+        let semaphore = Semaphore::new(0);
+        let res = timeout(Duration::from_millis(20), semaphore.acquire()).await;
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        // Several io_uring reads joined, which are canceled on timeout
+        let (read_end, write_end) = pipe();
+        let start = Instant::now();
+        let res = timeout(Duration::from_millis(20), async {
+            let mut a = [0u8; 4];
+            let mut b = [0u8; 4];
+            let reads = join_all(vec![read_end.read(&mut a, 0), read_end.read(&mut b, 0)]).await;
+            Ok(reads.len())
+        })
+        .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // A future completing in time is not affected
+        write_end.write_all(b"data", 0).await?;
+        let mut buf = [0u8; 4];
+        let n = timeout(Duration::from_secs(5), read_end.read(&mut buf, 0)).await?;
+        assert_eq!(&buf[..n], b"data");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn madvise_advises_anonymous_memory() {
+    const LEN: usize = 4 * 4096;
+    let map = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            LEN,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(map, libc::MAP_FAILED);
+    let memory = unsafe { std::slice::from_raw_parts_mut(map as *mut u8, LEN) };
+    memory.iter_mut().for_each(|b| *b = 0xaa);
+    let addr = memory.as_mut_ptr();
+    common::block_on(async move {
+        unsafe { madvise(addr, LEN, MadvAdvice::WillNeed).await? };
+        assert!(unsafe { std::slice::from_raw_parts(addr, LEN) }
+            .iter()
+            .all(|&b| b == 0xaa));
+        // Discarded private anonymous pages read back as zeros
+        unsafe { madvise(addr, LEN, MadvAdvice::DontNeed).await? };
+        assert!(unsafe { std::slice::from_raw_parts(addr, LEN) }
+            .iter()
+            .all(|&b| b == 0));
+        // The address must be page aligned
+        let res = unsafe { madvise(addr.add(1), 4096, MadvAdvice::WillNeed).await };
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(unsafe { libc::munmap(map, LEN) }, 0);
+}
+
+#[test]
+fn file_set_slots_are_updated_and_reused() {
+    use std::os::unix::io::AsRawFd;
+    let alpha = common::temp_path("alpha");
+    let bravo = common::temp_path("bravo");
+    std::fs::write(&alpha, b"alpha").unwrap();
+    std::fs::write(&bravo, b"bravo").unwrap();
+    let alpha = std::fs::File::open(&alpha).unwrap();
+    let bravo = std::fs::File::open(&bravo).unwrap();
+
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let files = match reactor.register_files(2) {
+        Ok(files) => files,
+        // Empty slots require Linux 5.5
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+        Err(e) => panic!("failed to register files: {:?}", e),
+    };
+    assert_eq!(files.len(), 2);
+    let busy = reactor
+        .register_files(2)
+        .err()
+        .and_then(|e| e.raw_os_error());
+    assert_eq!(busy, Some(libc::EBUSY));
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let mut buf = [0u8; 5];
+        let index = files.insert(alpha.as_raw_fd())?;
+        assert_eq!(index, 0);
+        assert_eq!(files.read(index, &mut buf, 0).await?, 5);
+        assert_eq!(&buf, b"alpha");
+
+        // The slot now refers to the other file
+        files.update(index, bravo.as_raw_fd())?;
+        assert_eq!(files.read(index, &mut buf, 0).await?, 5);
+        assert_eq!(&buf, b"bravo");
+
+        assert_eq!(files.insert(alpha.as_raw_fd())?, 1);
+        let full = files
+            .insert(alpha.as_raw_fd())
+            .err()
+            .and_then(|e| e.raw_os_error());
+        assert_eq!(full, Some(libc::ENFILE));
+        // A removed slot is reused
+        files.remove(0)?;
+        assert_eq!(files.insert(bravo.as_raw_fd())?, 0);
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn cancelling_a_token_ends_the_waits_of_its_children() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let token = CancellationToken::new();
+    let ended = Rc::new(Cell::new(0));
+
+    let (child, e) = (token.child_token(), ended.clone());
+    reactor.spawn(Priority::Normal, async move {
+        child.cancelled().await?;
+        e.set(e.get() + 1);
+        Ok(())
+    });
+    let (child, e) = (token.child_token(), ended.clone());
+    reactor.spawn(Priority::Normal, async move {
+        // Nothing is ever written to the pipe
+        let (read_end, _write_end) = pipe();
+        let mut buf = [0u8; 4];
+        let res = child.run(read_end.read(&mut buf, 0)).await;
+        assert!(matches!(res, Err(Error::Cancel)));
+        e.set(e.get() + 1);
+        Ok(())
+    });
+    let e = ended.clone();
+    reactor.spawn(Priority::Normal, async move {
+        // Cancelling a child leaves its parent alone
+        let child = token.child_token();
+        child.cancel();
+        assert!(child.is_cancelled());
+        sleep(Duration::from_millis(20)).await?;
+        assert!(!token.is_cancelled());
+        assert_eq!(e.get(), 0);
+        token.cancel();
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert_eq!(ended.get(), 2);
+
+    common::block_on(async {
+        let start = Instant::now();
+        let token = CancellationToken::new().with_timeout(Duration::from_millis(30));
+        assert!(!token.is_cancelled());
+        token.cancelled().await?;
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert!(token.is_cancelled());
+        Ok(())
+    })
+    .unwrap();
+}
@@ -0,0 +1,456 @@
+mod common;
+
+use agrippa::runtime::{Error, Priority, Reactor};
+use agrippa::tcp::{serve, ConnectOptions, ListenOptions};
+use agrippa::util::{sleep, timeout};
+use common::{block_on, socket_pair};
+use std::cell::Cell;
+use std::future::{poll_fn, Future};
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Duration;
+
+#[test]
+fn send_zc_round_trip() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let sent = client.send_zc(data.clone()).await?;
+        let mut received = vec![0; sent];
+        server.read_all(&mut received).await?;
+        assert_eq!(&received[..], &data[..sent]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn send_zc_dropped_in_flight() {
+    block_on(async {
+        let (client, _server) = socket_pair().await?;
+        // Larger than the socket buffers, so the send can not complete right away
+        let mut send = Box::pin(client.send_zc(vec![7; 64 * 1024 * 1024]));
+        let pending =
+            poll_fn(|context| Poll::Ready(send.as_mut().poll(context).is_pending())).await;
+        assert!(pending);
+        drop(send);
+        // The reactor keeps running once the canceled send has completed
+        sleep(Duration::from_millis(10)).await?;
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() {
+    // Bind and close a listener, so that nothing listens on the port
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let res = block_on(async move { agrippa::tcp::connect(address).await.map(|_| ()) });
+    match res {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused),
+        res => panic!("unexpected {:?}", res),
+    }
+}
+
+/// The port the socket is bound to
+fn local_port<S: std::os::unix::io::AsRawFd>(socket: &S) -> u16 {
+    let mut address: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(
+            socket.as_raw_fd(),
+            &mut address as *mut libc::sockaddr_in6 as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    assert_eq!(ret, 0);
+    u16::from_be(address.sin6_port)
+}
+
+#[test]
+fn dual_stack_accepts_ipv4_and_ipv6() {
+    block_on(async {
+        let listener = match ListenOptions::new().only_v6(false).listen("[::]:0").await {
+            Ok(listener) => listener,
+            // No IPv6 in this environment
+            Err(Error::Io(e)) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let port = local_port(&listener);
+
+        let _v4 = agrippa::tcp::connect(("127.0.0.1", port)).await?;
+        let (_, address) = listener.accept_with_addr().await?;
+        assert!(address.is_ipv4(), "mapped address not decoded: {}", address);
+        assert_eq!(address.ip(), std::net::Ipv4Addr::LOCALHOST);
+
+        let _v6 = agrippa::tcp::connect(("::1", port)).await?;
+        let (_, address) = listener.accept_with_addr().await?;
+        assert_eq!(address.ip(), std::net::Ipv6Addr::LOCALHOST);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn connected_and_accepted_sockets_are_cloexec() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        for socket in &[&client, &server] {
+            let flags = unsafe {
+                libc::fcntl(
+                    std::os::unix::io::AsRawFd::as_raw_fd(*socket),
+                    libc::F_GETFD,
+                )
+            };
+            assert!(flags >= 0);
+            assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn serve_survives_handler_panicking_before_its_future() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let (listener, address) = common::local_listener().unwrap();
+    let calls = Rc::new(Cell::new(0));
+    let server = reactor.spawn(Priority::Normal, async move {
+        serve(&listener, Priority::Normal, move |socket| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                panic!("handler panicked");
+            }
+            async move { socket.write_all(b"ok").await }
+        })
+        .await
+    });
+    reactor.spawn(Priority::Normal, async move {
+        // The connection of the panicking handler is closed
+        let first = agrippa::tcp::connect(address).await?;
+        let mut buf = [0u8; 2];
+        assert_eq!(first.read(&mut buf).await?, 0);
+        let second = agrippa::tcp::connect(address).await?;
+        second.read_all(&mut buf).await?;
+        assert_eq!(&buf, b"ok");
+        server.cancel()
+    });
+    reactor.run().unwrap();
+}
+
+#[test]
+fn connect_options_try_every_address() {
+    let (_listener, address) = common::local_listener().unwrap();
+    let res = block_on(async move {
+        // Fails at every address before connecting, the last error is returned
+        ConnectOptions::new()
+            .bind_to_device(&"x".repeat(64))
+            .connect(&[address, address][..])
+            .await
+            .map(|_| ())
+    });
+    match res {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        res => panic!("unexpected {:?}", res),
+    }
+    // Without the failing option the same addresses connect
+    block_on(async move {
+        ConnectOptions::new()
+            .connect(&[address, address][..])
+            .await?;
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn std_stream_is_driven_by_the_reactor() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    std::io::Write::write_all(&mut peer, b"hello").unwrap();
+    let received = block_on(async move {
+        let socket = agrippa::tcp::Socket::from_std(stream);
+        let mut buf = [0u8; 5];
+        socket.read_all(&mut buf).await?;
+        // The socket owns the fd, and closes it when dropped
+        drop(socket);
+        Ok(buf)
+    })
+    .unwrap();
+    assert_eq!(&received, b"hello");
+    let mut rest = Vec::new();
+    std::io::Read::read_to_end(&mut peer, &mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn resolve_localhost_and_connect_by_name() {
+    block_on(async {
+        let addrs = agrippa::net::resolve("localhost", 8080).await?;
+        assert!(!addrs.is_empty());
+        assert!(addrs
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 8080));
+
+        let (listener, address) = common::local_listener()?;
+        let client = agrippa::tcp::connect_host("localhost", address.port()).await?;
+        let server = listener.accept().await?;
+        client.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        server.read_all(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn writable_pends_while_send_buffer_is_full() {
+    use std::os::unix::io::AsRawFd;
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        // Fill the send buffer of the client, and the receive buffer of the server
+        let chunk = [7u8; 64 * 1024];
+        let mut sent = 0;
+        loop {
+            let ret = unsafe {
+                libc::send(
+                    client.as_raw_fd(),
+                    chunk.as_ptr() as *const libc::c_void,
+                    chunk.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if ret < 0 {
+                assert_eq!(
+                    std::io::Error::last_os_error().raw_os_error(),
+                    Some(libc::EAGAIN)
+                );
+                break;
+            }
+            sent += ret as usize;
+        }
+        let mut writable = Box::pin(client.writable());
+        let pending =
+            poll_fn(|context| Poll::Ready(writable.as_mut().poll(context).is_pending())).await;
+        assert!(pending);
+
+        let mut received = vec![0; sent];
+        server.read_all(&mut received).await?;
+        timeout(Duration::from_secs(5), writable).await?;
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn readable_resolves_when_data_arrives() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let waited = Rc::new(Cell::new(None));
+    let (w, r) = (waited.clone(), reactor.clone());
+    reactor.spawn(Priority::Normal, async move {
+        let (client, server) = socket_pair().await?;
+        r.spawn(Priority::Normal, async move {
+            sleep(Duration::from_millis(50)).await?;
+            client.write_all(b"ready").await
+        });
+        let mut buf = [0u8; 16];
+        assert_eq!(server.try_read(&mut buf)?, None);
+        let start = std::time::Instant::now();
+        server.readable().await?;
+        w.set(Some(start.elapsed()));
+        assert_eq!(server.try_read(&mut buf)?, Some(5));
+        assert_eq!(&buf[..5], b"ready");
+        Ok(())
+    });
+    reactor.run().unwrap();
+    let waited = waited.get().expect("reader did not finish");
+    assert!(
+        waited >= Duration::from_millis(40),
+        "resolved after {:?}",
+        waited
+    );
+}
+
+#[test]
+fn idle_timeout_fires_once_the_peer_goes_silent() {
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let reads = Rc::new(Cell::new(0));
+    let timed_out = Rc::new(Cell::new(false));
+    let (n, t, r) = (reads.clone(), timed_out.clone(), reactor.clone());
+    reactor.spawn(Priority::Normal, async move {
+        let (client, server) = socket_pair().await?;
+        r.spawn(Priority::Normal, async move {
+            // Each message arrives within the idle timeout of the previous one
+            for _ in 0..3 {
+                sleep(Duration::from_millis(60)).await?;
+                client.write_all(b"x").await?;
+            }
+            // Keep the connection open, but silent
+            sleep(Duration::from_millis(500)).await?;
+            Ok(())
+        });
+        let server = agrippa::tcp::with_idle_timeout(server, Duration::from_millis(100));
+        let mut buf = [0u8; 1];
+        loop {
+            match server.read(&mut buf).await {
+                Ok(1) => n.set(n.get() + 1),
+                Err(Error::Timeout) => break,
+                res => panic!("unexpected {:?}", res),
+            }
+        }
+        t.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert_eq!(reads.get(), 3);
+    assert!(timed_out.get());
+}
+
+#[test]
+fn read_timeout_returns_partial_data() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        client.write_all(b"abc").await?;
+        let mut buf = [0u8; 16];
+        let start = std::time::Instant::now();
+        // Fewer bytes than asked for arrive, and are returned once the timeout fires
+        let n = server
+            .read_timeout(&mut buf, Duration::from_millis(50))
+            .await?;
+        assert_eq!(&buf[..n], b"abc");
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // Nothing arrives, which is not an error
+        let n = server
+            .read_timeout(&mut buf, Duration::from_millis(20))
+            .await?;
+        assert_eq!(n, 0);
+        // The read ends early when the peer closes the connection
+        client.write_all(b"de").await?;
+        drop(client);
+        let start = std::time::Instant::now();
+        let n = server
+            .read_timeout(&mut buf, Duration::from_secs(5))
+            .await?;
+        assert_eq!(&buf[..n], b"de");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn buffer_sizes_are_set_and_inherited() {
+    const SIZE: usize = 32 * 1024;
+    block_on(async {
+        let (client, _server) = socket_pair().await?;
+        client.set_recv_buffer_size(SIZE)?;
+        client.set_send_buffer_size(SIZE)?;
+        // The kernel doubles the requested sizes
+        assert_eq!(client.recv_buffer_size()?, 2 * SIZE);
+        assert_eq!(client.send_buffer_size()?, 2 * SIZE);
+
+        let listener = ListenOptions::new()
+            .recv_buffer_size(SIZE)
+            .send_buffer_size(SIZE)
+            .listen("127.0.0.1:0")
+            .await?;
+        let _client = agrippa::tcp::connect(("127.0.0.1", local_port(&listener))).await?;
+        let accepted = listener.accept().await?;
+        assert_eq!(accepted.recv_buffer_size()?, 2 * SIZE);
+        assert_eq!(accepted.send_buffer_size()?, 2 * SIZE);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn drain_discards_up_to_the_limit() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        client.write_all(&vec![b'x'; 50_000]).await?;
+        client.write_all(b"next").await?;
+        assert_eq!(server.drain(50_000).await?, 50_000);
+        // The bytes after the limit are left for the next read
+        let mut buf = [0u8; 4];
+        server.read_all(&mut buf).await?;
+        assert_eq!(&buf, b"next");
+
+        client.write_all(b"tail").await?;
+        drop(client);
+        // Draining ends at the end of the stream
+        assert_eq!(server.drain(1000).await?, 4);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn multiplexer_matches_out_of_order_responses() {
+    use agrippa::util::{join_all, read_frame, write_frame, Multiplexer};
+    let reactor = Reactor::new(1024 * 1024).unwrap();
+    let done = Rc::new(Cell::new(false));
+    let (d, r) = (done.clone(), reactor.clone());
+    reactor.spawn(Priority::Normal, async move {
+        let (client, server) = socket_pair().await?;
+        r.spawn(Priority::Normal, async move {
+            let (first, request) = read_frame(&server, 1024).await?.unwrap();
+            assert_eq!(request, b"first");
+            let (second, request) = read_frame(&server, 1024).await?.unwrap();
+            assert_eq!(request, b"second");
+            // Answer the second request before the first
+            write_frame(&server, second, b"second response").await?;
+            write_frame(&server, first, b"first response").await?;
+            // Close the connection with a third request outstanding
+            read_frame(&server, 1024).await?.unwrap();
+            Ok(())
+        });
+        let client = Multiplexer::new(client).await?;
+        let responses = join_all(vec![client.request(b"first"), client.request(b"second")]).await;
+        let responses: Vec<_> = responses.into_iter().collect::<Result<_, _>>()?;
+        assert_eq!(
+            responses,
+            vec![b"first response".to_vec(), b"second response".to_vec()]
+        );
+        assert!(matches!(client.request(b"third").await, Err(Error::Eof)));
+        assert_eq!(client.outstanding(), 0);
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn write_more_and_cork_coalesce_writes() {
+    block_on(async {
+        let (client, server) = socket_pair().await?;
+        let mut buf = [0u8; 64];
+
+        // The header is held back until the body follows, so both arrive in
+        // one segment (best effort, as the kernel only promises to try)
+        client.write_all_more(b"header:").await?;
+        client.write_all(b"body").await?;
+        let n = server.read(&mut buf).await?;
+        assert_eq!(&buf[..n], b"header:body");
+
+        client.cork()?;
+        client.write_all(b"one,").await?;
+        client.write_all(b"two,").await?;
+        client.write_all(b"three").await?;
+        // Nothing is sent while corked, well within the 200ms limit
+        assert!(matches!(
+            timeout(Duration::from_millis(20), server.read(&mut buf)).await,
+            Err(Error::Timeout)
+        ));
+        client.uncork()?;
+        let n = server.read(&mut buf).await?;
+        assert_eq!(&buf[..n], b"one,two,three");
+        Ok(())
+    })
+    .unwrap();
+}
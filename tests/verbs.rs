@@ -0,0 +1,331 @@
+use agrippa::runtime::{Priority, Reactor, ReactorConfig};
+use agrippa::util::{join_all, sleep, timeout};
+use agrippa::verbs::{self, Connection};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Create a reactor with small verbs buffers, None without an rdma device
+fn verbs_reactor() -> Option<Rc<Reactor>> {
+    let mut config = ReactorConfig::new();
+    config.verbs_buffer_size(4096);
+    Reactor::with_config(&config).ok()
+}
+
+/// Connect two queue pairs of the device to each other
+async fn loopback_pair() -> agrippa::runtime::Result<(Connection, Connection)> {
+    let a = verbs::connect().await?;
+    let b = verbs::connect().await?;
+    let (a_address, b_address) = (a.local_address(), b.local_address());
+    let a = a.connect(&b_address)?;
+    let b = b.connect(&a_address)?;
+    a.wait_until_rts().await?;
+    b.wait_until_rts().await?;
+    Ok((a, b))
+}
+
+#[test]
+fn free_buffers_drop_as_receives_are_posted() {
+    // Without an rdma device there is nothing to test
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let before = reactor.verbs_stats();
+    assert_eq!(before.recvs_posted, 0);
+
+    // The receives are posted when the reactor first processes the device
+    reactor.spawn(Priority::Normal, async { Ok(()) });
+    reactor.run().unwrap();
+
+    let after = reactor.verbs_stats();
+    assert!(after.recvs_posted > 0);
+    assert_eq!(after.free_buffers, before.free_buffers - after.recvs_posted);
+}
+
+#[test]
+fn recv_batch_drains_five_messages() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (a, b) = loopback_pair().await?;
+        for imm in 0..5 {
+            a.send_with_imm(verbs::get_buffer().await?, imm).await?;
+        }
+        // Let the reactor process the receive completions
+        sleep(Duration::from_millis(10)).await?;
+        let buffers = b.recv_batch(16).await?;
+        let imms: Vec<_> = buffers.iter().map(|b| b.imm()).collect();
+        assert_eq!(imms, (0..5).map(Some).collect::<Vec<_>>());
+        for buffer in buffers {
+            verbs::put_buffer(buffer).await?;
+        }
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn reconnect_after_qp_error() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (mut a, b) = loopback_pair().await?;
+        // With its peer gone, the send of a fails and a enters the error state
+        b.close().await?;
+        assert!(a.send(verbs::get_buffer().await?).await.is_err());
+        assert!(!a.is_alive()?);
+
+        let c = verbs::connect().await?;
+        a.reconnect(&c.local_address()).await?;
+        let c = c.connect(&a.local_address())?;
+        a.wait_until_rts().await?;
+        c.wait_until_rts().await?;
+        a.send_with_imm(verbs::get_buffer().await?, 7).await?;
+        let buffer = c.recv().await?;
+        assert_eq!(buffer.imm(), Some(7));
+        verbs::put_buffer(buffer).await?;
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn failed_send_returns_its_buffer_once() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let total = reactor.verbs_stats().free_buffers;
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (a, b) = loopback_pair().await?;
+        for _ in 0..10 {
+            a.send(verbs::get_buffer().await?).await?;
+            verbs::put_buffer(b.recv().await?).await?;
+        }
+        // With its peer gone the send fails, and its completion is reclaimed
+        // on the error path rather than the success path
+        b.close().await?;
+        assert!(a.send(verbs::get_buffer().await?).await.is_err());
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+    let stats = reactor.verbs_stats();
+    assert_eq!(stats.sends_in_flight, 0);
+    assert!(stats.failed_completions > 0);
+    // A buffer returned twice would show up as an extra buffer, a leaked one as a missing one
+    assert_eq!(stats.free_buffers + stats.recvs_posted, total);
+}
+
+#[test]
+fn pipelined_sends_and_receives_do_not_overflow_the_cq() {
+    const MESSAGES: u32 = 256;
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let received = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let (r, reactor2) = (received.clone(), reactor.clone());
+    reactor.spawn(Priority::Normal, async move {
+        let (a, b) = loopback_pair().await?;
+        reactor2.spawn(Priority::Normal, async move {
+            for _ in 0..MESSAGES {
+                let buffer = b.recv().await?;
+                r.borrow_mut().push(buffer.imm());
+                verbs::put_buffer(buffer).await?;
+            }
+            Ok(())
+        });
+        // A full window of sends in flight while the receives complete, more
+        // completions than the receive depth + 1 of the original sizing
+        let sends = (0..MESSAGES).map(|imm| {
+            let a = &a;
+            async move { a.send_with_imm(verbs::get_buffer().await?, imm).await }
+        });
+        for res in join_all(sends).await {
+            res?;
+        }
+        assert!(a.is_alive()?);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    // Sends waiting for room in the send queue may be posted out of order
+    let mut received = received.take();
+    received.sort();
+    assert_eq!(received, (0..MESSAGES).map(Some).collect::<Vec<_>>());
+    assert_eq!(reactor.verbs_stats().failed_completions, 0);
+
+    // A completion queue without room for sends after the receives is refused
+    let mut config = ReactorConfig::new();
+    config.verbs_buffer_size(4096).verbs_cq_size(1);
+    assert!(Reactor::with_config(&config).is_err());
+}
+
+#[test]
+fn send_to_unresponsive_peer_times_out() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        // The peer never connects, so it never acknowledges the send
+        let peer = verbs::connect().await?;
+        let a = verbs::connect().await?.connect(&peer.local_address())?;
+        a.wait_until_rts().await?;
+        let start = std::time::Instant::now();
+        let res = a
+            .send_timeout(verbs::get_buffer().await?, Duration::from_millis(20))
+            .await;
+        assert!(matches!(res, Err(agrippa::runtime::Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn timeout_ends_verbs_recv() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (a, b) = loopback_pair().await?;
+        let res = timeout(Duration::from_millis(20), b.recv()).await;
+        assert!(matches!(res, Err(agrippa::runtime::Error::Timeout)));
+        // The connection still receives after the timed out recv
+        a.send_with_imm(verbs::get_buffer().await?, 3).await?;
+        let buffer = timeout(Duration::from_secs(5), b.recv()).await?;
+        assert_eq!(buffer.imm(), Some(3));
+        verbs::put_buffer(buffer).await?;
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn immediate_data_reaches_the_receiver() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (a, b) = loopback_pair().await?;
+        a.send_with_imm(verbs::get_buffer().await?, 0xdead_beef)
+            .await?;
+        a.send(verbs::get_buffer().await?).await?;
+        let with_imm = b.recv().await?;
+        assert_eq!(with_imm.imm(), Some(0xdead_beef));
+        verbs::put_buffer(with_imm).await?;
+        // A plain send carries no immediate data
+        let without_imm = b.recv().await?;
+        assert_eq!(without_imm.imm(), None);
+        verbs::put_buffer(without_imm).await?;
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn send_gather_sends_one_message_and_validates_segments() {
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let (a, b) = loopback_pair().await?;
+        let header = verbs::get_buffer().await?;
+        let body = verbs::get_buffer().await?;
+        // Two segments arrive as a single message
+        a.send_gather(&[(&header, 0, 0), (&body, 0, 0)]).await?;
+        let received = b.recv().await?;
+        verbs::put_buffer(received).await?;
+        let res = timeout(Duration::from_millis(20), b.recv()).await;
+        assert!(matches!(res, Err(agrippa::runtime::Error::Timeout)));
+
+        let invalid_input = |res: agrippa::runtime::Result<()>| match res {
+            Err(e) => e.kind() == Some(std::io::ErrorKind::InvalidInput),
+            Ok(()) => false,
+        };
+        // Beyond the used part of the buffer
+        assert!(invalid_input(a.send_gather(&[(&header, 0, 1)]).await));
+        // More segments than the default of 4 send sges
+        let segments = vec![(&header, 0, 0); 5];
+        assert!(invalid_input(a.send_gather(&segments).await));
+        assert!(invalid_input(a.send_gather(&[]).await));
+        assert!(a.is_alive()?);
+        verbs::put_buffer(header).await?;
+        verbs::put_buffer(body).await?;
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}
+
+#[test]
+fn qp_state_follows_the_connection() {
+    use agrippa::verbs::QpState;
+    let reactor = match verbs_reactor() {
+        Some(reactor) => reactor,
+        None => return,
+    };
+    let done = Rc::new(Cell::new(false));
+    let d = done.clone();
+    reactor.spawn(Priority::Normal, async move {
+        let a = verbs::connect().await?;
+        let b = verbs::connect().await?;
+        assert_eq!(a.qp_state()?, QpState::Init);
+        assert_eq!(b.qp_state()?, QpState::Init);
+        let (a_address, b_address) = (a.local_address(), b.local_address());
+        let a = a.connect(&b_address)?;
+        let b = b.connect(&a_address)?;
+        a.wait_until_rts().await?;
+        assert_eq!(a.qp_state()?, QpState::ReadyToSend);
+        assert_eq!(b.qp_state()?, QpState::ReadyToSend);
+
+        // With its peer gone, a failed send moves a to the error state
+        b.close().await?;
+        assert!(a.send(verbs::get_buffer().await?).await.is_err());
+        assert_eq!(a.qp_state()?, QpState::Error);
+        let res = a.wait_until_rts().await;
+        assert_eq!(
+            res.unwrap_err().kind(),
+            Some(std::io::ErrorKind::ConnectionAborted)
+        );
+        d.set(true);
+        Ok(())
+    });
+    reactor.run().unwrap();
+    assert!(done.get());
+}